@@ -0,0 +1,62 @@
+//! Battery voltage and state of charge. Shared so that the server judges a battery with the
+//! same curve the device reports with.
+
+/// Open circuit voltage of a single Li-ion cell against its state of charge in percent,
+/// at room temperature and ordered by voltage.
+pub const LI_ION_CURVE: &[(f32, f32)] = &[
+    (3.00, 0.0),
+    (3.45, 5.0),
+    (3.68, 10.0),
+    (3.74, 20.0),
+    (3.77, 30.0),
+    (3.79, 40.0),
+    (3.82, 50.0),
+    (3.87, 60.0),
+    (3.92, 70.0),
+    (3.98, 80.0),
+    (4.06, 90.0),
+    (4.20, 100.0),
+];
+
+/// Battery voltage in volts from the millivolts measured behind a resistor divider with the
+/// given ratio, i.e. `(R_top + R_bottom) / R_bottom`.
+pub fn battery_voltage(pin_millivolts: u32, divider_ratio: f32) -> f32 {
+    pin_millivolts as f32 * divider_ratio / 1000.0
+}
+
+/// Mean of the samples without the lowest and highest quarter, so that a few spikes from the
+/// camera or the radio do not move it. `None` without samples.
+pub fn trimmed_mean(samples: &mut [u16]) -> Option<u16> {
+    if samples.is_empty() {
+        return None;
+    }
+    samples.sort_unstable();
+    let trim = samples.len() / 4;
+    let kept = &samples[trim..samples.len() - trim];
+    let sum: u32 = kept.iter().map(|&s| u32::from(s)).sum();
+    Some((sum / kept.len() as u32) as u16)
+}
+
+/// State of charge in percent for `voltage`, interpolated linearly between the points of
+/// `curve` and clamped to its ends.
+pub fn state_of_charge(voltage: f32, curve: &[(f32, f32)]) -> f32 {
+    let (Some(&(low_voltage, low_charge)), Some(&(high_voltage, high_charge))) =
+        (curve.first(), curve.last())
+    else {
+        return 0.0;
+    };
+    if voltage <= low_voltage {
+        return low_charge;
+    }
+    if voltage >= high_voltage {
+        return high_charge;
+    }
+    curve
+        .windows(2)
+        .find(|pair| voltage <= pair[1].0)
+        .map(|pair| {
+            let ((v0, c0), (v1, c1)) = (pair[0], pair[1]);
+            c0 + (voltage - v0) / (v1 - v0) * (c1 - c0)
+        })
+        .unwrap_or(high_charge)
+}
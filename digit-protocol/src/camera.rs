@@ -0,0 +1,202 @@
+//! Who owns the camera driver and the frames it lends out.
+//!
+//! There is at most one [`Camera`] at a time, it initialises the driver when created and
+//! deinitialises it on drop. A [`Frame`] borrows the camera and is handed back to the driver
+//! exactly once, when it is dropped, and its pixels borrow the frame. The driver itself is
+//! reached through [`Driver`], so that these rules can be checked on the host:
+//!
+//! ```
+//! # use digit_protocol::camera::{Camera, doctest::Fake};
+//! let camera = Camera::new(Fake).unwrap();
+//! let frame = camera.frame().unwrap();
+//! let pixels = frame.data();
+//! assert_eq!(pixels.len(), 4);
+//! drop(frame);
+//! drop(camera);
+//! ```
+//!
+//! Each of the following is the same with one line changed or added, and does not compile.
+//! The pixels cannot be read once their frame has been handed back:
+//!
+//! ```compile_fail
+//! # use digit_protocol::camera::{Camera, doctest::Fake};
+//! let camera = Camera::new(Fake).unwrap();
+//! let frame = camera.frame().unwrap();
+//! let pixels = frame.data();
+//! drop(frame);
+//! assert_eq!(pixels.len(), 4);
+//! drop(camera);
+//! ```
+//!
+//! A frame is only handed back by dropping it, and cannot be duplicated to be handed back
+//! twice:
+//!
+//! ```compile_fail
+//! # use digit_protocol::camera::{Camera, doctest::Fake};
+//! let camera = Camera::new(Fake).unwrap();
+//! let frame = camera.frame().unwrap();
+//! let pixels = frame.data();
+//! assert_eq!(pixels.len(), 4);
+//! let copy = frame.clone();
+//! drop(frame);
+//! drop(camera);
+//! ```
+//!
+//! And the driver cannot be deinitialised while a frame is in use:
+//!
+//! ```compile_fail
+//! # use digit_protocol::camera::{Camera, doctest::Fake};
+//! let camera = Camera::new(Fake).unwrap();
+//! let frame = camera.frame().unwrap();
+//! let pixels = frame.data();
+//! assert_eq!(pixels.len(), 4);
+//! drop(camera);
+//! drop(frame);
+//! ```
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A driver without a camera, for the examples above.
+#[doc(hidden)]
+pub mod doctest {
+    use super::Driver;
+    use core::sync::atomic::AtomicBool;
+
+    static INSTANCE: AtomicBool = AtomicBool::new(false);
+
+    pub struct Fake;
+
+    // SAFETY: the frames are arrays of their own, valid for as long as they are borrowed
+    unsafe impl Driver for Fake {
+        type Frame = [u8; 4];
+        type Error = ();
+
+        fn instance() -> &'static AtomicBool {
+            &INSTANCE
+        }
+
+        fn in_use() {}
+
+        fn init(&mut self) -> Result<(), ()> {
+            Ok(())
+        }
+
+        fn deinit(&mut self) {}
+
+        fn frame_get(&self) -> Option<[u8; 4]> {
+            Some([0; 4])
+        }
+
+        unsafe fn frame_return(&self, _: &[u8; 4]) {}
+
+        unsafe fn pixels(frame: &[u8; 4]) -> &[u8] {
+            frame
+        }
+    }
+}
+
+/// The calls into a camera driver of which there is a single instance.
+///
+/// # Safety
+///
+/// Every implementation for the same driver returns the same flag from [`instance`]. A frame
+/// from [`frame_get`] stays valid, and the pixels [`pixels`] returns for it unchanged, until it
+/// is passed to [`frame_return`] or the driver is deinitialised.
+///
+/// [`instance`]: Driver::instance
+/// [`frame_get`]: Driver::frame_get
+/// [`frame_return`]: Driver::frame_return
+/// [`pixels`]: Driver::pixels
+pub unsafe trait Driver {
+    /// Handle of a frame lent out by the driver.
+    type Frame;
+    type Error;
+
+    /// Set while the driver is initialised.
+    fn instance() -> &'static AtomicBool;
+    /// The error [`Camera::new`] fails with while there is a camera already.
+    fn in_use() -> Self::Error;
+    fn init(&mut self) -> Result<(), Self::Error>;
+    fn deinit(&mut self);
+    /// The next frame, `None` if the driver has none.
+    fn frame_get(&self) -> Option<Self::Frame>;
+
+    /// Hands `frame` back to the driver.
+    ///
+    /// # Safety
+    ///
+    /// `frame` came from [`frame_get`](Driver::frame_get) and has not been handed back yet.
+    unsafe fn frame_return(&self, frame: &Self::Frame);
+
+    /// The pixels of `frame`.
+    ///
+    /// # Safety
+    ///
+    /// `frame` came from [`frame_get`](Driver::frame_get) and has not been handed back yet.
+    unsafe fn pixels(frame: &Self::Frame) -> &[u8];
+}
+
+/// The initialised driver, deinitialised on drop.
+pub struct Camera<D: Driver> {
+    driver: D,
+}
+
+impl<D: Driver> Camera<D> {
+    /// Initialises `driver`, failing with [`Driver::in_use`] if there is a camera already. A
+    /// second initialisation would pull the frames out from under the first camera.
+    pub fn new(mut driver: D) -> Result<Self, D::Error> {
+        if D::instance().swap(true, Ordering::AcqRel) {
+            return Err(D::in_use());
+        }
+        if let Err(e) = driver.init() {
+            D::instance().store(false, Ordering::Release);
+            return Err(e);
+        }
+        Ok(Self { driver })
+    }
+
+    /// The next frame, `None` if the driver has none.
+    pub fn frame(&self) -> Option<Frame<'_, D>> {
+        self.driver.frame_get().map(|frame| Frame {
+            frame,
+            camera: self,
+        })
+    }
+
+    pub fn driver(&self) -> &D {
+        &self.driver
+    }
+}
+
+impl<D: Driver> Drop for Camera<D> {
+    fn drop(&mut self) {
+        self.driver.deinit();
+        D::instance().store(false, Ordering::Release);
+    }
+}
+
+/// A frame lent out by the [`Camera`], handed back to the driver on drop.
+pub struct Frame<'a, D: Driver> {
+    frame: D::Frame,
+    camera: &'a Camera<D>,
+}
+
+impl<D: Driver> Frame<'_, D> {
+    pub fn data(&self) -> &[u8] {
+        // SAFETY: the frame is only handed back on drop, and the camera it borrows is still
+        // initialised
+        unsafe { D::pixels(&self.frame) }
+    }
+
+    /// The driver's handle of the frame, e.g. to read what else it knows about it.
+    pub fn handle(&self) -> &D::Frame {
+        &self.frame
+    }
+}
+
+impl<D: Driver> Drop for Frame<'_, D> {
+    fn drop(&mut self) {
+        // SAFETY: the frame came from the driver and is dropped only once
+        unsafe { self.camera.driver.frame_return(&self.frame) }
+    }
+}
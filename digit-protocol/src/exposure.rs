@@ -0,0 +1,86 @@
+//! Scores the frames the camera takes at different exposures, so that it uploads the one the
+//! digits are best read from: sharp, with contrast and without glare on the meter glass.
+
+/// Pixels at or above this are blown out, usually by the flash reflecting off the glass.
+const HIGHLIGHT: u8 = 250;
+/// Pixels at or below this are crushed to black.
+const SHADOW: u8 = 5;
+/// Share of the pixels left out at either end when measuring the contrast, so that a few
+/// stray pixels do not count.
+const CONTRAST_TAIL: usize = 20;
+/// Sharpness is a small number even for a sharp photo, this brings it on par with the
+/// contrast.
+const SHARPNESS_WEIGHT: f32 = 10.0;
+/// Glare hides digits outright, which weighs more than a dull photo.
+const CLIPPING_WEIGHT: f32 = 2.0;
+
+/// How good a frame is for reading the digits, every measure from 0 to 1.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameScore {
+    /// Mean strength of the edges, from the Laplacian of the pixels.
+    pub sharpness: f32,
+    /// Spread between the dark and the bright pixels, ignoring the darkest and brightest
+    /// 1/[`CONTRAST_TAIL`] of them.
+    pub contrast: f32,
+    /// Share of the pixels that are blown out or crushed to black.
+    pub clipped: f32,
+}
+
+impl FrameScore {
+    /// The measures weighed against each other, higher is better.
+    pub fn total(&self) -> f32 {
+        SHARPNESS_WEIGHT * self.sharpness + self.contrast - CLIPPING_WEIGHT * self.clipped
+    }
+}
+
+/// Scores a grayscale frame with one byte per pixel, row by row. `None` if the pixels do not
+/// make up a frame of `width` by `height` or it is too small to have edges.
+pub fn score(pixels: &[u8], width: usize, height: usize) -> Option<FrameScore> {
+    if width < 3 || height < 3 || width.checked_mul(height)? != pixels.len() {
+        return None;
+    }
+
+    let mut histogram = [0usize; 256];
+    for &pixel in pixels {
+        histogram[usize::from(pixel)] += 1;
+    }
+    let clipped: usize = histogram[..=usize::from(SHADOW)].iter().sum::<usize>()
+        + histogram[usize::from(HIGHLIGHT)..].iter().sum::<usize>();
+    let tail = pixels.len() / CONTRAST_TAIL;
+    let dark = percentile(&histogram, tail);
+    let bright = percentile(&histogram, pixels.len() - 1 - tail);
+
+    // The four neighbour Laplacian of every pixel off the border
+    let mut edges: u64 = 0;
+    for y in 1..height - 1 {
+        let row = y * width;
+        for x in 1..width - 1 {
+            let i = row + x;
+            let laplacian = 4 * i32::from(pixels[i])
+                - i32::from(pixels[i - 1])
+                - i32::from(pixels[i + 1])
+                - i32::from(pixels[i - width])
+                - i32::from(pixels[i + width]);
+            edges += u64::from(laplacian.unsigned_abs());
+        }
+    }
+    let interior = ((width - 2) * (height - 2)) as f32;
+
+    Some(FrameScore {
+        sharpness: edges as f32 / interior / (4.0 * 255.0),
+        contrast: f32::from(bright - dark) / 255.0,
+        clipped: clipped as f32 / pixels.len() as f32,
+    })
+}
+
+/// The value of the pixel with `rank` darker pixels before it.
+fn percentile(histogram: &[usize; 256], rank: usize) -> u8 {
+    let mut seen = 0;
+    for (value, &count) in histogram.iter().enumerate() {
+        seen += count;
+        if seen > rank {
+            return value as u8;
+        }
+    }
+    u8::MAX
+}
@@ -0,0 +1,40 @@
+//! Hex encoding of the keys, hashes and signatures the camera and the server exchange.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+/// Lowercase hex of `bytes`.
+pub fn encode(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        let _ = write!(hex, "{byte:02x}");
+    }
+    hex
+}
+
+/// The bytes of upper or lowercase `hex`, `None` if it is not hex or has an odd length.
+pub fn decode(hex: &str) -> Option<Vec<u8>> {
+    let hex = hex.as_bytes();
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| Some(digit(pair[0])? << 4 | digit(pair[1])?))
+        .collect()
+}
+
+/// Like [`decode`], for exactly `N` bytes.
+pub fn decode_array<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    decode(hex)?.try_into().ok()
+}
+
+/// The value of a single hex digit.
+pub(crate) fn digit(digit: u8) -> Option<u8> {
+    match digit {
+        b'0'..=b'9' => Some(digit - b'0'),
+        b'a'..=b'f' => Some(digit - b'a' + 10),
+        b'A'..=b'F' => Some(digit - b'A' + 10),
+        _ => None,
+    }
+}
@@ -0,0 +1,233 @@
+//! Everything the camera and the server have to agree on, and the camera's logic that can be
+//! tested on the host.
+//!
+//! The messages they exchange are defined here, so that both sides share the exact same types.
+//! Every message carries the [`PROTOCOL_VERSION`] it was written for, so that the server can
+//! tell old firmware apart and reject what is older than [`MIN_SUPPORTED_VERSION`]. The
+//! [`battery`], [`ota`], [`roi`] and [`sensor`] modules hold what else both sides read the
+//! same way: the battery's discharge curve, how firmware releases are numbered and signed,
+//! which part of the photo the camera uploads and the image settings it takes it with.
+//!
+//! The camera's decisions live here as well, because the firmware only builds for the ESP32.
+//! The [`camera`] module decides who owns the camera driver and its frames, the [`queue`]
+//! module keeps its backlog of check-ins, the [`exposure`] module scores the frames it takes,
+//! the [`supervisor`] module decides how a wake copes with failures and the [`settings`]
+//! module validates what it is provisioned with. The crate is `no_std` so that all of it runs
+//! on the camera unchanged.
+#![no_std]
+
+extern crate alloc;
+
+pub mod battery;
+pub mod camera;
+pub mod exposure;
+pub mod hex;
+pub mod ota;
+pub mod queue;
+pub mod roi;
+pub mod sensor;
+pub mod settings;
+pub mod supervisor;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use chrono::{DateTime, FixedOffset};
+use serde::{Deserialize, Serialize};
+
+/// Version of the messages defined here. Bump it whenever a change is not backwards compatible,
+/// fields that older readers can do without are added without one.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Oldest version of the messages the server still accepts. A device running older firmware
+/// has to keep being accepted until it has been offered an update.
+pub const MIN_SUPPORTED_VERSION: u16 = 1;
+
+/// A point in time together with the UTC offset of the device's clock, serialised as RFC 3339.
+pub type Timestamp = DateTime<FixedOffset>;
+
+/// Battery report sent by the camera to `/health`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HealthRequest {
+    pub version: u16,
+    pub device_id: String,
+    pub firmware_version: String,
+    pub timestamp: Timestamp,
+    /// Battery voltage in volts, `None` if the device could not measure it.
+    pub voltage: Option<f32>,
+    /// Estimated state of charge of the battery in percent, `None` if the device cannot tell.
+    #[serde(default)]
+    pub state_of_charge: Option<f32>,
+    /// Details for finding out why a device misbehaves.
+    #[serde(default)]
+    pub diagnostics: Diagnostics,
+}
+
+/// What a device knows about its own state. Everything is optional, a device leaves out what
+/// it cannot measure.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Diagnostics {
+    /// Signal strength of the access point in dBm.
+    pub wifi_rssi: Option<i8>,
+    pub wifi_channel: Option<u8>,
+    /// MAC address of the access point, e.g. `a4:2b:b0:12:34:56`.
+    pub wifi_bssid: Option<String>,
+    /// Why the chip last started, e.g. `power_on`, `deep_sleep` or `panic`.
+    pub reset_reason: Option<String>,
+    /// What woke the chip from deep sleep, e.g. `timer`.
+    pub wake_reason: Option<String>,
+    /// Milliseconds since the chip started, when the request was sent.
+    pub uptime_ms: Option<u64>,
+    /// Milliseconds it took to associate with the access point.
+    pub associate_ms: Option<u32>,
+    /// Milliseconds it took to get an address over DHCP.
+    pub dhcp_ms: Option<u32>,
+    /// Milliseconds it took to synchronise the clock over SNTP.
+    pub sntp_ms: Option<u32>,
+    /// Free heap in bytes.
+    pub free_heap: Option<u32>,
+    /// Free PSRAM in bytes.
+    pub free_psram: Option<u32>,
+    /// Chip temperature in °C.
+    pub chip_temperature: Option<f32>,
+    /// Commit the firmware was built from.
+    pub build_hash: Option<String>,
+    /// Milliseconds the previous wake spent taking the photo.
+    pub previous_capture_ms: Option<u32>,
+    /// Milliseconds the previous wake spent uploading.
+    pub previous_upload_ms: Option<u32>,
+}
+
+/// Answer to a [`HealthRequest`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HealthResponse {
+    pub version: u16,
+    /// The server's clock when the report arrived.
+    pub received_at: Timestamp,
+}
+
+/// Describes the photo in an upload. Sent as a JSON `metadata` field before the `file` field
+/// of the multipart body.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UploadMetadata {
+    pub version: u16,
+    pub device_id: String,
+    pub firmware_version: String,
+    /// When the photo was taken.
+    pub captured_at: Timestamp,
+    /// Brightness of the flash LED in percent while the photo was taken, 0 if it was off.
+    #[serde(default)]
+    pub flash_brightness: Option<u8>,
+}
+
+/// Answer to an upload, one entry for every photo in it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UploadResponse {
+    pub version: u16,
+    pub files: Vec<UploadedFile>,
+    /// When the device should wake up next.
+    pub next_wake: Timestamp,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UploadedFile {
+    /// Name the server stored the photo under.
+    pub file_name: String,
+    /// The same photo had already been uploaded, nothing new was stored.
+    pub duplicate: bool,
+}
+
+/// Telemetry sent as the JSON `telemetry` field of a check-in, the single request the device
+/// makes per wake. The photo follows it in the `file` field, if the device has one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckinRequest {
+    pub version: u16,
+    pub device_id: String,
+    pub firmware_version: String,
+    pub timestamp: Timestamp,
+    /// Battery voltage in volts, `None` if the device could not measure it.
+    pub voltage: Option<f32>,
+    /// Estimated state of charge of the battery in percent, `None` if the device cannot tell.
+    #[serde(default)]
+    pub state_of_charge: Option<f32>,
+    /// Details for finding out why a device misbehaves.
+    #[serde(default)]
+    pub diagnostics: Diagnostics,
+    /// When the photo was taken, `None` without a photo.
+    pub captured_at: Option<Timestamp>,
+    /// Brightness of the flash LED in percent while the photo was taken, 0 if it was off and
+    /// `None` without a photo.
+    #[serde(default)]
+    pub flash_brightness: Option<u8>,
+    /// Revision of the [`DeviceConfig`] the device is running with, 0 for its built-in one.
+    pub config_revision: u32,
+}
+
+/// Answer to a check-in.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CheckinResponse {
+    pub version: u16,
+    /// Where the photo was stored, `None` if the check-in had none.
+    pub file: Option<UploadedFile>,
+    /// When the device should wake up next.
+    pub next_wake: Timestamp,
+    /// Settings newer than the device's `config_revision`, if there are any.
+    pub config: Option<DeviceConfig>,
+    /// A firmware release newer than the one the device runs, if there is one for it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub firmware: Option<FirmwareUpdate>,
+}
+
+/// A firmware release offered to a device. The device downloads it from `path` with a signed
+/// `GET` and installs it only if the image matches `size` and `sha256` and the signature is
+/// valid for the key built into the device.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FirmwareUpdate {
+    /// Version of the release, see [`ota::FirmwareVersion`].
+    pub version: String,
+    /// Size of the image in bytes.
+    pub size: u32,
+    /// Hex encoded SHA-256 of the image.
+    pub sha256: String,
+    /// Hex encoded Ed25519 signature over [`ota::signed_message`].
+    pub signature: String,
+    /// Path on the server to download the image from.
+    pub path: String,
+}
+
+/// Settings the server asks a device to use from its next wake on. Settings that are not
+/// given keep the device's built-in value.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    /// Increases with every change, so the device can tell whether it is up to date.
+    pub revision: u32,
+    /// Light the flash LED while taking the photo.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flash: Option<bool>,
+    /// Brightness of the flash LED in percent, from 1 to 100.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flash_brightness: Option<u8>,
+    /// Milliseconds the flash LED is lit before the first frame, for its light to settle.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flash_warm_up_ms: Option<u16>,
+    /// Upload only this part of the photo instead of the whole frame.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub roi: Option<roi::Roi>,
+    /// Image settings of the camera sensor.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensor: Option<sensor::SensorProfile>,
+}
+
+/// Body of the server's error responses.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    /// Short machine readable name of the error.
+    pub error: String,
+    pub message: String,
+    /// Sending the same request again later may succeed.
+    pub retryable: bool,
+    /// The server's clock, given when the request was turned away because the device's clock
+    /// is off, so that the device can set it before trying again.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_time: Option<Timestamp>,
+}
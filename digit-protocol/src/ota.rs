@@ -0,0 +1,84 @@
+//! What the camera and the server need to agree on for firmware updates.
+//!
+//! Releases are numbered `major.minor.patch` and signed with Ed25519 by whoever builds them.
+//! The signature covers the [`signed_message`] of the release, which binds the SHA-256 of the
+//! image to its version, so that an old image cannot be offered under a newer version. The
+//! camera only installs a version [`is_newer`] than its own, so that it cannot be rolled back
+//! to an older release either.
+
+use alloc::format;
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+
+/// Prefix of the signed message, so that a signature over anything else is never mistaken
+/// for the signature of a release.
+const SIGNATURE_CONTEXT: &[u8] = b"digit-firmware\n";
+
+/// Version of a firmware release, e.g. `1.4.2`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FirmwareVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidVersion;
+
+impl fmt::Display for InvalidVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "firmware version must be major.minor.patch, e.g. 1.4.2")
+    }
+}
+
+impl FromStr for FirmwareVersion {
+    type Err = InvalidVersion;
+
+    fn from_str(version: &str) -> Result<Self, InvalidVersion> {
+        let mut parts = version.split('.').map(|part| {
+            // Leading zeros or signs would give one version several spellings
+            let canonical = !part.is_empty()
+                && part.bytes().all(|b| b.is_ascii_digit())
+                && (part == "0" || !part.starts_with('0'));
+            canonical
+                .then(|| part.parse().ok())
+                .flatten()
+                .ok_or(InvalidVersion)
+        });
+        let version = FirmwareVersion {
+            major: parts.next().ok_or(InvalidVersion)??,
+            minor: parts.next().ok_or(InvalidVersion)??,
+            patch: parts.next().ok_or(InvalidVersion)??,
+        };
+        match parts.next() {
+            Some(_) => Err(InvalidVersion),
+            None => Ok(version),
+        }
+    }
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Whether a device running `current` should install `offered`. A device whose version
+/// cannot be parsed, e.g. a development build, is never updated behind its developer's back.
+pub fn is_newer(current: &str, offered: FirmwareVersion) -> bool {
+    current
+        .parse::<FirmwareVersion>()
+        .is_ok_and(|current| offered > current)
+}
+
+/// The message a release is signed over: a fixed context, the version and the SHA-256 of
+/// the image.
+pub fn signed_message(version: FirmwareVersion, sha256: &[u8; 32]) -> Vec<u8> {
+    let version = format!("{version}\n");
+    let mut message = Vec::with_capacity(SIGNATURE_CONTEXT.len() + version.len() + sha256.len());
+    message.extend_from_slice(SIGNATURE_CONTEXT);
+    message.extend_from_slice(version.as_bytes());
+    message.extend_from_slice(sha256);
+    message
+}
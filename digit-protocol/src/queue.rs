@@ -0,0 +1,148 @@
+//! Check-ins the camera could not deliver, kept until the server has acknowledged them.
+//!
+//! Every entry is the serialised [`CheckinRequest`](crate::CheckinRequest) and, if the camera
+//! took one, its photo. They are stored as files named after an increasing sequence number,
+//! `00000042.JSN` and `00000042.JPG`, which fit the 8.3 names of a FAT formatted SD card. The
+//! photo is written before the telemetry and removed after it, so an entry exists exactly when
+//! its telemetry file does, even if the power is lost in between.
+
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+const TELEMETRY_EXTENSION: &str = "JSN";
+const IMAGE_EXTENSION: &str = "JPG";
+
+/// A flat directory of files the queue is kept in.
+pub trait QueueStorage {
+    type Error;
+
+    /// Names of all files, in any order.
+    fn files(&mut self) -> Result<Vec<String>, Self::Error>;
+    fn read(&mut self, name: &str) -> Result<Vec<u8>, Self::Error>;
+    /// Creates the file or replaces its contents.
+    fn write(&mut self, name: &str, data: &[u8]) -> Result<(), Self::Error>;
+    fn remove(&mut self, name: &str) -> Result<(), Self::Error>;
+}
+
+/// A check-in waiting to be sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedCheckin {
+    /// Position in the queue, pass it to [`OfflineQueue::acknowledge`] once delivered.
+    pub sequence: u32,
+    pub telemetry: Vec<u8>,
+    pub image: Option<Vec<u8>>,
+}
+
+/// A queue of at most `capacity` check-ins, delivered oldest first.
+pub struct OfflineQueue<S> {
+    storage: S,
+    capacity: usize,
+}
+
+impl<S: QueueStorage> OfflineQueue<S> {
+    /// Opens the queue kept in `storage` and removes photos left behind by an interrupted
+    /// write. A `capacity` of 0 is taken as 1.
+    pub fn open(mut storage: S, capacity: usize) -> Result<Self, S::Error> {
+        let files = storage.files()?;
+        let entries = sequences(&files, TELEMETRY_EXTENSION);
+        for sequence in sequences(&files, IMAGE_EXTENSION) {
+            if entries.binary_search(&sequence).is_err() {
+                storage.remove(&file_name(sequence, IMAGE_EXTENSION))?;
+            }
+        }
+        Ok(Self {
+            storage,
+            capacity: capacity.max(1),
+        })
+    }
+
+    /// Sequence numbers of the queued check-ins, oldest first.
+    fn entries(&mut self) -> Result<Vec<u32>, S::Error> {
+        Ok(sequences(&self.storage.files()?, TELEMETRY_EXTENSION))
+    }
+
+    pub fn len(&mut self) -> Result<usize, S::Error> {
+        Ok(self.entries()?.len())
+    }
+
+    pub fn is_empty(&mut self) -> Result<bool, S::Error> {
+        Ok(self.entries()?.is_empty())
+    }
+
+    /// Adds a check-in at the end of the queue. When the queue is full the oldest check-ins
+    /// are dropped to make room, their number is returned.
+    pub fn push(&mut self, telemetry: &[u8], image: Option<&[u8]>) -> Result<usize, S::Error> {
+        let entries = self.entries()?;
+        let dropped = (entries.len() + 1).saturating_sub(self.capacity);
+        for &sequence in &entries[..dropped] {
+            self.acknowledge(sequence)?;
+        }
+
+        let sequence = entries.last().map_or(1, |last| last + 1);
+        if let Some(image) = image {
+            self.storage
+                .write(&file_name(sequence, IMAGE_EXTENSION), image)?;
+        }
+        self.storage
+            .write(&file_name(sequence, TELEMETRY_EXTENSION), telemetry)?;
+        Ok(dropped)
+    }
+
+    /// The check-in that has waited longest, if any.
+    pub fn oldest(&mut self) -> Result<Option<QueuedCheckin>, S::Error> {
+        let files = self.storage.files()?;
+        let Some(&sequence) = sequences(&files, TELEMETRY_EXTENSION).first() else {
+            return Ok(None);
+        };
+        let image_name = file_name(sequence, IMAGE_EXTENSION);
+        let image = if files.contains(&image_name) {
+            Some(self.storage.read(&image_name)?)
+        } else {
+            None
+        };
+        Ok(Some(QueuedCheckin {
+            sequence,
+            telemetry: self
+                .storage
+                .read(&file_name(sequence, TELEMETRY_EXTENSION))?,
+            image,
+        }))
+    }
+
+    /// Removes the check-in `sequence` once the server has it, or it has to be given up on.
+    pub fn acknowledge(&mut self, sequence: u32) -> Result<(), S::Error> {
+        let files = self.storage.files()?;
+        for extension in [TELEMETRY_EXTENSION, IMAGE_EXTENSION] {
+            let name = file_name(sequence, extension);
+            if files.contains(&name) {
+                self.storage.remove(&name)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+}
+
+fn file_name(sequence: u32, extension: &str) -> String {
+    format!("{sequence:08}.{extension}")
+}
+
+/// Sorted sequence numbers of the files with `extension`. Other files are ignored.
+fn sequences(files: &[String], extension: &str) -> Vec<u32> {
+    let mut sequences: Vec<u32> = files
+        .iter()
+        .filter_map(|name| {
+            let (stem, ext) = name.split_once('.')?;
+            if !ext.eq_ignore_ascii_case(extension) || stem.len() != 8 {
+                return None;
+            }
+            stem.parse().ok()
+        })
+        .collect();
+    sequences.sort_unstable();
+    sequences
+}
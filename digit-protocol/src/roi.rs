@@ -0,0 +1,193 @@
+//! The region of interest: the part of the photo that shows the register, cut out and turned
+//! upright on the camera so that only a strip of the dial is uploaded instead of the whole
+//! frame.
+
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+use serde::{Deserialize, Serialize};
+
+/// Size of the full frame the region is given in, the UXGA resolution of the OV2640.
+pub const FRAME_WIDTH: u32 = 1600;
+pub const FRAME_HEIGHT: u32 = 1200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoiError {
+    /// The region has no pixels.
+    Empty,
+    /// The region reaches beyond the frame.
+    OutsideFrame,
+    /// The frame is not one byte per pixel of the given size.
+    FrameSize,
+    /// Not `x,y,width,height`.
+    Invalid,
+    /// Not one of 0, 90, 180 or 270 degrees.
+    InvalidRotation(u16),
+}
+
+impl fmt::Display for RoiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RoiError::Empty => write!(f, "region is empty"),
+            RoiError::OutsideFrame => write!(
+                f,
+                "region reaches beyond the {FRAME_WIDTH}x{FRAME_HEIGHT} frame"
+            ),
+            RoiError::FrameSize => write!(f, "frame does not match its size"),
+            RoiError::Invalid => write!(f, "region must be given as x,y,width,height"),
+            RoiError::InvalidRotation(degrees) => write!(
+                f,
+                "rotation must be 0, 90, 180 or 270 degrees, not {degrees}"
+            ),
+        }
+    }
+}
+
+/// Clockwise rotation that turns the cut out region upright.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "u16", try_from = "u16")]
+pub enum Rotation {
+    #[default]
+    None,
+    Quarter,
+    Half,
+    ThreeQuarters,
+}
+
+impl Rotation {
+    pub fn degrees(self) -> u16 {
+        match self {
+            Rotation::None => 0,
+            Rotation::Quarter => 90,
+            Rotation::Half => 180,
+            Rotation::ThreeQuarters => 270,
+        }
+    }
+}
+
+impl From<Rotation> for u16 {
+    fn from(rotation: Rotation) -> Self {
+        rotation.degrees()
+    }
+}
+
+impl TryFrom<u16> for Rotation {
+    type Error = RoiError;
+
+    fn try_from(degrees: u16) -> Result<Self, RoiError> {
+        match degrees {
+            0 => Ok(Rotation::None),
+            90 => Ok(Rotation::Quarter),
+            180 => Ok(Rotation::Half),
+            270 => Ok(Rotation::ThreeQuarters),
+            _ => Err(RoiError::InvalidRotation(degrees)),
+        }
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = RoiError;
+
+    fn from_str(degrees: &str) -> Result<Self, RoiError> {
+        let degrees = degrees
+            .trim()
+            .parse::<u16>()
+            .map_err(|_| RoiError::Invalid)?;
+        Rotation::try_from(degrees)
+    }
+}
+
+/// A rectangle of the frame in pixels, counted from its top left corner, and the rotation
+/// that turns it upright.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Roi {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    #[serde(default)]
+    pub rotation: Rotation,
+}
+
+impl Roi {
+    /// Checks that the region has pixels and lies within the frame.
+    pub fn validate(&self) -> Result<(), RoiError> {
+        if self.width == 0 || self.height == 0 {
+            return Err(RoiError::Empty);
+        }
+        let inside = self
+            .x
+            .checked_add(self.width)
+            .is_some_and(|right| right <= FRAME_WIDTH)
+            && self
+                .y
+                .checked_add(self.height)
+                .is_some_and(|bottom| bottom <= FRAME_HEIGHT);
+        if !inside {
+            return Err(RoiError::OutsideFrame);
+        }
+        Ok(())
+    }
+
+    /// Width and height of the region once it is turned upright.
+    pub fn output_size(&self) -> (u32, u32) {
+        match self.rotation {
+            Rotation::None | Rotation::Half => (self.width, self.height),
+            Rotation::Quarter | Rotation::ThreeQuarters => (self.height, self.width),
+        }
+    }
+
+    /// The pixel of the frame that ends up at `(x, y)` of the upright region.
+    fn source(&self, x: u32, y: u32) -> (u32, u32) {
+        let (column, row) = match self.rotation {
+            Rotation::None => (x, y),
+            Rotation::Quarter => (y, self.height - 1 - x),
+            Rotation::Half => (self.width - 1 - x, self.height - 1 - y),
+            Rotation::ThreeQuarters => (self.width - 1 - y, x),
+        };
+        (self.x + column, self.y + row)
+    }
+
+    /// Cuts the region out of a grayscale frame of [`FRAME_WIDTH`] by [`FRAME_HEIGHT`] with
+    /// one byte per pixel, row by row, and turns it upright. The result is
+    /// [`Self::output_size`] pixels in the same layout.
+    pub fn crop(&self, frame: &[u8]) -> Result<Vec<u8>, RoiError> {
+        self.validate()?;
+        if frame.len() != (FRAME_WIDTH * FRAME_HEIGHT) as usize {
+            return Err(RoiError::FrameSize);
+        }
+        let (width, height) = self.output_size();
+        let mut pixels = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let (column, row) = self.source(x, y);
+                pixels.push(frame[(row * FRAME_WIDTH + column) as usize]);
+            }
+        }
+        Ok(pixels)
+    }
+}
+
+/// Parses `x,y,width,height`, the region is not rotated.
+impl FromStr for Roi {
+    type Err = RoiError;
+
+    fn from_str(region: &str) -> Result<Self, RoiError> {
+        let mut numbers = region.split(',').map(|n| n.trim().parse::<u32>());
+        let mut next = || match numbers.next() {
+            Some(Ok(n)) => Ok(n),
+            _ => Err(RoiError::Invalid),
+        };
+        let roi = Roi {
+            x: next()?,
+            y: next()?,
+            width: next()?,
+            height: next()?,
+            rotation: Rotation::None,
+        };
+        if numbers.next().is_some() {
+            return Err(RoiError::Invalid);
+        }
+        Ok(roi)
+    }
+}
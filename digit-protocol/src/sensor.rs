@@ -0,0 +1,172 @@
+//! Image settings of the camera sensor, sent by the server to suit the light at a meter. Every
+//! setting is checked against the range the OV2640 supports when it is parsed, so a profile
+//! that reaches the camera can be applied as is.
+
+use core::fmt;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorError {
+    /// A level outside of [`Level::MIN`] to [`Level::MAX`].
+    InvalidLevel(i8),
+    /// Not one of the gain ceilings the sensor has.
+    InvalidGainCeiling(u8),
+}
+
+impl fmt::Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SensorError::InvalidLevel(level) => write!(
+                f,
+                "level must be from {} to {}, not {level}",
+                Level::MIN,
+                Level::MAX
+            ),
+            SensorError::InvalidGainCeiling(gain) => write!(
+                f,
+                "gain ceiling must be 2, 4, 8, 16, 32, 64 or 128, not {gain}"
+            ),
+        }
+    }
+}
+
+/// A setting the sensor adjusts in steps around its default of 0.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "i8", try_from = "i8")]
+pub struct Level(i8);
+
+impl Level {
+    pub const MIN: i8 = -2;
+    pub const MAX: i8 = 2;
+
+    pub fn new(level: i8) -> Result<Self, SensorError> {
+        if (Self::MIN..=Self::MAX).contains(&level) {
+            Ok(Level(level))
+        } else {
+            Err(SensorError::InvalidLevel(level))
+        }
+    }
+
+    pub fn get(self) -> i8 {
+        self.0
+    }
+}
+
+impl From<Level> for i8 {
+    fn from(level: Level) -> Self {
+        level.0
+    }
+}
+
+impl TryFrom<i8> for Level {
+    type Error = SensorError;
+
+    fn try_from(level: i8) -> Result<Self, SensorError> {
+        Level::new(level)
+    }
+}
+
+/// The most the automatic gain may amplify the signal. Higher brightens dark scenes at the
+/// cost of noise.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "u8", try_from = "u8")]
+pub enum GainCeiling {
+    X2,
+    X4,
+    X8,
+    X16,
+    X32,
+    X64,
+    X128,
+}
+
+impl GainCeiling {
+    const ALL: [GainCeiling; 7] = [
+        GainCeiling::X2,
+        GainCeiling::X4,
+        GainCeiling::X8,
+        GainCeiling::X16,
+        GainCeiling::X32,
+        GainCeiling::X64,
+        GainCeiling::X128,
+    ];
+
+    /// The factor, from 2 to 128.
+    pub fn gain(self) -> u8 {
+        2 << self.index()
+    }
+
+    /// The value the sensor driver takes, from 0 for 2x to 6 for 128x.
+    pub fn index(self) -> u8 {
+        self as u8
+    }
+}
+
+impl From<GainCeiling> for u8 {
+    fn from(ceiling: GainCeiling) -> Self {
+        ceiling.gain()
+    }
+}
+
+impl TryFrom<u8> for GainCeiling {
+    type Error = SensorError;
+
+    fn try_from(gain: u8) -> Result<Self, SensorError> {
+        GainCeiling::ALL
+            .into_iter()
+            .find(|ceiling| ceiling.gain() == gain)
+            .ok_or(SensorError::InvalidGainCeiling(gain))
+    }
+}
+
+/// How the sensor balances the colours. Photos are taken in grayscale, but the balance still
+/// weighs the colour channels the gray is made of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WhiteBalance {
+    Off,
+    Auto,
+    Sunny,
+    Cloudy,
+    Office,
+    Home,
+}
+
+impl WhiteBalance {
+    /// The mode the sensor driver takes, `None` for no white balance at all.
+    pub fn mode(self) -> Option<u8> {
+        match self {
+            WhiteBalance::Off => None,
+            WhiteBalance::Auto => Some(0),
+            WhiteBalance::Sunny => Some(1),
+            WhiteBalance::Cloudy => Some(2),
+            WhiteBalance::Office => Some(3),
+            WhiteBalance::Home => Some(4),
+        }
+    }
+}
+
+/// Image settings of the sensor. Settings that are not given keep the sensor's default.
+/// Exposure and gain are left out, the camera sets them for every frame it brackets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SensorProfile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub brightness: Option<Level>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contrast: Option<Level>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub saturation: Option<Level>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gain_ceiling: Option<GainCeiling>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub white_balance: Option<WhiteBalance>,
+    /// Mirror the frame left to right.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hmirror: Option<bool>,
+    /// Flip the frame upside down.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vflip: Option<bool>,
+    /// Brighten the corners the lens leaves darker.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lens_correction: Option<bool>,
+}
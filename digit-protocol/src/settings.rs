@@ -0,0 +1,266 @@
+//! Settings the camera is provisioned with instead of having them compiled in.
+//!
+//! They are entered in a form the camera serves from its own access point on first boot, and
+//! kept in its NVS flash as JSON.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use serde::{Deserialize, Serialize};
+
+use crate::hex;
+
+/// Longest SSID Wi-Fi allows, in bytes.
+const MAX_SSID_LEN: usize = 32;
+/// Shortest and longest WPA2 passphrase, in bytes.
+const PASSWORD_LEN: core::ops::RangeInclusive<usize> = 8..=63;
+const MAX_DEVICE_ID_LEN: usize = 64;
+const MAX_SERVER_ADDRESS_LEN: usize = 256;
+/// Room for a certificate with an RSA 4096 or smaller key.
+const MAX_SERVER_CERTIFICATE_LEN: usize = 3072;
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    pub wifi_ssid: String,
+    /// Empty for an open network.
+    pub wifi_password: String,
+    /// Address of the server without a trailing slash, e.g. `http://synology:3000`.
+    pub server_address: String,
+    /// PEM certificate an `https://` server must be signed with, either the CA that issued
+    /// its certificate or, for a self-signed one, the certificate itself. No other
+    /// certificate authority is trusted. Empty for plain HTTP.
+    #[serde(default)]
+    pub server_certificate: String,
+    /// Name the server knows this device by, see `digit-server keys add`. Empty for the id
+    /// derived from the MAC address, see [`device_id_from_mac`].
+    pub device_id: String,
+    /// Hex encoded key the requests are signed with, as printed by `digit-server keys add`.
+    /// A server with authentication turned off ignores it.
+    pub device_key: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingsError {
+    /// A required field is empty.
+    Missing(&'static str),
+    /// A field is longer than allowed, in bytes.
+    TooLong(&'static str, usize),
+    InvalidPassword,
+    InvalidServerAddress,
+    InvalidServerCertificate,
+    InvalidDeviceId,
+    InvalidDeviceKey,
+    /// The form is not valid URL encoded UTF-8.
+    InvalidForm,
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Missing(field) => write!(f, "{field} is missing"),
+            SettingsError::TooLong(field, max) => {
+                write!(f, "{field} is longer than {max} bytes")
+            }
+            SettingsError::InvalidPassword => write!(
+                f,
+                "Wi-Fi password must be empty or {} to {} characters",
+                PASSWORD_LEN.start(),
+                PASSWORD_LEN.end()
+            ),
+            SettingsError::InvalidServerAddress => {
+                write!(f, "server address must start with http:// or https://")
+            }
+            SettingsError::InvalidServerCertificate => {
+                write!(f, "server certificate must be a single PEM certificate")
+            }
+            SettingsError::InvalidDeviceId => {
+                write!(f, "device id may only contain letters, digits, '-' and '_'")
+            }
+            SettingsError::InvalidDeviceKey => write!(f, "device key must be hex encoded"),
+            SettingsError::InvalidForm => write!(f, "form is not valid"),
+        }
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<(), SettingsError> {
+        if self.wifi_ssid.is_empty() {
+            return Err(SettingsError::Missing("Wi-Fi name"));
+        }
+        if self.wifi_ssid.len() > MAX_SSID_LEN {
+            return Err(SettingsError::TooLong("Wi-Fi name", MAX_SSID_LEN));
+        }
+        if !self.wifi_password.is_empty() && !PASSWORD_LEN.contains(&self.wifi_password.len()) {
+            return Err(SettingsError::InvalidPassword);
+        }
+
+        if self.server_address.is_empty() {
+            return Err(SettingsError::Missing("server address"));
+        }
+        if self.server_address.len() > MAX_SERVER_ADDRESS_LEN {
+            return Err(SettingsError::TooLong(
+                "server address",
+                MAX_SERVER_ADDRESS_LEN,
+            ));
+        }
+        let host = self
+            .server_address
+            .strip_prefix("http://")
+            .or_else(|| self.server_address.strip_prefix("https://"))
+            .ok_or(SettingsError::InvalidServerAddress)?;
+        if host.is_empty() || host.contains(char::is_whitespace) || host.ends_with('/') {
+            return Err(SettingsError::InvalidServerAddress);
+        }
+        self.validate_server_certificate()?;
+
+        if self.device_id.len() > MAX_DEVICE_ID_LEN {
+            return Err(SettingsError::TooLong("device id", MAX_DEVICE_ID_LEN));
+        }
+        let valid_id = self
+            .device_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid_id {
+            return Err(SettingsError::InvalidDeviceId);
+        }
+
+        if self.device_key.is_empty() {
+            return Err(SettingsError::Missing("device key"));
+        }
+        self.device_key()?;
+        Ok(())
+    }
+
+    /// Whether the server is reached over HTTPS, verified against [`Self::server_certificate`].
+    pub fn uses_tls(&self) -> bool {
+        self.server_address.starts_with("https://")
+    }
+
+    fn validate_server_certificate(&self) -> Result<(), SettingsError> {
+        let certificate = self.server_certificate.as_str();
+        // HTTPS is only spoken with a pinned certificate
+        if certificate.is_empty() && self.uses_tls() {
+            return Err(SettingsError::Missing("server certificate"));
+        }
+        if certificate.is_empty() {
+            return Ok(());
+        }
+        if certificate.len() > MAX_SERVER_CERTIFICATE_LEN {
+            return Err(SettingsError::TooLong(
+                "server certificate",
+                MAX_SERVER_CERTIFICATE_LEN,
+            ));
+        }
+        let body = certificate
+            .strip_prefix(PEM_BEGIN)
+            .and_then(|rest| rest.trim_end().strip_suffix(PEM_END))
+            .ok_or(SettingsError::InvalidServerCertificate)?;
+        let base64 = body
+            .chars()
+            .filter(|c| !c.is_ascii_whitespace())
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '/' | '='));
+        if !base64 || body.trim().is_empty() {
+            return Err(SettingsError::InvalidServerCertificate);
+        }
+        Ok(())
+    }
+
+    /// The device key decoded from hex.
+    pub fn device_key(&self) -> Result<Vec<u8>, SettingsError> {
+        hex::decode(&self.device_key).ok_or(SettingsError::InvalidDeviceKey)
+    }
+
+    /// Reads the settings from a submitted `application/x-www-form-urlencoded` form with the
+    /// fields named like the settings. An empty password or key keeps the one of `current`,
+    /// so that they need not be entered again to change something else; `clear_password`
+    /// empties the password instead. An empty certificate likewise keeps the current one
+    /// while the address stays on HTTPS.
+    pub fn from_form(form: &[u8], current: Option<&Settings>) -> Result<Self, SettingsError> {
+        let form = core::str::from_utf8(form).map_err(|_| SettingsError::InvalidForm)?;
+        let mut settings = Settings {
+            wifi_ssid: String::new(),
+            wifi_password: String::new(),
+            server_address: String::new(),
+            server_certificate: String::new(),
+            device_id: String::new(),
+            device_key: String::new(),
+        };
+        let mut clear_password = false;
+        for pair in form.split('&').filter(|pair| !pair.is_empty()) {
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let raw = url_decode(value)?;
+            let value = raw.trim();
+            match url_decode(name)?.as_str() {
+                "wifi_ssid" => settings.wifi_ssid = value.to_string(),
+                // Spaces around a passphrase are part of it
+                "wifi_password" => settings.wifi_password = raw.clone(),
+                "server_address" => {
+                    settings.server_address = value.trim_end_matches('/').to_string()
+                }
+                "server_certificate" => settings.server_certificate = normalize_pem(value),
+                "device_id" => settings.device_id = value.to_string(),
+                "device_key" => settings.device_key = value.to_ascii_lowercase(),
+                "clear_password" => clear_password = true,
+                _ => {}
+            }
+        }
+
+        if let Some(current) = current {
+            if settings.wifi_password.is_empty() && !clear_password {
+                settings.wifi_password = current.wifi_password.clone();
+            }
+            if settings.device_key.is_empty() {
+                settings.device_key = current.device_key.clone();
+            }
+            if settings.server_certificate.is_empty() && settings.uses_tls() {
+                settings.server_certificate = current.server_certificate.clone();
+            }
+        }
+        settings.validate()?;
+        Ok(settings)
+    }
+}
+
+/// Lines of a pasted certificate, with the line endings browsers submit (`\r\n`) turned into
+/// `\n` and a final newline, as the TLS stack expects them.
+fn normalize_pem(pem: &str) -> String {
+    let mut normalized = String::with_capacity(pem.len() + 1);
+    for line in pem.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        normalized.push_str(line);
+        normalized.push('\n');
+    }
+    normalized
+}
+
+/// The id a camera goes by unless it was given another, e.g. `espcam-246f28a1b2c3`. It stays
+/// the same across reflashing and resetting the settings.
+pub fn device_id_from_mac(mac: [u8; 6]) -> String {
+    let mut id = String::from("espcam-");
+    for byte in mac {
+        let _ = write!(id, "{byte:02x}");
+    }
+    id
+}
+
+/// Decodes `+` and `%XX` escapes of a form field.
+fn url_decode(value: &str) -> Result<String, SettingsError> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        bytes.push(match byte {
+            b'+' => b' ',
+            b'%' => {
+                let high = input.next().and_then(hex::digit);
+                let low = input.next().and_then(hex::digit);
+                match (high, low) {
+                    (Some(high), Some(low)) => high << 4 | low,
+                    _ => return Err(SettingsError::InvalidForm),
+                }
+            }
+            byte => byte,
+        });
+    }
+    String::from_utf8(bytes).map_err(|_| SettingsError::InvalidForm)
+}
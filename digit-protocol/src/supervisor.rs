@@ -0,0 +1,176 @@
+//! How the camera's wake cycle copes with failing steps.
+//!
+//! Every step is tried a few times with exponential backoff in between. A step that keeps
+//! failing is skipped where the wake can do without it: without Wi-Fi the photo is still taken
+//! and queued, without a photo the camera still checks in. The whole wake is bounded by a
+//! deadline, so that the camera always gets back to deep sleep, and after a failed wake it
+//! sleeps for less than scheduled to try again sooner.
+
+use core::time::Duration;
+
+/// Steps of a wake, in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Connect to the Wi-Fi network.
+    Connect,
+    /// Set the clock with SNTP.
+    SyncTime,
+    /// Take the photo.
+    Capture,
+    /// Send the telemetry and the photo to the server.
+    CheckIn,
+    /// Keep the check-in for a later wake, after it could not be sent.
+    Queue,
+}
+
+/// How a step went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Succeeded,
+    /// Failed, trying again may help.
+    Failed,
+    /// Failed in a way trying again within this wake cannot fix, but a later wake may, e.g.
+    /// the server did not accept the device key or the clock.
+    Deferred,
+    /// Failed in a way trying again cannot fix, e.g. the server rejected the content of the
+    /// request.
+    Rejected,
+}
+
+/// What to do next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Run `step` after waiting for `after`.
+    Run { step: Step, after: Duration },
+    /// Go to deep sleep.
+    Sleep,
+}
+
+/// How often a step is tried and how long to wait in between.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Attempts in total, including the first one.
+    pub attempts: u32,
+    /// Wait after the first failure, doubled after every further one.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Wait after the `failures`th failure in a row, 0 before the first.
+    pub fn delay(&self, failures: u32) -> Duration {
+        if failures == 0 {
+            return Duration::ZERO;
+        }
+        let factor = 1u32.checked_shl(failures - 1).unwrap_or(u32::MAX);
+        self.initial_delay
+            .checked_mul(factor)
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+    }
+}
+
+/// The state of one wake.
+#[derive(Debug, Clone)]
+pub struct WakeCycle {
+    policy: RetryPolicy,
+    deadline: Duration,
+    step: Option<Step>,
+    /// Failures of the current step so far.
+    failures: u32,
+    online: bool,
+    checked_in: bool,
+}
+
+impl WakeCycle {
+    /// A wake that retries every step with `policy` and gives up `deadline` after it started.
+    pub fn new(policy: RetryPolicy, deadline: Duration) -> Self {
+        Self {
+            policy,
+            deadline,
+            step: Some(Step::Connect),
+            failures: 0,
+            online: false,
+            checked_in: false,
+        }
+    }
+
+    /// The first action of the wake.
+    pub fn start(&self) -> Action {
+        self.action(Duration::ZERO)
+    }
+
+    /// Records the `outcome` of the step the last action ran, `elapsed` after the wake started,
+    /// and returns what to do next.
+    pub fn record(&mut self, outcome: Outcome, elapsed: Duration) -> Action {
+        let Some(step) = self.step else {
+            return Action::Sleep;
+        };
+        if elapsed >= self.deadline {
+            self.step = None;
+            return Action::Sleep;
+        }
+
+        if outcome == Outcome::Failed {
+            self.failures += 1;
+            let after = self.policy.delay(self.failures);
+            if self.failures < self.policy.attempts && elapsed + after < self.deadline {
+                return self.action(after);
+            }
+        }
+
+        let succeeded = outcome == Outcome::Succeeded;
+        self.failures = 0;
+        self.step = match step {
+            Step::Connect => {
+                self.online = succeeded;
+                Some(if succeeded {
+                    Step::SyncTime
+                } else {
+                    Step::Capture
+                })
+            }
+            Step::SyncTime => Some(Step::Capture),
+            Step::Capture if self.online => Some(Step::CheckIn),
+            Step::Capture => Some(Step::Queue),
+            Step::CheckIn => {
+                self.checked_in = succeeded;
+                // A check-in the server rejected would be rejected again on a later wake
+                (outcome != Outcome::Succeeded && outcome != Outcome::Rejected)
+                    .then_some(Step::Queue)
+            }
+            Step::Queue => None,
+        };
+        self.action(Duration::ZERO)
+    }
+
+    fn action(&self, after: Duration) -> Action {
+        match self.step {
+            Some(step) => Action::Run { step, after },
+            None => Action::Sleep,
+        }
+    }
+
+    /// Whether the wake reached the server.
+    pub fn checked_in(&self) -> bool {
+        self.checked_in
+    }
+}
+
+/// How long to sleep when the schedule asks for `scheduled`, after `failed_wakes` wakes in a
+/// row did not reach the server: `shortest` after the first failure, doubled after every
+/// further one and never longer than scheduled.
+pub fn sleep_after_failures(
+    scheduled: Duration,
+    failed_wakes: u32,
+    shortest: Duration,
+) -> Duration {
+    let backoff = RetryPolicy {
+        attempts: 0,
+        initial_delay: shortest,
+        max_delay: scheduled,
+    };
+    match failed_wakes {
+        0 => scheduled,
+        n => backoff.delay(n),
+    }
+}
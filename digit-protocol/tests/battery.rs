@@ -0,0 +1,64 @@
+use digit_protocol::battery::*;
+
+fn assert_close(actual: f32, expected: f32) {
+    assert!(
+        (actual - expected).abs() < 1e-3,
+        "{actual} is not {expected}"
+    );
+}
+
+#[test]
+fn divider_scales_pin_voltage() {
+    // Two equal resistors halve the battery voltage
+    assert_close(battery_voltage(1950, 2.0), 3.9);
+    // 100k over 47k
+    assert_close(battery_voltage(1300, 147.0 / 47.0), 4.066);
+    assert_close(battery_voltage(0, 2.0), 0.0);
+}
+
+#[test]
+fn trimmed_mean_ignores_spikes() {
+    let mut samples = [1900, 1901, 1899, 1900, 3000, 1900, 10, 1900];
+    assert_eq!(trimmed_mean(&mut samples), Some(1900));
+}
+
+#[test]
+fn trimmed_mean_of_few_samples() {
+    assert_eq!(trimmed_mean(&mut []), None);
+    assert_eq!(trimmed_mean(&mut [1234]), Some(1234));
+    assert_eq!(trimmed_mean(&mut [1000, 2000]), Some(1500));
+}
+
+#[test]
+fn state_of_charge_is_clamped() {
+    assert_close(state_of_charge(2.5, LI_ION_CURVE), 0.0);
+    assert_close(state_of_charge(4.35, LI_ION_CURVE), 100.0);
+}
+
+#[test]
+fn state_of_charge_hits_curve_points() {
+    for &(voltage, charge) in LI_ION_CURVE {
+        assert_close(state_of_charge(voltage, LI_ION_CURVE), charge);
+    }
+}
+
+#[test]
+fn state_of_charge_interpolates() {
+    assert_close(state_of_charge(3.845, LI_ION_CURVE), 55.0);
+    assert_close(state_of_charge(4.13, LI_ION_CURVE), 95.0);
+}
+
+#[test]
+fn state_of_charge_rises_with_voltage() {
+    let mut previous = -1.0;
+    for millivolts in 2900..4300 {
+        let charge = state_of_charge(millivolts as f32 / 1000.0, LI_ION_CURVE);
+        assert!(charge >= previous, "charge drops at {millivolts} mV");
+        previous = charge;
+    }
+}
+
+#[test]
+fn empty_curve_reads_empty() {
+    assert_close(state_of_charge(3.9, &[]), 0.0);
+}
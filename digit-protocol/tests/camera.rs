@@ -0,0 +1,121 @@
+use digit_protocol::camera::*;
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::Mutex;
+
+static INSTANCE: AtomicBool = AtomicBool::new(false);
+
+/// The tests share the instance flag, so they take turns.
+static TURN: Mutex<()> = Mutex::new(());
+
+/// What the fake driver was asked to do, shared with the test.
+#[derive(Default)]
+struct Calls {
+    inits: Cell<u32>,
+    deinits: Cell<u32>,
+    frames: Cell<u32>,
+    returned: Cell<u32>,
+}
+
+struct Fake {
+    calls: Rc<Calls>,
+    fail_init: bool,
+}
+
+impl Fake {
+    fn new(calls: &Rc<Calls>) -> Self {
+        Self {
+            calls: calls.clone(),
+            fail_init: false,
+        }
+    }
+}
+
+unsafe impl Driver for Fake {
+    type Frame = Vec<u8>;
+    type Error = &'static str;
+
+    fn instance() -> &'static AtomicBool {
+        &INSTANCE
+    }
+
+    fn in_use() -> &'static str {
+        "in use"
+    }
+
+    fn init(&mut self) -> Result<(), &'static str> {
+        if self.fail_init {
+            return Err("init failed");
+        }
+        self.calls.inits.set(self.calls.inits.get() + 1);
+        Ok(())
+    }
+
+    fn deinit(&mut self) {
+        self.calls.deinits.set(self.calls.deinits.get() + 1);
+    }
+
+    fn frame_get(&self) -> Option<Vec<u8>> {
+        let n = self.calls.frames.get();
+        self.calls.frames.set(n + 1);
+        Some(vec![n as u8; 4])
+    }
+
+    unsafe fn frame_return(&self, _: &Vec<u8>) {
+        self.calls.returned.set(self.calls.returned.get() + 1);
+    }
+
+    unsafe fn pixels(frame: &Vec<u8>) -> &[u8] {
+        frame
+    }
+}
+
+#[test]
+fn allows_a_single_camera_at_a_time() {
+    let _turn = TURN.lock().unwrap();
+    let calls = Rc::new(Calls::default());
+    let camera = Camera::new(Fake::new(&calls)).unwrap();
+    assert_eq!(Camera::new(Fake::new(&calls)).err(), Some("in use"));
+    assert_eq!(calls.inits.get(), 1);
+
+    drop(camera);
+    assert_eq!(calls.deinits.get(), 1);
+    let _camera = Camera::new(Fake::new(&calls)).unwrap();
+    assert_eq!(calls.inits.get(), 2);
+}
+
+#[test]
+fn frees_the_camera_when_the_driver_fails_to_initialise() {
+    let _turn = TURN.lock().unwrap();
+    let calls = Rc::new(Calls::default());
+    let failing = Fake {
+        calls: calls.clone(),
+        fail_init: true,
+    };
+    assert_eq!(Camera::new(failing).err(), Some("init failed"));
+    assert_eq!(calls.deinits.get(), 0);
+    assert!(Camera::new(Fake::new(&calls)).is_ok());
+}
+
+#[test]
+fn hands_every_frame_back_once() {
+    let _turn = TURN.lock().unwrap();
+    let calls = Rc::new(Calls::default());
+    let camera = Camera::new(Fake::new(&calls)).unwrap();
+
+    // Frames that are not kept are handed back right away
+    camera.frame();
+    camera.frame();
+    assert_eq!(calls.returned.get(), 2);
+
+    let frame = camera.frame().unwrap();
+    assert_eq!(frame.data(), [2; 4]);
+    assert_eq!(calls.returned.get(), 2);
+    drop(frame);
+    assert_eq!(calls.returned.get(), 3);
+
+    drop(camera);
+    assert_eq!(calls.frames.get(), 3);
+    assert_eq!(calls.returned.get(), 3);
+}
@@ -0,0 +1,95 @@
+use digit_protocol::exposure::*;
+
+const WIDTH: usize = 64;
+const HEIGHT: usize = 32;
+
+/// White bars on a dark background, like the digits on the wheels of a meter.
+fn digits() -> Vec<u8> {
+    (0..HEIGHT)
+        .flat_map(|_| (0..WIDTH).map(|x| if x % 8 < 3 { 220 } else { 30 }))
+        .collect()
+}
+
+/// `pixels` averaged with their left and right neighbours, like a photo out of focus.
+fn blurred(pixels: &[u8]) -> Vec<u8> {
+    (0..pixels.len())
+        .map(|i| {
+            let x = i % WIDTH;
+            let left = if x > 0 { pixels[i - 1] } else { pixels[i] };
+            let right = if x + 1 < WIDTH {
+                pixels[i + 1]
+            } else {
+                pixels[i]
+            };
+            ((u16::from(left) + u16::from(pixels[i]) + u16::from(right)) / 3) as u8
+        })
+        .collect()
+}
+
+fn score_of(pixels: &[u8]) -> FrameScore {
+    score(pixels, WIDTH, HEIGHT).unwrap()
+}
+
+#[test]
+fn flat_frame_scores_nothing() {
+    let score = score_of(&[128; WIDTH * HEIGHT]);
+    assert_eq!(
+        score,
+        FrameScore {
+            sharpness: 0.0,
+            contrast: 0.0,
+            clipped: 0.0
+        }
+    );
+    assert_eq!(score.total(), 0.0);
+}
+
+#[test]
+fn sharp_frame_beats_blurred_one() {
+    let sharp = score_of(&digits());
+    let blurred = score_of(&blurred(&digits()));
+    assert!(sharp.sharpness > blurred.sharpness, "{sharp:?} {blurred:?}");
+    assert!(sharp.total() > blurred.total());
+}
+
+#[test]
+fn glare_costs_more_than_it_gains() {
+    let clean = digits();
+    // The flash reflected off the glass over a quarter of the frame
+    let mut glare = clean.clone();
+    for y in 8..24 {
+        for x in 16..48 {
+            glare[y * WIDTH + x] = 255;
+        }
+    }
+    let clean = score_of(&clean);
+    let glare = score_of(&glare);
+    assert_eq!(clean.clipped, 0.0);
+    assert!(glare.clipped > 0.2, "{glare:?}");
+    assert!(clean.total() > glare.total(), "{clean:?} {glare:?}");
+}
+
+#[test]
+fn underexposed_frame_loses() {
+    let dark: Vec<u8> = digits().iter().map(|p| p / 8).collect();
+    let normal = score_of(&digits());
+    let dark = score_of(&dark);
+    assert!(dark.contrast < normal.contrast / 4.0, "{dark:?}");
+    assert!(dark.clipped > 0.5, "{dark:?}");
+    assert!(normal.total() > dark.total());
+}
+
+#[test]
+fn contrast_ignores_stray_pixels() {
+    let mut pixels = vec![100; WIDTH * HEIGHT];
+    pixels[0] = 0;
+    pixels[1] = 255;
+    assert_eq!(score_of(&pixels).contrast, 0.0);
+}
+
+#[test]
+fn rejects_frames_of_wrong_size() {
+    assert_eq!(score(&digits(), WIDTH, HEIGHT + 1), None);
+    assert_eq!(score(&[0; 4], 2, 2), None);
+    assert_eq!(score(&[], usize::MAX, 2), None);
+}
@@ -0,0 +1,28 @@
+use digit_protocol::hex::{decode, decode_array, encode};
+
+#[test]
+fn round_trips_bytes() {
+    let bytes = [0x00, 0x0f, 0xa5, 0xff];
+    assert_eq!(encode(&bytes), "000fa5ff");
+    assert_eq!(decode("000fa5ff").unwrap(), bytes);
+    assert_eq!(decode("000FA5FF").unwrap(), bytes);
+    assert_eq!(encode(&[]), "");
+    assert_eq!(decode("").unwrap(), []);
+}
+
+#[test]
+fn rejects_malformed_hex() {
+    assert_eq!(decode("abc"), None);
+    assert_eq!(decode("zz"), None);
+    assert_eq!(decode("0x12"), None);
+    assert_eq!(decode(" 12"), None);
+    // A multi-byte character must not be split
+    assert_eq!(decode("é1"), None);
+}
+
+#[test]
+fn decodes_fixed_lengths() {
+    assert_eq!(decode_array::<2>("abcd"), Some([0xab, 0xcd]));
+    assert_eq!(decode_array::<3>("abcd"), None);
+    assert_eq!(decode_array::<1>("abcd"), None);
+}
@@ -0,0 +1,61 @@
+use digit_protocol::ota::*;
+
+fn version(major: u32, minor: u32, patch: u32) -> FirmwareVersion {
+    FirmwareVersion {
+        major,
+        minor,
+        patch,
+    }
+}
+
+#[test]
+fn parses_versions() {
+    assert_eq!("1.4.2".parse(), Ok(version(1, 4, 2)));
+    assert_eq!("0.10.0".parse(), Ok(version(0, 10, 0)));
+    assert_eq!(version(12, 0, 3).to_string(), "12.0.3");
+    for invalid in [
+        "",
+        "1",
+        "1.2",
+        "1.2.3.4",
+        "1.2.x",
+        "v1.2.3",
+        "1.02.3",
+        "1.2.-3",
+        "1.2.3-dev",
+        "1..3",
+        "99999999999.0.0",
+    ] {
+        assert_eq!(
+            invalid.parse::<FirmwareVersion>(),
+            Err(InvalidVersion),
+            "{invalid}"
+        );
+    }
+}
+
+#[test]
+fn orders_versions_numerically() {
+    assert!(version(0, 10, 0) > version(0, 9, 9));
+    assert!(version(1, 0, 0) > version(0, 99, 99));
+    assert!(version(1, 2, 10) > version(1, 2, 9));
+}
+
+#[test]
+fn updates_only_to_newer_versions() {
+    assert!(is_newer("0.1.0", version(0, 2, 0)));
+    assert!(!is_newer("0.2.0", version(0, 2, 0)));
+    assert!(!is_newer("0.3.0", version(0, 2, 0)));
+    // A development build is left alone
+    assert!(!is_newer("0.1.0-dirty", version(9, 0, 0)));
+}
+
+#[test]
+fn signed_message_binds_version_and_image() {
+    let sha256 = [7; 32];
+    let message = signed_message(version(1, 2, 3), &sha256);
+    assert!(message.starts_with(b"digit-firmware\n1.2.3\n"));
+    assert!(message.ends_with(&sha256));
+    assert_ne!(message, signed_message(version(1, 2, 4), &sha256));
+    assert_ne!(message, signed_message(version(1, 2, 3), &[8; 32]));
+}
@@ -0,0 +1,142 @@
+use digit_protocol::queue::*;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+/// Files kept in memory, shared between clones so that a queue can be reopened.
+#[derive(Clone, Default)]
+struct MemoryStorage(Rc<RefCell<BTreeMap<String, Vec<u8>>>>);
+
+impl QueueStorage for MemoryStorage {
+    type Error = String;
+
+    fn files(&mut self) -> Result<Vec<String>, String> {
+        Ok(self.0.borrow().keys().cloned().collect())
+    }
+
+    fn read(&mut self, name: &str) -> Result<Vec<u8>, String> {
+        self.0
+            .borrow()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| format!("{name} not found"))
+    }
+
+    fn write(&mut self, name: &str, data: &[u8]) -> Result<(), String> {
+        self.0.borrow_mut().insert(name.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &str) -> Result<(), String> {
+        self.0
+            .borrow_mut()
+            .remove(name)
+            .map(drop)
+            .ok_or_else(|| format!("{name} not found"))
+    }
+}
+
+impl MemoryStorage {
+    fn names(&self) -> Vec<String> {
+        self.0.borrow().keys().cloned().collect()
+    }
+}
+
+/// Sends everything in the queue, oldest first.
+fn drain(queue: &mut OfflineQueue<MemoryStorage>) -> Vec<QueuedCheckin> {
+    let mut sent = Vec::new();
+    while let Some(checkin) = queue.oldest().unwrap() {
+        queue.acknowledge(checkin.sequence).unwrap();
+        sent.push(checkin);
+    }
+    sent
+}
+
+#[test]
+fn delivers_oldest_first() {
+    let mut queue = OfflineQueue::open(MemoryStorage::default(), 10).unwrap();
+    queue.push(b"first", Some(b"jpeg 1")).unwrap();
+    queue.push(b"second", None).unwrap();
+    queue.push(b"third", Some(b"jpeg 3")).unwrap();
+    assert_eq!(queue.len().unwrap(), 3);
+
+    let sent = drain(&mut queue);
+    let telemetry: Vec<_> = sent.iter().map(|c| c.telemetry.as_slice()).collect();
+    assert_eq!(telemetry, [&b"first"[..], b"second", b"third"]);
+    assert_eq!(sent[0].image.as_deref(), Some(&b"jpeg 1"[..]));
+    assert_eq!(sent[1].image, None);
+    assert!(queue.is_empty().unwrap());
+}
+
+#[test]
+fn keeps_entries_until_acknowledged() {
+    let storage = MemoryStorage::default();
+    let mut queue = OfflineQueue::open(storage.clone(), 10).unwrap();
+    queue.push(b"telemetry", Some(b"jpeg")).unwrap();
+
+    // Reading without acknowledging, e.g. because the upload failed, keeps the entry
+    let first = queue.oldest().unwrap().unwrap();
+    assert_eq!(queue.oldest().unwrap(), Some(first.clone()));
+
+    // It survives a reboot
+    let mut queue = OfflineQueue::open(storage.clone(), 10).unwrap();
+    assert_eq!(queue.oldest().unwrap(), Some(first.clone()));
+
+    queue.acknowledge(first.sequence).unwrap();
+    assert!(storage.names().is_empty());
+}
+
+#[test]
+fn drops_oldest_when_full() {
+    let mut queue = OfflineQueue::open(MemoryStorage::default(), 2).unwrap();
+    assert_eq!(queue.push(b"1", None).unwrap(), 0);
+    assert_eq!(queue.push(b"2", Some(b"jpeg")).unwrap(), 0);
+    assert_eq!(queue.push(b"3", None).unwrap(), 1);
+    assert_eq!(queue.len().unwrap(), 2);
+
+    let telemetry: Vec<_> = drain(&mut queue).into_iter().map(|c| c.telemetry).collect();
+    assert_eq!(telemetry, [b"2".to_vec(), b"3".to_vec()]);
+}
+
+#[test]
+fn sequence_continues_after_acknowledging() {
+    let storage = MemoryStorage::default();
+    let mut queue = OfflineQueue::open(storage.clone(), 10).unwrap();
+    queue.push(b"1", None).unwrap();
+    queue.push(b"2", None).unwrap();
+    let first = queue.oldest().unwrap().unwrap();
+    queue.acknowledge(first.sequence).unwrap();
+    queue.push(b"3", Some(b"jpeg")).unwrap();
+
+    assert_eq!(
+        storage.names(),
+        ["00000002.JSN", "00000003.JPG", "00000003.JSN"]
+    );
+}
+
+#[test]
+fn removes_photos_of_interrupted_writes() {
+    let mut storage = MemoryStorage::default();
+    storage.write("00000001.JPG", b"jpeg 1").unwrap();
+    storage.write("00000001.JSN", b"telemetry 1").unwrap();
+    // The power went out before the telemetry was written
+    storage.write("00000002.JPG", b"jpeg 2").unwrap();
+    storage.write("README.TXT", b"not ours").unwrap();
+
+    let mut queue = OfflineQueue::open(storage.clone(), 10).unwrap();
+    assert_eq!(
+        storage.names(),
+        ["00000001.JPG", "00000001.JSN", "README.TXT"]
+    );
+    assert_eq!(queue.len().unwrap(), 1);
+    assert_eq!(drain(&mut queue)[0].image.as_deref(), Some(&b"jpeg 1"[..]));
+    assert_eq!(storage.names(), ["README.TXT"]);
+}
+
+#[test]
+fn capacity_of_zero_keeps_one() {
+    let mut queue = OfflineQueue::open(MemoryStorage::default(), 0).unwrap();
+    queue.push(b"1", None).unwrap();
+    assert_eq!(queue.push(b"2", None).unwrap(), 1);
+    assert_eq!(queue.oldest().unwrap().unwrap().telemetry, b"2");
+}
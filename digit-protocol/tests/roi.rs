@@ -0,0 +1,105 @@
+use digit_protocol::roi::*;
+
+/// A frame whose pixels tell where they are: the column in the low and the row in the high
+/// nibble, for the top left 16 by 16 pixels.
+fn frame() -> Vec<u8> {
+    (0..FRAME_HEIGHT)
+        .flat_map(|y| (0..FRAME_WIDTH).map(move |x| ((y % 16) << 4 | (x % 16)) as u8))
+        .collect()
+}
+
+fn roi(rotation: Rotation) -> Roi {
+    Roi {
+        x: 1,
+        y: 2,
+        width: 3,
+        height: 2,
+        rotation,
+    }
+}
+
+#[test]
+fn crops_without_rotation() {
+    let roi = roi(Rotation::None);
+    assert_eq!(roi.output_size(), (3, 2));
+    assert_eq!(
+        roi.crop(&frame()).unwrap(),
+        [0x21, 0x22, 0x23, 0x31, 0x32, 0x33]
+    );
+}
+
+#[test]
+fn turns_region_upright() {
+    // The region as it is in the frame:
+    //   21 22 23
+    //   31 32 33
+    let quarter = roi(Rotation::Quarter);
+    assert_eq!(quarter.output_size(), (2, 3));
+    assert_eq!(
+        quarter.crop(&frame()).unwrap(),
+        [0x31, 0x21, 0x32, 0x22, 0x33, 0x23]
+    );
+    assert_eq!(
+        roi(Rotation::Half).crop(&frame()).unwrap(),
+        [0x33, 0x32, 0x31, 0x23, 0x22, 0x21]
+    );
+    assert_eq!(
+        roi(Rotation::ThreeQuarters).crop(&frame()).unwrap(),
+        [0x23, 0x33, 0x22, 0x32, 0x21, 0x31]
+    );
+}
+
+#[test]
+fn shrinks_payload_by_an_order_of_magnitude() {
+    let strip = Roi {
+        x: 400,
+        y: 520,
+        width: 800,
+        height: 160,
+        rotation: Rotation::None,
+    };
+    let frame = frame();
+    assert!(strip.crop(&frame).unwrap().len() * 10 < frame.len());
+}
+
+#[test]
+fn rejects_regions_outside_frame() {
+    let edge = Roi {
+        x: FRAME_WIDTH - 10,
+        y: FRAME_HEIGHT - 10,
+        width: 10,
+        height: 10,
+        rotation: Rotation::Quarter,
+    };
+    assert_eq!(edge.validate(), Ok(()));
+    assert_eq!(edge.crop(&frame()).unwrap().len(), 100);
+
+    let beyond = Roi { width: 11, ..edge };
+    assert_eq!(beyond.validate(), Err(RoiError::OutsideFrame));
+    let overflowing = Roi {
+        x: u32::MAX,
+        ..edge
+    };
+    assert_eq!(overflowing.validate(), Err(RoiError::OutsideFrame));
+    assert_eq!(Roi { height: 0, ..edge }.validate(), Err(RoiError::Empty));
+    assert_eq!(edge.crop(&[0; 16]), Err(RoiError::FrameSize));
+}
+
+#[test]
+fn parses_regions_and_rotations() {
+    assert_eq!("1, 2,3,2".parse(), Ok(roi(Rotation::None)));
+    for invalid in ["", "1,2,3", "1,2,3,2,5", "1,2,-3,2", "a,b,c,d"] {
+        assert_eq!(invalid.parse::<Roi>(), Err(RoiError::Invalid), "{invalid}");
+    }
+    assert_eq!("270".parse(), Ok(Rotation::ThreeQuarters));
+    assert_eq!("45".parse::<Rotation>(), Err(RoiError::InvalidRotation(45)));
+}
+
+#[test]
+fn rotation_is_serialised_in_degrees() {
+    let json = serde_json::to_string(&roi(Rotation::Quarter)).unwrap();
+    assert_eq!(json, r#"{"x":1,"y":2,"width":3,"height":2,"rotation":90}"#);
+    let roi: Roi = serde_json::from_str(r#"{"x":1,"y":2,"width":3,"height":2}"#).unwrap();
+    assert_eq!(roi.rotation, Rotation::None);
+    assert!(serde_json::from_str::<Rotation>("45").is_err());
+}
@@ -0,0 +1,76 @@
+use digit_protocol::sensor::*;
+
+fn parse(json: &str) -> Result<SensorProfile, serde_json::Error> {
+    serde_json::from_str(json)
+}
+
+#[test]
+fn parses_profile() {
+    let profile = parse(
+        r#"{"brightness":1,"contrast":2,"saturation":-2,"gain_ceiling":16,
+            "white_balance":"office","vflip":true}"#,
+    )
+    .unwrap();
+    assert_eq!(
+        profile,
+        SensorProfile {
+            brightness: Some(Level::new(1).unwrap()),
+            contrast: Some(Level::new(2).unwrap()),
+            saturation: Some(Level::new(-2).unwrap()),
+            gain_ceiling: Some(GainCeiling::X16),
+            white_balance: Some(WhiteBalance::Office),
+            vflip: Some(true),
+            ..SensorProfile::default()
+        }
+    );
+    assert_eq!(profile.gain_ceiling.unwrap().index(), 3);
+    assert_eq!(profile.white_balance.unwrap().mode(), Some(3));
+}
+
+#[test]
+fn leaves_out_unset_settings() {
+    assert_eq!(parse("{}").unwrap(), SensorProfile::default());
+    assert_eq!(
+        serde_json::to_string(&SensorProfile {
+            contrast: Some(Level::new(-1).unwrap()),
+            ..SensorProfile::default()
+        })
+        .unwrap(),
+        r#"{"contrast":-1}"#
+    );
+}
+
+#[test]
+fn rejects_levels_out_of_range() {
+    assert_eq!(Level::new(3), Err(SensorError::InvalidLevel(3)));
+    assert_eq!(Level::new(-3), Err(SensorError::InvalidLevel(-3)));
+    assert_eq!(Level::new(-2).unwrap().get(), -2);
+    assert!(parse(r#"{"brightness":3}"#).is_err());
+    assert!(parse(r#"{"contrast":-5}"#).is_err());
+}
+
+#[test]
+fn gain_ceilings_are_powers_of_two() {
+    for (index, gain) in [2, 4, 8, 16, 32, 64, 128].into_iter().enumerate() {
+        let ceiling = GainCeiling::try_from(gain).unwrap();
+        assert_eq!(ceiling.gain(), gain);
+        assert_eq!(usize::from(ceiling.index()), index);
+    }
+    assert_eq!(
+        GainCeiling::try_from(3),
+        Err(SensorError::InvalidGainCeiling(3))
+    );
+    assert!(parse(r#"{"gain_ceiling":0}"#).is_err());
+    assert!(parse(r#"{"gain_ceiling":256}"#).is_err());
+}
+
+#[test]
+fn white_balance_off_has_no_mode() {
+    assert_eq!(WhiteBalance::Off.mode(), None);
+    assert_eq!(WhiteBalance::Auto.mode(), Some(0));
+    assert_eq!(
+        parse(r#"{"white_balance":"off"}"#).unwrap().white_balance,
+        Some(WhiteBalance::Off)
+    );
+    assert!(parse(r#"{"white_balance":"tungsten"}"#).is_err());
+}
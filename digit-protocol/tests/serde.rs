@@ -0,0 +1,222 @@
+use chrono::{FixedOffset, TimeZone};
+use digit_protocol::roi::{Roi, Rotation};
+use digit_protocol::sensor::{GainCeiling, Level, SensorProfile, WhiteBalance};
+use digit_protocol::*;
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Debug;
+
+fn timestamp() -> Timestamp {
+    FixedOffset::east_opt(3 * 3600)
+        .unwrap()
+        .with_ymd_and_hms(2025, 6, 30, 22, 0, 5)
+        .unwrap()
+}
+
+fn diagnostics() -> Diagnostics {
+    Diagnostics {
+        wifi_rssi: Some(-67),
+        wifi_channel: Some(6),
+        wifi_bssid: Some("a4:2b:b0:12:34:56".into()),
+        reset_reason: Some("deep_sleep".into()),
+        wake_reason: Some("timer".into()),
+        uptime_ms: Some(5230),
+        associate_ms: Some(812),
+        dhcp_ms: Some(230),
+        sntp_ms: Some(95),
+        free_heap: Some(151_232),
+        free_psram: Some(3_920_000),
+        chip_temperature: Some(41.5),
+        build_hash: Some("1a196bb".into()),
+        previous_capture_ms: Some(1210),
+        previous_upload_ms: Some(2405),
+    }
+}
+
+fn round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(message: &T) {
+    let json = serde_json::to_string(message).unwrap();
+    let parsed: T = serde_json::from_str(&json).unwrap();
+    assert_eq!(&parsed, message, "{json}");
+}
+
+#[test]
+fn health_request_round_trips() {
+    round_trip(&HealthRequest {
+        version: PROTOCOL_VERSION,
+        device_id: "espcam".into(),
+        firmware_version: "0.1.0".into(),
+        timestamp: timestamp(),
+        voltage: Some(3.7),
+        state_of_charge: Some(45.5),
+        diagnostics: diagnostics(),
+    });
+}
+
+#[test]
+fn unmeasured_voltage_is_sent_as_null() {
+    let request = HealthRequest {
+        version: PROTOCOL_VERSION,
+        device_id: "espcam".into(),
+        firmware_version: "0.1.0".into(),
+        timestamp: timestamp(),
+        voltage: None,
+        state_of_charge: None,
+        diagnostics: Diagnostics::default(),
+    };
+    let json = serde_json::to_value(&request).unwrap();
+    assert_eq!(json["voltage"], serde_json::Value::Null);
+    round_trip(&request);
+}
+
+#[test]
+fn health_response_round_trips() {
+    round_trip(&HealthResponse {
+        version: PROTOCOL_VERSION,
+        received_at: timestamp(),
+    });
+}
+
+#[test]
+fn upload_metadata_round_trips() {
+    round_trip(&UploadMetadata {
+        version: PROTOCOL_VERSION,
+        device_id: "espcam".into(),
+        firmware_version: "0.1.0".into(),
+        captured_at: timestamp(),
+        flash_brightness: Some(60),
+    });
+}
+
+#[test]
+fn upload_response_round_trips() {
+    round_trip(&UploadResponse {
+        version: PROTOCOL_VERSION,
+        files: vec![
+            UploadedFile {
+                file_name: "espcam_20250630T220005_0123456789abcdef.jpg".into(),
+                duplicate: false,
+            },
+            UploadedFile {
+                file_name: "espcam_20250629T220004_fedcba9876543210.jpg".into(),
+                duplicate: true,
+            },
+        ],
+        next_wake: timestamp(),
+    });
+}
+
+#[test]
+fn checkin_request_round_trips() {
+    round_trip(&CheckinRequest {
+        version: PROTOCOL_VERSION,
+        device_id: "espcam".into(),
+        firmware_version: "0.1.0".into(),
+        timestamp: timestamp(),
+        voltage: Some(3.7),
+        state_of_charge: Some(45.5),
+        diagnostics: diagnostics(),
+        captured_at: Some(timestamp()),
+        flash_brightness: Some(0),
+        config_revision: 2,
+    });
+}
+
+#[test]
+fn checkin_response_round_trips() {
+    round_trip(&CheckinResponse {
+        version: PROTOCOL_VERSION,
+        file: Some(UploadedFile {
+            file_name: "espcam_20250630T220005_0123456789abcdef.jpg".into(),
+            duplicate: false,
+        }),
+        next_wake: timestamp(),
+        config: Some(DeviceConfig {
+            revision: 3,
+            flash: Some(true),
+            flash_brightness: Some(40),
+            flash_warm_up_ms: Some(150),
+            roi: Some(Roi {
+                x: 400,
+                y: 520,
+                width: 800,
+                height: 160,
+                rotation: Rotation::Half,
+            }),
+            sensor: Some(SensorProfile {
+                contrast: Some(Level::new(2).unwrap()),
+                gain_ceiling: Some(GainCeiling::X8),
+                white_balance: Some(WhiteBalance::Off),
+                ..SensorProfile::default()
+            }),
+        }),
+        firmware: None,
+    });
+    round_trip(&CheckinResponse {
+        version: PROTOCOL_VERSION,
+        file: None,
+        next_wake: timestamp(),
+        config: None,
+        firmware: Some(FirmwareUpdate {
+            version: "0.2.0".into(),
+            size: 1_048_576,
+            sha256: "ab".repeat(32),
+            signature: "cd".repeat(64),
+            path: "/v1/firmware/0.2.0".into(),
+        }),
+    });
+}
+
+#[test]
+fn device_config_leaves_out_unset_settings() {
+    let json = serde_json::to_string(&DeviceConfig {
+        revision: 1,
+        flash: None,
+        flash_brightness: None,
+        flash_warm_up_ms: None,
+        roi: None,
+        sensor: None,
+    })
+    .unwrap();
+    assert_eq!(json, r#"{"revision":1}"#);
+    round_trip(&DeviceConfig::default());
+}
+
+#[test]
+fn error_body_round_trips() {
+    round_trip(&ErrorBody {
+        error: "not_jpeg".into(),
+        message: "the uploaded file is not a JPEG image".into(),
+        retryable: false,
+        server_time: None,
+    });
+    round_trip(&ErrorBody {
+        error: "clock_skew".into(),
+        message: "timestamp 0 is too far from server time".into(),
+        retryable: true,
+        server_time: Some(timestamp()),
+    });
+}
+
+#[test]
+fn timestamp_keeps_its_offset() {
+    let json = serde_json::to_value(HealthResponse {
+        version: PROTOCOL_VERSION,
+        received_at: timestamp(),
+    })
+    .unwrap();
+    assert_eq!(json["received_at"], "2025-06-30T22:00:05+03:00");
+}
+
+#[test]
+fn diagnostics_may_be_left_out() {
+    let request: HealthRequest = serde_json::from_str(
+        r#"{"version":1,"device_id":"espcam","firmware_version":"0.1.0",
+            "timestamp":"2025-06-30T22:00:05+03:00","voltage":3.7}"#,
+    )
+    .unwrap();
+    assert_eq!(request.diagnostics, Diagnostics::default());
+    assert_eq!(request.state_of_charge, None);
+
+    let partial: Diagnostics = serde_json::from_str(r#"{"wifi_rssi":-80}"#).unwrap();
+    assert_eq!(partial.wifi_rssi, Some(-80));
+    assert_eq!(partial.free_heap, None);
+}
@@ -0,0 +1,219 @@
+use digit_protocol::settings::*;
+
+const CERTIFICATE: &str = "-----BEGIN CERTIFICATE-----\nMIIBszCCAVmgAwIBAgIU\nZm9v/bar=\n\
+    -----END CERTIFICATE-----\n";
+
+fn settings() -> Settings {
+    Settings {
+        wifi_ssid: "Home".into(),
+        wifi_password: "correct horse".into(),
+        server_address: "http://synology:3000".into(),
+        server_certificate: String::new(),
+        device_id: "espcam".into(),
+        device_key: "00ff10".into(),
+    }
+}
+
+#[test]
+fn parses_form() {
+    let form = b"wifi_ssid=My+Home&wifi_password=%20pass%26word&server_address=https%3A%2F%2Fdigit.example%2F\
+        &device_id=+kitchen-1+&device_key=ABCD&submit=Save\
+        &server_certificate=-----BEGIN+CERTIFICATE-----%0D%0AMIIBszCCAVmgAwIBAgIU%0D%0A++Zm9v%2Fbar%3D%0D%0A\
+        -----END+CERTIFICATE-----%0D%0A";
+    let parsed = Settings::from_form(form, None).unwrap();
+    assert_eq!(
+        parsed,
+        Settings {
+            wifi_ssid: "My Home".into(),
+            wifi_password: " pass&word".into(),
+            server_address: "https://digit.example".into(),
+            server_certificate: CERTIFICATE.into(),
+            device_id: "kitchen-1".into(),
+            device_key: "abcd".into(),
+        }
+    );
+    assert_eq!(parsed.device_key().unwrap(), [0xab, 0xcd]);
+}
+
+#[test]
+fn empty_secrets_keep_current() {
+    let current = settings();
+    let form =
+        b"wifi_ssid=Other&wifi_password=&server_address=http://x&device_id=espcam&device_key=";
+    let parsed = Settings::from_form(form, Some(&current)).unwrap();
+    assert_eq!(parsed.wifi_ssid, "Other");
+    assert_eq!(parsed.wifi_password, current.wifi_password);
+    assert_eq!(parsed.device_key, current.device_key);
+
+    let form = b"wifi_ssid=Other&server_address=http://x&device_id=espcam&clear_password=on";
+    let parsed = Settings::from_form(form, Some(&current)).unwrap();
+    assert_eq!(parsed.wifi_password, "");
+    // Every camera needs a key to sign with
+    assert_eq!(parsed.device_key, current.device_key);
+    assert_eq!(
+        Settings::from_form(form, None),
+        Err(SettingsError::Missing("device key"))
+    );
+}
+
+#[test]
+fn rejects_missing_fields() {
+    assert_eq!(
+        Settings::from_form(b"server_address=http://x&device_id=a", None),
+        Err(SettingsError::Missing("Wi-Fi name"))
+    );
+    assert_eq!(
+        Settings::from_form(b"wifi_ssid=a&device_id=a", None),
+        Err(SettingsError::Missing("server address"))
+    );
+}
+
+#[test]
+fn device_id_defaults_to_mac() {
+    let parsed = Settings::from_form(
+        b"wifi_ssid=a&server_address=http://x&device_id=&device_key=ab",
+        None,
+    );
+    assert_eq!(parsed.unwrap().device_id, "");
+    assert_eq!(
+        device_id_from_mac([0x24, 0x6f, 0x28, 0xa1, 0xb2, 0x0c]),
+        "espcam-246f28a1b20c"
+    );
+}
+
+#[test]
+fn rejects_malformed_form() {
+    assert_eq!(
+        Settings::from_form(b"wifi_ssid=%zz", None),
+        Err(SettingsError::InvalidForm)
+    );
+    assert_eq!(
+        Settings::from_form(b"wifi_ssid=%4", None),
+        Err(SettingsError::InvalidForm)
+    );
+    assert_eq!(
+        Settings::from_form(b"wifi_ssid=%ff", None),
+        Err(SettingsError::InvalidForm)
+    );
+}
+
+#[test]
+fn validates_fields() {
+    assert_eq!(settings().validate(), Ok(()));
+
+    let check = |change: fn(&mut Settings)| {
+        let mut settings = settings();
+        change(&mut settings);
+        settings.validate()
+    };
+    assert_eq!(
+        check(|s| s.wifi_ssid = "x".repeat(33)),
+        Err(SettingsError::TooLong("Wi-Fi name", 32))
+    );
+    assert_eq!(check(|s| s.wifi_password.clear()), Ok(()));
+    assert_eq!(
+        check(|s| s.wifi_password = "short".into()),
+        Err(SettingsError::InvalidPassword)
+    );
+    assert_eq!(
+        check(|s| s.wifi_password = "x".repeat(64)),
+        Err(SettingsError::InvalidPassword)
+    );
+    for address in [
+        "synology:3000",
+        "ftp://synology",
+        "http://",
+        "http://a b",
+        "http://x/",
+    ] {
+        let mut settings = settings();
+        settings.server_address = address.into();
+        assert_eq!(
+            settings.validate(),
+            Err(SettingsError::InvalidServerAddress),
+            "{address}"
+        );
+    }
+    assert_eq!(
+        check(|s| s.device_id = "kitchen/1".into()),
+        Err(SettingsError::InvalidDeviceId)
+    );
+    assert_eq!(
+        check(|s| s.device_id = "x".repeat(65)),
+        Err(SettingsError::TooLong("device id", 64))
+    );
+    assert_eq!(
+        check(|s| s.device_key.clear()),
+        Err(SettingsError::Missing("device key"))
+    );
+    assert_eq!(
+        check(|s| s.device_key = "abc".into()),
+        Err(SettingsError::InvalidDeviceKey)
+    );
+    assert_eq!(
+        check(|s| s.device_key = "zz".into()),
+        Err(SettingsError::InvalidDeviceKey)
+    );
+}
+
+#[test]
+fn round_trips_as_json() {
+    let json = serde_json::to_string(&settings()).unwrap();
+    assert_eq!(serde_json::from_str::<Settings>(&json).unwrap(), settings());
+}
+
+#[test]
+fn https_needs_pinned_certificate() {
+    let mut settings = settings();
+    settings.server_address = "https://digit.example".into();
+    assert_eq!(
+        settings.validate(),
+        Err(SettingsError::Missing("server certificate"))
+    );
+    settings.server_certificate = CERTIFICATE.into();
+    assert_eq!(settings.validate(), Ok(()));
+    assert!(settings.uses_tls());
+
+    for invalid in [
+        "MIIBszCCAVmgAwIBAgIU",
+        "-----BEGIN CERTIFICATE-----\n-----END CERTIFICATE-----\n",
+        "-----BEGIN CERTIFICATE-----\nnot base64!\n-----END CERTIFICATE-----\n",
+        &[CERTIFICATE, CERTIFICATE].concat(),
+    ] {
+        settings.server_certificate = invalid.into();
+        assert_eq!(
+            settings.validate(),
+            Err(SettingsError::InvalidServerCertificate),
+            "{invalid}"
+        );
+    }
+    settings.server_certificate = CERTIFICATE.repeat(40);
+    assert_eq!(
+        settings.validate(),
+        Err(SettingsError::TooLong("server certificate", 3072))
+    );
+}
+
+#[test]
+fn empty_certificate_keeps_current_over_https() {
+    let mut current = settings();
+    current.server_address = "https://digit.example".into();
+    current.server_certificate = CERTIFICATE.into();
+    let form = b"wifi_ssid=Home&server_address=https://digit.example&device_id=espcam\
+        &server_certificate=";
+    let parsed = Settings::from_form(form, Some(&current)).unwrap();
+    assert_eq!(parsed.server_certificate, CERTIFICATE);
+
+    // Going back to plain HTTP drops it
+    let form = b"wifi_ssid=Home&server_address=http://synology:3000&device_id=espcam";
+    let parsed = Settings::from_form(form, Some(&current)).unwrap();
+    assert_eq!(parsed.server_certificate, "");
+}
+
+#[test]
+fn reads_settings_saved_before_certificates() {
+    let json = r#"{"wifi_ssid":"Home","wifi_password":"","server_address":"http://synology:3000",
+        "device_id":"espcam","device_key":""}"#;
+    let settings = serde_json::from_str::<Settings>(json).unwrap();
+    assert_eq!(settings.server_certificate, "");
+}
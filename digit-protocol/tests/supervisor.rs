@@ -0,0 +1,154 @@
+use core::time::Duration;
+use digit_protocol::supervisor::*;
+
+const POLICY: RetryPolicy = RetryPolicy {
+    attempts: 3,
+    initial_delay: Duration::from_secs(1),
+    max_delay: Duration::from_secs(5),
+};
+const DEADLINE: Duration = Duration::from_secs(120);
+
+fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+}
+
+fn run(step: Step) -> Action {
+    Action::Run {
+        step,
+        after: Duration::ZERO,
+    }
+}
+
+fn retry(step: Step, after: u64) -> Action {
+    Action::Run {
+        step,
+        after: secs(after),
+    }
+}
+
+#[test]
+fn delay_doubles_up_to_max() {
+    let delays: Vec<_> = (0..6).map(|failures| POLICY.delay(failures)).collect();
+    assert_eq!(
+        delays,
+        [secs(0), secs(1), secs(2), secs(4), secs(5), secs(5)]
+    );
+    assert_eq!(POLICY.delay(u32::MAX), secs(5));
+}
+
+#[test]
+fn successful_wake() {
+    let mut cycle = WakeCycle::new(POLICY, DEADLINE);
+    assert_eq!(cycle.start(), run(Step::Connect));
+    assert_eq!(
+        cycle.record(Outcome::Succeeded, secs(5)),
+        run(Step::SyncTime)
+    );
+    assert_eq!(
+        cycle.record(Outcome::Succeeded, secs(6)),
+        run(Step::Capture)
+    );
+    assert_eq!(
+        cycle.record(Outcome::Succeeded, secs(8)),
+        run(Step::CheckIn)
+    );
+    assert_eq!(cycle.record(Outcome::Succeeded, secs(10)), Action::Sleep);
+    assert!(cycle.checked_in());
+}
+
+#[test]
+fn retries_with_backoff() {
+    let mut cycle = WakeCycle::new(POLICY, DEADLINE);
+    assert_eq!(
+        cycle.record(Outcome::Failed, secs(5)),
+        retry(Step::Connect, 1)
+    );
+    assert_eq!(
+        cycle.record(Outcome::Failed, secs(11)),
+        retry(Step::Connect, 2)
+    );
+    assert_eq!(
+        cycle.record(Outcome::Succeeded, secs(15)),
+        run(Step::SyncTime)
+    );
+    // The next step starts over
+    assert_eq!(
+        cycle.record(Outcome::Failed, secs(16)),
+        retry(Step::SyncTime, 1)
+    );
+}
+
+#[test]
+fn offline_wake_queues_the_photo() {
+    let mut cycle = WakeCycle::new(POLICY, DEADLINE);
+    cycle.record(Outcome::Failed, secs(5));
+    cycle.record(Outcome::Failed, secs(11));
+    assert_eq!(cycle.record(Outcome::Failed, secs(17)), run(Step::Capture));
+    assert_eq!(cycle.record(Outcome::Succeeded, secs(19)), run(Step::Queue));
+    assert_eq!(cycle.record(Outcome::Succeeded, secs(20)), Action::Sleep);
+    assert!(!cycle.checked_in());
+}
+
+#[test]
+fn checks_in_without_time_or_photo() {
+    let mut cycle = WakeCycle::new(POLICY, DEADLINE);
+    cycle.record(Outcome::Succeeded, secs(5));
+    assert_eq!(cycle.record(Outcome::Rejected, secs(6)), run(Step::Capture));
+    assert_eq!(cycle.record(Outcome::Rejected, secs(7)), run(Step::CheckIn));
+    assert_eq!(cycle.record(Outcome::Succeeded, secs(8)), Action::Sleep);
+}
+
+#[test]
+fn failed_check_in_is_queued_rejected_is_not() {
+    let mut cycle = WakeCycle::new(POLICY, DEADLINE);
+    cycle.record(Outcome::Succeeded, secs(5));
+    cycle.record(Outcome::Succeeded, secs(6));
+    cycle.record(Outcome::Succeeded, secs(7));
+    let mut rejected = cycle.clone();
+
+    cycle.record(Outcome::Failed, secs(10));
+    cycle.record(Outcome::Failed, secs(20));
+    assert_eq!(cycle.record(Outcome::Failed, secs(30)), run(Step::Queue));
+    assert!(!cycle.checked_in());
+
+    assert_eq!(rejected.record(Outcome::Rejected, secs(10)), Action::Sleep);
+    assert!(!rejected.checked_in());
+}
+
+#[test]
+fn deferred_check_in_is_queued_without_retrying() {
+    let mut cycle = WakeCycle::new(POLICY, DEADLINE);
+    cycle.record(Outcome::Succeeded, secs(5));
+    cycle.record(Outcome::Succeeded, secs(6));
+    cycle.record(Outcome::Succeeded, secs(7));
+
+    assert_eq!(cycle.record(Outcome::Deferred, secs(10)), run(Step::Queue));
+    assert_eq!(cycle.record(Outcome::Succeeded, secs(11)), Action::Sleep);
+    assert!(!cycle.checked_in());
+}
+
+#[test]
+fn deadline_ends_the_wake() {
+    let mut cycle = WakeCycle::new(POLICY, DEADLINE);
+    assert_eq!(cycle.record(Outcome::Succeeded, DEADLINE), Action::Sleep);
+    assert_eq!(cycle.record(Outcome::Succeeded, secs(1)), Action::Sleep);
+}
+
+#[test]
+fn no_retry_past_the_deadline() {
+    let mut cycle = WakeCycle::new(POLICY, DEADLINE);
+    // Waiting a second would reach the deadline, so Wi-Fi is given up on right away
+    assert_eq!(cycle.record(Outcome::Failed, secs(119)), run(Step::Capture));
+}
+
+#[test]
+fn failed_wakes_sleep_less() {
+    let day = secs(24 * 3600);
+    assert_eq!(sleep_after_failures(day, 0, secs(600)), day);
+    assert_eq!(sleep_after_failures(day, 1, secs(600)), secs(600));
+    assert_eq!(sleep_after_failures(day, 3, secs(600)), secs(2400));
+    assert_eq!(sleep_after_failures(day, 10, secs(600)), day);
+    assert_eq!(sleep_after_failures(day, u32::MAX, secs(600)), day);
+    // Never longer than scheduled
+    assert_eq!(sleep_after_failures(secs(300), 1, secs(600)), secs(300));
+}
@@ -0,0 +1,773 @@
+use chrono::{DateTime, Duration, Utc};
+use chrono_tz::Tz;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use crate::report::MIN_CONFIDENCE;
+use crate::storage::{Storage, StorageError};
+
+/// How far back the voltage trend is fitted.
+const TREND_WINDOW: Duration = Duration::days(7);
+/// The trend needs at least this many samples spread over at least a day to mean anything.
+const TREND_MIN_SAMPLES: usize = 3;
+/// How long sending a notification may take. The alerter is locked meanwhile, so an
+/// unresponsive sink must not hold up the next evaluation for long.
+const SEND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+#[derive(Debug, thiserror::Error)]
+pub enum AlertError {
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] ureq::Error),
+    #[error("invalid e-mail address: {0}")]
+    Address(#[from] lettre::address::AddressError),
+    #[error("could not build e-mail: {0}")]
+    Email(#[from] lettre::error::Error),
+    #[error("could not send e-mail: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("storage failure: {0}")]
+    Storage(#[from] StorageError),
+}
+
+/// The `[alerts]` section of the configuration file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlertConfig {
+    /// Minutes between two evaluations of the rules.
+    pub check_interval_minutes: u32,
+    /// Hours after which an alert that is still active is sent again.
+    pub repeat_hours: u32,
+    /// Alert when the battery is below this many volts.
+    pub low_voltage: Option<f32>,
+    /// Voltage the device browns out at, used to predict when the battery runs out.
+    pub cutoff_voltage: Option<f32>,
+    /// Alert when the battery is predicted to reach the cutoff within this many days.
+    pub trend_days: f32,
+    /// Alert when a device is this many hours late for its scheduled check-in.
+    pub missed_checkin_hours: Option<u32>,
+    /// Alert when this many photos in a row could not be read.
+    pub recognition_failures: Option<u32>,
+    pub sinks: Vec<SinkConfig>,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            check_interval_minutes: 15,
+            repeat_hours: 24,
+            low_voltage: Some(3.5),
+            cutoff_voltage: Some(3.3),
+            trend_days: 3.0,
+            missed_checkin_hours: Some(2),
+            recognition_failures: Some(3),
+            sinks: Vec::new(),
+        }
+    }
+}
+
+/// Where notifications are delivered, one `[[alerts.sinks]]` table each.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SinkConfig {
+    /// E-mail over SMTP with STARTTLS.
+    Smtp {
+        server: String,
+        port: Option<u16>,
+        username: Option<String>,
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+    },
+    /// A JSON POST of the notification to any URL.
+    Webhook { url: String },
+    /// A push notification through an ntfy topic URL, e.g. `https://ntfy.sh/my-meter`.
+    Ntfy { url: String, token: Option<String> },
+}
+
+impl AlertConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.check_interval_minutes == 0 {
+            return Err("alert check interval must be at least a minute".into());
+        }
+        if self.trend_days <= 0.0 {
+            return Err("alert trend days must be positive".into());
+        }
+        for sink in &self.sinks {
+            match sink {
+                SinkConfig::Smtp { to, .. } if to.is_empty() => {
+                    return Err("SMTP alert sink needs at least one recipient".into());
+                }
+                SinkConfig::Webhook { url } | SinkConfig::Ntfy { url, .. }
+                    if !url.starts_with("http://") && !url.starts_with("https://") =>
+                {
+                    return Err(format!("alert sink URL {url} is not an HTTP URL"));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertKind {
+    LowVoltage,
+    VoltageTrend,
+    MissedCheckin,
+    RecognitionFailures,
+}
+
+/// A problem with a device.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Alert {
+    pub device_id: String,
+    pub kind: AlertKind,
+    pub message: String,
+}
+
+/// What the rules are evaluated against.
+#[derive(Debug, Clone, Default)]
+pub struct DeviceHistory {
+    pub device_id: String,
+    /// Battery voltages with the time they were received, oldest first.
+    pub voltages: Vec<(DateTime<Utc>, f32)>,
+    /// When the device was due to check in after its last one, `None` if it never did.
+    pub expected_at: Option<DateTime<Utc>>,
+    /// Whether the latest photos could be read, newest first.
+    pub recognitions: Vec<bool>,
+}
+
+/// Evaluates the rules for one device.
+pub fn evaluate(config: &AlertConfig, history: &DeviceHistory, now: DateTime<Utc>) -> Vec<Alert> {
+    let mut alerts = Vec::new();
+    let mut alert = |kind, message| {
+        alerts.push(Alert {
+            device_id: history.device_id.clone(),
+            kind,
+            message,
+        })
+    };
+
+    let latest = history.voltages.last().map(|&(_, voltage)| voltage);
+    if let (Some(threshold), Some(voltage)) = (config.low_voltage, latest)
+        && voltage < threshold
+    {
+        alert(
+            AlertKind::LowVoltage,
+            format!("battery at {voltage:.2} V, below {threshold:.2} V"),
+        );
+    }
+
+    if let (Some(cutoff), Some(voltage)) = (config.cutoff_voltage, latest)
+        && voltage > cutoff
+        && let Some(days) = days_until(&history.voltages, cutoff)
+        && days <= config.trend_days
+    {
+        alert(
+            AlertKind::VoltageTrend,
+            format!("battery at {voltage:.2} V will reach {cutoff:.2} V in {days:.1} days"),
+        );
+    }
+
+    if let (Some(hours), Some(expected_at)) = (config.missed_checkin_hours, history.expected_at) {
+        let late = now - expected_at;
+        if late > Duration::hours(i64::from(hours)) {
+            alert(
+                AlertKind::MissedCheckin,
+                format!(
+                    "expected to check in at {}, now {} hours late",
+                    expected_at.format("%Y-%m-%d %H:%M UTC"),
+                    late.num_hours()
+                ),
+            );
+        }
+    }
+
+    if let Some(limit) = config.recognition_failures {
+        let failures = history.recognitions.iter().take_while(|ok| !**ok).count();
+        if limit > 0 && failures >= limit as usize {
+            alert(
+                AlertKind::RecognitionFailures,
+                format!("the last {failures} photos could not be read"),
+            );
+        }
+    }
+    alerts
+}
+
+/// Days until a least squares line through `voltages` reaches `cutoff`, counted from the last
+/// sample. `None` if the voltage is not dropping or there is too little data.
+pub fn days_until(voltages: &[(DateTime<Utc>, f32)], cutoff: f32) -> Option<f32> {
+    let (first, last) = (voltages.first()?.0, voltages.last()?.0);
+    if voltages.len() < TREND_MIN_SAMPLES || last - first < Duration::days(1) {
+        return None;
+    }
+
+    let days = |time: DateTime<Utc>| (time - first).num_seconds() as f64 / 86_400.0;
+    let n = voltages.len() as f64;
+    let mean_x = voltages.iter().map(|&(t, _)| days(t)).sum::<f64>() / n;
+    let mean_y = voltages.iter().map(|&(_, v)| f64::from(v)).sum::<f64>() / n;
+    let (mut covariance, mut variance) = (0.0, 0.0);
+    for &(time, voltage) in voltages {
+        let dx = days(time) - mean_x;
+        covariance += dx * (f64::from(voltage) - mean_y);
+        variance += dx * dx;
+    }
+    let slope = covariance / variance;
+    if slope >= 0.0 {
+        return None;
+    }
+
+    let now = mean_y + slope * (days(last) - mean_x);
+    Some(((f64::from(cutoff) - now) / slope).max(0.0) as f32)
+}
+
+/// An alert being raised, repeated or cleared.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Notification {
+    #[serde(flatten)]
+    pub alert: Alert,
+    /// The problem is gone.
+    pub resolved: bool,
+}
+
+impl Notification {
+    pub fn title(&self) -> String {
+        let problem = match self.alert.kind {
+            AlertKind::LowVoltage => "battery low",
+            AlertKind::VoltageTrend => "battery running out",
+            AlertKind::MissedCheckin => "missed check-in",
+            AlertKind::RecognitionFailures => "meter unreadable",
+        };
+        let resolved = if self.resolved { "resolved: " } else { "" };
+        format!("{resolved}{} {problem}", self.alert.device_id)
+    }
+}
+
+impl fmt::Display for Notification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.title(), self.alert.message)
+    }
+}
+
+/// Delivers notifications somewhere a person sees them.
+pub trait AlertSink: Send {
+    fn name(&self) -> &'static str;
+    fn send(&self, notification: &Notification) -> Result<(), AlertError>;
+}
+
+pub struct SmtpSink {
+    transport: SmtpTransport,
+    from: String,
+    to: Vec<String>,
+}
+
+impl SmtpSink {
+    fn message(&self, notification: &Notification) -> Result<Message, AlertError> {
+        let mut message = Message::builder()
+            .from(self.from.parse()?)
+            .subject(notification.title());
+        for to in &self.to {
+            message = message.to(to.parse()?);
+        }
+        Ok(message.body(notification.alert.message.clone())?)
+    }
+}
+
+impl AlertSink for SmtpSink {
+    fn name(&self) -> &'static str {
+        "smtp"
+    }
+
+    fn send(&self, notification: &Notification) -> Result<(), AlertError> {
+        self.transport.send(&self.message(notification)?)?;
+        Ok(())
+    }
+}
+
+/// A client that gives up on a request after `timeout`.
+fn http_agent(timeout: std::time::Duration) -> ureq::Agent {
+    ureq::Agent::config_builder()
+        .timeout_global(Some(timeout))
+        .build()
+        .new_agent()
+}
+
+pub struct WebhookSink {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl AlertSink for WebhookSink {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn send(&self, notification: &Notification) -> Result<(), AlertError> {
+        self.agent.post(&self.url).send_json(notification)?;
+        Ok(())
+    }
+}
+
+pub struct NtfySink {
+    url: String,
+    token: Option<String>,
+    agent: ureq::Agent,
+}
+
+impl AlertSink for NtfySink {
+    fn name(&self) -> &'static str {
+        "ntfy"
+    }
+
+    fn send(&self, notification: &Notification) -> Result<(), AlertError> {
+        let (priority, tags) = if notification.resolved {
+            ("default", "white_check_mark")
+        } else {
+            ("high", "warning")
+        };
+        let mut request = self
+            .agent
+            .post(&self.url)
+            .header("Title", notification.title())
+            .header("Priority", priority)
+            .header("Tags", tags);
+        if let Some(token) = &self.token {
+            request = request.header("Authorization", format!("Bearer {token}"));
+        }
+        request.send(notification.alert.message.as_str())?;
+        Ok(())
+    }
+}
+
+impl SinkConfig {
+    pub fn build(&self) -> Result<Box<dyn AlertSink>, AlertError> {
+        Ok(match self.clone() {
+            SinkConfig::Smtp {
+                server,
+                port,
+                username,
+                password,
+                from,
+                to,
+            } => {
+                let mut transport =
+                    SmtpTransport::starttls_relay(&server)?.timeout(Some(SEND_TIMEOUT));
+                if let Some(port) = port {
+                    transport = transport.port(port);
+                }
+                if let (Some(username), Some(password)) = (username, password) {
+                    transport = transport.credentials(Credentials::new(username, password));
+                }
+                Box::new(SmtpSink {
+                    transport: transport.build(),
+                    from,
+                    to,
+                })
+            }
+            SinkConfig::Webhook { url } => Box::new(WebhookSink {
+                url,
+                agent: http_agent(SEND_TIMEOUT),
+            }),
+            SinkConfig::Ntfy { url, token } => Box::new(NtfySink {
+                url,
+                token,
+                agent: http_agent(SEND_TIMEOUT),
+            }),
+        })
+    }
+}
+
+/// Keeps track of the active alerts, so that every problem is notified once when it appears,
+/// again every `repeat_hours` while it lasts and once more when it is gone.
+pub struct Alerter {
+    sinks: Vec<Box<dyn AlertSink>>,
+    repeat: Duration,
+    /// Active alerts and when they were last notified.
+    active: HashMap<(String, AlertKind), (Alert, DateTime<Utc>)>,
+}
+
+impl Alerter {
+    pub fn new(sinks: Vec<Box<dyn AlertSink>>, repeat_hours: u32) -> Self {
+        Self {
+            sinks,
+            repeat: Duration::hours(i64::from(repeat_hours)),
+            active: HashMap::new(),
+        }
+    }
+
+    /// Notifies the changes between the active alerts and the current `alerts`, which must
+    /// cover every device.
+    pub fn update(&mut self, alerts: Vec<Alert>, now: DateTime<Utc>) {
+        let mut notifications = Vec::new();
+        let current: HashMap<_, _> = alerts
+            .into_iter()
+            .map(|alert| ((alert.device_id.clone(), alert.kind), alert))
+            .collect();
+
+        self.active.retain(|key, (alert, _)| {
+            let active = current.contains_key(key);
+            if !active {
+                notifications.push(Notification {
+                    alert: alert.clone(),
+                    resolved: true,
+                });
+            }
+            active
+        });
+        for (key, alert) in current {
+            let due = self
+                .active
+                .get(&key)
+                .is_none_or(|&(_, notified_at)| now - notified_at >= self.repeat);
+            if due {
+                notifications.push(Notification {
+                    alert: alert.clone(),
+                    resolved: false,
+                });
+                self.active.insert(key, (alert, now));
+            }
+        }
+
+        for notification in &notifications {
+            log::warn!("Alert {notification}");
+            for sink in &self.sinks {
+                if let Err(e) = sink.send(notification) {
+                    log::error!("Failed to send alert through {}: {e}", sink.name());
+                }
+            }
+        }
+    }
+}
+
+/// Collects the history of every device the rules look at.
+fn histories(
+    storage: &Storage,
+    config: &AlertConfig,
+    timezone: Tz,
+    now: DateTime<Utc>,
+) -> Result<Vec<DeviceHistory>, AlertError> {
+    let limit = config.recognition_failures.unwrap_or(0);
+    let mut histories = Vec::new();
+    for device_id in storage.device_ids()? {
+        let voltages = storage
+            .voltages(&device_id, now - TREND_WINDOW)?
+            .into_iter()
+            .map(|(time, voltage)| (time.to_utc(), voltage))
+            .collect();
+        let expected_at = match storage.last_seen(&device_id)? {
+            Some(last_seen) => Some(
+                storage
+                    .device_schedule(&device_id)?
+                    .next_wake(last_seen.with_timezone(&timezone))
+                    .to_utc(),
+            ),
+            None => None,
+        };
+        let recognitions = storage
+            .recognitions(&device_id, limit)?
+            .into_iter()
+            .map(|confidence| confidence.is_some_and(|c| c >= MIN_CONFIDENCE))
+            .collect();
+        histories.push(DeviceHistory {
+            device_id,
+            voltages,
+            expected_at,
+            recognitions,
+        });
+    }
+    Ok(histories)
+}
+
+/// Evaluates the alert rules every `check_interval_minutes` and notifies the sinks.
+pub async fn monitor(storage: Arc<Storage>, config: AlertConfig, timezone: Tz) {
+    let sinks = config
+        .sinks
+        .iter()
+        .filter_map(|sink| match sink.build() {
+            Ok(sink) => Some(sink),
+            Err(e) => {
+                log::error!("Failed to set up alert sink: {e}");
+                None
+            }
+        })
+        .collect();
+    let alerter = Arc::new(Mutex::new(Alerter::new(sinks, config.repeat_hours)));
+    let config = Arc::new(config);
+
+    let interval = std::time::Duration::from_secs(u64::from(config.check_interval_minutes) * 60);
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let storage = storage.clone();
+        let config = config.clone();
+        let alerter = alerter.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            let now = Utc::now();
+            let alerts = histories(&storage, &config, timezone, now)?
+                .iter()
+                .flat_map(|history| evaluate(&config, history, now))
+                .collect();
+            alerter
+                .lock()
+                .unwrap_or_else(|p| p.into_inner())
+                .update(alerts, now);
+            Ok::<_, AlertError>(())
+        })
+        .await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => log::error!("Failed to evaluate alerts: {e}"),
+            Err(e) => log::error!("Alert task failed: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    fn at(hours: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_750_000_000, 0).unwrap() + Duration::hours(hours)
+    }
+
+    fn history(voltages: &[(i64, f32)]) -> DeviceHistory {
+        DeviceHistory {
+            device_id: "espcam".into(),
+            voltages: voltages.iter().map(|&(h, v)| (at(h), v)).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn kinds(alerts: &[Alert]) -> Vec<AlertKind> {
+        alerts.iter().map(|alert| alert.kind).collect()
+    }
+
+    /// Remembers what it was asked to send.
+    #[derive(Clone, Default)]
+    struct FakeSink(Arc<Mutex<Vec<Notification>>>);
+
+    impl AlertSink for FakeSink {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn send(&self, notification: &Notification) -> Result<(), AlertError> {
+            self.0.lock().unwrap().push(notification.clone());
+            Ok(())
+        }
+    }
+
+    impl FakeSink {
+        fn take(&self) -> Vec<(AlertKind, bool)> {
+            self.0
+                .lock()
+                .unwrap()
+                .drain(..)
+                .map(|n| (n.alert.kind, n.resolved))
+                .collect()
+        }
+    }
+
+    /// Answers a single HTTP request on a local port and returns its head and body.
+    fn fake_server() -> (String, std::thread::JoinHandle<(String, String)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/alerts", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut head = String::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                head.push_str(&line.to_ascii_lowercase());
+            }
+            let length = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .map_or(0, |length| length.trim().parse().unwrap());
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            (head, String::from_utf8(body).unwrap())
+        });
+        (url, server)
+    }
+
+    fn notification() -> Notification {
+        Notification {
+            alert: Alert {
+                device_id: "espcam".into(),
+                kind: AlertKind::LowVoltage,
+                message: "battery at 3.40 V, below 3.50 V".into(),
+            },
+            resolved: false,
+        }
+    }
+
+    #[test]
+    fn low_voltage_uses_latest_sample() {
+        let config = AlertConfig::default();
+        let low = history(&[(0, 3.9), (24, 3.4)]);
+        assert_eq!(
+            kinds(&evaluate(&config, &low, at(25))),
+            [AlertKind::LowVoltage]
+        );
+        let recovered = history(&[(0, 3.4), (24, 3.9)]);
+        assert!(evaluate(&config, &recovered, at(25)).is_empty());
+    }
+
+    #[test]
+    fn trend_predicts_cutoff() {
+        // Dropping 0.1 V a day from 3.8 V reaches 3.3 V in 3 more days
+        let voltages: Vec<_> = (0..3)
+            .map(|day| (at(day * 24), 3.8 - 0.1 * day as f32))
+            .collect();
+        let days = days_until(&voltages, 3.3).unwrap();
+        assert!((days - 3.0).abs() < 0.01, "{days}");
+
+        let config = AlertConfig::default();
+        let history = history(&[(0, 3.8), (24, 3.7), (48, 3.6)]);
+        assert_eq!(
+            kinds(&evaluate(&config, &history, at(49))),
+            [AlertKind::VoltageTrend]
+        );
+        let config = AlertConfig {
+            trend_days: 2.0,
+            ..config
+        };
+        assert!(evaluate(&config, &history, at(49)).is_empty());
+    }
+
+    #[test]
+    fn trend_needs_a_falling_voltage_over_a_day() {
+        assert_eq!(
+            days_until(&[(at(0), 3.8), (at(1), 3.7), (at(2), 3.6)], 3.3),
+            None
+        );
+        assert_eq!(days_until(&[(at(0), 3.8), (at(48), 3.7)], 3.3), None);
+        assert_eq!(
+            days_until(&[(at(0), 3.6), (at(24), 3.7), (at(48), 3.8)], 3.3),
+            None
+        );
+    }
+
+    #[test]
+    fn missed_checkin_after_grace() {
+        let config = AlertConfig::default();
+        let history = DeviceHistory {
+            expected_at: Some(at(0)),
+            ..history(&[])
+        };
+        assert!(evaluate(&config, &history, at(2)).is_empty());
+        assert_eq!(
+            kinds(&evaluate(&config, &history, at(3))),
+            [AlertKind::MissedCheckin]
+        );
+    }
+
+    #[test]
+    fn recognition_failures_in_a_row() {
+        let config = AlertConfig::default();
+        let mut history = history(&[]);
+        history.recognitions = vec![false, false, true, false];
+        assert!(evaluate(&config, &history, at(0)).is_empty());
+        history.recognitions = vec![false, false, false, true];
+        assert_eq!(
+            kinds(&evaluate(&config, &history, at(0))),
+            [AlertKind::RecognitionFailures]
+        );
+    }
+
+    #[test]
+    fn alerter_notifies_raise_repeat_and_resolve() {
+        let sink = FakeSink::default();
+        let mut alerter = Alerter::new(vec![Box::new(sink.clone())], 24);
+        let alert = notification().alert;
+
+        alerter.update(vec![alert.clone()], at(0));
+        assert_eq!(sink.take(), [(AlertKind::LowVoltage, false)]);
+        alerter.update(vec![alert.clone()], at(1));
+        assert_eq!(sink.take(), []);
+        alerter.update(vec![alert.clone()], at(24));
+        assert_eq!(sink.take(), [(AlertKind::LowVoltage, false)]);
+        alerter.update(vec![], at(25));
+        assert_eq!(sink.take(), [(AlertKind::LowVoltage, true)]);
+        alerter.update(vec![], at(26));
+        assert_eq!(sink.take(), []);
+    }
+
+    #[test]
+    fn webhook_posts_json() {
+        let (url, server) = fake_server();
+        let sink = SinkConfig::Webhook { url }.build().unwrap();
+        sink.send(&notification()).unwrap();
+
+        let (head, body) = server.join().unwrap();
+        assert!(head.starts_with("post /alerts "), "{head}");
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["device_id"], "espcam");
+        assert_eq!(body["kind"], "low_voltage");
+        assert_eq!(body["resolved"], false);
+    }
+
+    #[test]
+    fn webhook_gives_up_on_a_silent_endpoint() {
+        // Accepts the connection but never answers
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let sink = WebhookSink {
+            url: format!("http://{}/alerts", listener.local_addr().unwrap()),
+            agent: http_agent(std::time::Duration::from_millis(200)),
+        };
+        let started = std::time::Instant::now();
+        assert!(sink.send(&notification()).is_err());
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn ntfy_posts_title_and_message() {
+        let (url, server) = fake_server();
+        let sink = SinkConfig::Ntfy {
+            url,
+            token: Some("secret".into()),
+        }
+        .build()
+        .unwrap();
+        sink.send(&notification()).unwrap();
+
+        let (head, body) = server.join().unwrap();
+        assert!(head.contains("title: espcam battery low"), "{head}");
+        assert!(head.contains("priority: high"), "{head}");
+        assert!(head.contains("authorization: bearer secret"), "{head}");
+        assert_eq!(body, "battery at 3.40 V, below 3.50 V");
+    }
+
+    #[test]
+    fn smtp_message_is_addressed() {
+        let sink = SmtpSink {
+            transport: SmtpTransport::builder_dangerous("127.0.0.1").build(),
+            from: "meter@example.com".into(),
+            to: vec!["me@example.com".into(), "you@example.com".into()],
+        };
+        let message = sink.message(&notification()).unwrap();
+        let message = String::from_utf8(message.formatted()).unwrap();
+        assert!(message.contains("Subject: espcam battery low"), "{message}");
+        assert!(
+            message.contains("To: me@example.com, you@example.com"),
+            "{message}"
+        );
+        assert!(message.contains("battery at 3.40 V"), "{message}");
+    }
+}
@@ -0,0 +1,317 @@
+use axum::{
+    body::Body,
+    extract::{Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use digit_protocol::hex;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use crate::AppState;
+use crate::error::AppError;
+use crate::storage::DEFAULT_DEVICE;
+
+const DEVICE_ID_HEADER: &str = "x-device-id";
+const TIMESTAMP_HEADER: &str = "x-timestamp";
+const SIGNATURE_HEADER: &str = "x-signature";
+
+/// How far the timestamp of a request may be from the server's clock, in seconds.
+const MAX_CLOCK_SKEW: i64 = 5 * 60;
+/// Length of the generated device keys in bytes.
+const KEY_LENGTH: usize = 32;
+
+/// The device a request was signed by, added to the request extensions by [`verify`].
+#[derive(Debug, Clone)]
+pub struct Device(pub String);
+
+/// Remembers the signatures seen within the allowed clock skew, so that a captured request
+/// cannot be sent again.
+#[derive(Default)]
+pub struct ReplayGuard {
+    seen: Mutex<HashMap<Vec<u8>, i64>>,
+}
+
+impl ReplayGuard {
+    /// Returns false if the signature has been seen before.
+    fn check(&self, signature: &[u8], timestamp: i64, now: i64) -> bool {
+        let mut seen = self.seen.lock().unwrap_or_else(|p| p.into_inner());
+        seen.retain(|_, &mut t| (now - t).abs() <= MAX_CLOCK_SKEW);
+        seen.insert(signature.to_vec(), timestamp).is_none()
+    }
+}
+
+/// Generates a new random device key, hex encoded.
+pub fn generate_key() -> Result<String, getrandom::Error> {
+    let mut key = [0u8; KEY_LENGTH];
+    getrandom::fill(&mut key)?;
+    Ok(hex::encode(&key))
+}
+
+/// HMAC-SHA256 over the timestamp and the body. The device computes the same over the
+/// request it sends and puts the result in the signature header.
+fn mac(key: &[u8], timestamp: i64, body: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b"\n");
+    mac.update(body);
+    mac
+}
+
+/// Middleware that lets through only requests signed with the key of a known device.
+/// When authentication is disabled every request is attributed to the default device.
+pub async fn verify(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let (mut parts, body) = request.into_parts();
+    if !state.config.features.auth {
+        parts.extensions.insert(Device(DEFAULT_DEVICE.to_string()));
+        return Ok(next.run(Request::from_parts(parts, body)).await);
+    }
+
+    let header = |name: &str| {
+        parts
+            .headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| AppError::Unauthorized(format!("missing {name} header")))
+    };
+    let device_id = header(DEVICE_ID_HEADER)?;
+    let timestamp: i64 = header(TIMESTAMP_HEADER)?
+        .parse()
+        .map_err(|_| AppError::Unauthorized("malformed timestamp".into()))?;
+    let signature = hex::decode(&header(SIGNATURE_HEADER)?)
+        .ok_or_else(|| AppError::Unauthorized("malformed signature".into()))?;
+
+    let server_time = chrono::Utc::now();
+    let now = server_time.timestamp();
+    if (now - timestamp).abs() > MAX_CLOCK_SKEW {
+        return Err(AppError::ClockSkew {
+            timestamp,
+            now: server_time,
+        });
+    }
+
+    let key = state
+        .storage
+        .device_key(&device_id)?
+        .and_then(|key| hex::decode(&key))
+        .ok_or_else(|| AppError::Unauthorized(format!("unknown device {device_id}")))?;
+
+    let body = axum::body::to_bytes(body, state.config.max_body_size)
+        .await
+        .map_err(|_| AppError::PayloadTooLarge)?;
+
+    if mac(&key, timestamp, &body)
+        .verify_slice(&signature)
+        .is_err()
+    {
+        return Err(AppError::Unauthorized(format!(
+            "invalid signature from {device_id}"
+        )));
+    }
+    if !state.replay_guard.check(&signature, timestamp, now) {
+        return Err(AppError::Replayed(device_id));
+    }
+
+    parts.extensions.insert(Device(device_id));
+    Ok(next.run(Request::from_parts(parts, Body::from(body))).await)
+}
+
+/// Middleware that lets through only requests bearing the admin token of the configuration.
+pub async fn verify_admin(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let Some(token) = &state.config.admin_token else {
+        return Err(AppError::Unauthorized(
+            "admin endpoints are disabled, set an admin token".into(),
+        ));
+    };
+    let given = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| AppError::Unauthorized("missing admin token".into()))?;
+    // Compared as MACs, so that the time taken tells nothing about the token
+    let expected = mac(token.as_bytes(), 0, b"admin").finalize().into_bytes();
+    if mac(given.as_bytes(), 0, b"admin")
+        .verify_slice(&expected)
+        .is_err()
+    {
+        return Err(AppError::Unauthorized("invalid admin token".into()));
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Args, Config};
+    use crate::recognition::Recognizer;
+    use crate::storage::Storage;
+    use axum::http::StatusCode;
+    use axum::routing::post;
+    use axum::{Extension, Router};
+    use clap::Parser;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    /// A server with authentication on that answers every request with the device it was
+    /// signed by, with its data in a directory of its own, deleted on drop.
+    struct TestServer {
+        state: AppState,
+        directory: PathBuf,
+    }
+
+    impl TestServer {
+        fn new(name: &str) -> Self {
+            let directory =
+                std::env::temp_dir().join(format!("digit-auth-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&directory);
+            let args = Args::parse_from([
+                "digit-server",
+                "--data-dir",
+                directory.to_str().unwrap(),
+                "--recognition",
+                "false",
+            ]);
+            let config = Config::load(args).unwrap();
+            let storage = Storage::open(&config.data_directory).unwrap();
+            storage.set_device_key("cam-1", KEY).unwrap();
+            let state = AppState {
+                config: Arc::new(config),
+                storage: Arc::new(storage),
+                recognizer: Arc::new(Recognizer::new()),
+                replay_guard: Arc::new(ReplayGuard::default()),
+            };
+            Self { state, directory }
+        }
+
+        /// Posts `body` signed with `key` at `timestamp`.
+        async fn post(&self, key: &str, timestamp: i64, body: &'static [u8]) -> Response {
+            let signature = mac(&hex::decode(key).unwrap(), timestamp, body)
+                .finalize()
+                .into_bytes();
+            self.send(timestamp, &hex::encode(&signature), body).await
+        }
+
+        async fn send(&self, timestamp: i64, signature: &str, body: &'static [u8]) -> Response {
+            let request = Request::post("/")
+                .header(DEVICE_ID_HEADER, "cam-1")
+                .header(TIMESTAMP_HEADER, timestamp.to_string())
+                .header(SIGNATURE_HEADER, signature)
+                .body(Body::from(body))
+                .unwrap();
+            Router::new()
+                .route(
+                    "/",
+                    post(|Extension(Device(device_id)): Extension<Device>| async { device_id }),
+                )
+                .route_layer(axum::middleware::from_fn_with_state(
+                    self.state.clone(),
+                    verify,
+                ))
+                .with_state(self.state.clone())
+                .oneshot(request)
+                .await
+                .unwrap()
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.directory);
+        }
+    }
+
+    async fn json(response: Response) -> serde_json::Value {
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn accepts_only_requests_signed_with_the_device_key() {
+        let server = TestServer::new("signing");
+        let now = chrono::Utc::now().timestamp();
+
+        let response = server.post(KEY, now, b"reading").await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"cam-1");
+
+        let other_key = generate_key().unwrap();
+        let response = server.post(&other_key, now, b"reading").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let signature = mac(&hex::decode(KEY).unwrap(), now, b"reading")
+            .finalize()
+            .into_bytes();
+        let response = server
+            .send(now, &hex::encode(&signature), b"tampered")
+            .await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(json(response).await["retryable"], false);
+
+        let response = server.send(now, "not hex", b"reading").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_outside_the_clock_skew() {
+        let server = TestServer::new("skew");
+        let now = chrono::Utc::now().timestamp();
+
+        for timestamp in [now - MAX_CLOCK_SKEW - 10, now + MAX_CLOCK_SKEW + 10] {
+            let response = server.post(KEY, timestamp, b"reading").await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{timestamp}");
+            let body = json(response).await;
+            assert_eq!(body["error"], "clock_skew");
+            assert_eq!(body["retryable"], true);
+            let server_time =
+                chrono::DateTime::parse_from_rfc3339(body["server_time"].as_str().unwrap())
+                    .unwrap();
+            assert!((server_time.timestamp() - now).abs() <= 5, "{server_time}");
+        }
+        let response = server
+            .post(KEY, now - MAX_CLOCK_SKEW + 10, b"reading")
+            .await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn replayed_request_may_be_signed_again() {
+        let server = TestServer::new("replay");
+        let now = chrono::Utc::now().timestamp();
+
+        assert_eq!(
+            server.post(KEY, now, b"reading").await.status(),
+            StatusCode::OK
+        );
+        let response = server.post(KEY, now, b"reading").await;
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = json(response).await;
+        assert_eq!(body["error"], "replayed");
+        assert_eq!(body["retryable"], true);
+
+        assert_eq!(
+            server.post(KEY, now + 1, b"reading").await.status(),
+            StatusCode::OK
+        );
+    }
+}
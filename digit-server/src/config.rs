@@ -0,0 +1,572 @@
+use chrono::NaiveTime;
+use chrono_tz::Tz;
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use digit_protocol::hex;
+use digit_protocol::roi::{Roi, Rotation};
+use digit_protocol::sensor::SensorProfile;
+
+use crate::alerts::AlertConfig;
+use crate::meter::{DEFAULT_METER, Meter, MeterKind};
+use crate::tls::TlsConfig;
+
+/// Configuration file read when no other is given. It is fine for it not to exist.
+const DEFAULT_CONFIG_FILE: &str = "digit-server.toml";
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("could not read config file {0}: {1}")]
+    Read(PathBuf, std::io::Error),
+    #[error("could not parse config file {0}: {1}")]
+    Parse(PathBuf, toml::de::Error),
+    #[error("invalid configuration: {0}")]
+    Invalid(String),
+}
+
+/// Command line flags. Every flag can also be set with the environment variable next to it,
+/// and both take precedence over the configuration file.
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Stores and reads the meter photos sent by the camera"
+)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Configuration file in TOML format
+    #[arg(long, env = "DIGIT_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen on
+    #[arg(long, env = "DIGIT_BIND")]
+    bind: Option<SocketAddr>,
+    /// Directory for the photos, the database and the reports
+    #[arg(long, env = "DIGIT_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+    /// Largest accepted request body in bytes
+    #[arg(long, env = "DIGIT_MAX_BODY_SIZE")]
+    max_body_size: Option<usize>,
+    /// Days to keep the photos for, 0 keeps them forever. Readings are always kept.
+    #[arg(long, env = "DIGIT_RETENTION_DAYS")]
+    retention_days: Option<u32>,
+    /// Time zone the months of the reports are counted in, e.g. Europe/Tallinn
+    #[arg(long, env = "DIGIT_TIMEZONE")]
+    timezone: Option<String>,
+    /// What the default meter measures, the one of the cameras not put on another
+    #[arg(long, env = "DIGIT_METER_KIND")]
+    meter_kind: Option<MeterKind>,
+    /// Unit of the default meter, e.g. m³ or kWh. Defaults to the usual one of its kind.
+    #[arg(long, env = "DIGIT_METER_UNIT")]
+    meter_unit: Option<String>,
+    /// Number of wheels on the default meter, including the fractional ones
+    #[arg(long, env = "DIGIT_METER_DIGITS")]
+    meter_digits: Option<usize>,
+    /// Number of wheels showing fractions of the unit
+    #[arg(long, env = "DIGIT_METER_DECIMALS")]
+    meter_decimals: Option<usize>,
+    /// Read the meter from every uploaded photo
+    #[arg(long, env = "DIGIT_RECOGNITION")]
+    recognition: Option<bool>,
+    /// Write the monthly reports at the end of each month
+    #[arg(long, env = "DIGIT_REPORTS")]
+    reports: Option<bool>,
+    /// Accept uploads and health reports only when signed with a device key
+    #[arg(long, env = "DIGIT_AUTH")]
+    auth: Option<bool>,
+    /// Notify the alert sinks of the configuration file about failing devices
+    #[arg(long, env = "DIGIT_ALERTS")]
+    alerts: Option<bool>,
+    /// Hex encoded public key the firmware releases are signed with. Without it no updates
+    /// are offered.
+    #[arg(long, env = "DIGIT_FIRMWARE_PUBLIC_KEY")]
+    firmware_public_key: Option<String>,
+    /// Bearer token of the admin endpoints, which are disabled without it
+    #[arg(long, env = "DIGIT_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+    /// PEM file with the certificate to serve HTTPS with, plain HTTP is served without it
+    #[arg(long, env = "DIGIT_TLS_CERT", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM file with the private key of the certificate
+    #[arg(long, env = "DIGIT_TLS_KEY", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Manage the keys devices sign their requests with
+    #[command(subcommand)]
+    Keys(KeysCommand),
+    /// Manage the devices
+    #[command(subcommand)]
+    Devices(DevicesCommand),
+    /// Sign firmware releases
+    #[command(subcommand)]
+    Firmware(FirmwareCommand),
+    /// Manage the meters the devices watch
+    #[command(subcommand)]
+    Meters(MetersCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeysCommand {
+    /// Generate a new key for a device, replacing its current one
+    Add { device_id: String },
+    /// List the devices that have a key
+    List,
+    /// Remove the key of a device, so that it can no longer upload
+    Remove { device_id: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DevicesCommand {
+    /// List the devices with their meter, firmware group and when they were last heard of
+    List,
+    /// Replace the settings a device picks up at its next check-in. Settings that are not
+    /// given fall back to the device's built-in ones.
+    Configure {
+        device_id: String,
+        /// Light the flash LED while taking the photo
+        #[arg(long)]
+        flash: Option<bool>,
+        /// Brightness of the flash LED in percent
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100))]
+        flash_brightness: Option<u8>,
+        /// Milliseconds the flash LED is lit before the first frame is taken
+        #[arg(long)]
+        flash_warm_up_ms: Option<u16>,
+        /// Upload only this part of the 1600x1200 photo, given as x,y,width,height
+        #[arg(long, value_parser = parse_roi)]
+        roi: Option<Roi>,
+        /// Turn the part clockwise by 0, 90, 180 or 270 degrees to make it upright
+        #[arg(long, default_value = "0", requires = "roi", value_parser = parse_rotation)]
+        rotation: Rotation,
+        /// Image settings of the sensor as JSON, e.g. '{"contrast":2,"gain_ceiling":8}'
+        #[arg(long, value_parser = parse_sensor_profile)]
+        sensor: Option<SensorProfile>,
+    },
+    /// Set when a device wakes up, in the server's time zone
+    Schedule {
+        device_id: String,
+        /// Wake once a day at this time, e.g. 22:00
+        #[arg(
+            long,
+            required_unless_present = "every_hours",
+            conflicts_with = "every_hours"
+        )]
+        daily: Option<NaiveTime>,
+        /// Wake every this many hours, counted from midnight
+        #[arg(long)]
+        every_hours: Option<u32>,
+        /// Wake additionally in this many last days of each month
+        #[arg(long, default_value_t = 0, requires = "month_end_at")]
+        month_end_days: u32,
+        /// Time of day of the extra wakes near the end of the month, can be repeated
+        #[arg(long)]
+        month_end_at: Vec<NaiveTime>,
+    },
+    /// Put a device into a firmware group, it is offered the releases of that group only
+    Group { device_id: String, group: String },
+    /// Put a device on a meter, its photos are read as that meter's from now on
+    Meter { device_id: String, meter_id: String },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum MetersCommand {
+    /// Add a meter, or change the one with the same id. The default meter is set in the
+    /// configuration instead.
+    Add {
+        meter_id: String,
+        /// What the meter measures
+        #[arg(long)]
+        kind: MeterKind,
+        /// Unit of the register, defaults to m³ for gas and water and kWh for electricity
+        #[arg(long)]
+        unit: Option<String>,
+        /// Number of wheels on the register, including the fractional ones
+        #[arg(long)]
+        digits: usize,
+        /// Number of wheels showing fractions of the unit
+        #[arg(long, default_value_t = 0)]
+        decimals: usize,
+    },
+    /// List the meters
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum FirmwareCommand {
+    /// Generate a key pair for signing releases. The public key goes into the server's and
+    /// the firmware's configuration, the private key stays with whoever builds the releases.
+    Keygen,
+    /// Print the signature of a firmware image, to upload together with it
+    Sign {
+        /// File with the hex encoded private key
+        #[arg(long)]
+        key_file: PathBuf,
+        /// Version of the release, the one in the firmware's Cargo.toml
+        #[arg(long)]
+        version: String,
+        /// The image, e.g. made with `espflash save-image`
+        image: PathBuf,
+    },
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<SocketAddr>,
+    data_dir: Option<PathBuf>,
+    max_body_size: Option<usize>,
+    retention_days: Option<u32>,
+    timezone: Option<String>,
+    meter: FileMeterConfig,
+    features: FileFeatures,
+    alerts: AlertConfig,
+    firmware: FileFirmwareConfig,
+    tls: FileTlsConfig,
+    admin_token: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileTlsConfig {
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileFirmwareConfig {
+    public_key: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileMeterConfig {
+    kind: Option<MeterKind>,
+    unit: Option<String>,
+    digits: Option<usize>,
+    decimals: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileFeatures {
+    recognition: Option<bool>,
+    reports: Option<bool>,
+    auth: Option<bool>,
+    alerts: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Features {
+    pub recognition: bool,
+    pub reports: bool,
+    pub auth: bool,
+    pub alerts: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: SocketAddr,
+    pub data_directory: PathBuf,
+    pub max_body_size: usize,
+    /// Days to keep the photos for, `None` keeps them forever.
+    pub retention_days: Option<u32>,
+    pub timezone: Tz,
+    /// The meter of the cameras that were not put on another, see `digit-server meters`.
+    pub meter: Meter,
+    pub features: Features,
+    /// Alert rules and sinks, only from the configuration file.
+    pub alerts: AlertConfig,
+    /// Key the firmware releases are signed with, `None` turns off updates.
+    pub firmware_public_key: Option<String>,
+    /// Token the admin endpoints expect, `None` turns them off.
+    pub admin_token: Option<String>,
+    /// Certificate to serve HTTPS with, `None` serves plain HTTP.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Config {
+    /// Builds the configuration from the command line, the environment and the configuration file.
+    pub fn load(args: Args) -> Result<Self, ConfigError> {
+        let file = match &args.config {
+            Some(path) => read_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                read_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => FileConfig::default(),
+        };
+
+        // The flags come in pairs, so a pair given on the command line replaces the file's
+        let tls = match (
+            args.tls_cert.or(file.tls.cert),
+            args.tls_key.or(file.tls.key),
+        ) {
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+            (None, None) => None,
+            _ => {
+                return Err(ConfigError::Invalid(
+                    "TLS needs both a certificate and a key".into(),
+                ));
+            }
+        };
+        // The camera was first put on the gas meter
+        let meter_kind = args
+            .meter_kind
+            .or(file.meter.kind)
+            .unwrap_or(MeterKind::Gas);
+        let timezone = args
+            .timezone
+            .or(file.timezone)
+            .unwrap_or_else(|| "UTC".to_string());
+        let config = Config {
+            bind: args
+                .bind
+                .or(file.bind)
+                .unwrap_or(SocketAddr::from(([0, 0, 0, 0], 3000))),
+            data_directory: args
+                .data_dir
+                .or(file.data_dir)
+                .unwrap_or_else(|| PathBuf::from("data")),
+            max_body_size: args
+                .max_body_size
+                .or(file.max_body_size)
+                .unwrap_or(8 * 1024 * 1024),
+            retention_days: args
+                .retention_days
+                .or(file.retention_days)
+                .filter(|&days| days > 0),
+            timezone: timezone
+                .parse()
+                .map_err(|_| ConfigError::Invalid(format!("unknown time zone {timezone}")))?,
+            meter: Meter {
+                id: DEFAULT_METER.to_string(),
+                kind: meter_kind,
+                unit: args
+                    .meter_unit
+                    .or(file.meter.unit)
+                    .unwrap_or_else(|| meter_kind.default_unit().to_string()),
+                digits: args.meter_digits.or(file.meter.digits).unwrap_or(8),
+                decimals: args.meter_decimals.or(file.meter.decimals).unwrap_or(3),
+            },
+            features: Features {
+                recognition: args
+                    .recognition
+                    .or(file.features.recognition)
+                    .unwrap_or(true),
+                reports: args.reports.or(file.features.reports).unwrap_or(true),
+                auth: args.auth.or(file.features.auth).unwrap_or(true),
+                alerts: args.alerts.or(file.features.alerts).unwrap_or(true),
+            },
+            alerts: file.alerts,
+            firmware_public_key: args.firmware_public_key.or(file.firmware.public_key),
+            admin_token: args.admin_token.or(file.admin_token),
+            tls,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if self.data_directory.as_os_str().is_empty() {
+            return Err(ConfigError::Invalid("data directory is empty".into()));
+        }
+        if self.max_body_size < 1024 {
+            return Err(ConfigError::Invalid(format!(
+                "max body size of {} bytes is too small for any photo",
+                self.max_body_size
+            )));
+        }
+        self.meter
+            .validate()
+            .map_err(|e| ConfigError::Invalid(e.to_string()))?;
+        self.alerts.validate().map_err(ConfigError::Invalid)?;
+        if let Some(key) = &self.firmware_public_key
+            && hex::decode_array::<32>(key).is_none()
+        {
+            return Err(ConfigError::Invalid(
+                "firmware public key must be 32 hex encoded bytes".into(),
+            ));
+        }
+        if self
+            .admin_token
+            .as_ref()
+            .is_some_and(|token| token.len() < 16)
+        {
+            return Err(ConfigError::Invalid(
+                "admin token must be at least 16 characters".into(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn reports_directory(&self) -> PathBuf {
+        self.data_directory.join("reports")
+    }
+}
+
+fn parse_roi(region: &str) -> Result<Roi, String> {
+    let roi: Roi = region.parse().map_err(|e| format!("{e}"))?;
+    roi.validate().map_err(|e| format!("{e}"))?;
+    Ok(roi)
+}
+
+fn parse_rotation(degrees: &str) -> Result<Rotation, String> {
+    degrees.parse().map_err(|e| format!("{e}"))
+}
+
+fn parse_sensor_profile(json: &str) -> Result<SensorProfile, String> {
+    serde_json::from_str(json).map_err(|e| format!("{e}"))
+}
+
+fn read_file(path: &Path) -> Result<FileConfig, ConfigError> {
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_path_buf(), e))?;
+    toml::from_str(&contents).map_err(|e| ConfigError::Parse(path.to_path_buf(), e))
+}
+
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let enabled = |on: bool| if on { "enabled" } else { "disabled" };
+        writeln!(f, "bind address:   {}", self.bind)?;
+        match &self.tls {
+            Some(tls) => writeln!(f, "tls:            {}", tls.cert.display())?,
+            None => writeln!(f, "tls:            disabled")?,
+        }
+        writeln!(f, "data directory: {}", self.data_directory.display())?;
+        writeln!(f, "max body size:  {} bytes", self.max_body_size)?;
+        match self.retention_days {
+            Some(days) => writeln!(f, "retention:      {days} days")?,
+            None => writeln!(f, "retention:      forever")?,
+        }
+        writeln!(f, "timezone:       {}", self.timezone)?;
+        writeln!(
+            f,
+            "default meter:  {}, {} digits, {} decimals, {}",
+            self.meter.kind, self.meter.digits, self.meter.decimals, self.meter.unit
+        )?;
+        writeln!(f, "recognition:    {}", enabled(self.features.recognition))?;
+        writeln!(f, "reports:        {}", enabled(self.features.reports))?;
+        writeln!(f, "authentication: {}", enabled(self.features.auth))?;
+        writeln!(
+            f,
+            "firmware:       {}",
+            enabled(self.firmware_public_key.is_some())
+        )?;
+        writeln!(f, "admin:          {}", enabled(self.admin_token.is_some()))?;
+        match self.alerts.sinks.len() {
+            _ if !self.features.alerts => write!(f, "alerts:         disabled"),
+            0 => write!(f, "alerts:         no sinks"),
+            sinks => write!(f, "alerts:         {sinks} sinks"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `contents` to a configuration file of its own and loads it with `flags`.
+    fn load(name: &str, contents: &str, flags: &[&str]) -> Result<Config, ConfigError> {
+        let path =
+            std::env::temp_dir().join(format!("digit-config-{}-{name}.toml", std::process::id()));
+        std::fs::write(&path, contents).unwrap();
+        let args = Args::parse_from(
+            ["digit-server", "--config", path.to_str().unwrap()]
+                .iter()
+                .chain(flags),
+        );
+        let config = Config::load(args);
+        let _ = std::fs::remove_file(&path);
+        config
+    }
+
+    #[test]
+    fn loads_example_file() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("digit-server.example.toml");
+        let contents = std::fs::read_to_string(path).unwrap();
+        let config = load("example", &contents, &[]).unwrap();
+        assert_eq!(config.timezone, chrono_tz::Europe::Tallinn);
+        assert_eq!(config.retention_days, None);
+        assert!(config.features.auth);
+    }
+
+    #[test]
+    fn flags_take_precedence_over_file() {
+        let file = r#"
+            bind = "127.0.0.1:4000"
+            data_dir = "/srv/digit"
+            retention_days = 30
+            timezone = "Europe/Tallinn"
+            [meter]
+            kind = "water"
+            digits = 6
+            decimals = 2
+            [features]
+            auth = false
+            reports = false
+        "#;
+        let config = load(
+            "layers",
+            file,
+            &[
+                "--retention-days",
+                "0",
+                "--timezone",
+                "UTC",
+                "--reports",
+                "true",
+            ],
+        )
+        .unwrap();
+        // From the file
+        assert_eq!(config.bind, "127.0.0.1:4000".parse().unwrap());
+        assert_eq!(config.data_directory, Path::new("/srv/digit"));
+        assert_eq!(config.meter.kind, MeterKind::Water);
+        assert_eq!(config.meter.unit, "m³");
+        assert_eq!((config.meter.digits, config.meter.decimals), (6, 2));
+        assert!(!config.features.auth);
+        // From the flags
+        assert_eq!(config.retention_days, None);
+        assert_eq!(config.timezone, chrono_tz::UTC);
+        assert!(config.features.reports);
+        // Neither
+        assert_eq!(config.max_body_size, 8 * 1024 * 1024);
+        assert!(config.features.recognition);
+        assert_eq!(config.reports_directory(), Path::new("/srv/digit/reports"));
+    }
+
+    #[test]
+    fn rejects_invalid_configuration() {
+        let invalid = [
+            ("unknown-key", "colour = \"red\"", &[][..]),
+            ("timezone", "", &["--timezone", "Mars/Olympus"][..]),
+            ("body-size", "max_body_size = 100", &[][..]),
+            ("decimals", "[meter]\ndigits = 3\ndecimals = 3", &[][..]),
+            ("admin-token", "admin_token = \"short\"", &[][..]),
+            ("public-key", "[firmware]\npublic_key = \"abcd\"", &[][..]),
+            ("tls", "[tls]\ncert = \"cert.pem\"", &[][..]),
+            ("empty-dir", "data_dir = \"\"", &[][..]),
+        ];
+        for (name, file, flags) in invalid {
+            assert!(load(name, file, flags).is_err(), "{name}");
+        }
+        assert!(matches!(
+            load("parse", "retention_days = \"forever\"", &[]),
+            Err(ConfigError::Parse(..))
+        ));
+    }
+
+    #[test]
+    fn summary_tells_what_is_enabled() {
+        let config = load("summary", "retention_days = 7", &["--auth", "false"]).unwrap();
+        let summary = config.to_string();
+        assert!(summary.contains("retention:      7 days"), "{summary}");
+        assert!(summary.contains("authentication: disabled"), "{summary}");
+        assert!(summary.contains("admin:          disabled"), "{summary}");
+    }
+}
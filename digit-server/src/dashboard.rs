@@ -0,0 +1,959 @@
+use axum::{
+    Router,
+    extract::{Path as UrlPath, Query, State},
+    http::header,
+    response::{Html, IntoResponse, Response},
+    routing::get,
+};
+use chrono::{
+    DateTime, Datelike, Days, Local, Months, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc,
+};
+use chrono_tz::Tz;
+use digit_protocol::Timestamp;
+use serde::Deserialize;
+use std::fmt::Write;
+
+use crate::AppState;
+use crate::error::AppError;
+use crate::meter::Meter;
+use crate::report::{MIN_CONFIDENCE, escape};
+use crate::storage::{DeviceDiagnostics, Photo, Storage, StorageError};
+
+/// Days of battery voltages shown for a device.
+const VOLTAGE_DAYS: u64 = 30;
+const CHART_WIDTH: f64 = 720.0;
+const CHART_HEIGHT: f64 = 220.0;
+/// Room left of and below the plot for the labels.
+const CHART_MARGIN: f64 = 60.0;
+/// Everything the pages need to look right, so that they work without internet access.
+const STYLE: &str = "\
+body{font-family:sans-serif;max-width:760px;margin:1em auto;padding:0 1em;color:#222}\
+nav a{margin-right:1em}\
+table{border-collapse:collapse}th,td{text-align:left;padding:.2em 1em .2em 0}\
+.photo{position:relative;display:inline-block;margin:0}.photo img{display:block;max-width:100%}\
+.reading{position:absolute;top:.5em;left:.5em;padding:.2em .4em;background:rgba(0,0,0,.7);\
+color:#fff;font:bold 1.4em monospace}\
+.uncertain{color:#fc6}.overdue{color:#c00}\
+svg{width:100%;height:auto}svg text{font-size:12px;fill:#444}\
+.axis{stroke:#888}.bar{fill:#48c}.line{fill:none;stroke:#48c;stroke-width:2}";
+
+/// The read-only pages for people: meters with their latest photo and consumption, and the
+/// status of the devices.
+pub fn routes() -> Router<AppState> {
+    Router::new()
+        .route("/", get(overview))
+        .route("/meters/{meter}", get(meter_page))
+        .route("/devices", get(devices_page))
+        .route("/devices/{device}", get(device_page))
+        .route("/photos/{file_name}", get(photo))
+}
+
+/// Length of the bars of the consumption chart.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+}
+
+impl Period {
+    const ALL: [Period; 3] = [Period::Day, Period::Week, Period::Month];
+
+    fn as_str(self) -> &'static str {
+        match self {
+            Period::Day => "day",
+            Period::Week => "week",
+            Period::Month => "month",
+        }
+    }
+
+    /// Number of periods in the chart.
+    fn count(self) -> usize {
+        match self {
+            Period::Day => 30,
+            Period::Week | Period::Month => 12,
+        }
+    }
+
+    /// First day of the period containing `day`. Weeks start on Monday.
+    fn start(self, day: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => day,
+            Period::Week => day - Days::new(day.weekday().num_days_from_monday().into()),
+            Period::Month => day.with_day(1).unwrap_or(day),
+        }
+    }
+
+    /// First day of the period after the one starting at `start`.
+    fn next(self, start: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => start + Days::new(1),
+            Period::Week => start + Days::new(7),
+            Period::Month => start + Months::new(1),
+        }
+    }
+
+    /// First day of the period before the one starting at `start`.
+    fn previous(self, start: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Day => start - Days::new(1),
+            Period::Week => start - Days::new(7),
+            Period::Month => start - Months::new(1),
+        }
+    }
+
+    /// First day of the oldest period in the chart when it ends with the period of `today`.
+    fn first(self, today: NaiveDate) -> NaiveDate {
+        (1..self.count()).fold(self.start(today), |start, _| self.previous(start))
+    }
+
+    fn label(self, start: NaiveDate) -> String {
+        match self {
+            Period::Day => start.format("%m-%d").to_string(),
+            Period::Week => format!("W{}", start.iso_week().week()),
+            Period::Month => start.format("%Y-%m").to_string(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct MeterQuery {
+    /// Length of the bars of the consumption chart, a day when not given.
+    period: Option<String>,
+}
+
+/// Consumption in each period of the chart ending with the period of `today`, oldest first,
+/// from readings sorted by time. A period counts from the last reading before it to its own
+/// last one, so what is used between two periods is not lost. Periods without readings have
+/// no consumption.
+fn consumption(
+    readings: &[(NaiveDateTime, f64)],
+    period: Period,
+    today: NaiveDate,
+) -> Vec<(NaiveDate, Option<f64>)> {
+    let mut start = period.first(today);
+    let mut previous = readings
+        .iter()
+        .take_while(|(time, _)| time.date() < start)
+        .last()
+        .map(|(_, value)| *value);
+
+    let mut periods = Vec::new();
+    while start <= today {
+        let end = period.next(start);
+        let mut within = readings
+            .iter()
+            .filter(|(time, _)| time.date() >= start && time.date() < end)
+            .map(|(_, value)| *value);
+        let first = within.next();
+        let last = within.next_back().or(first);
+        periods.push((start, last.zip(previous.or(first)).map(|(l, p)| l - p)));
+        previous = last.or(previous);
+        start = end;
+    }
+    periods
+}
+
+// The pages are built off the async runtime, as they query the storage
+
+async fn overview(State(state): State<AppState>) -> Result<Html<String>, AppError> {
+    tokio::task::spawn_blocking(move || render_overview(&state)).await?
+}
+
+fn render_overview(state: &AppState) -> Result<Html<String>, AppError> {
+    let mut body = String::new();
+    for meter in state.storage.meters()? {
+        let _ = writeln!(
+            body,
+            "<h2><a href=\"/meters/{id}\">{kind} meter {id}</a></h2>",
+            id = escape(&meter.id),
+            kind = meter.kind.title()
+        );
+        body.push_str(&latest_photo(
+            &state.storage,
+            &meter,
+            state.config.timezone,
+        )?);
+    }
+    Ok(page("Meters", &body))
+}
+
+async fn meter_page(
+    State(state): State<AppState>,
+    UrlPath(meter_id): UrlPath<String>,
+    Query(query): Query<MeterQuery>,
+) -> Result<Html<String>, AppError> {
+    tokio::task::spawn_blocking(move || render_meter_page(&state, &meter_id, &query)).await?
+}
+
+fn render_meter_page(
+    state: &AppState,
+    meter_id: &str,
+    query: &MeterQuery,
+) -> Result<Html<String>, AppError> {
+    let period = match query.period.as_deref() {
+        None => Period::Day,
+        Some(name) => Period::ALL
+            .into_iter()
+            .find(|period| period.as_str() == name)
+            .ok_or_else(|| AppError::BadRequest(format!("unknown period {name}")))?,
+    };
+    let meter = state
+        .storage
+        .meter(meter_id)?
+        .ok_or_else(|| AppError::NotFound(format!("meter {meter_id}")))?;
+
+    let today = Utc::now()
+        .with_timezone(&state.config.timezone)
+        .date_naive();
+    // The period before the chart provides the reading its first period starts from
+    let readings: Vec<(NaiveDateTime, f64)> = state
+        .storage
+        .readings(
+            &meter.id,
+            period
+                .previous(period.first(today))
+                .and_time(NaiveTime::MIN),
+            (today + Days::new(1)).and_time(NaiveTime::MIN),
+        )?
+        .into_iter()
+        .filter(|r| r.reading.confidence() >= MIN_CONFIDENCE)
+        .filter_map(|r| Some((r.captured_at, meter.value(&r.reading.value)?)))
+        .collect();
+    let bars: Vec<(String, Option<f64>)> = consumption(&readings, period, today)
+        .into_iter()
+        .map(|(start, used)| (period.label(start), used))
+        .collect();
+
+    let mut body = latest_photo(&state.storage, &meter, state.config.timezone)?;
+    body.push_str("<h2>Consumption</h2>\n<p>");
+    for other in Period::ALL {
+        if other == period {
+            let _ = write!(body, "<strong>By {}</strong> ", other.as_str());
+        } else {
+            let _ = write!(
+                body,
+                "<a href=\"?period={name}\">By {name}</a> ",
+                name = other.as_str()
+            );
+        }
+    }
+    body.push_str("</p>\n");
+    body.push_str(&bar_chart(&bars, &meter));
+    let _ = writeln!(
+        body,
+        "<p><a href=\"/reports/{month}?meter={id}&amp;format=html\">Report of {month}</a></p>",
+        month = today.format("%Y-%m"),
+        id = escape(&meter.id)
+    );
+    Ok(page(
+        &format!("{} meter {}", meter.kind.title(), meter.id),
+        &body,
+    ))
+}
+
+async fn devices_page(State(state): State<AppState>) -> Result<Html<String>, AppError> {
+    tokio::task::spawn_blocking(move || render_devices_page(&state)).await?
+}
+
+fn render_devices_page(state: &AppState) -> Result<Html<String>, AppError> {
+    let now = Utc::now();
+    let mut body = String::from(
+        "<table>\n<tr><th>Device</th><th>Meter</th><th>Last check-in</th>\
+         <th>Next expected wake</th><th>Battery</th></tr>\n",
+    );
+    for device_id in state.storage.device_ids()? {
+        let status = DeviceStatus::load(&state.storage, &device_id, state.config.timezone, now)?;
+        let _ = writeln!(
+            body,
+            "<tr><td><a href=\"/devices/{id}\">{id}</a></td><td>{meter}</td><td>{last_seen}</td>\
+             <td>{next_wake}</td><td>{battery}</td></tr>",
+            id = escape(&device_id),
+            meter = status.meter_link(),
+            last_seen = status.last_seen(state.config.timezone),
+            next_wake = status.next_wake(state.config.timezone, now),
+            battery = status.battery(),
+        );
+    }
+    body.push_str("</table>\n");
+    Ok(page("Devices", &body))
+}
+
+async fn device_page(
+    State(state): State<AppState>,
+    UrlPath(device_id): UrlPath<String>,
+) -> Result<Html<String>, AppError> {
+    tokio::task::spawn_blocking(move || render_device_page(&state, &device_id)).await?
+}
+
+fn render_device_page(state: &AppState, device_id: &str) -> Result<Html<String>, AppError> {
+    if !state.storage.device_ids()?.iter().any(|id| id == device_id) {
+        return Err(AppError::NotFound(format!("device {device_id}")));
+    }
+    let now = Utc::now();
+    let timezone = state.config.timezone;
+    let status = DeviceStatus::load(&state.storage, device_id, timezone, now)?;
+
+    let mut body = format!(
+        "<table>\n\
+         <tr><th>Meter</th><td>{meter}</td></tr>\n\
+         <tr><th>Last check-in</th><td>{last_seen}</td></tr>\n\
+         <tr><th>Next expected wake</th><td>{next_wake}</td></tr>\n\
+         <tr><th>Battery</th><td>{battery}</td></tr>\n\
+         </table>\n<h2>Battery voltage</h2>\n{chart}",
+        meter = status.meter_link(),
+        last_seen = status.last_seen(timezone),
+        next_wake = status.next_wake(timezone, now),
+        battery = status.battery(),
+        chart = voltage_chart(&status.voltages, now - Days::new(VOLTAGE_DAYS), now),
+    );
+    if let Some(diagnostics) = state.storage.latest_diagnostics(device_id)? {
+        body.push_str(&diagnostics_table(&diagnostics, timezone));
+    }
+    let recognitions = state.storage.recognition_by_flash(device_id)?;
+    if !recognitions.is_empty() {
+        body.push_str(
+            "<h2>Recognition by flash brightness</h2>\n<table>\n\
+             <tr><th>Flash</th><th>Photos</th><th>Read</th><th>Mean confidence</th></tr>\n",
+        );
+        for recognition in recognitions {
+            let _ = writeln!(
+                body,
+                "<tr><td>{brightness}</td><td>{photos}</td><td>{read}</td>\
+                 <td>{confidence}</td></tr>",
+                brightness = match recognition.brightness {
+                    0 => "off".into(),
+                    brightness => format!("{brightness}%"),
+                },
+                photos = recognition.photos,
+                read = recognition.read,
+                confidence = recognition
+                    .mean_confidence
+                    .map_or("-".into(), |confidence| format!(
+                        "{:.0}%",
+                        confidence * 100.0
+                    )),
+            );
+        }
+        body.push_str("</table>\n");
+    }
+    Ok(page(&format!("Device {device_id}"), &body))
+}
+
+async fn photo(
+    State(state): State<AppState>,
+    UrlPath(file_name): UrlPath<String>,
+) -> Result<Response, AppError> {
+    // Only names of stored photos are looked up, nothing else in the data directory is served
+    let stored = {
+        let (state, file_name) = (state.clone(), file_name.clone());
+        tokio::task::spawn_blocking(move || state.storage.has_photo(&file_name)).await??
+    };
+    if !stored {
+        return Err(AppError::NotFound(format!("photo {file_name}")));
+    }
+    let jpeg = match tokio::fs::read(state.storage.data_directory().join(&file_name)).await {
+        Ok(jpeg) => jpeg,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(AppError::NotFound(format!("photo {file_name}")));
+        }
+        Err(e) => return Err(StorageError::from(e).into()),
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, "image/jpeg"),
+            // A stored photo never changes
+            (header::CACHE_CONTROL, "max-age=86400, immutable"),
+        ],
+        jpeg,
+    )
+        .into_response())
+}
+
+/// The diagnostics a device last reported, leaving out what it did not measure.
+fn diagnostics_table(latest: &DeviceDiagnostics, timezone: Tz) -> String {
+    let diagnostics = &latest.diagnostics;
+    let ms = |ms: Option<u32>| ms.map(|ms| format!("{ms} ms"));
+    let kib = |bytes: Option<u32>| bytes.map(|bytes| format!("{} KiB", bytes / 1024));
+    let rows = [
+        ("Firmware", latest.firmware_version.clone()),
+        ("Build", diagnostics.build_hash.clone()),
+        ("Reset reason", diagnostics.reset_reason.clone()),
+        ("Wake reason", diagnostics.wake_reason.clone()),
+        (
+            "Wi-Fi signal",
+            diagnostics.wifi_rssi.map(|rssi| format!("{rssi} dBm")),
+        ),
+        (
+            "Wi-Fi channel",
+            diagnostics.wifi_channel.map(|channel| channel.to_string()),
+        ),
+        ("Access point", diagnostics.wifi_bssid.clone()),
+        ("Associating", ms(diagnostics.associate_ms)),
+        ("DHCP", ms(diagnostics.dhcp_ms)),
+        ("SNTP", ms(diagnostics.sntp_ms)),
+        ("Uptime", diagnostics.uptime_ms.map(|ms| format!("{ms} ms"))),
+        ("Previous capture", ms(diagnostics.previous_capture_ms)),
+        ("Previous upload", ms(diagnostics.previous_upload_ms)),
+        ("Free heap", kib(diagnostics.free_heap)),
+        ("Free PSRAM", kib(diagnostics.free_psram)),
+        (
+            "Chip temperature",
+            diagnostics
+                .chip_temperature
+                .map(|temperature| format!("{temperature:.1} °C")),
+        ),
+    ];
+    let rows: Vec<_> = rows
+        .into_iter()
+        .filter_map(|(name, value)| Some((name, value?)))
+        .collect();
+    if rows.is_empty() {
+        return String::new();
+    }
+    let mut table = format!(
+        "<h2>Diagnostics</h2>\n<p>Reported {}.</p>\n<table>\n",
+        local_time(latest.received_at, timezone)
+    );
+    for (name, value) in rows {
+        let _ = writeln!(table, "<tr><th>{name}</th><td>{}</td></tr>", escape(&value));
+    }
+    table.push_str("</table>\n");
+    table
+}
+
+/// What the pages show about a device.
+struct DeviceStatus {
+    meter: Meter,
+    last_seen: Option<DateTime<Local>>,
+    /// When the device should wake up after its last check-in.
+    expected_at: Option<Timestamp>,
+    /// Battery voltages of the last [`VOLTAGE_DAYS`] days, oldest first.
+    voltages: Vec<(DateTime<Local>, f32)>,
+}
+
+impl DeviceStatus {
+    fn load(
+        storage: &Storage,
+        device_id: &str,
+        timezone: Tz,
+        now: DateTime<Utc>,
+    ) -> Result<Self, StorageError> {
+        let last_seen = storage.last_seen(device_id)?;
+        let expected_at = match last_seen {
+            Some(last_seen) => Some(
+                storage
+                    .device_schedule(device_id)?
+                    .next_wake(last_seen.with_timezone(&timezone)),
+            ),
+            None => None,
+        };
+        Ok(Self {
+            meter: storage.device_meter(device_id)?,
+            last_seen,
+            expected_at,
+            voltages: storage.voltages(device_id, now - Days::new(VOLTAGE_DAYS))?,
+        })
+    }
+
+    fn meter_link(&self) -> String {
+        format!(
+            "<a href=\"/meters/{id}\">{id}</a>",
+            id = escape(&self.meter.id)
+        )
+    }
+
+    fn last_seen(&self, timezone: Tz) -> String {
+        self.last_seen
+            .map(|time| local_time(time, timezone))
+            .unwrap_or("never".into())
+    }
+
+    fn next_wake(&self, timezone: Tz, now: DateTime<Utc>) -> String {
+        match self.expected_at {
+            Some(time) if time < now => format!(
+                "<span class=\"overdue\">{} (overdue)</span>",
+                local_time(time, timezone)
+            ),
+            Some(time) => local_time(time, timezone),
+            None => "-".into(),
+        }
+    }
+
+    fn battery(&self) -> String {
+        self.voltages
+            .last()
+            .map(|(_, voltage)| format!("{voltage:.2} V"))
+            .unwrap_or("-".into())
+    }
+}
+
+/// The latest photo of `meter` with the reading laid over it.
+fn latest_photo(storage: &Storage, meter: &Meter, timezone: Tz) -> Result<String, StorageError> {
+    let Some(photo) = storage.latest_photo(&meter.id)? else {
+        return Ok("<p>No photos yet.</p>\n".into());
+    };
+    let (class, reading) = overlay(&photo, meter);
+    let taken = match photo.captured_at {
+        Some(captured_at) => captured_at.format("%Y-%m-%d %H:%M").to_string(),
+        None => local_time(photo.received_at, timezone),
+    };
+    let flash = match photo.flash_brightness {
+        Some(0) => ", without flash".into(),
+        Some(brightness) => format!(", flash at {brightness}%"),
+        None => String::new(),
+    };
+    Ok(format!(
+        "<figure class=\"photo\"><img src=\"/photos/{file}\" alt=\"Photo of meter {meter}\">\
+         <span class=\"{class}\">{reading}</span>\
+         <figcaption>Taken {taken} by <a href=\"/devices/{device}\">{device}</a>{flash}\
+         </figcaption></figure>\n",
+        file = escape(&photo.file_name),
+        meter = escape(&meter.id),
+        device = escape(&photo.device_id),
+    ))
+}
+
+/// CSS class and text of what was read from `photo`.
+fn overlay(photo: &Photo, meter: &Meter) -> (&'static str, String) {
+    let Some(reading) = &photo.reading else {
+        return match photo.recognition_error {
+            Some(_) => ("reading uncertain", "Not readable".into()),
+            None => ("reading", "Not read yet".into()),
+        };
+    };
+    let confidence = reading.confidence();
+    let class = if confidence >= MIN_CONFIDENCE {
+        "reading"
+    } else {
+        "reading uncertain"
+    };
+    let value = match meter.value(&reading.value) {
+        Some(value) => meter.format(value),
+        None => escape(&reading.value),
+    };
+    (class, format!("{value} ({:.0}%)", confidence * 100.0))
+}
+
+/// Bars of the values with their labels below, scaled to the largest value. Missing values
+/// leave a gap.
+fn bar_chart(bars: &[(String, Option<f64>)], meter: &Meter) -> String {
+    let max = bars
+        .iter()
+        .filter_map(|(_, value)| *value)
+        .fold(0.0, f64::max);
+    let plot_width = CHART_WIDTH - CHART_MARGIN;
+    let plot_height = CHART_HEIGHT - CHART_MARGIN;
+    let slot = plot_width / bars.len().max(1) as f64;
+    // At most twelve labels fit below the bars
+    let step = bars.len().div_ceil(12).max(1);
+
+    let mut svg = chart_start();
+    let _ = writeln!(
+        svg,
+        "<text x=\"{x}\" y=\"12\" text-anchor=\"end\">{max}</text>",
+        x = CHART_MARGIN - 6.0,
+        max = meter.format(max)
+    );
+    for (i, (label, value)) in bars.iter().enumerate() {
+        let x = CHART_MARGIN + i as f64 * slot;
+        if let Some(value) = value
+            && max > 0.0
+        {
+            let height = value.max(0.0) / max * plot_height;
+            let _ = writeln!(
+                svg,
+                "<rect class=\"bar\" x=\"{x:.1}\" y=\"{y:.1}\" width=\"{width:.1}\" \
+                 height=\"{height:.1}\"><title>{label}: {value}</title></rect>",
+                x = x + slot * 0.1,
+                y = plot_height - height,
+                width = slot * 0.8,
+                value = meter.format(*value),
+            );
+        }
+        if i % step == 0 {
+            let _ = writeln!(
+                svg,
+                "<text x=\"{x:.1}\" y=\"{y}\" text-anchor=\"middle\">{label}</text>",
+                x = x + slot / 2.0,
+                y = plot_height + 18.0,
+            );
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// Line of the battery voltages between `from` and `to`, scaled to the lowest and highest
+/// voltage.
+fn voltage_chart(
+    voltages: &[(DateTime<Local>, f32)],
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> String {
+    if voltages.is_empty() {
+        return format!("<p>No battery voltages in the last {VOLTAGE_DAYS} days.</p>\n");
+    }
+    let plot_width = CHART_WIDTH - CHART_MARGIN;
+    let plot_height = CHART_HEIGHT - CHART_MARGIN;
+    let low = voltages.iter().map(|(_, v)| *v).fold(f32::MAX, f32::min) - 0.05;
+    let high = voltages.iter().map(|(_, v)| *v).fold(f32::MIN, f32::max) + 0.05;
+    let duration = (to - from).num_seconds().max(1) as f64;
+
+    let mut svg = chart_start();
+    let _ = writeln!(
+        svg,
+        "<text x=\"{x}\" y=\"12\" text-anchor=\"end\">{high:.2} V</text>\n\
+         <text x=\"{x}\" y=\"{plot_height}\" text-anchor=\"end\">{low:.2} V</text>\n\
+         <text x=\"{CHART_MARGIN}\" y=\"{y}\">{from}</text>\n\
+         <text x=\"{CHART_WIDTH}\" y=\"{y}\" text-anchor=\"end\">{to}</text>",
+        x = CHART_MARGIN - 6.0,
+        y = plot_height + 18.0,
+        from = from.format("%Y-%m-%d"),
+        to = to.format("%Y-%m-%d"),
+    );
+    svg.push_str("<polyline class=\"line\" points=\"");
+    for (time, voltage) in voltages {
+        let x = CHART_MARGIN + (time.to_utc() - from).num_seconds() as f64 / duration * plot_width;
+        let y = f64::from((high - voltage) / (high - low)) * plot_height;
+        let _ = write!(svg, "{x:.1},{y:.1} ");
+    }
+    svg.push_str("\"/>\n</svg>\n");
+    svg
+}
+
+/// The opening tag of a chart and its axes.
+fn chart_start() -> String {
+    format!(
+        "<svg viewBox=\"0 0 {CHART_WIDTH} {CHART_HEIGHT}\" xmlns=\"http://www.w3.org/2000/svg\">\n\
+         <line class=\"axis\" x1=\"{CHART_MARGIN}\" y1=\"0\" x2=\"{CHART_MARGIN}\" y2=\"{y}\"/>\n\
+         <line class=\"axis\" x1=\"{CHART_MARGIN}\" y1=\"{y}\" x2=\"{CHART_WIDTH}\" y2=\"{y}\"/>\n",
+        y = CHART_HEIGHT - CHART_MARGIN
+    )
+}
+
+fn page(title: &str, body: &str) -> Html<String> {
+    Html(format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\">\
+         <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+         <title>{title}</title><style>{STYLE}</style></head>\n<body>\n\
+         <nav><a href=\"/\">Meters</a><a href=\"/devices\">Devices</a></nav>\n\
+         <h1>{title}</h1>\n{body}</body>\n</html>\n",
+        title = escape(title)
+    ))
+}
+
+fn local_time<T: TimeZone>(time: DateTime<T>, timezone: Tz) -> String {
+    time.with_timezone(&timezone)
+        .format("%Y-%m-%d %H:%M")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::ReplayGuard;
+    use crate::config::{Args, Config};
+    use crate::recognition::{DigitReading, Reading, Recognizer};
+    use crate::storage::HealthSample;
+    use axum::body::Body;
+    use axum::http::{Request, StatusCode};
+    use clap::Parser;
+    use digit_protocol::{Diagnostics, PROTOCOL_VERSION, UploadMetadata};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    const JPEG: &[u8] = &[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, 0xFF, 0xD9];
+
+    /// The state of a server with its data in a directory of its own, deleted on drop.
+    struct TestServer {
+        state: AppState,
+        directory: PathBuf,
+    }
+
+    impl TestServer {
+        fn new(name: &str) -> Self {
+            let directory =
+                std::env::temp_dir().join(format!("digit-dashboard-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&directory);
+            let args = Args::parse_from([
+                "digit-server",
+                "--data-dir",
+                directory.to_str().unwrap(),
+                "--timezone",
+                "UTC",
+            ]);
+            let config = Config::load(args).unwrap();
+            let storage = Storage::open(&config.data_directory).unwrap();
+            storage.save_meter(&config.meter).unwrap();
+            let state = AppState {
+                config: Arc::new(config),
+                storage: Arc::new(storage),
+                recognizer: Arc::new(Recognizer::new()),
+                replay_guard: Arc::new(ReplayGuard::default()),
+            };
+            Self { state, directory }
+        }
+
+        fn storage(&self) -> &Storage {
+            &self.state.storage
+        }
+
+        /// Stores a photo of the default meter read as `value` and returns its name.
+        fn add_photo(&self, captured_at: &str, value: &str, confidence: f32) -> String {
+            self.add_flash_photo(captured_at, value, confidence, None)
+        }
+
+        /// Like [`Self::add_photo`], taken with the flash at `flash_brightness`.
+        fn add_flash_photo(
+            &self,
+            captured_at: &str,
+            value: &str,
+            confidence: f32,
+            flash_brightness: Option<u8>,
+        ) -> String {
+            // Photos with the same content would be stored only once
+            let jpeg = [JPEG, captured_at.as_bytes()].concat();
+            let metadata = flash_brightness.map(|flash_brightness| UploadMetadata {
+                version: PROTOCOL_VERSION,
+                device_id: "espcam".into(),
+                firmware_version: "0.1.0".into(),
+                captured_at: format!("{captured_at}Z").parse().unwrap(),
+                flash_brightness: Some(flash_brightness),
+            });
+            let upload = self
+                .storage()
+                .store_upload(
+                    "espcam",
+                    Some(&format!("{captured_at}.jpg")),
+                    metadata.as_ref(),
+                    &jpeg,
+                )
+                .unwrap();
+            let reading = Reading {
+                value: value.into(),
+                digits: vec![
+                    DigitReading {
+                        digit: 0,
+                        confidence,
+                    };
+                    value.len()
+                ],
+                recognized_at: Local::now(),
+            };
+            self.storage().add_reading(upload.id, &reading).unwrap();
+            upload.file_name
+        }
+
+        async fn get(&self, uri: &str) -> (StatusCode, Vec<u8>) {
+            let response = routes()
+                .with_state(self.state.clone())
+                .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, body.to_vec())
+        }
+
+        async fn page(&self, uri: &str) -> String {
+            let (status, body) = self.get(uri).await;
+            assert_eq!(status, StatusCode::OK, "{uri}");
+            String::from_utf8(body).unwrap()
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.directory);
+        }
+    }
+
+    fn reading(time: &str, value: f64) -> (NaiveDateTime, f64) {
+        (time.parse().unwrap(), value)
+    }
+
+    #[test]
+    fn consumption_counts_from_last_reading_of_previous_period() {
+        let today = NaiveDate::from_ymd_opt(2026, 10, 14).unwrap();
+        let readings = [
+            reading("2026-09-30T22:00:00", 10.0),
+            reading("2026-10-01T07:00:00", 10.5),
+            reading("2026-10-01T22:00:00", 12.0),
+            reading("2026-10-13T08:00:00", 15.0),
+            reading("2026-10-14T08:00:00", 15.25),
+        ];
+
+        let days = consumption(&readings, Period::Day, today);
+        assert_eq!(days.len(), 30);
+        assert_eq!(days[0].0, NaiveDate::from_ymd_opt(2026, 9, 15).unwrap());
+        let day = |d| {
+            days.iter()
+                .find(|(start, _)| start.day() == d && start.month() == 10)
+        };
+        assert_eq!(day(1).unwrap().1, Some(2.0));
+        assert_eq!(day(2).unwrap().1, None);
+        assert_eq!(day(13).unwrap().1, Some(3.0));
+        assert_eq!(day(14).unwrap().1, Some(0.25));
+        // The first reading has nothing before it to count from
+        assert_eq!(
+            days[15],
+            (NaiveDate::from_ymd_opt(2026, 9, 30).unwrap(), Some(0.0))
+        );
+
+        let weeks = consumption(&readings, Period::Week, today);
+        assert_eq!(weeks.len(), 12);
+        assert_eq!(weeks[11].0, NaiveDate::from_ymd_opt(2026, 10, 12).unwrap());
+        assert_eq!(weeks[11].1, Some(3.25));
+        assert_eq!(weeks[10].1, None);
+        assert_eq!(weeks[9].1, Some(2.0));
+
+        let months = consumption(&readings, Period::Month, today);
+        assert_eq!(
+            months[11],
+            (NaiveDate::from_ymd_opt(2026, 10, 1).unwrap(), Some(5.25))
+        );
+        assert_eq!(months[10].1, Some(0.0));
+    }
+
+    #[tokio::test]
+    async fn overview_shows_latest_photo_with_reading() {
+        let server = TestServer::new("overview");
+        assert!(server.page("/").await.contains("No photos yet"));
+
+        server.add_photo("2026-10-01T08:00:00", "00012000", 0.9);
+        let latest = server.add_photo("2026-10-02T08:00:00", "00012345", 0.9);
+        let page = server.page("/").await;
+        assert!(
+            page.contains(&format!("src=\"/photos/{latest}\"")),
+            "{page}"
+        );
+        assert!(page.contains("12.345 m³ (90%)"), "{page}");
+        assert!(page.contains("href=\"/meters/default\""), "{page}");
+
+        server.add_photo("2026-10-03T08:00:00", "00012400", 0.2);
+        assert!(server.page("/").await.contains("reading uncertain"));
+    }
+
+    #[tokio::test]
+    async fn meter_page_charts_consumption() {
+        let server = TestServer::new("meter");
+        let today = Utc::now().date_naive();
+        for (days_ago, value) in [(2, "00012000"), (1, "00012500"), (0, "00013000")] {
+            let day = today - Days::new(days_ago);
+            server.add_photo(&format!("{day}T08:00:00"), value, 0.9);
+        }
+
+        for period in ["", "?period=day", "?period=week", "?period=month"] {
+            let page = server.page(&format!("/meters/default{period}")).await;
+            assert!(page.contains("Gas meter default"), "{page}");
+            assert!(page.contains("<rect class=\"bar\""), "{page}");
+        }
+        let page = server.page("/meters/default?period=day").await;
+        assert!(page.contains(": 0.500 m³</title>"), "{page}");
+
+        let (status, _) = server.get("/meters/default?period=year").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = server.get("/meters/nope").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn device_pages_show_status_and_voltages() {
+        let server = TestServer::new("devices");
+        server
+            .storage()
+            .add_health_sample(
+                "espcam",
+                &HealthSample {
+                    timestamp: Utc::now().fixed_offset(),
+                    voltage: Some(3.91),
+                    state_of_charge: None,
+                    firmware_version: Some("0.2.0"),
+                    diagnostics: Some(&Diagnostics {
+                        wifi_rssi: Some(-71),
+                        reset_reason: Some("brownout".into()),
+                        free_heap: Some(150 * 1024),
+                        ..Diagnostics::default()
+                    }),
+                },
+            )
+            .unwrap();
+
+        let page = server.page("/devices").await;
+        assert!(page.contains("href=\"/devices/espcam\""), "{page}");
+        assert!(page.contains("<td>3.91 V</td>"), "{page}");
+
+        let page = server.page("/devices/espcam").await;
+        assert!(page.contains("Next expected wake"), "{page}");
+        assert!(!page.contains("never"), "{page}");
+        assert!(page.contains("<polyline class=\"line\""), "{page}");
+        assert!(page.contains("<h2>Diagnostics</h2>"), "{page}");
+        assert!(
+            page.contains("<tr><th>Firmware</th><td>0.2.0</td></tr>"),
+            "{page}"
+        );
+        assert!(
+            page.contains("<tr><th>Wi-Fi signal</th><td>-71 dBm</td></tr>"),
+            "{page}"
+        );
+        assert!(
+            page.contains("<tr><th>Reset reason</th><td>brownout</td></tr>"),
+            "{page}"
+        );
+        assert!(
+            page.contains("<tr><th>Free heap</th><td>150 KiB</td></tr>"),
+            "{page}"
+        );
+        assert!(!page.contains("Free PSRAM"), "{page}");
+
+        let (status, _) = server.get("/devices/other").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn device_page_compares_recognition_by_flash_brightness() {
+        let server = TestServer::new("flash");
+        server.add_photo("2026-10-01T08:00:00", "00012000", 0.9);
+        assert!(
+            !server
+                .page("/devices/espcam")
+                .await
+                .contains("Recognition by flash brightness")
+        );
+
+        server.add_flash_photo("2026-10-02T08:00:00", "00012100", 0.5, Some(100));
+        server.add_flash_photo("2026-10-03T08:00:00", "00012200", 0.7, Some(100));
+        server.add_flash_photo("2026-10-04T08:00:00", "00012300", 0.9, Some(40));
+        let page = server.page("/devices/espcam").await;
+        assert!(
+            page.contains("<tr><td>40%</td><td>1</td><td>1</td><td>90%</td></tr>"),
+            "{page}"
+        );
+        assert!(
+            page.contains("<tr><td>100%</td><td>2</td><td>2</td><td>60%</td></tr>"),
+            "{page}"
+        );
+        let page = server.page("/").await;
+        assert!(page.contains("flash at 40%"), "{page}");
+    }
+
+    #[tokio::test]
+    async fn serves_only_stored_photos() {
+        let server = TestServer::new("photos");
+        let name = server.add_photo("2026-10-01T08:00:00", "00012000", 0.9);
+        let (status, body) = server.get(&format!("/photos/{name}")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, [JPEG, b"2026-10-01T08:00:00"].concat());
+
+        let (status, _) = server.get("/photos/digit-logger.db").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        server.storage().prune_uploads(Local::now()).unwrap();
+        let (status, _) = server.get(&format!("/photos/{name}")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+}
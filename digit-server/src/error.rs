@@ -0,0 +1,299 @@
+use axum::{
+    Json,
+    extract::multipart::MultipartError,
+    extract::rejection::JsonRejection,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use digit_protocol::{ErrorBody, MIN_SUPPORTED_VERSION, PROTOCOL_VERSION};
+use std::io::ErrorKind;
+
+use crate::firmware::FirmwareError;
+use crate::report::ReportError;
+use crate::storage::StorageError;
+
+/// Errors returned by the handlers. Every error is logged and turned into a status code with
+/// a JSON body telling the device whether trying again later could help.
+#[derive(Debug, thiserror::Error)]
+pub enum AppError {
+    #[error("malformed multipart body: {0}")]
+    MalformedMultipart(MultipartError),
+    #[error("malformed JSON body: {0}")]
+    MalformedJson(#[from] JsonRejection),
+    #[error("the request has no `file` field")]
+    MissingFile,
+    #[error("the request has no `telemetry` field")]
+    MissingTelemetry,
+    #[error("unexpected field {0:?}, every field may be sent once")]
+    UnexpectedField(String),
+    #[error("the uploaded file is not a JPEG image")]
+    NotJpeg,
+    #[error("the request body is too large")]
+    PayloadTooLarge,
+    #[error("invalid file name {0:?}")]
+    InvalidFileName(String),
+    /// Either side may be updated to a version the other understands, so the request is
+    /// worth keeping.
+    #[error(
+        "protocol version {0} is not supported, expected {MIN_SUPPORTED_VERSION} to {PROTOCOL_VERSION}"
+    )]
+    UnsupportedVersion(u16),
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    /// The device's clock is off, e.g. after a power loss without SNTP. It may sign the
+    /// request again once it has set its clock to the server time in the response.
+    #[error("timestamp {timestamp} is too far from server time {now}")]
+    ClockSkew { timestamp: i64, now: DateTime<Utc> },
+    /// A request signed exactly like one before. The device may sign it again, the same
+    /// request retried within a second carries the same timestamp and signature.
+    #[error("replayed request from {0}, sign it again")]
+    Replayed(String),
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("{0} not found")]
+    NotFound(String),
+    #[error("{0} already exists")]
+    Conflict(String),
+    #[error("invalid firmware: {0}")]
+    Firmware(#[from] FirmwareError),
+    #[error("storage failure: {0}")]
+    Storage(StorageError),
+    #[error("report failure: {0}")]
+    Report(ReportError),
+    #[error("background task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+impl AppError {
+    fn status(&self) -> StatusCode {
+        match self {
+            AppError::MalformedJson(rejection) => rejection.status(),
+            AppError::MalformedMultipart(_)
+            | AppError::MissingFile
+            | AppError::MissingTelemetry
+            | AppError::UnexpectedField(_)
+            | AppError::InvalidFileName(_)
+            | AppError::UnsupportedVersion(_)
+            | AppError::BadRequest(_)
+            | AppError::Firmware(_) => StatusCode::BAD_REQUEST,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) | AppError::Replayed(_) => StatusCode::CONFLICT,
+            AppError::Unauthorized(_) | AppError::ClockSkew { .. } => StatusCode::UNAUTHORIZED,
+            AppError::NotJpeg => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AppError::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            AppError::Report(ReportError::InvalidMonth(_)) => StatusCode::BAD_REQUEST,
+            AppError::Storage(StorageError::Io(e)) if e.kind() == ErrorKind::StorageFull => {
+                StatusCode::INSUFFICIENT_STORAGE
+            }
+            AppError::Storage(_) | AppError::Report(_) | AppError::Task(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    /// Whether sending the request again may succeed: failures on our side may well be gone
+    /// by then, bad requests will not be.
+    fn retryable(&self) -> bool {
+        self.status().is_server_error()
+            || matches!(
+                self,
+                AppError::Replayed(_)
+                    | AppError::UnsupportedVersion(_)
+                    | AppError::ClockSkew { .. }
+            )
+    }
+
+    /// Short machine readable name of the error.
+    fn code(&self) -> &'static str {
+        match self {
+            AppError::MalformedMultipart(_) => "malformed_multipart",
+            AppError::MalformedJson(_) => "malformed_json",
+            AppError::MissingFile => "missing_file",
+            AppError::MissingTelemetry => "missing_telemetry",
+            AppError::UnexpectedField(_) => "unexpected_field",
+            AppError::NotJpeg => "not_jpeg",
+            AppError::PayloadTooLarge => "payload_too_large",
+            AppError::InvalidFileName(_) => "invalid_file_name",
+            AppError::UnsupportedVersion(_) => "unsupported_version",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::ClockSkew { .. } => "clock_skew",
+            AppError::Replayed(_) => "replayed",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::Firmware(_) => "invalid_firmware",
+            AppError::Report(ReportError::InvalidMonth(_)) => "invalid_month",
+            AppError::Storage(_) | AppError::Report(_) | AppError::Task(_) => "internal",
+        }
+    }
+}
+
+impl From<MultipartError> for AppError {
+    fn from(error: MultipartError) -> Self {
+        if error.status() == StatusCode::PAYLOAD_TOO_LARGE {
+            AppError::PayloadTooLarge
+        } else {
+            AppError::MalformedMultipart(error)
+        }
+    }
+}
+
+impl From<StorageError> for AppError {
+    fn from(error: StorageError) -> Self {
+        match error {
+            StorageError::InvalidFileName(name) => AppError::InvalidFileName(name),
+            error => AppError::Storage(error),
+        }
+    }
+}
+
+impl From<ReportError> for AppError {
+    fn from(error: ReportError) -> Self {
+        match error {
+            ReportError::Storage(error) => error.into(),
+            error => AppError::Report(error),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            log::error!("{self}");
+        } else {
+            log::warn!("Rejected request: {self}");
+        }
+
+        let body = ErrorBody {
+            error: self.code().to_string(),
+            message: self.to_string(),
+            retryable: self.retryable(),
+            server_time: match self {
+                AppError::ClockSkew { now, .. } => Some(now.fixed_offset()),
+                _ => None,
+            },
+        };
+        (status, Json(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn respond(error: AppError) -> (StatusCode, ErrorBody) {
+        let response = error.into_response();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn maps_errors_to_status_codes() {
+        let io = |kind: ErrorKind| StorageError::Io(std::io::Error::from(kind));
+        let cases = [
+            (
+                AppError::MissingFile,
+                StatusCode::BAD_REQUEST,
+                "missing_file",
+            ),
+            (
+                AppError::NotJpeg,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "not_jpeg",
+            ),
+            (
+                AppError::PayloadTooLarge,
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload_too_large",
+            ),
+            (
+                AppError::UnsupportedVersion(0),
+                StatusCode::BAD_REQUEST,
+                "unsupported_version",
+            ),
+            (
+                AppError::Unauthorized("no key".into()),
+                StatusCode::UNAUTHORIZED,
+                "unauthorized",
+            ),
+            (
+                AppError::ClockSkew {
+                    timestamp: 0,
+                    now: Utc::now(),
+                },
+                StatusCode::UNAUTHORIZED,
+                "clock_skew",
+            ),
+            (
+                AppError::NotFound("meter".into()),
+                StatusCode::NOT_FOUND,
+                "not_found",
+            ),
+            (
+                StorageError::InvalidFileName("../x".into()).into(),
+                StatusCode::BAD_REQUEST,
+                "invalid_file_name",
+            ),
+            (
+                io(ErrorKind::StorageFull).into(),
+                StatusCode::INSUFFICIENT_STORAGE,
+                "internal",
+            ),
+            (
+                io(ErrorKind::PermissionDenied).into(),
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+            ),
+            (
+                ReportError::InvalidMonth("2025-13".into()).into(),
+                StatusCode::BAD_REQUEST,
+                "invalid_month",
+            ),
+            (
+                ReportError::Storage(StorageError::InvalidFileName("x".into())).into(),
+                StatusCode::BAD_REQUEST,
+                "invalid_file_name",
+            ),
+        ];
+        for (error, status, code) in cases {
+            assert_eq!((error.status(), error.code()), (status, code), "{error}");
+        }
+    }
+
+    #[tokio::test]
+    async fn only_server_errors_replays_versions_and_clock_skew_are_retryable() {
+        let (status, body) = respond(AppError::NotJpeg).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(body.error, "not_jpeg");
+        assert_eq!(body.message, "the uploaded file is not a JPEG image");
+        assert!(!body.retryable);
+
+        let (status, body) = respond(AppError::Replayed("cam-1".into())).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(body.retryable);
+
+        let (status, body) = respond(AppError::UnsupportedVersion(PROTOCOL_VERSION + 1)).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert!(body.retryable);
+        assert_eq!(body.server_time, None);
+
+        let now = Utc::now();
+        let (status, body) = respond(AppError::ClockSkew { timestamp: 0, now }).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert!(body.retryable);
+        assert_eq!(body.server_time, Some(now.fixed_offset()));
+
+        let (_, body) = respond(AppError::Unauthorized("no key".into())).await;
+        assert!(!body.retryable);
+
+        let full = StorageError::Io(std::io::Error::from(ErrorKind::StorageFull));
+        let (status, body) = respond(full.into()).await;
+        assert_eq!(status, StatusCode::INSUFFICIENT_STORAGE);
+        assert!(body.retryable);
+    }
+}
@@ -0,0 +1,239 @@
+use chrono::{DateTime, Local};
+use digit_protocol::ota::{self, FirmwareVersion};
+use digit_protocol::{FirmwareUpdate, hex};
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+/// Group of the devices that were not put into any other.
+pub const DEFAULT_GROUP: &str = "default";
+const MAX_GROUP_LENGTH: usize = 32;
+
+#[derive(Debug, thiserror::Error)]
+pub enum FirmwareError {
+    #[error("invalid firmware version {0:?}, expected major.minor.patch")]
+    InvalidVersion(String),
+    #[error("invalid group name {0:?}, use up to 32 letters, digits, '-' and '_'")]
+    InvalidGroup(String),
+    #[error("malformed firmware key, expected 32 hex encoded bytes")]
+    InvalidKey,
+    #[error("malformed signature, expected 64 hex encoded bytes")]
+    MalformedSignature,
+    #[error("the signature does not match the image and version {0}")]
+    BadSignature(FirmwareVersion),
+    #[error("an image of {0} bytes is too large")]
+    TooLarge(usize),
+    #[error("could not generate a key: {0}")]
+    Random(#[from] getrandom::Error),
+}
+
+/// A firmware image stored for a group of devices.
+#[derive(Debug, Clone, Serialize)]
+pub struct FirmwareRelease {
+    pub group: String,
+    pub version: String,
+    /// Path of the image relative to the data directory.
+    #[serde(skip)]
+    pub file_name: String,
+    pub size: u64,
+    /// Hex encoded SHA-256 of the image.
+    pub sha256: String,
+    /// Hex encoded Ed25519 signature over [`ota::signed_message`].
+    pub signature: String,
+    pub created_at: DateTime<Local>,
+}
+
+impl FirmwareRelease {
+    /// The release as offered to a device in the answer to its check-in.
+    pub fn offer(&self) -> FirmwareUpdate {
+        FirmwareUpdate {
+            version: self.version.clone(),
+            size: self.size as u32,
+            sha256: self.sha256.clone(),
+            signature: self.signature.clone(),
+            path: format!("/v1/firmware/{}", self.version),
+        }
+    }
+}
+
+pub fn parse_version(version: &str) -> Result<FirmwareVersion, FirmwareError> {
+    version
+        .parse()
+        .map_err(|_| FirmwareError::InvalidVersion(version.to_string()))
+}
+
+/// Group names end up in paths, both on disk and in URLs.
+pub fn validate_group(group: &str) -> Result<(), FirmwareError> {
+    let valid = !group.is_empty()
+        && group.len() <= MAX_GROUP_LENGTH
+        && group
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(FirmwareError::InvalidGroup(group.to_string()))
+    }
+}
+
+/// Generates a new signing key, returning it and its public key, both hex encoded.
+pub fn generate_key() -> Result<(String, String), FirmwareError> {
+    let mut seed = [0u8; 32];
+    getrandom::fill(&mut seed)?;
+    let key = SigningKey::from_bytes(&seed);
+    Ok((
+        hex::encode(&seed),
+        hex::encode(key.verifying_key().as_bytes()),
+    ))
+}
+
+/// Signs `image` as release `version` with the hex encoded signing key.
+pub fn sign(key: &str, version: FirmwareVersion, image: &[u8]) -> Result<String, FirmwareError> {
+    let seed: [u8; 32] = hex::decode_array(key.trim()).ok_or(FirmwareError::InvalidKey)?;
+    let message = ota::signed_message(version, &sha256(image));
+    Ok(hex::encode(
+        &SigningKey::from_bytes(&seed).sign(&message).to_bytes(),
+    ))
+}
+
+/// Checks that `signature` was made over `image` as release `version` with the key whose
+/// public half is `public_key`. Returns the hex encoded SHA-256 of the image.
+pub fn verify(
+    public_key: &str,
+    version: FirmwareVersion,
+    image: &[u8],
+    signature: &str,
+) -> Result<String, FirmwareError> {
+    if u32::try_from(image.len()).is_err() {
+        return Err(FirmwareError::TooLarge(image.len()));
+    }
+    let public_key: [u8; 32] = hex::decode_array(public_key).ok_or(FirmwareError::InvalidKey)?;
+    let public_key =
+        VerifyingKey::from_bytes(&public_key).map_err(|_| FirmwareError::InvalidKey)?;
+    let signature: [u8; 64] =
+        hex::decode_array(signature.trim()).ok_or(FirmwareError::MalformedSignature)?;
+
+    let digest = sha256(image);
+    public_key
+        .verify_strict(
+            &ota::signed_message(version, &digest),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|_| FirmwareError::BadSignature(version))?;
+    Ok(hex::encode(&digest))
+}
+
+/// The newest of `releases` a device running `current` should update to, if any.
+pub fn pick_update<'a>(
+    releases: &'a [FirmwareRelease],
+    current: &str,
+) -> Option<&'a FirmwareRelease> {
+    releases
+        .iter()
+        .filter_map(|release| Some((release.version.parse().ok()?, release)))
+        .filter(|&(version, _)| ota::is_newer(current, version))
+        .max_by_key(|&(version, _)| version)
+        .map(|(_, release)| release)
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IMAGE: &[u8] = b"\xe9firmware image";
+
+    fn release(version: &str) -> FirmwareRelease {
+        FirmwareRelease {
+            group: DEFAULT_GROUP.into(),
+            version: version.into(),
+            file_name: format!("firmware/default/{version}.bin"),
+            size: 0,
+            sha256: String::new(),
+            signature: String::new(),
+            created_at: Local::now(),
+        }
+    }
+
+    #[test]
+    fn signed_release_verifies() {
+        let (key, public_key) = generate_key().unwrap();
+        let version = parse_version("1.2.0").unwrap();
+        let signature = sign(&key, version, IMAGE).unwrap();
+        let digest = verify(&public_key, version, IMAGE, &signature).unwrap();
+        assert_eq!(digest, hex::encode(&sha256(IMAGE)));
+    }
+
+    #[test]
+    fn rejects_tampered_release() {
+        let (key, public_key) = generate_key().unwrap();
+        let version = parse_version("1.2.0").unwrap();
+        let signature = sign(&key, version, IMAGE).unwrap();
+
+        let tampered = [IMAGE, b"!"].concat();
+        assert!(matches!(
+            verify(&public_key, version, &tampered, &signature),
+            Err(FirmwareError::BadSignature(_))
+        ));
+        // The same image cannot be passed off as a newer release
+        let newer = parse_version("1.3.0").unwrap();
+        assert!(matches!(
+            verify(&public_key, newer, IMAGE, &signature),
+            Err(FirmwareError::BadSignature(_))
+        ));
+        let (_, other_key) = generate_key().unwrap();
+        assert!(matches!(
+            verify(&other_key, version, IMAGE, &signature),
+            Err(FirmwareError::BadSignature(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        let (key, public_key) = generate_key().unwrap();
+        let version = parse_version("1.2.0").unwrap();
+        assert!(matches!(
+            verify(&public_key, version, IMAGE, "abcd"),
+            Err(FirmwareError::MalformedSignature)
+        ));
+        assert!(matches!(
+            verify("abcd", version, IMAGE, &sign(&key, version, IMAGE).unwrap()),
+            Err(FirmwareError::InvalidKey)
+        ));
+        assert!(matches!(
+            sign("not hex", version, IMAGE),
+            Err(FirmwareError::InvalidKey)
+        ));
+        assert!(parse_version("1.2").is_err());
+    }
+
+    #[test]
+    fn picks_newest_newer_release() {
+        let releases = [release("0.2.0"), release("0.10.0"), release("0.3.1")];
+        let pick = |current| pick_update(&releases, current).map(|r| r.version.as_str());
+        assert_eq!(pick("0.1.0"), Some("0.10.0"));
+        assert_eq!(pick("0.10.0"), None);
+        assert_eq!(pick("1.0.0"), None);
+        assert_eq!(pick("dev"), None);
+        assert_eq!(pick_update(&[], "0.1.0").map(|r| &r.version), None);
+    }
+
+    #[test]
+    fn validates_group_names() {
+        assert!(validate_group("default").is_ok());
+        assert!(validate_group("kitchen_meters-2").is_ok());
+        for invalid in ["", "../x", "a b", &"x".repeat(33)] {
+            assert!(validate_group(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn offers_release_by_version() {
+        let offer = release("0.2.0").offer();
+        assert_eq!(offer.version, "0.2.0");
+        assert_eq!(offer.path, "/v1/firmware/0.2.0");
+    }
+}
@@ -1,73 +1,969 @@
 use axum::{
-    Router,
-    extract::{Json, Multipart},
-    http::StatusCode,
-    routing::{get, post},
+    Extension, Router,
+    extract::{
+        DefaultBodyLimit, Json, Multipart, Path as UrlPath, Query, State, rejection::JsonRejection,
+    },
+    http::{StatusCode, header},
+    middleware,
+    response::{IntoResponse, Response},
+    routing::{delete, get, post},
 };
-use serde::{Deserialize, Serialize};
-use std::path::Path;
-use tokio::io::AsyncWriteExt;
+use digit_protocol::roi::Roi;
+use digit_protocol::{
+    CheckinRequest, CheckinResponse, DeviceConfig, FirmwareUpdate, HealthRequest, HealthResponse,
+    MIN_SUPPORTED_VERSION, PROTOCOL_VERSION, Timestamp, UploadMetadata, UploadResponse,
+    UploadedFile,
+};
+use serde::Deserialize;
+use std::sync::Arc;
 
-const UPLOADS_DIRECTORY: &str = "data";
-const HEALTH_LOG_FILE: &str = "data/health.log";
+mod alerts;
+mod auth;
+mod config;
+mod dashboard;
+mod error;
+mod firmware;
+mod meter;
+mod recognition;
+mod report;
+mod schedule;
+mod storage;
+mod tls;
 
-#[derive(Serialize, Deserialize)]
-struct HealthRequest {
-    voltage: f32,
-    timestamp: String,
+use auth::{Device, ReplayGuard};
+use clap::Parser;
+use config::{Args, Command, Config, DevicesCommand, FirmwareCommand, KeysCommand, MetersCommand};
+use error::AppError;
+use firmware::FirmwareRelease;
+use meter::{DEFAULT_METER, Meter};
+use recognition::Recognizer;
+use report::ReportFormat;
+use schedule::{WakeRule, WakeSchedule};
+use storage::{DEFAULT_DEVICE, HealthSample, Storage, StoredUpload};
+
+/// First bytes of every JPEG file.
+const JPEG_MAGIC: &[u8] = &[0xFF, 0xD8, 0xFF];
+
+#[derive(Clone)]
+struct AppState {
+    config: Arc<Config>,
+    storage: Arc<Storage>,
+    recognizer: Arc<Recognizer>,
+    replay_guard: Arc<ReplayGuard>,
+}
+
+#[derive(Deserialize)]
+struct ReportQuery {
+    format: Option<String>,
+    /// Meter to report on, the default meter when not given.
+    meter: Option<String>,
 }
 
 #[tokio::main]
 async fn main() {
-    env_logger::Builder::new()
-        .filter_level(log::LevelFilter::max())
-        .init();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let mut args = Args::parse();
+    let command = args.command.take();
+    let config = match Config::load(args) {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            log::error!("{e}");
+            std::process::exit(1);
+        }
+    };
+
+    let storage = match Storage::open(&config.data_directory) {
+        Ok(storage) => Arc::new(storage),
+        Err(e) => {
+            log::error!(
+                "Failed to open storage in {}: {e}",
+                config.data_directory.display()
+            );
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = storage.save_meter(&config.meter) {
+        log::error!("Failed to store the default meter: {e}");
+        std::process::exit(1);
+    }
+
+    if let Some(command) = command {
+        if let Err(e) = run_command(&storage, command) {
+            log::error!("{e}");
+            std::process::exit(1);
+        }
+        return;
+    }
+    log::info!("Configuration:\n{config}");
+    let tls_config = match config.tls.as_ref().map(tls::server_config).transpose() {
+        Ok(tls_config) => tls_config,
+        Err(e) => {
+            log::error!("Failed to set up TLS: {e}");
+            std::process::exit(1);
+        }
+    };
 
-    // Create uploads directory
-    if let Err(e) = std::fs::create_dir_all(UPLOADS_DIRECTORY) {
-        log::error!("Failed to create uploads directory: {e}");
+    let state = AppState {
+        config: config.clone(),
+        storage: storage.clone(),
+        recognizer: Arc::new(Recognizer::new()),
+        replay_guard: Arc::new(ReplayGuard::default()),
+    };
+
+    let app = router(state);
+
+    if config.features.reports {
+        tokio::spawn(report::schedule(
+            storage.clone(),
+            config.reports_directory(),
+            config.timezone,
+        ));
+    }
+    if config.features.alerts && !config.alerts.sinks.is_empty() {
+        tokio::spawn(alerts::monitor(
+            storage.clone(),
+            config.alerts.clone(),
+            config.timezone,
+        ));
+    }
+    if let Some(days) = config.retention_days {
+        tokio::spawn(storage::enforce_retention(storage, days));
     }
 
-    let app = Router::new()
-        .route("/", get(root))
+    if let Some(tls_config) = tls_config {
+        if let Err(e) = axum_server::bind_rustls(config.bind, tls_config)
+            .serve(app.into_make_service())
+            .await
+        {
+            log::error!("Failed to listen on {}: {e}", config.bind);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let listener = match tokio::net::TcpListener::bind(config.bind).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Failed to listen on {}: {e}", config.bind);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = axum::serve(listener, app).await {
+        log::error!("Failed to serve on {}: {e}", config.bind);
+        std::process::exit(1);
+    }
+}
+
+/// The endpoints of the devices, the admins and the dashboard.
+fn router(state: AppState) -> Router {
+    let device_routes = Router::new()
         .route("/upload", post(upload_file))
-        .route("/health", post(health));
+        .route("/health", post(health))
+        .route("/v1/checkin", post(checkin))
+        .route("/v1/firmware/{version}", get(download_firmware))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::verify));
+    let admin_routes = Router::new()
+        .route("/admin/firmware", get(list_firmware))
+        .route("/admin/firmware/{group}", post(upload_firmware))
+        .route("/admin/firmware/{group}/{version}", delete(remove_firmware))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::verify_admin,
+        ));
+    Router::new()
+        .route("/reports/{month}", get(monthly_report))
+        .merge(dashboard::routes())
+        .merge(device_routes)
+        .merge(admin_routes)
+        .layer(DefaultBodyLimit::max(state.config.max_body_size))
+        .with_state(state)
+}
+
+/// Runs a command given on the command line instead of the server.
+fn run_command(storage: &Storage, command: Command) -> Result<(), Box<dyn std::error::Error>> {
+    match command {
+        Command::Keys(KeysCommand::Add { device_id }) => {
+            let key = auth::generate_key()?;
+            storage.set_device_key(&device_id, &key)?;
+            println!("{key}");
+        }
+        Command::Keys(KeysCommand::List) => {
+            for (device_id, created_at) in storage.device_keys()? {
+                println!("{device_id}\t{}", created_at.format("%Y-%m-%d %H:%M:%S"));
+            }
+        }
+        Command::Keys(KeysCommand::Remove { device_id }) => {
+            if !storage.remove_device_key(&device_id)? {
+                return Err(format!("device {device_id} has no key").into());
+            }
+        }
+        Command::Devices(DevicesCommand::Configure {
+            device_id,
+            flash,
+            flash_brightness,
+            flash_warm_up_ms,
+            roi,
+            rotation,
+            sensor,
+        }) => {
+            let config = DeviceConfig {
+                revision: 0,
+                flash,
+                flash_brightness,
+                flash_warm_up_ms,
+                roi: roi.map(|roi| Roi { rotation, ..roi }),
+                sensor,
+            };
+            let config = storage.set_device_config(&device_id, config)?;
+            println!("{}", serde_json::to_string(&config)?);
+        }
+        Command::Devices(DevicesCommand::Schedule {
+            device_id,
+            daily,
+            every_hours,
+            month_end_days,
+            month_end_at,
+        }) => {
+            let rule = match (daily, every_hours) {
+                (Some(at), _) => WakeRule::Daily { at },
+                (None, Some(hours)) => WakeRule::EveryHours { hours },
+                (None, None) => return Err("give either --daily or --every-hours".into()),
+            };
+            let schedule = WakeSchedule {
+                rule,
+                month_end_days,
+                month_end_times: month_end_at,
+            };
+            schedule.validate()?;
+            storage.set_device_schedule(&device_id, &schedule)?;
+            println!("{device_id} wakes {schedule}");
+        }
+        Command::Devices(DevicesCommand::List) => {
+            for device_id in storage.device_ids()? {
+                let meter = storage.device_meter(&device_id)?;
+                let group = storage.device_group(&device_id)?;
+                let last_seen = storage.last_seen(&device_id)?.map_or("never".into(), |at| {
+                    at.format("%Y-%m-%d %H:%M:%S").to_string()
+                });
+                println!("{device_id}\t{}\t{group}\t{last_seen}", meter.id);
+            }
+        }
+        Command::Devices(DevicesCommand::Group { device_id, group }) => {
+            firmware::validate_group(&group)?;
+            storage.set_device_group(&device_id, &group)?;
+        }
+        Command::Devices(DevicesCommand::Meter {
+            device_id,
+            meter_id,
+        }) => {
+            storage.set_device_meter(&device_id, &meter_id)?;
+        }
+        Command::Meters(MetersCommand::Add {
+            meter_id,
+            kind,
+            unit,
+            digits,
+            decimals,
+        }) => {
+            if meter_id == DEFAULT_METER {
+                return Err("the default meter is set in the configuration".into());
+            }
+            let meter = Meter {
+                id: meter_id,
+                kind,
+                unit: unit.unwrap_or_else(|| kind.default_unit().to_string()),
+                digits,
+                decimals,
+            };
+            meter.validate()?;
+            storage.save_meter(&meter)?;
+        }
+        Command::Meters(MetersCommand::List) => {
+            for meter in storage.meters()? {
+                println!(
+                    "{}\t{}\t{} digits, {} decimals\t{}",
+                    meter.id, meter.kind, meter.digits, meter.decimals, meter.unit
+                );
+            }
+        }
+        Command::Firmware(FirmwareCommand::Keygen) => {
+            let (key, public_key) = firmware::generate_key()?;
+            println!("private key: {key}");
+            println!("public key:  {public_key}");
+        }
+        Command::Firmware(FirmwareCommand::Sign {
+            key_file,
+            version,
+            image,
+        }) => {
+            let key = std::fs::read_to_string(&key_file)
+                .map_err(|e| format!("could not read {}: {e}", key_file.display()))?;
+            let image = std::fs::read(&image)
+                .map_err(|e| format!("could not read {}: {e}", image.display()))?;
+            let version = firmware::parse_version(&version)?;
+            println!("{}", firmware::sign(&key, version, &image)?);
+        }
+    }
+    Ok(())
+}
 
-    // run our app with hyper, listening globally on port 3000
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+/// Checks that a message is one this server understands and comes from the device that
+/// signed the request. Without authentication the device is taken at its word.
+fn check_sender(
+    state: &AppState,
+    Device(device_id): Device,
+    version: u16,
+    claimed_id: &str,
+) -> Result<String, AppError> {
+    if !(MIN_SUPPORTED_VERSION..=PROTOCOL_VERSION).contains(&version) {
+        return Err(AppError::UnsupportedVersion(version));
+    }
+    if !state.config.features.auth {
+        return Ok(claimed_id.to_string());
+    }
+    if claimed_id != device_id {
+        return Err(AppError::Unauthorized(format!(
+            "request signed by {device_id} claims to come from {claimed_id}"
+        )));
+    }
+    Ok(device_id)
 }
 
-async fn root() -> &'static str {
-    "Hello, World!"
+async fn upload_file(
+    State(state): State<AppState>,
+    Extension(device): Extension<Device>,
+    mut multipart: Multipart,
+) -> Result<Json<UploadResponse>, AppError> {
+    // Everything is read before anything is stored, so a bad field cannot leave the
+    // request half stored
+    let mut metadata: Option<UploadMetadata> = None;
+    let mut photo = None;
+    while let Some(field) = multipart.next_field().await? {
+        match field.name() {
+            Some("metadata") if metadata.is_none() => {
+                let parsed = serde_json::from_slice(&field.bytes().await?)
+                    .map_err(|e| AppError::BadRequest(format!("malformed metadata: {e}")))?;
+                metadata = Some(parsed);
+            }
+            Some("file") if photo.is_none() => {
+                let client_name = field.file_name().map(str::to_string);
+                log::info!(
+                    "Received file: {}",
+                    client_name.as_deref().unwrap_or("<unnamed>")
+                );
+                let image = field.bytes().await?;
+                if !image.starts_with(JPEG_MAGIC) {
+                    return Err(AppError::NotJpeg);
+                }
+                photo = Some((client_name, image));
+            }
+            name => return Err(AppError::UnexpectedField(name.unwrap_or_default().into())),
+        }
+    }
+    let device_id = match &metadata {
+        Some(metadata) => check_sender(&state, device, metadata.version, &metadata.device_id)?,
+        None => device.0,
+    };
+    let (client_name, image) = photo.ok_or(AppError::MissingFile)?;
+
+    let (upload, next_wake) = {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || {
+            let upload = state.storage.store_upload(
+                &device_id,
+                client_name.as_deref(),
+                metadata.as_ref(),
+                &image,
+            )?;
+            Ok::<_, AppError>((upload, next_wake(&state, &device_id)?))
+        })
+        .await??
+    };
+    let files = vec![UploadedFile {
+        file_name: upload.file_name.clone(),
+        duplicate: upload.duplicate,
+    }];
+    if upload.duplicate {
+        log::info!("Already have this photo as {}", upload.file_name);
+    } else {
+        log::info!("Stored photo as {}", upload.file_name);
+        spawn_recognition(&state, upload);
+    }
+
+    Ok(Json(UploadResponse {
+        version: PROTOCOL_VERSION,
+        files,
+        next_wake,
+    }))
 }
 
-async fn upload_file(mut multipart: Multipart) -> StatusCode {
-    while let Some(mut field) = multipart.next_field().await.unwrap() {
-        let filename = field.file_name().unwrap().to_string();
-        log::info!("Received file: {filename}");
-        let path = Path::new(UPLOADS_DIRECTORY).join(&filename);
+/// Reads the meter from a newly stored photo in the background, the device does not need to
+/// wait for it.
+fn spawn_recognition(state: &AppState, upload: StoredUpload) {
+    if !state.config.features.recognition {
+        return;
+    }
+    let state = state.clone();
+    tokio::task::spawn_blocking(move || {
+        let meter = match state.storage.meter(&upload.meter_id) {
+            Ok(Some(meter)) => meter,
+            Ok(None) => {
+                log::error!(
+                    "Photo {} is of unknown meter {}",
+                    upload.file_name,
+                    upload.meter_id
+                );
+                return;
+            }
+            Err(e) => {
+                log::error!("Failed to load meter {}: {e}", upload.meter_id);
+                return;
+            }
+        };
+        let path = state.storage.data_directory().join(&upload.file_name);
+        match state.recognizer.recognize_file(&path, meter.digits) {
+            Ok(reading) => {
+                log::info!(
+                    "Read {} of meter {} from {} with confidence {:.2}",
+                    reading.value,
+                    meter.id,
+                    upload.file_name,
+                    reading.confidence()
+                );
+                if let Err(e) = state.storage.add_reading(upload.id, &reading) {
+                    log::error!("Failed to store reading of {}: {e}", upload.file_name);
+                }
+            }
+            Err(e) => {
+                log::error!("Failed to read meter from {}: {e}", upload.file_name);
+                if let Err(e) = state
+                    .storage
+                    .add_recognition_error(upload.id, &e.to_string())
+                {
+                    log::error!("Failed to store error of {}: {e}", upload.file_name);
+                }
+            }
+        }
+    });
+}
 
-        let mut file = tokio::fs::File::create(path).await.unwrap();
-        while let Some(chunk) = field.chunk().await.unwrap() {
-            file.write_all(&chunk).await.unwrap();
+/// Everything the device has to say on a wake in one request: its telemetry in the
+/// `telemetry` field, followed by the photo in the `file` field if it took one.
+async fn checkin(
+    State(state): State<AppState>,
+    Extension(device): Extension<Device>,
+    mut multipart: Multipart,
+) -> Result<Json<CheckinResponse>, AppError> {
+    let mut telemetry = None;
+    let mut photo = None;
+    while let Some(field) = multipart.next_field().await? {
+        match field.name() {
+            Some("telemetry") if telemetry.is_none() => {
+                let parsed: CheckinRequest = serde_json::from_slice(&field.bytes().await?)
+                    .map_err(|e| AppError::BadRequest(format!("malformed telemetry: {e}")))?;
+                telemetry = Some(parsed);
+            }
+            Some("file") if photo.is_none() => {
+                let client_name = field.file_name().map(str::to_string);
+                let image = field.bytes().await?;
+                if !image.starts_with(JPEG_MAGIC) {
+                    return Err(AppError::NotJpeg);
+                }
+                photo = Some((client_name, image));
+            }
+            name => return Err(AppError::UnexpectedField(name.unwrap_or_default().into())),
         }
     }
-    StatusCode::OK
+    let telemetry = telemetry.ok_or(AppError::MissingTelemetry)?;
+    let device_id = check_sender(&state, device, telemetry.version, &telemetry.device_id)?;
+    log::info!(
+        "Check-in from {device_id}: battery voltage {}, firmware {}, {}",
+        telemetry
+            .voltage
+            .map_or("unknown".to_string(), |voltage| voltage.to_string()),
+        telemetry.firmware_version,
+        if photo.is_some() {
+            "with photo"
+        } else {
+            "no photo"
+        }
+    );
+
+    if let Some(reason) = telemetry
+        .diagnostics
+        .reset_reason
+        .as_deref()
+        .filter(|reason| !matches!(*reason, "power_on" | "deep_sleep"))
+    {
+        log::warn!("{device_id} restarted unexpectedly: {reason}");
+    }
+
+    let (upload, config, next_wake, firmware) = {
+        let state = state.clone();
+        tokio::task::spawn_blocking(move || {
+            let upload = state.storage.store_checkin(
+                &device_id,
+                &telemetry,
+                photo.as_ref().and_then(|(name, _)| name.as_deref()),
+                photo.as_ref().map(|(_, image)| &image[..]),
+            )?;
+            let config = state
+                .storage
+                .pending_config(&device_id, telemetry.config_revision)?;
+            let next_wake = next_wake(&state, &device_id)?;
+            let firmware = firmware_update(&state, &device_id, &telemetry.firmware_version)?;
+            Ok::<_, AppError>((upload, config, next_wake, firmware))
+        })
+        .await??
+    };
+    let file = upload.as_ref().map(|upload| UploadedFile {
+        file_name: upload.file_name.clone(),
+        duplicate: upload.duplicate,
+    });
+    if let Some(upload) = upload.filter(|upload| !upload.duplicate) {
+        log::info!("Stored photo as {}", upload.file_name);
+        spawn_recognition(&state, upload);
+    }
+
+    Ok(Json(CheckinResponse {
+        version: PROTOCOL_VERSION,
+        file,
+        next_wake,
+        config,
+        firmware,
+    }))
 }
 
-async fn health(Json(request): Json<HealthRequest>) -> StatusCode {
-    log::info!("Got device battery voltage: {}", request.voltage);
+/// The newest release of the device's group if it runs an older one. Nothing is offered
+/// while there is no key to check the releases with.
+fn firmware_update(
+    state: &AppState,
+    device_id: &str,
+    current: &str,
+) -> Result<Option<FirmwareUpdate>, AppError> {
+    if state.config.firmware_public_key.is_none() {
+        return Ok(None);
+    }
+    let group = state.storage.device_group(device_id)?;
+    let releases = state.storage.firmware_releases(Some(&group))?;
+    let Some(release) = firmware::pick_update(&releases, current) else {
+        return Ok(None);
+    };
+    log::info!(
+        "Offering firmware {} of group {group} to {device_id}, which runs {current}",
+        release.version
+    );
+    Ok(Some(release.offer()))
+}
 
-    let mut file = tokio::fs::OpenOptions::new()
-        .append(true)
-        .create(true)
-        .open(HEALTH_LOG_FILE)
-        .await
-        .unwrap();
-    file.write_all(&format!("{},{}\n", request.timestamp, request.voltage).as_bytes())
+/// The image of a release of the requesting device's group.
+async fn download_firmware(
+    State(state): State<AppState>,
+    Extension(Device(device_id)): Extension<Device>,
+    UrlPath(version): UrlPath<String>,
+) -> Result<Response, AppError> {
+    let release = {
+        let (state, device_id, version) = (state.clone(), device_id.clone(), version.clone());
+        tokio::task::spawn_blocking(move || {
+            let group = state.storage.device_group(&device_id)?;
+            state
+                .storage
+                .firmware_release(&group, &version)?
+                .ok_or_else(|| AppError::NotFound(format!("firmware {version} of group {group}")))
+        })
+        .await??
+    };
+    let image = tokio::fs::read(state.storage.data_directory().join(&release.file_name))
         .await
-        .unwrap();
+        .map_err(storage::StorageError::from)?;
+    log::info!("Sending firmware {version} to {device_id}");
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], image).into_response())
+}
+
+async fn list_firmware(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<FirmwareRelease>>, AppError> {
+    let releases =
+        tokio::task::spawn_blocking(move || state.storage.firmware_releases(None)).await??;
+    Ok(Json(releases))
+}
+
+/// Adds a release to `group` from a multipart body with the `version`, the hex encoded
+/// `signature` and the image in the `file` field. Only releases signed with the configured
+/// key are accepted.
+async fn upload_firmware(
+    State(state): State<AppState>,
+    UrlPath(group): UrlPath<String>,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<FirmwareRelease>), AppError> {
+    firmware::validate_group(&group)?;
+    let public_key = state.config.firmware_public_key.as_deref().ok_or_else(|| {
+        AppError::BadRequest("firmware updates are disabled, set a firmware public key".into())
+    })?;
+
+    let (mut version, mut signature, mut image) = (None, None, None);
+    while let Some(field) = multipart.next_field().await? {
+        match field.name() {
+            Some("version") if version.is_none() => version = Some(field.text().await?),
+            Some("signature") if signature.is_none() => signature = Some(field.text().await?),
+            Some("file") if image.is_none() => image = Some(field.bytes().await?),
+            name => return Err(AppError::UnexpectedField(name.unwrap_or_default().into())),
+        }
+    }
+    let (Some(version), Some(signature), Some(image)) = (version, signature, image) else {
+        return Err(AppError::BadRequest(
+            "a release needs the version, signature and file fields".into(),
+        ));
+    };
+    let version = firmware::parse_version(version.trim())?;
+    let sha256 = firmware::verify(public_key, version, &image, &signature)?;
+
+    let version = version.to_string();
+    let release = {
+        let (group, version) = (group.clone(), version.clone());
+        tokio::task::spawn_blocking(move || {
+            if state.storage.firmware_release(&group, &version)?.is_some() {
+                return Err(AppError::Conflict(format!(
+                    "firmware {version} of group {group}"
+                )));
+            }
+            Ok(state.storage.add_firmware_release(
+                &group,
+                &version,
+                &image,
+                &sha256,
+                signature.trim(),
+            )?)
+        })
+        .await??
+    };
+    log::info!(
+        "Added firmware {version} to group {group}, {} bytes",
+        release.size
+    );
+    Ok((StatusCode::CREATED, Json(release)))
+}
+
+async fn remove_firmware(
+    State(state): State<AppState>,
+    UrlPath((group, version)): UrlPath<(String, String)>,
+) -> Result<StatusCode, AppError> {
+    let removed = {
+        let (group, version) = (group.clone(), version.clone());
+        tokio::task::spawn_blocking(move || state.storage.remove_firmware_release(&group, &version))
+            .await??
+    };
+    if !removed {
+        return Err(AppError::NotFound(format!(
+            "firmware {version} of group {group}"
+        )));
+    }
+    log::info!("Removed firmware {version} from group {group}");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// When `device_id` should wake up next according to its schedule.
+fn next_wake(state: &AppState, device_id: &str) -> Result<Timestamp, AppError> {
+    let schedule = state.storage.device_schedule(device_id)?;
+    Ok(schedule.next_wake(chrono::Utc::now().with_timezone(&state.config.timezone)))
+}
+
+/// Battery report of the firmware from before the protocol had versions. It is not signed,
+/// so it only gets through with authentication turned off, and is taken to come from the
+/// default device.
+#[derive(Debug, Deserialize)]
+struct LegacyHealthRequest {
+    voltage: f32,
+    /// The camera's clock, which it kept in UTC without saying so.
+    timestamp: chrono::NaiveDateTime,
+}
+
+async fn health(
+    State(state): State<AppState>,
+    Extension(device): Extension<Device>,
+    request: Result<Json<serde_json::Value>, JsonRejection>,
+) -> Result<Json<HealthResponse>, AppError> {
+    let Json(body) = request?;
+    if body.get("version").is_none() {
+        let request: LegacyHealthRequest = serde_json::from_value(body)
+            .map_err(|e| AppError::BadRequest(format!("malformed health report: {e}")))?;
+        log::info!("Got legacy battery voltage: {}", request.voltage);
+        let sample = HealthSample {
+            timestamp: request.timestamp.and_utc().fixed_offset(),
+            voltage: Some(request.voltage),
+            state_of_charge: None,
+            firmware_version: None,
+            diagnostics: None,
+        };
+        tokio::task::spawn_blocking(move || {
+            state.storage.add_health_sample(DEFAULT_DEVICE, &sample)
+        })
+        .await??;
+        return Ok(Json(HealthResponse {
+            version: PROTOCOL_VERSION,
+            received_at: chrono::Local::now().fixed_offset(),
+        }));
+    }
+
+    let request: HealthRequest = serde_json::from_value(body)
+        .map_err(|e| AppError::BadRequest(format!("malformed health report: {e}")))?;
+    let device_id = check_sender(&state, device, request.version, &request.device_id)?;
+    log::info!(
+        "Got {device_id} battery voltage: {} (firmware {})",
+        request
+            .voltage
+            .map_or("unknown".to_string(), |voltage| voltage.to_string()),
+        request.firmware_version
+    );
+
+    tokio::task::spawn_blocking(move || {
+        state
+            .storage
+            .add_health_sample(&device_id, &(&request).into())
+    })
+    .await??;
+
+    Ok(Json(HealthResponse {
+        version: PROTOCOL_VERSION,
+        received_at: chrono::Local::now().fixed_offset(),
+    }))
+}
+
+async fn monthly_report(
+    State(state): State<AppState>,
+    UrlPath(month): UrlPath<String>,
+    Query(query): Query<ReportQuery>,
+) -> Result<Response, AppError> {
+    let format = match query.format.as_deref() {
+        None | Some("json") => ReportFormat::Json,
+        Some("csv") => ReportFormat::Csv,
+        Some("html") => ReportFormat::Html,
+        Some(other) => {
+            return Err(AppError::BadRequest(format!(
+                "unknown report format {other}"
+            )));
+        }
+    };
+    let month = report::parse_month(&month)?;
+    let meter_id = query.meter.unwrap_or_else(|| DEFAULT_METER.to_string());
+
+    let body = tokio::task::spawn_blocking(move || {
+        let meter = state
+            .storage
+            .meter(&meter_id)?
+            .ok_or_else(|| AppError::NotFound(format!("meter {meter_id}")))?;
+        Ok::<_, AppError>(
+            report::generate(&state.storage, &meter, month, state.config.timezone)?
+                .render(format)?,
+        )
+    })
+    .await??;
+
+    Ok(([(header::CONTENT_TYPE, format.content_type())], body).into_response())
+}
 
-    StatusCode::OK
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use std::path::PathBuf;
+    use tower::ServiceExt;
+
+    const JPEG: &[u8] = &[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10, 0xFF, 0xD9];
+    const BOUNDARY: &str = "digit-test-boundary";
+
+    /// A multipart field: its name, file name and contents.
+    type Part<'a> = (&'a str, Option<&'a str>, &'a [u8]);
+
+    /// A server without authentication and recognition, with its data in a directory of its
+    /// own, deleted on drop.
+    struct TestServer {
+        state: AppState,
+        directory: PathBuf,
+    }
+
+    impl TestServer {
+        fn new(name: &str) -> Self {
+            let directory =
+                std::env::temp_dir().join(format!("digit-server-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&directory);
+            let args = Args::parse_from([
+                "digit-server",
+                "--data-dir",
+                directory.to_str().unwrap(),
+                "--auth",
+                "false",
+                "--recognition",
+                "false",
+            ]);
+            let config = Config::load(args).unwrap();
+            let storage = Storage::open(&config.data_directory).unwrap();
+            storage.save_meter(&config.meter).unwrap();
+            let state = AppState {
+                config: Arc::new(config),
+                storage: Arc::new(storage),
+                recognizer: Arc::new(Recognizer::new()),
+                replay_guard: Arc::new(ReplayGuard::default()),
+            };
+            Self { state, directory }
+        }
+
+        fn storage(&self) -> &Storage {
+            &self.state.storage
+        }
+
+        /// Posts a multipart body of `parts`.
+        async fn post(&self, uri: &str, parts: &[Part<'_>]) -> (StatusCode, serde_json::Value) {
+            let mut body = Vec::new();
+            for (name, file_name, contents) in parts {
+                body.extend_from_slice(format!("--{BOUNDARY}\r\n").as_bytes());
+                let file_name = file_name
+                    .map(|file_name| format!("; filename=\"{file_name}\""))
+                    .unwrap_or_default();
+                body.extend_from_slice(
+                    format!("Content-Disposition: form-data; name=\"{name}\"{file_name}\r\n\r\n")
+                        .as_bytes(),
+                );
+                body.extend_from_slice(contents);
+                body.extend_from_slice(b"\r\n");
+            }
+            body.extend_from_slice(format!("--{BOUNDARY}--\r\n").as_bytes());
+
+            let request = Request::post(uri)
+                .header(
+                    header::CONTENT_TYPE,
+                    format!("multipart/form-data; boundary={BOUNDARY}"),
+                )
+                .body(Body::from(body))
+                .unwrap();
+            let response = router(self.state.clone()).oneshot(request).await.unwrap();
+            let status = response.status();
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.directory);
+        }
+    }
+
+    #[tokio::test]
+    async fn upload_stores_photo() {
+        let server = TestServer::new("upload");
+        let (status, body) = server
+            .post(
+                "/upload",
+                &[("file", Some("2025-07-01T22:00:00.jpg"), JPEG)],
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{body}");
+        assert_eq!(body["files"][0]["duplicate"], false);
+        let photo = server
+            .storage()
+            .latest_photo(DEFAULT_METER)
+            .unwrap()
+            .unwrap();
+        assert_eq!(body["files"][0]["file_name"], photo.file_name);
+    }
+
+    #[tokio::test]
+    async fn upload_rejects_extra_fields_before_storing() {
+        let server = TestServer::new("upload-extra");
+        let other = [JPEG, b"other"].concat();
+        let requests: [&[Part]; 3] = [
+            &[
+                ("file", Some("a.jpg"), JPEG),
+                ("file", Some("b.jpg"), &other),
+            ],
+            &[
+                ("file", Some("a.jpg"), JPEG),
+                ("file", Some("b.jpg"), b"not a photo"),
+            ],
+            &[("file", Some("a.jpg"), JPEG), ("comment", None, b"hello")],
+        ];
+        for parts in requests {
+            let (status, body) = server.post("/upload", parts).await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{body}");
+            assert_eq!(body["error"], "unexpected_field");
+            assert_eq!(body["retryable"], false);
+        }
+        assert!(
+            server
+                .storage()
+                .latest_photo(DEFAULT_METER)
+                .unwrap()
+                .is_none()
+        );
+
+        let (status, body) = server.post("/upload", &[]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "missing_file");
+    }
+
+    #[tokio::test]
+    async fn health_accepts_reports_of_unversioned_firmware() {
+        let server = TestServer::new("health-legacy");
+        let request = Request::post("/health")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(
+                r#"{"voltage":3.91,"timestamp":"2025-07-01T22:00:00"}"#,
+            ))
+            .unwrap();
+        let response = router(server.state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let voltages = server
+            .storage()
+            .voltages(DEFAULT_DEVICE, chrono::DateTime::UNIX_EPOCH)
+            .unwrap();
+        assert_eq!(voltages.len(), 1);
+        assert_eq!(voltages[0].1, 3.91);
+
+        let request = Request::post("/health")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"voltage":3.91}"#))
+            .unwrap();
+        let response = router(server.state.clone()).oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn checkin_accepts_only_supported_versions() {
+        let server = TestServer::new("checkin-version");
+        let telemetry = |version: u16| {
+            serde_json::json!({
+                "version": version,
+                "device_id": "espcam",
+                "firmware_version": "0.1.0",
+                "timestamp": "2026-10-01T22:00:00+03:00",
+                "voltage": 3.9,
+                "captured_at": null,
+                "config_revision": 0,
+            })
+            .to_string()
+        };
+
+        for version in [MIN_SUPPORTED_VERSION, PROTOCOL_VERSION] {
+            let telemetry = telemetry(version);
+            let (status, body) = server
+                .post("/v1/checkin", &[("telemetry", None, telemetry.as_bytes())])
+                .await;
+            assert_eq!(status, StatusCode::OK, "version {version}: {body}");
+        }
+
+        for version in [MIN_SUPPORTED_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let telemetry = telemetry(version);
+            let (status, body) = server
+                .post("/v1/checkin", &[("telemetry", None, telemetry.as_bytes())])
+                .await;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "version {version}: {body}");
+            assert_eq!(body["error"], "unsupported_version");
+            assert_eq!(body["retryable"], true);
+        }
+    }
 }
@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Meter of the devices that were not put on any other, set by `[meter]` in the
+/// configuration.
+pub const DEFAULT_METER: &str = "default";
+const MAX_ID_LENGTH: usize = 32;
+const MAX_UNIT_LENGTH: usize = 16;
+
+#[derive(Debug, thiserror::Error)]
+pub enum MeterError {
+    #[error("invalid meter id {0:?}, use up to 32 letters, digits, '-' and '_'")]
+    InvalidId(String),
+    #[error("unknown meter type {0:?}, expected gas, water or electricity")]
+    UnknownKind(String),
+    #[error("meter digits must be between 1 and 12, not {0}")]
+    InvalidDigits(usize),
+    #[error("meter decimals ({decimals}) must be fewer than meter digits ({digits})")]
+    InvalidDecimals { decimals: usize, digits: usize },
+    #[error("invalid unit {0:?}, expected up to 16 characters")]
+    InvalidUnit(String),
+}
+
+/// What a meter measures.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum MeterKind {
+    Gas,
+    Water,
+    /// A mechanical register, read like the others.
+    Electricity,
+}
+
+impl MeterKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MeterKind::Gas => "gas",
+            MeterKind::Water => "water",
+            MeterKind::Electricity => "electricity",
+        }
+    }
+
+    /// Name of the kind as the start of a sentence or title.
+    pub fn title(self) -> &'static str {
+        match self {
+            MeterKind::Gas => "Gas",
+            MeterKind::Water => "Water",
+            MeterKind::Electricity => "Electricity",
+        }
+    }
+
+    pub fn default_unit(self) -> &'static str {
+        match self {
+            MeterKind::Gas | MeterKind::Water => "m³",
+            MeterKind::Electricity => "kWh",
+        }
+    }
+}
+
+impl FromStr for MeterKind {
+    type Err = MeterError;
+
+    fn from_str(kind: &str) -> Result<Self, MeterError> {
+        match kind {
+            "gas" => Ok(MeterKind::Gas),
+            "water" => Ok(MeterKind::Water),
+            "electricity" => Ok(MeterKind::Electricity),
+            _ => Err(MeterError::UnknownKind(kind.to_string())),
+        }
+    }
+}
+
+impl fmt::Display for MeterKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A meter watched by one or more cameras.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Meter {
+    pub id: String,
+    pub kind: MeterKind,
+    /// Unit of the register, e.g. m³ or kWh.
+    pub unit: String,
+    /// Number of wheels on the register, including the fractional ones.
+    pub digits: usize,
+    /// Number of wheels showing fractions of the unit.
+    pub decimals: usize,
+}
+
+impl Meter {
+    pub fn validate(&self) -> Result<(), MeterError> {
+        validate_id(&self.id)?;
+        if !(1..=12).contains(&self.digits) {
+            return Err(MeterError::InvalidDigits(self.digits));
+        }
+        if self.decimals >= self.digits {
+            return Err(MeterError::InvalidDecimals {
+                decimals: self.decimals,
+                digits: self.digits,
+            });
+        }
+        if self.unit.trim().is_empty() || self.unit.chars().count() > MAX_UNIT_LENGTH {
+            return Err(MeterError::InvalidUnit(self.unit.clone()));
+        }
+        Ok(())
+    }
+
+    /// The value of the wheels read as `digits`, e.g. 12.345 for "00012345" with three
+    /// decimals.
+    pub fn value(&self, digits: &str) -> Option<f64> {
+        let value = digits.parse::<u64>().ok()?;
+        Some(value as f64 / 10f64.powi(self.decimals as i32))
+    }
+
+    /// `value` with as many decimals as the register has, followed by the unit.
+    pub fn format(&self, value: f64) -> String {
+        format!("{value:.decimals$} {}", self.unit, decimals = self.decimals)
+    }
+}
+
+/// Meter ids end up in paths, both on disk and in URLs.
+pub fn validate_id(id: &str) -> Result<(), MeterError> {
+    let valid = !id.is_empty()
+        && id.len() <= MAX_ID_LENGTH
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(MeterError::InvalidId(id.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meter() -> Meter {
+        Meter {
+            id: "water".into(),
+            kind: MeterKind::Water,
+            unit: "m³".into(),
+            digits: 8,
+            decimals: 3,
+        }
+    }
+
+    #[test]
+    fn reads_value_with_decimals() {
+        let meter = meter();
+        assert_eq!(meter.value("00012345"), Some(12.345));
+        assert_eq!(meter.value("0001234?"), None);
+        assert_eq!(meter.format(12.3), "12.300 m³");
+
+        let register = Meter {
+            kind: MeterKind::Electricity,
+            unit: "kWh".into(),
+            digits: 6,
+            decimals: 0,
+            ..meter
+        };
+        assert_eq!(register.value("004711"), Some(4711.0));
+        assert_eq!(register.format(4711.0), "4711 kWh");
+    }
+
+    #[test]
+    fn validates_meters() {
+        assert!(meter().validate().is_ok());
+        let invalid = [
+            Meter {
+                id: "../gas".into(),
+                ..meter()
+            },
+            Meter {
+                digits: 13,
+                ..meter()
+            },
+            Meter {
+                decimals: 8,
+                ..meter()
+            },
+            Meter {
+                unit: " ".into(),
+                ..meter()
+            },
+        ];
+        for meter in invalid {
+            assert!(meter.validate().is_err(), "{meter:?}");
+        }
+    }
+
+    #[test]
+    fn parses_kinds() {
+        for kind in [MeterKind::Gas, MeterKind::Water, MeterKind::Electricity] {
+            assert_eq!(kind.as_str().parse::<MeterKind>().unwrap(), kind);
+        }
+        assert!("heat".parse::<MeterKind>().is_err());
+        assert_eq!(MeterKind::Electricity.default_unit(), "kWh");
+    }
+}
@@ -0,0 +1,541 @@
+use image::{GrayImage, ImageFormat, imageops};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Working width the photo is scaled down to before searching for the digit window.
+const WORK_WIDTH: u32 = 800;
+/// Size of the normalised grid every digit cell is resampled to before matching.
+const GRID_WIDTH: usize = 16;
+const GRID_HEIGHT: usize = 24;
+/// Minimum luminance step between neighbouring pixels that counts as an edge.
+const EDGE_THRESHOLD: i16 = 32;
+
+/// 5x7 bitmaps of the digits 0-9, used as matching templates for the wheels. Digits that
+/// meter fonts draw in more than one way have a bitmap for each.
+#[rustfmt::skip]
+const DIGIT_FONT: [(u8, [u8; 7]); 11] = [
+    (0, [0b01110, 0b10001, 0b10001, 0b10001, 0b10001, 0b10001, 0b01110]),
+    (1, [0b00100, 0b01100, 0b00100, 0b00100, 0b00100, 0b00100, 0b01110]),
+    (2, [0b01110, 0b10001, 0b00001, 0b00010, 0b00100, 0b01000, 0b11111]),
+    (3, [0b11111, 0b00010, 0b00100, 0b00010, 0b00001, 0b10001, 0b01110]),
+    (3, [0b01110, 0b10001, 0b00001, 0b00110, 0b00001, 0b10001, 0b01110]),
+    (4, [0b00010, 0b00110, 0b01010, 0b10010, 0b11111, 0b00010, 0b00010]),
+    (5, [0b11111, 0b10000, 0b11110, 0b00001, 0b00001, 0b10001, 0b01110]),
+    (6, [0b00110, 0b01000, 0b10000, 0b11110, 0b10001, 0b10001, 0b01110]),
+    (7, [0b11111, 0b00001, 0b00010, 0b00100, 0b01000, 0b01000, 0b01000]),
+    (8, [0b01110, 0b10001, 0b10001, 0b01110, 0b10001, 0b10001, 0b01110]),
+    (9, [0b01110, 0b10001, 0b10001, 0b01111, 0b00001, 0b00010, 0b01100]),
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum RecognitionError {
+    #[error("could not decode image: {0}")]
+    Decode(#[from] image::ImageError),
+    #[error("could not find the digit window")]
+    WindowNotFound,
+    #[error("could not read image: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DigitReading {
+    pub digit: u8,
+    pub confidence: f32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reading {
+    /// The recognised digits from left to right, e.g. "01234567".
+    pub value: String,
+    pub digits: Vec<DigitReading>,
+    pub recognized_at: chrono::DateTime<chrono::Local>,
+}
+
+impl Reading {
+    /// Confidence of the least certain digit, which bounds the confidence of the whole reading.
+    pub fn confidence(&self) -> f32 {
+        self.digits.iter().map(|d| d.confidence).fold(1.0, f32::min)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Rect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+pub struct Recognizer {
+    templates: Vec<(u8, [f32; GRID_WIDTH * GRID_HEIGHT])>,
+}
+
+impl Default for Recognizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Recognizer {
+    pub fn new() -> Self {
+        Self {
+            templates: DIGIT_FONT
+                .iter()
+                .map(|(digit, glyph)| (*digit, render_template(glyph)))
+                .collect(),
+        }
+    }
+
+    /// Reads a meter with `digit_count` wheels from a JPEG photo.
+    pub fn recognize(&self, jpeg: &[u8], digit_count: usize) -> Result<Reading, RecognitionError> {
+        let image = decode(jpeg)?;
+        let window = locate_window(&image, digit_count).ok_or(RecognitionError::WindowNotFound)?;
+        log::debug!("Digit window found at {window:?}");
+
+        // Spread the rounding over the cells, so the last ones do not drift off their wheels
+        let cell_left = |i: u32| window.x + window.width * i / digit_count as u32;
+        let digits: Vec<DigitReading> = (0..digit_count as u32)
+            .map(|i| {
+                let cell = imageops::crop_imm(
+                    &image,
+                    cell_left(i),
+                    window.y,
+                    cell_left(i + 1) - cell_left(i),
+                    window.height,
+                )
+                .to_image();
+                self.classify(&cell)
+            })
+            .collect();
+
+        Ok(Reading {
+            value: digits.iter().map(|d| char::from(b'0' + d.digit)).collect(),
+            digits,
+            recognized_at: chrono::Local::now(),
+        })
+    }
+
+    /// Reads a meter with `digit_count` wheels from the JPEG photo at `path`.
+    pub fn recognize_file(
+        &self,
+        path: &Path,
+        digit_count: usize,
+    ) -> Result<Reading, RecognitionError> {
+        self.recognize(&std::fs::read(path)?, digit_count)
+    }
+
+    fn classify(&self, cell: &GrayImage) -> DigitReading {
+        let (grid, split) = normalise_cell(cell);
+
+        let mut scores = [-1.0f32; 10];
+        for (digit, template) in &self.templates {
+            let score = &mut scores[usize::from(*digit)];
+            *score = score.max(correlation(&grid, template));
+        }
+        let (digit, best) = scores
+            .iter()
+            .copied()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(&b.1))
+            .unwrap_or((0, 0.0));
+
+        // Softmax over the correlation scores, so that a wheel halfway between two digits
+        // yields a low confidence for either of them.
+        let total: f32 = scores.iter().map(|s| ((s - best) * 15.0).exp()).sum();
+        // Such a wheel shows the bottom of one digit over the top of the next, which may
+        // still match a third digit well
+        let confidence = if split { 0.5 / total } else { 1.0 / total };
+
+        DigitReading {
+            digit: digit as u8,
+            confidence,
+        }
+    }
+}
+
+/// Decodes a JPEG photo to grayscale, scaled down to at most [`WORK_WIDTH`] pixels wide.
+fn decode(jpeg: &[u8]) -> Result<GrayImage, RecognitionError> {
+    let image = image::load_from_memory_with_format(jpeg, ImageFormat::Jpeg)?.to_luma8();
+    Ok(if image.width() > WORK_WIDTH {
+        let height = image.height() * WORK_WIDTH / image.width();
+        imageops::resize(&image, WORK_WIDTH, height, imageops::FilterType::Triangle)
+    } else {
+        image
+    })
+}
+
+/// Finds the odometer window as the horizontal band with the most vertical edges,
+/// trimmed to the columns where those edges are.
+fn locate_window(image: &GrayImage, digit_count: usize) -> Option<Rect> {
+    let (width, height) = image.dimensions();
+    if width < 2 || height < 2 {
+        return None;
+    }
+
+    let is_edge = |x: u32, y: u32| {
+        let a = image.get_pixel(x, y).0[0] as i16;
+        let b = image.get_pixel(x + 1, y).0[0] as i16;
+        (a - b).abs() > EDGE_THRESHOLD
+    };
+
+    let rows: Vec<f32> = (0..height)
+        .map(|y| (0..width - 1).filter(|&x| is_edge(x, y)).count() as f32)
+        .collect();
+    let rows = smooth(&rows, (height as usize / 40).max(1));
+    let (peak, peak_energy) = rows
+        .iter()
+        .copied()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(&b.1))?;
+    if peak_energy <= 0.0 {
+        return None;
+    }
+
+    let mut top = peak;
+    while top > 0 && rows[top - 1] > peak_energy * 0.5 {
+        top -= 1;
+    }
+    let mut bottom = peak;
+    while bottom + 1 < rows.len() && rows[bottom + 1] > peak_energy * 0.5 {
+        bottom += 1;
+    }
+
+    let columns: Vec<f32> = (0..width - 1)
+        .map(|x| (top..=bottom).filter(|&y| is_edge(x, y as u32)).count() as f32)
+        .collect();
+    let smoothed = smooth(&columns, (width as usize / 100).max(1));
+    let column_peak = smoothed.iter().copied().fold(0.0, f32::max);
+    let left = smoothed.iter().position(|&c| c > column_peak * 0.25)?;
+    let right = smoothed.iter().rposition(|&c| c > column_peak * 0.25)?;
+    // Smoothing widens the window, the rims of the outer wheels are where its edges are
+    let rim = (bottom - top + 1) as f32 * 0.5;
+    let (left, right) = match (
+        (left..=right).find(|&x| columns[x] > rim),
+        (left..=right).rev().find(|&x| columns[x] > rim),
+    ) {
+        (Some(rim_left), Some(rim_right)) if rim_left < rim_right => (rim_left + 1, rim_right),
+        _ => (left, right),
+    };
+
+    let window = Rect {
+        x: left as u32,
+        y: top as u32,
+        width: (right - left + 1) as u32,
+        height: (bottom - top + 1) as u32,
+    };
+
+    // Each wheel is roughly 0.4-2 times as wide as it is tall
+    let aspect = window.width as f32 / window.height as f32;
+    let count = digit_count as f32;
+    if window.width < digit_count as u32 || !(count * 0.4..=count * 2.0).contains(&aspect) {
+        return None;
+    }
+    Some(window)
+}
+
+/// Moving average over `radius` samples on both sides.
+fn smooth(values: &[f32], radius: usize) -> Vec<f32> {
+    (0..values.len())
+        .map(|i| {
+            let window = &values[i.saturating_sub(radius)..(i + radius + 1).min(values.len())];
+            window.iter().sum::<f32>() / window.len() as f32
+        })
+        .collect()
+}
+
+/// Binarises a digit cell, crops it to the ink and resamples it to the matching grid.
+/// The returned values are the fraction of ink in each grid cell, and whether the ink is
+/// split by a blank band across the cell, as on a wheel turning between two digits.
+fn normalise_cell(cell: &GrayImage) -> ([f32; GRID_WIDTH * GRID_HEIGHT], bool) {
+    let threshold = otsu_threshold(cell);
+    let dark = cell.pixels().filter(|p| p.0[0] <= threshold).count();
+    // The digit is the minority of the cell, be it white on black or black on white
+    let ink_is_dark = dark * 2 < (cell.width() * cell.height()) as usize;
+    let (width, height) = cell.dimensions();
+    let mut ink: Vec<bool> = cell
+        .pixels()
+        .map(|p| (p.0[0] <= threshold) == ink_is_dark)
+        .collect();
+    // The gaps between the wheels and the rim of the window reach into the cell from its
+    // sides, the digit does not
+    let mut without_rims = ink.clone();
+    clear_from_sides(&mut without_rims, width, height);
+    if without_rims.contains(&true) {
+        ink = without_rims;
+    }
+    let is_ink = |x: u32, y: u32| ink[(y * width + x) as usize];
+
+    let ink_rows: Vec<u32> = (0..height)
+        .filter(|&y| (0..width).filter(|&x| is_ink(x, y)).count() * 20 > width as usize)
+        .collect();
+    let ink_columns: Vec<u32> = (0..width)
+        .filter(|&x| (0..height).filter(|&y| is_ink(x, y)).count() * 20 > height as usize)
+        .collect();
+    let (top, bottom) = match (ink_rows.first(), ink_rows.last()) {
+        (Some(&top), Some(&bottom)) => (top, bottom + 1),
+        _ => (0, height),
+    };
+    let gap = ink_rows
+        .windows(2)
+        .map(|w| w[1] - w[0] - 1)
+        .max()
+        .unwrap_or(0);
+    let split = gap * 10 > bottom - top;
+    let (left, right) = match (ink_columns.first(), ink_columns.last()) {
+        (Some(&left), Some(&right)) => (left, right + 1),
+        _ => (0, width),
+    };
+
+    let mut grid = [0.0; GRID_WIDTH * GRID_HEIGHT];
+    for (i, value) in grid.iter_mut().enumerate() {
+        let (gx, gy) = ((i % GRID_WIDTH) as u32, (i / GRID_WIDTH) as u32);
+        let x0 = left + (right - left) * gx / GRID_WIDTH as u32;
+        let x1 = (left + (right - left) * (gx + 1) / GRID_WIDTH as u32).max(x0 + 1);
+        let y0 = top + (bottom - top) * gy / GRID_HEIGHT as u32;
+        let y1 = (top + (bottom - top) * (gy + 1) / GRID_HEIGHT as u32).max(y0 + 1);
+
+        let mut count = 0;
+        for y in y0..y1.min(height) {
+            for x in x0..x1.min(width) {
+                count += is_ink(x, y) as u32;
+            }
+        }
+        *value = count as f32 / ((x1 - x0) * (y1 - y0)) as f32;
+    }
+    (grid, split)
+}
+
+/// Clears the ink connected to the outer tenth of the mask on the left and the right, where
+/// the cell may be off from the wheel by a few pixels.
+fn clear_from_sides(ink: &mut [bool], width: u32, height: u32) {
+    let (width, height) = (width as usize, height as usize);
+    let margin = (width / 10).max(1);
+    let mut stack: Vec<usize> = (0..height)
+        .flat_map(|y| (0..margin).flat_map(move |x| [y * width + x, y * width + width - 1 - x]))
+        .collect();
+    while let Some(i) = stack.pop() {
+        if !ink[i] {
+            continue;
+        }
+        ink[i] = false;
+        let (x, y) = (i % width, i / width);
+        if x > 0 {
+            stack.push(i - 1);
+        }
+        if x + 1 < width {
+            stack.push(i + 1);
+        }
+        if y > 0 {
+            stack.push(i - width);
+        }
+        if y + 1 < height {
+            stack.push(i + width);
+        }
+    }
+}
+
+/// Picks the threshold that best separates the cell into two classes of brightness.
+fn otsu_threshold(image: &GrayImage) -> u8 {
+    let mut histogram = [0u32; 256];
+    for pixel in image.pixels() {
+        histogram[pixel.0[0] as usize] += 1;
+    }
+    let total = (image.width() * image.height()) as f64;
+    let sum: f64 = histogram
+        .iter()
+        .enumerate()
+        .map(|(i, &count)| i as f64 * count as f64)
+        .sum();
+
+    let (mut best, mut best_variance) = (0, 0.0);
+    let (mut background_weight, mut background_sum) = (0.0, 0.0);
+    for (i, &count) in histogram.iter().enumerate() {
+        background_weight += count as f64;
+        background_sum += i as f64 * count as f64;
+        let foreground_weight = total - background_weight;
+        if background_weight == 0.0 || foreground_weight == 0.0 {
+            continue;
+        }
+        let mean_difference =
+            background_sum / background_weight - (sum - background_sum) / foreground_weight;
+        let variance = background_weight * foreground_weight * mean_difference * mean_difference;
+        if variance > best_variance {
+            best = i as u8;
+            best_variance = variance;
+        }
+    }
+    best
+}
+
+/// Renders a 5x7 font glyph onto the matching grid with bilinear sampling.
+fn render_template(glyph: &[u8; 7]) -> [f32; GRID_WIDTH * GRID_HEIGHT] {
+    let bit = |x: isize, y: isize| {
+        if (0..5).contains(&x) && (0..7).contains(&y) {
+            ((glyph[y as usize] >> (4 - x)) & 1) as f32
+        } else {
+            0.0
+        }
+    };
+
+    let mut grid = [0.0; GRID_WIDTH * GRID_HEIGHT];
+    for (i, value) in grid.iter_mut().enumerate() {
+        let fx = ((i % GRID_WIDTH) as f32 + 0.5) * 5.0 / GRID_WIDTH as f32 - 0.5;
+        let fy = ((i / GRID_WIDTH) as f32 + 0.5) * 7.0 / GRID_HEIGHT as f32 - 0.5;
+        let (x, y) = (fx.floor() as isize, fy.floor() as isize);
+        let (dx, dy) = (fx - fx.floor(), fy - fy.floor());
+        *value = bit(x, y) * (1.0 - dx) * (1.0 - dy)
+            + bit(x + 1, y) * dx * (1.0 - dy)
+            + bit(x, y + 1) * (1.0 - dx) * dy
+            + bit(x + 1, y + 1) * dx * dy;
+    }
+    grid
+}
+
+/// Zero-mean normalised cross-correlation, in the range -1..=1.
+fn correlation(a: &[f32], b: &[f32]) -> f32 {
+    let mean_a = a.iter().sum::<f32>() / a.len() as f32;
+    let mean_b = b.iter().sum::<f32>() / b.len() as f32;
+    let (mut ab, mut aa, mut bb) = (0.0, 0.0, 0.0);
+    for (x, y) in a.iter().zip(b) {
+        let (x, y) = (x - mean_a, y - mean_b);
+        ab += x * y;
+        aa += x * x;
+        bb += y * y;
+    }
+    if aa == 0.0 || bb == 0.0 {
+        0.0
+    } else {
+        ab / (aa * bb).sqrt()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::report::MIN_CONFIDENCE;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/meters")
+            .join(name)
+    }
+
+    /// Reads a fixture, whose file name ends in the value on its wheels.
+    fn read(name: &str) -> (String, Reading) {
+        let label = name
+            .trim_end_matches(".jpg")
+            .rsplit('_')
+            .next()
+            .unwrap()
+            .to_string();
+        let reading = Recognizer::new()
+            .recognize_file(&fixture(name), label.len())
+            .unwrap();
+        (label, reading)
+    }
+
+    /// The rendered photos show dark wheels on a light face, the window has to span them from
+    /// the first to the last and stay within their height.
+    #[test]
+    fn finds_the_digit_window() {
+        let names = [
+            "gas_00012345.jpg",
+            "gas_04827061.jpg",
+            "water_98765.jpg",
+            "rolling_0001234x.jpg",
+        ];
+        for name in names {
+            let image = decode(&std::fs::read(fixture(name)).unwrap()).unwrap();
+            let digit_count = name
+                .trim_end_matches(".jpg")
+                .rsplit('_')
+                .next()
+                .unwrap()
+                .len();
+            let window = locate_window(&image, digit_count).unwrap();
+
+            let wheels: Vec<(u32, u32)> = image
+                .enumerate_pixels()
+                .filter(|(_, _, pixel)| pixel.0[0] < 90)
+                .map(|(x, y, _)| (x, y))
+                .collect();
+            let left = wheels.iter().map(|&(x, _)| x).min().unwrap();
+            let right = wheels.iter().map(|&(x, _)| x).max().unwrap();
+            let top = wheels.iter().map(|&(_, y)| y).min().unwrap();
+            let bottom = wheels.iter().map(|&(_, y)| y).max().unwrap();
+
+            let tolerance = (right - left) / 50;
+            assert!(window.x.abs_diff(left) <= tolerance, "{name}: {window:?}");
+            assert!(
+                (window.x + window.width - 1).abs_diff(right) <= tolerance,
+                "{name}: {window:?}"
+            );
+            assert!(
+                window.y >= top && window.y + window.height - 1 <= bottom,
+                "{name}: {window:?}"
+            );
+        }
+    }
+
+    /// Share of the photos taken by a camera in `fixtures/meters/real` that have to be read
+    /// right. The others may only be doubted, never read wrong with confidence. A photo with a
+    /// wheel turning between two digits is only read right if that wheel is doubted.
+    const REAL_PHOTO_ACCURACY: f32 = 0.8;
+
+    #[test]
+    #[ignore = "needs photos taken by a camera in tests/fixtures/meters/real"]
+    fn reads_real_meter_photos() {
+        let directory = fixture("real");
+        let mut names: Vec<String> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.ends_with(".jpg"))
+            .collect();
+        names.sort();
+        assert!(!names.is_empty(), "no photos in {}", directory.display());
+
+        let mut right = 0;
+        for name in &names {
+            let (label, reading) = read(&format!("real/{name}"));
+            let turning = label.contains('x');
+            let digits_right = reading
+                .value
+                .chars()
+                .zip(label.chars())
+                .all(|(digit, label)| label == 'x' || digit == label);
+            if digits_right && (!turning || reading.confidence() < MIN_CONFIDENCE) {
+                right += 1;
+            } else {
+                assert!(
+                    reading.confidence() < MIN_CONFIDENCE,
+                    "{name} read as {} at {}",
+                    reading.value,
+                    reading.confidence()
+                );
+            }
+        }
+        let accuracy = right as f32 / names.len() as f32;
+        assert!(
+            accuracy >= REAL_PHOTO_ACCURACY,
+            "read {right} of {} photos right",
+            names.len()
+        );
+    }
+
+    #[test]
+    fn fails_without_a_digit_window() {
+        let mut jpeg = Vec::new();
+        GrayImage::from_pixel(320, 240, image::Luma([128]))
+            .write_to(&mut std::io::Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+        assert!(matches!(
+            Recognizer::new().recognize(&jpeg, 8),
+            Err(RecognitionError::WindowNotFound)
+        ));
+        assert!(matches!(
+            Recognizer::new().recognize(b"not a jpeg", 8),
+            Err(RecognitionError::Decode(_))
+        ));
+    }
+}
@@ -0,0 +1,649 @@
+use chrono::{Datelike, Days, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use serde::Serialize;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::meter::{DEFAULT_METER, Meter};
+use crate::storage::{Storage, StorageError};
+
+/// Readings less certain than this are left out of the reports.
+pub const MIN_CONFIDENCE: f32 = 0.5;
+/// Time of the last day of the month at which the report is generated.
+const REPORT_TIME: NaiveTime = NaiveTime::from_hms_opt(23, 55, 0).unwrap();
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReportError {
+    #[error("invalid month {0}, expected YYYY-MM")]
+    InvalidMonth(String),
+    #[error("could not load readings: {0}")]
+    Storage(#[from] StorageError),
+    #[error("could not write report: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not serialise report: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportReading {
+    pub timestamp: NaiveDateTime,
+    /// Meter reading in the unit of the meter.
+    pub value: f64,
+    pub confidence: f32,
+}
+
+/// A run of consecutive days without any reading.
+#[derive(Debug, Clone, Serialize)]
+pub struct Gap {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct MonthlyReport {
+    /// The month in YYYY-MM format.
+    pub month: String,
+    pub meter: Meter,
+    /// The last reading of the month before, which the consumption is counted from.
+    pub baseline: Option<ReportReading>,
+    pub first: Option<ReportReading>,
+    pub last: Option<ReportReading>,
+    /// Consumption from the baseline, or the first reading without one, to the last reading
+    /// in the unit of the meter.
+    pub consumption: Option<f64>,
+    /// Average daily consumption over the days the consumption is counted over, in the unit
+    /// of the meter.
+    pub daily_average: Option<f64>,
+    pub gaps: Vec<Gap>,
+    pub readings: Vec<ReportReading>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReportFormat {
+    Csv,
+    Json,
+    Html,
+}
+
+impl ReportFormat {
+    pub const ALL: [ReportFormat; 3] = [ReportFormat::Csv, ReportFormat::Json, ReportFormat::Html];
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ReportFormat::Csv => "csv",
+            ReportFormat::Json => "json",
+            ReportFormat::Html => "html",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ReportFormat::Csv => "text/csv",
+            ReportFormat::Json => "application/json",
+            ReportFormat::Html => "text/html; charset=utf-8",
+        }
+    }
+}
+
+/// Parses a month given as YYYY-MM into its first day.
+pub fn parse_month(month: &str) -> Result<NaiveDate, ReportError> {
+    NaiveDate::parse_from_str(&format!("{month}-01"), "%Y-%m-%d")
+        .map_err(|_| ReportError::InvalidMonth(month.to_string()))
+}
+
+/// Builds the report of `meter` for the month starting at `month` from the stored readings.
+/// `timezone` is the one the month is counted in.
+pub fn generate(
+    storage: &Storage,
+    meter: &Meter,
+    month: NaiveDate,
+    timezone: Tz,
+) -> Result<MonthlyReport, ReportError> {
+    let previous_month = month - chrono::Months::new(1);
+    let next_month = month + chrono::Months::new(1);
+    // A baseline from further back would count the consumption of the months in between
+    let baseline = report_readings(storage, meter, previous_month, month)?.pop();
+    let readings = report_readings(storage, meter, month, next_month)?;
+    let today = Utc::now().with_timezone(&timezone).date_naive();
+    Ok(summarise(meter, month, baseline, readings, today))
+}
+
+/// The readings of `meter` in `from..to` that are certain enough to report.
+fn report_readings(
+    storage: &Storage,
+    meter: &Meter,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<ReportReading>, ReportError> {
+    Ok(storage
+        .readings(
+            &meter.id,
+            from.and_time(NaiveTime::MIN),
+            to.and_time(NaiveTime::MIN),
+        )?
+        .into_iter()
+        .filter(|r| r.reading.confidence() >= MIN_CONFIDENCE)
+        .filter_map(|r| {
+            Some(ReportReading {
+                timestamp: r.captured_at,
+                value: meter.value(&r.reading.value)?,
+                confidence: r.reading.confidence(),
+            })
+        })
+        .collect())
+}
+
+/// Builds the report from the `readings` in the month, counting the consumption from
+/// `baseline` if there is one, and the gaps up to `today`.
+fn summarise(
+    meter: &Meter,
+    month: NaiveDate,
+    baseline: Option<ReportReading>,
+    readings: Vec<ReportReading>,
+    today: NaiveDate,
+) -> MonthlyReport {
+    let first = readings.first().cloned();
+    let last = readings.last().cloned();
+    let (consumption, daily_average) = match (baseline.as_ref().or(first.as_ref()), &last) {
+        (Some(start), Some(last)) => {
+            let consumption = last.value - start.value;
+            let days = (last.timestamp - start.timestamp).num_seconds() as f64 / 86400.0;
+            (
+                Some(consumption),
+                (days >= 1.0).then_some(consumption / days),
+            )
+        }
+        _ => (None, None),
+    };
+
+    // Days in the future cannot have readings yet
+    let next_month = month + chrono::Months::new(1);
+    let end = if next_month > today {
+        today + Days::new(1)
+    } else {
+        next_month
+    };
+    let mut gaps: Vec<Gap> = Vec::new();
+    let mut day = month;
+    while day < end {
+        if !readings.iter().any(|r| r.timestamp.date() == day) {
+            match gaps.last_mut() {
+                Some(gap) if gap.to + Days::new(1) == day => gap.to = day,
+                _ => gaps.push(Gap { from: day, to: day }),
+            }
+        }
+        day = day + Days::new(1);
+    }
+
+    MonthlyReport {
+        month: month.format("%Y-%m").to_string(),
+        meter: meter.clone(),
+        baseline,
+        first,
+        last,
+        consumption,
+        daily_average,
+        gaps,
+        readings,
+    }
+}
+
+impl MonthlyReport {
+    pub fn render(&self, format: ReportFormat) -> Result<String, ReportError> {
+        Ok(match format {
+            ReportFormat::Csv => self.to_csv(),
+            ReportFormat::Json => serde_json::to_string_pretty(self)?,
+            ReportFormat::Html => self.to_html(),
+        })
+    }
+
+    fn to_csv(&self) -> String {
+        let mut csv = String::from("timestamp,reading,consumption\n");
+        let mut previous = self.baseline.as_ref().map(|baseline| baseline.value);
+        for reading in &self.readings {
+            let consumption = previous.map(|p| reading.value - p).unwrap_or(0.0);
+            let _ = writeln!(
+                csv,
+                "{},{:.decimals$},{:.decimals$}",
+                reading.timestamp.format("%Y-%m-%dT%H:%M:%S"),
+                reading.value,
+                consumption,
+                decimals = self.meter.decimals
+            );
+            previous = Some(reading.value);
+        }
+        csv
+    }
+
+    fn to_html(&self) -> String {
+        // The unit is set by whoever adds the meter
+        let value = |v: Option<f64>| {
+            v.map(|v| escape(&self.meter.format(v)))
+                .unwrap_or("-".into())
+        };
+        let reading = |r: &Option<ReportReading>| {
+            r.as_ref()
+                .map(|r| format!("{} at {}", escape(&self.meter.format(r.value)), r.timestamp))
+                .unwrap_or("-".into())
+        };
+
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{kind} consumption {month}</title></head>\n<body>\n\
+             <h1>{kind} consumption {month}</h1>\n<p>Meter {id}</p>\n<table>\n\
+             <tr><th>Previous month</th><td>{baseline}</td></tr>\n\
+             <tr><th>First reading</th><td>{first}</td></tr>\n\
+             <tr><th>Last reading</th><td>{last}</td></tr>\n\
+             <tr><th>Consumption</th><td>{consumption}</td></tr>\n\
+             <tr><th>Daily average</th><td>{average}</td></tr>\n\
+             </table>\n",
+            kind = self.meter.kind.title(),
+            id = escape(&self.meter.id),
+            month = self.month,
+            baseline = reading(&self.baseline),
+            first = reading(&self.first),
+            last = reading(&self.last),
+            consumption = value(self.consumption),
+            average = value(self.daily_average),
+        );
+
+        if !self.gaps.is_empty() {
+            html.push_str("<h2>Days without readings</h2>\n<ul>\n");
+            for gap in &self.gaps {
+                if gap.from == gap.to {
+                    let _ = writeln!(html, "<li>{}</li>", gap.from);
+                } else {
+                    let _ = writeln!(html, "<li>{} to {}</li>", gap.from, gap.to);
+                }
+            }
+            html.push_str("</ul>\n");
+        }
+
+        html.push_str("<h2>Readings</h2>\n<table>\n<tr><th>Time</th><th>Reading</th><th>Confidence</th></tr>\n");
+        for reading in &self.readings {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td><td>{:.2}</td></tr>",
+                reading.timestamp,
+                escape(&self.meter.format(reading.value)),
+                reading.confidence
+            );
+        }
+        html.push_str("</table>\n</body>\n</html>\n");
+        html
+    }
+
+    /// Writes the report in every format to `directory`, see [`path`].
+    pub fn write(&self, directory: &Path) -> Result<(), ReportError> {
+        for format in ReportFormat::ALL {
+            let path = path(directory, &self.meter.id, &self.month, format);
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::write(path, self.render(format)?)?;
+        }
+        Ok(())
+    }
+}
+
+/// Path of the report of `meter_id` for `month` in `format`. The reports of the default meter
+/// are YYYY-MM.{csv,json,html} in `directory`, those of other meters are in a directory named
+/// after the meter within it.
+pub fn path(directory: &Path, meter_id: &str, month: &str, format: ReportFormat) -> PathBuf {
+    let file_name = format!("{month}.{}", format.extension());
+    if meter_id == DEFAULT_METER {
+        directory.join(file_name)
+    } else {
+        directory.join(meter_id).join(file_name)
+    }
+}
+
+/// Escapes text for HTML, inside elements as well as attribute values.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The months of `meter` from the one of its first reading up to `before` that have no report
+/// in `directory`.
+fn missing_months(
+    storage: &Storage,
+    meter: &Meter,
+    directory: &Path,
+    before: NaiveDate,
+) -> Result<Vec<NaiveDate>, ReportError> {
+    let Some(first) = storage.first_reading_at(&meter.id)? else {
+        return Ok(Vec::new());
+    };
+    let mut months = Vec::new();
+    let mut month = first.date().with_day(1).unwrap();
+    while month < before {
+        let name = month.format("%Y-%m").to_string();
+        if !path(directory, &meter.id, &name, ReportFormat::Json).exists() {
+            months.push(month);
+        }
+        month = month + chrono::Months::new(1);
+    }
+    Ok(months)
+}
+
+/// Writes the reports of every meter for the months `months` picks for it. One meter or month
+/// without a report does not keep the others from theirs.
+fn write_reports(
+    storage: &Storage,
+    directory: &Path,
+    timezone: Tz,
+    months: impl Fn(&Meter) -> Result<Vec<NaiveDate>, ReportError>,
+) {
+    let meters = match storage.meters() {
+        Ok(meters) => meters,
+        Err(e) => {
+            log::error!("Failed to load the meters: {e}");
+            return;
+        }
+    };
+    for meter in &meters {
+        let months = match months(meter) {
+            Ok(months) => months,
+            Err(e) => {
+                log::error!("Failed to find the months to report on {}: {e}", meter.id);
+                continue;
+            }
+        };
+        for month in months {
+            let name = month.format("%Y-%m");
+            match generate(storage, meter, month, timezone)
+                .and_then(|report| report.write(directory))
+            {
+                Ok(()) => log::info!("Wrote monthly report of {} for {name}", meter.id),
+                Err(e) => log::error!(
+                    "Failed to write monthly report of {} for {name}: {e}",
+                    meter.id
+                ),
+            }
+        }
+    }
+}
+
+/// Generates the reports of the months that ended while the server was not running, then the
+/// report of every meter and month on the last day of the month, forever.
+pub async fn schedule(storage: Arc<Storage>, reports_directory: PathBuf, timezone: Tz) {
+    let this_month = Utc::now()
+        .with_timezone(&timezone)
+        .date_naive()
+        .with_day(1)
+        .unwrap();
+    let catch_up = {
+        let storage = storage.clone();
+        let reports_directory = reports_directory.clone();
+        tokio::task::spawn_blocking(move || {
+            write_reports(&storage, &reports_directory, timezone, |meter| {
+                missing_months(&storage, meter, &reports_directory, this_month)
+            })
+        })
+    };
+    if let Err(e) = catch_up.await {
+        log::error!("Monthly report task failed: {e}");
+    }
+
+    loop {
+        let now = Utc::now().with_timezone(&timezone);
+        let month = now.date_naive().with_day(1).unwrap();
+        let last_day = month + chrono::Months::new(1) - Days::new(1);
+        let run_at = timezone
+            .from_local_datetime(&last_day.and_time(REPORT_TIME))
+            .earliest()
+            .unwrap_or(now);
+
+        if run_at > now {
+            log::info!("Next monthly report at {run_at}");
+            tokio::time::sleep((run_at - now).to_std().unwrap_or_default()).await;
+        }
+
+        let storage = storage.clone();
+        let reports_directory = reports_directory.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            write_reports(&storage, &reports_directory, timezone, |_| Ok(vec![month]))
+        })
+        .await;
+        if let Err(e) = result {
+            log::error!("Monthly report task failed: {e}");
+        }
+
+        // Make sure the next iteration is already in the next month
+        let next_month = timezone
+            .from_local_datetime(&(month + chrono::Months::new(1)).and_time(NaiveTime::MIN))
+            .earliest();
+        if let Some(next_month) = next_month {
+            let wait = (next_month.to_utc() - Utc::now())
+                .to_std()
+                .unwrap_or_default();
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::meter::{DEFAULT_METER, MeterKind};
+    use crate::recognition::{DigitReading, Reading};
+    use chrono::Local;
+
+    fn meter() -> Meter {
+        Meter {
+            id: DEFAULT_METER.into(),
+            kind: MeterKind::Gas,
+            unit: "m³".into(),
+            digits: 8,
+            decimals: 3,
+        }
+    }
+
+    fn date(date: &str) -> NaiveDate {
+        date.parse().unwrap()
+    }
+
+    fn reading(timestamp: &str, value: f64) -> ReportReading {
+        ReportReading {
+            timestamp: timestamp.parse().unwrap(),
+            value,
+            confidence: 0.9,
+        }
+    }
+
+    #[test]
+    fn counts_consumption_from_last_reading_of_previous_month() {
+        let report = summarise(
+            &meter(),
+            date("2026-09-01"),
+            Some(reading("2026-08-31T20:00:00", 100.0)),
+            vec![
+                reading("2026-09-02T08:00:00", 101.0),
+                reading("2026-09-30T20:00:00", 130.0),
+            ],
+            date("2026-10-14"),
+        );
+        assert_eq!(report.consumption, Some(30.0));
+        assert_eq!(report.daily_average, Some(1.0));
+        assert_eq!(report.first.as_ref().unwrap().value, 101.0);
+        assert_eq!(
+            report.render(ReportFormat::Csv).unwrap(),
+            "timestamp,reading,consumption\n\
+             2026-09-02T08:00:00,101.000,1.000\n\
+             2026-09-30T20:00:00,130.000,29.000\n"
+        );
+    }
+
+    #[test]
+    fn counts_from_first_reading_without_previous_month() {
+        let report = summarise(
+            &meter(),
+            date("2026-09-01"),
+            None,
+            vec![
+                reading("2026-09-01T08:00:00", 100.0),
+                reading("2026-09-11T08:00:00", 105.0),
+            ],
+            date("2026-10-14"),
+        );
+        assert_eq!(report.consumption, Some(5.0));
+        assert_eq!(report.daily_average, Some(0.5));
+
+        let empty = summarise(
+            &meter(),
+            date("2026-09-01"),
+            None,
+            Vec::new(),
+            date("2026-10-14"),
+        );
+        assert_eq!(empty.consumption, None);
+        assert_eq!(empty.daily_average, None);
+    }
+
+    #[test]
+    fn finds_days_without_readings_up_to_today() {
+        let report = summarise(
+            &meter(),
+            date("2026-10-01"),
+            None,
+            vec![
+                reading("2026-10-01T08:00:00", 100.0),
+                reading("2026-10-04T08:00:00", 101.0),
+            ],
+            date("2026-10-06"),
+        );
+        let gaps: Vec<(NaiveDate, NaiveDate)> =
+            report.gaps.iter().map(|gap| (gap.from, gap.to)).collect();
+        assert_eq!(
+            gaps,
+            [
+                (date("2026-10-02"), date("2026-10-03")),
+                (date("2026-10-05"), date("2026-10-06")),
+            ]
+        );
+    }
+
+    fn add_reading(storage: &Storage, captured_at: &str, value: &str, confidence: f32) {
+        let upload = storage
+            .store_upload(
+                "espcam",
+                Some(&format!("{captured_at}.jpg")),
+                None,
+                captured_at.as_bytes(),
+            )
+            .unwrap();
+        let reading = Reading {
+            value: value.into(),
+            digits: vec![
+                DigitReading {
+                    digit: 0,
+                    confidence
+                };
+                value.len()
+            ],
+            recognized_at: Local::now(),
+        };
+        storage.add_reading(upload.id, &reading).unwrap();
+    }
+
+    #[test]
+    fn escapes_html() {
+        assert_eq!(
+            escape("<a href=\"x\">&'"),
+            "&lt;a href=&quot;x&quot;&gt;&amp;&#39;"
+        );
+
+        let meter = Meter {
+            id: "<b>".into(),
+            unit: "m&sup3;".into(),
+            ..meter()
+        };
+        let report = summarise(
+            &meter,
+            date("2026-09-01"),
+            None,
+            vec![reading("2026-09-01T08:00:00", 100.0)],
+            date("2026-10-14"),
+        );
+        let html = report.render(ReportFormat::Html).unwrap();
+        assert!(html.contains("Meter &lt;b&gt;"));
+        assert!(html.contains("m&amp;sup3;"));
+        assert!(!html.contains("<b>"));
+    }
+
+    #[test]
+    fn keeps_reports_of_the_default_meter_in_the_reports_directory() {
+        let directory = Path::new("/data/reports");
+        assert_eq!(
+            path(directory, DEFAULT_METER, "2026-09", ReportFormat::Csv),
+            Path::new("/data/reports/2026-09.csv")
+        );
+        assert_eq!(
+            path(directory, "water", "2026-09", ReportFormat::Html),
+            Path::new("/data/reports/water/2026-09.html")
+        );
+    }
+
+    #[test]
+    fn writes_reports_of_months_missed_while_stopped() {
+        let directory =
+            std::env::temp_dir().join(format!("digit-report-missed-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let storage = Storage::open(&directory).unwrap();
+        storage.save_meter(&meter()).unwrap();
+        let reports = directory.join("reports");
+        add_reading(&storage, "2026-06-30T20:00:00", "00090000", 0.9);
+        add_reading(&storage, "2026-08-15T20:00:00", "00099000", 0.9);
+        report_for(&storage, "2026-07").write(&reports).unwrap();
+
+        let missing = missing_months(&storage, &meter(), &reports, date("2026-09-01")).unwrap();
+        assert_eq!(missing, [date("2026-06-01"), date("2026-08-01")]);
+
+        write_reports(&storage, &reports, Tz::UTC, |meter| {
+            missing_months(&storage, meter, &reports, date("2026-09-01"))
+        });
+        let missing = missing_months(&storage, &meter(), &reports, date("2026-09-01")).unwrap();
+        let written = reports.join("2026-08.csv").exists();
+        let _ = std::fs::remove_dir_all(&directory);
+        assert!(missing.is_empty());
+        assert!(written);
+    }
+
+    fn report_for(storage: &Storage, month: &str) -> MonthlyReport {
+        generate(storage, &meter(), parse_month(month).unwrap(), Tz::UTC).unwrap()
+    }
+
+    #[test]
+    fn generates_from_stored_readings() {
+        let directory = std::env::temp_dir().join(format!("digit-report-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let storage = Storage::open(&directory).unwrap();
+        storage.save_meter(&meter()).unwrap();
+        let add = |captured_at: &str, value: &str, confidence: f32| {
+            add_reading(&storage, captured_at, value, confidence)
+        };
+        add("2026-07-31T20:00:00", "00090000", 0.9);
+        add("2026-08-30T20:00:00", "00099000", 0.9);
+        // Too uncertain to count from
+        add("2026-08-31T20:00:00", "00199000", 0.2);
+        add("2026-09-15T08:00:00", "00105000", 0.9);
+        add("2026-09-30T20:00:00", "00110000", 0.9);
+
+        let report = generate(&storage, &meter(), date("2026-09-01"), Tz::UTC).unwrap();
+        let _ = std::fs::remove_dir_all(&directory);
+        assert_eq!(report.baseline.unwrap().value, 99.0);
+        assert_eq!(report.readings.len(), 2);
+        assert_eq!(report.consumption, Some(11.0));
+    }
+}
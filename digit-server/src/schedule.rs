@@ -0,0 +1,268 @@
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, NaiveTime, TimeDelta, TimeZone};
+use chrono_tz::Tz;
+use digit_protocol::Timestamp;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// Time of day the devices wake up at unless told otherwise, the same the camera has always used.
+const DEFAULT_WAKE_TIME: NaiveTime = NaiveTime::from_hms_opt(22, 0, 0).unwrap();
+
+/// When a device should wake up, in the server's time zone.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WakeSchedule {
+    pub rule: WakeRule,
+    /// Number of days at the end of each month with extra wakes at `month_end_times`, so that
+    /// the month's last reading is close to its end.
+    #[serde(default)]
+    pub month_end_days: u32,
+    #[serde(default)]
+    pub month_end_times: Vec<NaiveTime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum WakeRule {
+    /// Once a day at the given time.
+    Daily { at: NaiveTime },
+    /// Every `hours` hours, counted from midnight.
+    EveryHours { hours: u32 },
+}
+
+impl Default for WakeSchedule {
+    fn default() -> Self {
+        Self {
+            rule: WakeRule::Daily {
+                at: DEFAULT_WAKE_TIME,
+            },
+            month_end_days: 0,
+            month_end_times: Vec::new(),
+        }
+    }
+}
+
+impl WakeSchedule {
+    pub fn validate(&self) -> Result<(), String> {
+        if let WakeRule::EveryHours { hours } = self.rule
+            && !(1..=24).contains(&hours)
+        {
+            return Err(format!("must wake every 1 to 24 hours, not {hours}"));
+        }
+        if self.month_end_days > 28 {
+            return Err(format!(
+                "the end of the month is at most 28 days, not {}",
+                self.month_end_days
+            ));
+        }
+        if self.month_end_days > 0 && self.month_end_times.is_empty() {
+            return Err("month end days need at least one wake time".into());
+        }
+        Ok(())
+    }
+
+    /// The wake times on `day`, in no particular order.
+    fn times_on(&self, day: NaiveDate) -> Vec<NaiveTime> {
+        let mut times = match self.rule {
+            WakeRule::Daily { at } => vec![at],
+            WakeRule::EveryHours { hours } => (0..24)
+                .step_by(hours.max(1) as usize)
+                .filter_map(|hour| NaiveTime::from_hms_opt(hour, 0, 0))
+                .collect(),
+        };
+        let first_of_month = day.with_day(1).unwrap();
+        let days_left = (first_of_month + Months::new(1)).signed_duration_since(day);
+        if days_left.num_days() <= i64::from(self.month_end_days) {
+            times.extend(&self.month_end_times);
+        }
+        times
+    }
+
+    /// The first wake time after `now`, in the offset of `now`'s time zone at that moment.
+    pub fn next_wake(&self, now: DateTime<Tz>) -> Timestamp {
+        let timezone = now.timezone();
+        let today = now.date_naive();
+        // Every schedule wakes at least once a day, so tomorrow always has a wake time. A time
+        // skipped when the clocks go forward is taken an hour later, a time that happens twice
+        // when they go back the first time.
+        [today, today + Days::new(1)]
+            .into_iter()
+            .flat_map(|day| {
+                self.times_on(day).into_iter().filter_map(move |time| {
+                    let local = day.and_time(time);
+                    timezone.from_local_datetime(&local).earliest().or_else(|| {
+                        timezone
+                            .from_local_datetime(&(local + TimeDelta::hours(1)))
+                            .earliest()
+                    })
+                })
+            })
+            .filter(|wake| *wake > now)
+            .min()
+            .unwrap_or(now + Days::new(1))
+            .fixed_offset()
+    }
+}
+
+impl fmt::Display for WakeSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rule {
+            WakeRule::Daily { at } => write!(f, "daily at {}", at.format("%H:%M"))?,
+            WakeRule::EveryHours { hours } => write!(f, "every {hours} hours")?,
+        }
+        if self.month_end_days > 0 {
+            let times: Vec<String> = self
+                .month_end_times
+                .iter()
+                .map(|time| time.format("%H:%M").to_string())
+                .collect();
+            write!(
+                f,
+                ", also at {} in the last {} days of the month",
+                times.join(", "),
+                self.month_end_days
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono_tz::Europe::Berlin;
+
+    fn time(time: &str) -> NaiveTime {
+        time.parse().unwrap()
+    }
+
+    fn berlin(local: &str) -> DateTime<Tz> {
+        let local = local.parse().unwrap();
+        Berlin.from_local_datetime(&local).earliest().unwrap()
+    }
+
+    fn daily(at: &str) -> WakeSchedule {
+        WakeSchedule {
+            rule: WakeRule::Daily { at: time(at) },
+            ..WakeSchedule::default()
+        }
+    }
+
+    fn wake(schedule: &WakeSchedule, now: &str) -> String {
+        schedule.next_wake(berlin(now)).to_rfc3339()
+    }
+
+    #[test]
+    fn wakes_at_the_next_time_of_the_schedule() {
+        let schedule = daily("22:00");
+        assert_eq!(
+            wake(&schedule, "2025-07-01T21:59:00"),
+            "2025-07-01T22:00:00+02:00"
+        );
+        assert_eq!(
+            wake(&schedule, "2025-07-01T22:00:00"),
+            "2025-07-02T22:00:00+02:00"
+        );
+
+        let schedule = WakeSchedule {
+            rule: WakeRule::EveryHours { hours: 6 },
+            ..WakeSchedule::default()
+        };
+        assert_eq!(
+            wake(&schedule, "2025-07-01T13:00:00"),
+            "2025-07-01T18:00:00+02:00"
+        );
+        assert_eq!(
+            wake(&schedule, "2025-07-01T19:00:00"),
+            "2025-07-02T00:00:00+02:00"
+        );
+    }
+
+    #[test]
+    fn wakes_an_hour_later_when_the_clocks_go_forward() {
+        // On 2025-03-30 the clocks in Berlin go from 02:00 straight to 03:00
+        let schedule = daily("02:30");
+        assert_eq!(
+            wake(&schedule, "2025-03-29T23:00:00"),
+            "2025-03-30T03:30:00+02:00"
+        );
+        assert_eq!(
+            wake(&schedule, "2025-03-30T04:00:00"),
+            "2025-03-31T02:30:00+02:00"
+        );
+
+        let schedule = WakeSchedule {
+            rule: WakeRule::EveryHours { hours: 1 },
+            ..WakeSchedule::default()
+        };
+        assert_eq!(
+            wake(&schedule, "2025-03-30T01:30:00"),
+            "2025-03-30T03:00:00+02:00"
+        );
+    }
+
+    #[test]
+    fn wakes_once_when_the_clocks_go_back() {
+        // On 2025-10-26 the clocks in Berlin go from 03:00 back to 02:00
+        let schedule = daily("02:30");
+        assert_eq!(
+            wake(&schedule, "2025-10-26T01:00:00"),
+            "2025-10-26T02:30:00+02:00"
+        );
+        let second_half_hour = Berlin
+            .from_local_datetime(&"2025-10-26T02:15:00".parse().unwrap())
+            .latest()
+            .unwrap();
+        assert_eq!(
+            schedule.next_wake(second_half_hour).to_rfc3339(),
+            "2025-10-27T02:30:00+01:00"
+        );
+    }
+
+    #[test]
+    fn wakes_more_often_at_the_end_of_the_month() {
+        let schedule = WakeSchedule {
+            month_end_days: 1,
+            month_end_times: vec![time("23:30")],
+            ..daily("22:00")
+        };
+        assert_eq!(
+            wake(&schedule, "2025-01-30T22:30:00"),
+            "2025-01-31T22:00:00+01:00"
+        );
+        assert_eq!(
+            wake(&schedule, "2025-01-31T22:30:00"),
+            "2025-01-31T23:30:00+01:00"
+        );
+        // The 31st of a 30 day month does not exist, the 30th is its last day
+        assert_eq!(
+            wake(&schedule, "2025-04-30T22:30:00"),
+            "2025-04-30T23:30:00+02:00"
+        );
+        assert_eq!(
+            wake(&schedule, "2025-02-28T22:30:00"),
+            "2025-02-28T23:30:00+01:00"
+        );
+        assert_eq!(
+            wake(&schedule, "2024-02-28T22:30:00"),
+            "2024-02-29T22:00:00+01:00"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        assert!(WakeSchedule::default().validate().is_ok());
+        let every = |hours| WakeSchedule {
+            rule: WakeRule::EveryHours { hours },
+            ..WakeSchedule::default()
+        };
+        assert!(every(0).validate().is_err());
+        assert!(every(25).validate().is_err());
+        let month_end = |days, times| WakeSchedule {
+            month_end_days: days,
+            month_end_times: times,
+            ..WakeSchedule::default()
+        };
+        assert!(month_end(29, vec![time("23:00")]).validate().is_err());
+        assert!(month_end(2, vec![]).validate().is_err());
+        assert!(month_end(2, vec![time("23:00")]).validate().is_ok());
+    }
+}
@@ -0,0 +1,1706 @@
+use chrono::{DateTime, Local, NaiveDateTime, Utc};
+use digit_protocol::{
+    CheckinRequest, DeviceConfig, Diagnostics, HealthRequest, Timestamp, UploadMetadata, hex,
+};
+use rusqlite::{Connection, OptionalExtension, params};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::firmware::{DEFAULT_GROUP, FirmwareRelease};
+use crate::meter::{DEFAULT_METER, Meter, MeterError};
+use crate::recognition::Reading;
+use crate::schedule::WakeSchedule;
+
+/// Name of the database file inside the data directory.
+const DATABASE_FILE: &str = "digit-logger.db";
+/// Name of the CSV file the battery levels were logged to before the database existed.
+const LEGACY_HEALTH_LOG: &str = "health.log";
+/// Format of the capture time in the photo names given by the device.
+const CAPTURE_TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+/// Device that uploads and health samples are attributed to until devices identify themselves.
+pub const DEFAULT_DEVICE: &str = "espcam";
+
+/// Schema migrations, the n-th entry upgrades the database from version n to n + 1.
+const MIGRATIONS: &[&str] = &[
+    "
+    CREATE TABLE devices (
+        id TEXT PRIMARY KEY,
+        created_at TEXT NOT NULL
+    );
+    CREATE TABLE uploads (
+        id INTEGER PRIMARY KEY,
+        device_id TEXT NOT NULL REFERENCES devices(id),
+        file_name TEXT NOT NULL UNIQUE,
+        captured_at TEXT,
+        received_at TEXT NOT NULL,
+        size INTEGER NOT NULL
+    );
+    CREATE TABLE health_samples (
+        id INTEGER PRIMARY KEY,
+        device_id TEXT NOT NULL REFERENCES devices(id),
+        timestamp TEXT NOT NULL,
+        received_at TEXT NOT NULL,
+        voltage REAL NOT NULL
+    );
+    CREATE TABLE readings (
+        id INTEGER PRIMARY KEY,
+        upload_id INTEGER NOT NULL UNIQUE REFERENCES uploads(id),
+        value TEXT NOT NULL,
+        confidence REAL NOT NULL,
+        digits TEXT NOT NULL,
+        recognized_at TEXT NOT NULL
+    );
+    CREATE INDEX uploads_captured_at ON uploads(captured_at);
+",
+    "
+    ALTER TABLE uploads ADD COLUMN sha256 TEXT;
+    CREATE UNIQUE INDEX uploads_sha256 ON uploads(sha256);
+",
+    "
+    ALTER TABLE uploads ADD COLUMN pruned_at TEXT;
+",
+    "
+    CREATE TABLE device_keys (
+        device_id TEXT PRIMARY KEY REFERENCES devices(id),
+        key TEXT NOT NULL,
+        created_at TEXT NOT NULL
+    );
+",
+    "
+    ALTER TABLE uploads ADD COLUMN firmware_version TEXT;
+    ALTER TABLE health_samples ADD COLUMN firmware_version TEXT;
+",
+    "
+    ALTER TABLE devices ADD COLUMN config TEXT;
+",
+    "
+    ALTER TABLE devices ADD COLUMN schedule TEXT;
+",
+    "
+    ALTER TABLE health_samples ADD COLUMN state_of_charge REAL;
+",
+    "
+    ALTER TABLE health_samples ADD COLUMN wifi_rssi INTEGER;
+    ALTER TABLE health_samples ADD COLUMN wifi_channel INTEGER;
+    ALTER TABLE health_samples ADD COLUMN wifi_bssid TEXT;
+    ALTER TABLE health_samples ADD COLUMN reset_reason TEXT;
+    ALTER TABLE health_samples ADD COLUMN wake_reason TEXT;
+    ALTER TABLE health_samples ADD COLUMN uptime_ms INTEGER;
+    ALTER TABLE health_samples ADD COLUMN associate_ms INTEGER;
+    ALTER TABLE health_samples ADD COLUMN dhcp_ms INTEGER;
+    ALTER TABLE health_samples ADD COLUMN sntp_ms INTEGER;
+    ALTER TABLE health_samples ADD COLUMN free_heap INTEGER;
+    ALTER TABLE health_samples ADD COLUMN free_psram INTEGER;
+    ALTER TABLE health_samples ADD COLUMN chip_temperature REAL;
+    ALTER TABLE health_samples ADD COLUMN build_hash TEXT;
+    ALTER TABLE health_samples ADD COLUMN previous_capture_ms INTEGER;
+    ALTER TABLE health_samples ADD COLUMN previous_upload_ms INTEGER;
+",
+    "
+    ALTER TABLE uploads ADD COLUMN recognition_error TEXT;
+",
+    "
+    ALTER TABLE devices ADD COLUMN firmware_group TEXT;
+    CREATE TABLE firmware_releases (
+        id INTEGER PRIMARY KEY,
+        device_group TEXT NOT NULL,
+        version TEXT NOT NULL,
+        file_name TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        sha256 TEXT NOT NULL,
+        signature TEXT NOT NULL,
+        created_at TEXT NOT NULL,
+        UNIQUE (device_group, version)
+    );
+",
+    // The default meter is kept in line with the configuration by `save_meter` on start
+    "
+    CREATE TABLE meters (
+        id TEXT PRIMARY KEY,
+        kind TEXT NOT NULL,
+        unit TEXT NOT NULL,
+        digits INTEGER NOT NULL,
+        decimals INTEGER NOT NULL
+    );
+    INSERT INTO meters (id, kind, unit, digits, decimals) VALUES ('default', 'gas', 'm³', 8, 3);
+    ALTER TABLE devices ADD COLUMN meter_id TEXT REFERENCES meters(id);
+    ALTER TABLE uploads ADD COLUMN meter_id TEXT REFERENCES meters(id);
+    UPDATE uploads SET meter_id = 'default';
+",
+    "
+    ALTER TABLE uploads ADD COLUMN flash_brightness INTEGER;
+",
+    // A pruned photo may be uploaded again
+    "
+    DROP INDEX uploads_sha256;
+    CREATE UNIQUE INDEX uploads_sha256 ON uploads(sha256) WHERE pruned_at IS NULL;
+",
+    // Devices used to send 0 V when they could not measure the battery
+    "
+    ALTER TABLE health_samples ADD COLUMN measured_voltage REAL;
+    UPDATE health_samples SET measured_voltage = NULLIF(voltage, 0);
+    ALTER TABLE health_samples DROP COLUMN voltage;
+    ALTER TABLE health_samples RENAME COLUMN measured_voltage TO voltage;
+",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum StorageError {
+    #[error("invalid file name {0:?}")]
+    InvalidFileName(String),
+    #[error("database error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("could not (de)serialise: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("unknown meter {0}")]
+    UnknownMeter(String),
+    #[error(transparent)]
+    Meter(#[from] MeterError),
+}
+
+/// A reading together with the time its photo was taken.
+#[derive(Debug, Clone)]
+pub struct StoredReading {
+    pub captured_at: NaiveDateTime,
+    pub reading: Reading,
+}
+
+/// What a device reported about itself.
+#[derive(Debug, Clone)]
+pub struct HealthSample<'a> {
+    pub timestamp: Timestamp,
+    /// Battery voltage in volts, `None` if the device could not measure it.
+    pub voltage: Option<f32>,
+    /// Estimated state of charge of the battery in percent.
+    pub state_of_charge: Option<f32>,
+    pub firmware_version: Option<&'a str>,
+    pub diagnostics: Option<&'a Diagnostics>,
+}
+
+impl<'a> From<&'a HealthRequest> for HealthSample<'a> {
+    fn from(request: &'a HealthRequest) -> Self {
+        Self {
+            timestamp: request.timestamp,
+            voltage: request.voltage,
+            state_of_charge: request.state_of_charge,
+            firmware_version: Some(&request.firmware_version),
+            diagnostics: Some(&request.diagnostics),
+        }
+    }
+}
+
+impl<'a> From<&'a CheckinRequest> for HealthSample<'a> {
+    fn from(request: &'a CheckinRequest) -> Self {
+        Self {
+            timestamp: request.timestamp,
+            voltage: request.voltage,
+            state_of_charge: request.state_of_charge,
+            firmware_version: Some(&request.firmware_version),
+            diagnostics: Some(&request.diagnostics),
+        }
+    }
+}
+
+/// A photo stored in the data directory.
+#[derive(Debug, Clone)]
+pub struct StoredUpload {
+    pub id: i64,
+    pub file_name: String,
+    /// The meter the device was on when it took the photo.
+    pub meter_id: String,
+    /// The same photo had already been uploaded, nothing new was stored.
+    pub duplicate: bool,
+}
+
+/// A photo still in the data directory, with its reading once it has been recognised.
+#[derive(Debug, Clone)]
+pub struct Photo {
+    pub file_name: String,
+    pub device_id: String,
+    pub captured_at: Option<NaiveDateTime>,
+    pub received_at: DateTime<Local>,
+    pub reading: Option<Reading>,
+    /// Why the photo could not be read.
+    pub recognition_error: Option<String>,
+    /// Brightness of the flash LED in percent, if the device told.
+    pub flash_brightness: Option<u8>,
+}
+
+/// How well the photos of a device taken at one flash brightness were read.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlashRecognition {
+    /// Brightness of the flash LED in percent, 0 for photos taken without it.
+    pub brightness: u8,
+    /// Photos the recognition is done with.
+    pub photos: u32,
+    /// Photos that could be read.
+    pub read: u32,
+    /// Mean confidence of the readings, `None` if none could be read.
+    pub mean_confidence: Option<f32>,
+}
+
+/// What a device last reported about itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceDiagnostics {
+    pub received_at: DateTime<Local>,
+    pub firmware_version: Option<String>,
+    pub diagnostics: Diagnostics,
+}
+
+/// What a device sent along with a photo.
+#[derive(Debug, Clone, Copy, Default)]
+struct PhotoDetails<'a> {
+    captured_at: Option<NaiveDateTime>,
+    firmware_version: Option<&'a str>,
+    flash_brightness: Option<u8>,
+}
+
+/// Keeps track of devices, uploaded photos, health samples and meter readings. The photos
+/// themselves are files in the data directory, everything else lives in an SQLite database
+/// next to them.
+pub struct Storage {
+    connection: Mutex<Connection>,
+    data_directory: PathBuf,
+}
+
+impl Storage {
+    /// Opens the database in `data_directory`, creating and migrating it when needed.
+    pub fn open(data_directory: &Path) -> Result<Self, StorageError> {
+        std::fs::create_dir_all(data_directory)?;
+        let connection = Connection::open(data_directory.join(DATABASE_FILE))?;
+        connection.pragma_update(None, "foreign_keys", true)?;
+
+        let storage = Self {
+            connection: Mutex::new(connection),
+            data_directory: data_directory.to_path_buf(),
+        };
+        storage.migrate()?;
+        Ok(storage)
+    }
+
+    pub fn data_directory(&self) -> &Path {
+        &self.data_directory
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        // A panic while holding the lock cannot leave SQLite in an inconsistent state
+        self.connection
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Applies the pending migrations. A new database also gets the files written before the
+    /// database existed, in the same transaction, so a failed import is tried again on the
+    /// next start rather than left half done.
+    fn migrate(&self) -> Result<(), StorageError> {
+        let mut connection = self.connection();
+        let version: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+
+        let transaction = connection.transaction()?;
+        for (version, migration) in (1..).zip(MIGRATIONS).skip(version as usize) {
+            log::info!("Migrating database to version {version}");
+            transaction.execute_batch(migration)?;
+            transaction.pragma_update(None, "user_version", version)?;
+        }
+        if version == 0 {
+            Self::import_legacy_files(&transaction, &self.data_directory)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Imports the health log, photos and readings written before the database existed.
+    fn import_legacy_files(
+        connection: &Connection,
+        data_directory: &Path,
+    ) -> Result<(), StorageError> {
+        let health_log = data_directory.join(LEGACY_HEALTH_LOG);
+        if health_log.exists() {
+            let mut count = 0;
+            for line in std::fs::read_to_string(&health_log)?.lines() {
+                let Some((timestamp, voltage)) = line.split_once(',') else {
+                    continue;
+                };
+                let (Ok(timestamp), Ok(voltage)) = (
+                    NaiveDateTime::parse_from_str(timestamp.trim(), CAPTURE_TIME_FORMAT),
+                    voltage.trim().parse(),
+                ) else {
+                    log::warn!("Skipping malformed health log line: {line}");
+                    continue;
+                };
+                let sample = HealthSample {
+                    // The camera used to keep its clock in UTC without saying so
+                    timestamp: timestamp.and_utc().fixed_offset(),
+                    voltage: Some(voltage),
+                    state_of_charge: None,
+                    firmware_version: None,
+                    diagnostics: None,
+                };
+                Self::insert_health_sample(connection, DEFAULT_DEVICE, &sample)?;
+                count += 1;
+            }
+            log::info!(
+                "Imported {count} health samples from {}",
+                health_log.display()
+            );
+        }
+
+        let mut count = 0;
+        for entry in std::fs::read_dir(data_directory)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|e| e != "jpg" && e != "jpeg") {
+                continue;
+            }
+            let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let image = std::fs::read(&path)?;
+            let hash = sha256(&image);
+            if Self::find_upload(connection, &hash)?.is_some() {
+                log::info!("Skipping {file_name}, it is a duplicate of an earlier photo");
+                continue;
+            }
+            let (upload_id, _) = Self::insert_upload(
+                connection,
+                DEFAULT_DEVICE,
+                file_name,
+                &PhotoDetails {
+                    captured_at: parse_capture_time(file_name),
+                    ..PhotoDetails::default()
+                },
+                &hash,
+                image.len(),
+            )?;
+            count += 1;
+
+            // Readings used to be stored as JSON next to the photo
+            let reading_path = path.with_extension("json");
+            if reading_path.exists() {
+                match serde_json::from_slice::<Reading>(&std::fs::read(&reading_path)?) {
+                    Ok(reading) => Self::insert_reading(connection, upload_id, &reading)?,
+                    Err(e) => log::warn!("Skipping reading {}: {e}", reading_path.display()),
+                }
+            }
+        }
+        log::info!("Imported {count} photos");
+        Ok(())
+    }
+
+    fn ensure_device(connection: &Connection, device_id: &str) -> Result<(), StorageError> {
+        connection.execute(
+            "INSERT OR IGNORE INTO devices (id, created_at) VALUES (?1, ?2)",
+            params![device_id, Local::now()],
+        )?;
+        Ok(())
+    }
+
+    /// Sets the hex encoded key `device_id` signs its requests with.
+    pub fn set_device_key(&self, device_id: &str, key: &str) -> Result<(), StorageError> {
+        let connection = self.connection();
+        Self::ensure_device(&connection, device_id)?;
+        connection.execute(
+            "INSERT OR REPLACE INTO device_keys (device_id, key, created_at) VALUES (?1, ?2, ?3)",
+            params![device_id, key, Local::now()],
+        )?;
+        Ok(())
+    }
+
+    pub fn device_key(&self, device_id: &str) -> Result<Option<String>, StorageError> {
+        Ok(self
+            .connection()
+            .query_row(
+                "SELECT key FROM device_keys WHERE device_id = ?1",
+                params![device_id],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    /// Devices that have a key, with the time the key was created.
+    pub fn device_keys(&self) -> Result<Vec<(String, DateTime<Local>)>, StorageError> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare("SELECT device_id, created_at FROM device_keys ORDER BY device_id")?;
+        let keys = statement
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+        Ok(keys)
+    }
+
+    /// Removes the key of `device_id` and returns whether it had one.
+    pub fn remove_device_key(&self, device_id: &str) -> Result<bool, StorageError> {
+        let removed = self.connection().execute(
+            "DELETE FROM device_keys WHERE device_id = ?1",
+            params![device_id],
+        )?;
+        Ok(removed > 0)
+    }
+
+    /// Replaces the settings `device_id` is asked to use with `config`, under the next revision.
+    /// Returns the stored configuration.
+    pub fn set_device_config(
+        &self,
+        device_id: &str,
+        config: DeviceConfig,
+    ) -> Result<DeviceConfig, StorageError> {
+        let connection = self.connection();
+        Self::ensure_device(&connection, device_id)?;
+        let revision =
+            Self::device_config(&connection, device_id)?.map_or(0, |current| current.revision);
+        let config = DeviceConfig {
+            revision: revision + 1,
+            ..config
+        };
+        connection.execute(
+            "UPDATE devices SET config = ?1 WHERE id = ?2",
+            params![serde_json::to_string(&config)?, device_id],
+        )?;
+        Ok(config)
+    }
+
+    /// Settings of `device_id` if they are newer than the `revision` the device runs with.
+    pub fn pending_config(
+        &self,
+        device_id: &str,
+        revision: u32,
+    ) -> Result<Option<DeviceConfig>, StorageError> {
+        Ok(Self::device_config(&self.connection(), device_id)?
+            .filter(|config| config.revision > revision))
+    }
+
+    fn device_config(
+        connection: &Connection,
+        device_id: &str,
+    ) -> Result<Option<DeviceConfig>, StorageError> {
+        let config: Option<String> = connection
+            .query_row(
+                "SELECT config FROM devices WHERE id = ?1",
+                params![device_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        Ok(config.map(|c| serde_json::from_str(&c)).transpose()?)
+    }
+
+    pub fn set_device_schedule(
+        &self,
+        device_id: &str,
+        schedule: &WakeSchedule,
+    ) -> Result<(), StorageError> {
+        let connection = self.connection();
+        Self::ensure_device(&connection, device_id)?;
+        connection.execute(
+            "UPDATE devices SET schedule = ?1 WHERE id = ?2",
+            params![serde_json::to_string(schedule)?, device_id],
+        )?;
+        Ok(())
+    }
+
+    /// The wake schedule of `device_id`, the default one if it has none of its own.
+    pub fn device_schedule(&self, device_id: &str) -> Result<WakeSchedule, StorageError> {
+        let schedule: Option<String> = self
+            .connection()
+            .query_row(
+                "SELECT schedule FROM devices WHERE id = ?1",
+                params![device_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        Ok(schedule
+            .map(|s| serde_json::from_str(&s))
+            .transpose()?
+            .unwrap_or_default())
+    }
+
+    /// Puts `device_id` into the firmware group `group`.
+    pub fn set_device_group(&self, device_id: &str, group: &str) -> Result<(), StorageError> {
+        let connection = self.connection();
+        Self::ensure_device(&connection, device_id)?;
+        connection.execute(
+            "UPDATE devices SET firmware_group = ?1 WHERE id = ?2",
+            params![group, device_id],
+        )?;
+        Ok(())
+    }
+
+    /// The firmware group of `device_id`, [`DEFAULT_GROUP`] if it was not put into any.
+    pub fn device_group(&self, device_id: &str) -> Result<String, StorageError> {
+        let group: Option<String> = self
+            .connection()
+            .query_row(
+                "SELECT firmware_group FROM devices WHERE id = ?1",
+                params![device_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        Ok(group.unwrap_or_else(|| DEFAULT_GROUP.to_string()))
+    }
+
+    /// Adds `meter`, or replaces the meter with its id.
+    pub fn save_meter(&self, meter: &Meter) -> Result<(), StorageError> {
+        self.connection().execute(
+            "INSERT INTO meters (id, kind, unit, digits, decimals) VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT (id) DO UPDATE
+             SET kind = ?2, unit = ?3, digits = ?4, decimals = ?5",
+            params![
+                meter.id,
+                meter.kind.as_str(),
+                meter.unit,
+                meter.digits as i64,
+                meter.decimals as i64
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn meters(&self) -> Result<Vec<Meter>, StorageError> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare("SELECT id, kind, unit, digits, decimals FROM meters ORDER BY id")?;
+        let rows = statement
+            .query_map([], meter_row)?
+            .collect::<Result<Vec<_>, _>>()?;
+        rows.into_iter().map(meter_from_row).collect()
+    }
+
+    pub fn meter(&self, meter_id: &str) -> Result<Option<Meter>, StorageError> {
+        Self::find_meter(&self.connection(), meter_id)
+    }
+
+    fn find_meter(connection: &Connection, meter_id: &str) -> Result<Option<Meter>, StorageError> {
+        connection
+            .query_row(
+                "SELECT id, kind, unit, digits, decimals FROM meters WHERE id = ?1",
+                params![meter_id],
+                meter_row,
+            )
+            .optional()?
+            .map(meter_from_row)
+            .transpose()
+    }
+
+    /// Puts `device_id` on the meter `meter_id`, its photos are read as that meter's from
+    /// now on. The photos it took before stay with the meter they were taken of.
+    pub fn set_device_meter(&self, device_id: &str, meter_id: &str) -> Result<(), StorageError> {
+        let connection = self.connection();
+        if Self::find_meter(&connection, meter_id)?.is_none() {
+            return Err(StorageError::UnknownMeter(meter_id.to_string()));
+        }
+        Self::ensure_device(&connection, device_id)?;
+        connection.execute(
+            "UPDATE devices SET meter_id = ?1 WHERE id = ?2",
+            params![meter_id, device_id],
+        )?;
+        Ok(())
+    }
+
+    /// The meter `device_id` is on, the default meter if it was not put on any.
+    pub fn device_meter(&self, device_id: &str) -> Result<Meter, StorageError> {
+        let connection = self.connection();
+        let meter_id = Self::device_meter_id(&connection, device_id)?;
+        Self::find_meter(&connection, &meter_id)?.ok_or(StorageError::UnknownMeter(meter_id))
+    }
+
+    fn device_meter_id(connection: &Connection, device_id: &str) -> Result<String, StorageError> {
+        let meter_id: Option<String> = connection
+            .query_row(
+                "SELECT meter_id FROM devices WHERE id = ?1",
+                params![device_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .flatten();
+        Ok(meter_id.unwrap_or_else(|| DEFAULT_METER.to_string()))
+    }
+
+    /// Writes a firmware image to `firmware/<group>/<version>.bin` in the data directory and
+    /// records it. The version and the signature have been checked by the caller.
+    pub fn add_firmware_release(
+        &self,
+        group: &str,
+        version: &str,
+        image: &[u8],
+        sha256: &str,
+        signature: &str,
+    ) -> Result<FirmwareRelease, StorageError> {
+        let connection = self.connection();
+        let file_name = format!("firmware/{group}/{version}.bin");
+        let path = self.data_directory.join(&file_name);
+        if let Some(directory) = path.parent() {
+            std::fs::create_dir_all(directory)?;
+        }
+        // A file left over from a release that was removed from the database is replaced
+        std::fs::write(&path, image)?;
+
+        let release = FirmwareRelease {
+            group: group.to_string(),
+            version: version.to_string(),
+            file_name,
+            size: image.len() as u64,
+            sha256: sha256.to_string(),
+            signature: signature.to_string(),
+            created_at: Local::now(),
+        };
+        let inserted = connection.execute(
+            "INSERT INTO firmware_releases
+                 (device_group, version, file_name, size, sha256, signature, created_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                release.group,
+                release.version,
+                release.file_name,
+                release.size as i64,
+                release.sha256,
+                release.signature,
+                release.created_at
+            ],
+        );
+        if let Err(e) = inserted {
+            std::fs::remove_file(&path)?;
+            return Err(e.into());
+        }
+        Ok(release)
+    }
+
+    /// The firmware releases of `group`, or of all groups, oldest first.
+    pub fn firmware_releases(
+        &self,
+        group: Option<&str>,
+    ) -> Result<Vec<FirmwareRelease>, StorageError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT device_group, version, file_name, size, sha256, signature, created_at
+             FROM firmware_releases
+             WHERE ?1 IS NULL OR device_group = ?1
+             ORDER BY id",
+        )?;
+        let releases = statement
+            .query_map(params![group], |row| {
+                Ok(FirmwareRelease {
+                    group: row.get(0)?,
+                    version: row.get(1)?,
+                    file_name: row.get(2)?,
+                    size: row.get::<_, i64>(3)? as u64,
+                    sha256: row.get(4)?,
+                    signature: row.get(5)?,
+                    created_at: row.get(6)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(releases)
+    }
+
+    pub fn firmware_release(
+        &self,
+        group: &str,
+        version: &str,
+    ) -> Result<Option<FirmwareRelease>, StorageError> {
+        Ok(self
+            .firmware_releases(Some(group))?
+            .into_iter()
+            .find(|release| release.version == version))
+    }
+
+    /// Removes a firmware release and its image, returning whether there was one.
+    pub fn remove_firmware_release(
+        &self,
+        group: &str,
+        version: &str,
+    ) -> Result<bool, StorageError> {
+        let Some(release) = self.firmware_release(group, version)? else {
+            return Ok(false);
+        };
+        self.connection().execute(
+            "DELETE FROM firmware_releases WHERE device_group = ?1 AND version = ?2",
+            params![group, version],
+        )?;
+        match std::fs::remove_file(self.data_directory.join(&release.file_name)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(true),
+        }
+    }
+
+    pub fn add_health_sample(
+        &self,
+        device_id: &str,
+        sample: &HealthSample,
+    ) -> Result<(), StorageError> {
+        Self::insert_health_sample(&self.connection(), device_id, sample)
+    }
+
+    /// Records a sample unless the device sent one with the same timestamp before, as it does
+    /// when it retries a request whose answer it never got.
+    fn insert_health_sample(
+        connection: &Connection,
+        device_id: &str,
+        sample: &HealthSample,
+    ) -> Result<(), StorageError> {
+        Self::ensure_device(connection, device_id)?;
+        let recorded: bool = connection.query_row(
+            "SELECT EXISTS (SELECT 1 FROM health_samples WHERE device_id = ?1 AND timestamp = ?2)",
+            params![device_id, sample.timestamp],
+            |row| row.get(0),
+        )?;
+        if recorded {
+            log::info!(
+                "Health sample of {device_id} at {} is already recorded",
+                sample.timestamp
+            );
+            return Ok(());
+        }
+        let empty = Diagnostics::default();
+        let diagnostics = sample.diagnostics.unwrap_or(&empty);
+        connection.execute(
+            "INSERT INTO health_samples
+                 (device_id, timestamp, received_at, voltage, state_of_charge, firmware_version,
+                  wifi_rssi, wifi_channel, wifi_bssid, reset_reason, wake_reason, uptime_ms,
+                  associate_ms, dhcp_ms, sntp_ms, free_heap, free_psram, chip_temperature,
+                  build_hash, previous_capture_ms, previous_upload_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+                     ?18, ?19, ?20, ?21)",
+            params![
+                device_id,
+                sample.timestamp,
+                Local::now(),
+                sample.voltage,
+                sample.state_of_charge,
+                sample.firmware_version,
+                diagnostics.wifi_rssi,
+                diagnostics.wifi_channel,
+                diagnostics.wifi_bssid,
+                diagnostics.reset_reason,
+                diagnostics.wake_reason,
+                diagnostics.uptime_ms.map(|ms| ms as i64),
+                diagnostics.associate_ms,
+                diagnostics.dhcp_ms,
+                diagnostics.sntp_ms,
+                diagnostics.free_heap,
+                diagnostics.free_psram,
+                diagnostics.chip_temperature,
+                diagnostics.build_hash,
+                diagnostics.previous_capture_ms,
+                diagnostics.previous_upload_ms,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Stores a photo uploaded by `device_id` under a name derived from its content, the device
+    /// and the capture time. The capture time is taken from the `metadata` sent along with the
+    /// photo, or failing that from `client_name`, the name the device gave the photo, which is
+    /// validated but otherwise never used on the file system.
+    /// A photo that has been uploaded before is not stored again.
+    pub fn store_upload(
+        &self,
+        device_id: &str,
+        client_name: Option<&str>,
+        metadata: Option<&UploadMetadata>,
+        image: &[u8],
+    ) -> Result<StoredUpload, StorageError> {
+        // Capture times are kept in the device's local time, like the names have always been
+        let details = metadata.map_or_else(PhotoDetails::default, |metadata| PhotoDetails {
+            captured_at: Some(metadata.captured_at.naive_local()),
+            firmware_version: Some(&metadata.firmware_version),
+            flash_brightness: metadata.flash_brightness,
+        });
+        self.store_photo(&self.connection(), device_id, client_name, details, image)
+    }
+
+    /// Stores the telemetry of a check-in together with its photo, if it has one, so that
+    /// either both or neither are recorded. The photo is stored like in [`Self::store_upload`].
+    pub fn store_checkin(
+        &self,
+        device_id: &str,
+        checkin: &CheckinRequest,
+        client_name: Option<&str>,
+        image: Option<&[u8]>,
+    ) -> Result<Option<StoredUpload>, StorageError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        Self::insert_health_sample(&transaction, device_id, &checkin.into())?;
+        let upload = match image {
+            Some(image) => Some(self.store_photo(
+                &transaction,
+                device_id,
+                client_name,
+                PhotoDetails {
+                    captured_at: checkin.captured_at.map(|t| t.naive_local()),
+                    firmware_version: Some(&checkin.firmware_version),
+                    flash_brightness: checkin.flash_brightness,
+                },
+                image,
+            )?),
+            None => None,
+        };
+
+        if let Err(e) = transaction.commit() {
+            if let Some(upload) = upload.as_ref().filter(|upload| !upload.duplicate) {
+                std::fs::remove_file(self.data_directory.join(&upload.file_name))?;
+            }
+            return Err(e.into());
+        }
+        Ok(upload)
+    }
+
+    /// Writes a photo to the data directory and records it. The capture time in `details`
+    /// takes precedence over the time in `client_name`.
+    fn store_photo(
+        &self,
+        connection: &Connection,
+        device_id: &str,
+        client_name: Option<&str>,
+        details: PhotoDetails,
+        image: &[u8],
+    ) -> Result<StoredUpload, StorageError> {
+        let named_at = match client_name {
+            Some(name) => {
+                validate_client_name(name)?;
+                parse_capture_time(name)
+            }
+            None => None,
+        };
+        let details = PhotoDetails {
+            captured_at: details.captured_at.or(named_at),
+            ..details
+        };
+
+        // The connection stays locked until the photo is recorded, so the same photo
+        // arriving twice at once cannot slip past this check
+        let hash = sha256(image);
+        if let Some(upload) = Self::find_upload(connection, &hash)? {
+            return Ok(upload);
+        }
+
+        let file_name = format!(
+            "{}_{}_{}.jpg",
+            sanitize(device_id),
+            details
+                .captured_at
+                .unwrap_or(Local::now().naive_local())
+                .format("%Y%m%dT%H%M%S"),
+            &hash[..16]
+        );
+        let path = self.data_directory.join(&file_name);
+        // Never overwrite an existing file, even if the database does not know about it
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+        {
+            Ok(mut file) => {
+                if let Err(e) = std::io::Write::write_all(&mut file, image) {
+                    let _ = std::fs::remove_file(&path);
+                    return Err(e.into());
+                }
+            }
+            // Left behind by a crash before the photo was recorded, it is the same photo
+            Err(e)
+                if e.kind() == std::io::ErrorKind::AlreadyExists
+                    && std::fs::read(&path).is_ok_and(|existing| sha256(&existing) == hash) =>
+            {
+                log::info!("Recording {file_name}, which was stored but not recorded before");
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        match Self::insert_upload(
+            connection,
+            device_id,
+            &file_name,
+            &details,
+            &hash,
+            image.len(),
+        ) {
+            Ok((id, meter_id)) => Ok(StoredUpload {
+                id,
+                file_name,
+                meter_id,
+                duplicate: false,
+            }),
+            Err(e) => {
+                std::fs::remove_file(&path)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// The earlier upload of the photo with the hash `sha256`, if it has not been pruned.
+    fn find_upload(
+        connection: &Connection,
+        sha256: &str,
+    ) -> Result<Option<StoredUpload>, StorageError> {
+        Ok(connection
+            .query_row(
+                "SELECT id, file_name, meter_id FROM uploads
+                 WHERE sha256 = ?1 AND pruned_at IS NULL",
+                params![sha256],
+                |row| {
+                    Ok(StoredUpload {
+                        id: row.get(0)?,
+                        file_name: row.get(1)?,
+                        meter_id: row.get(2)?,
+                        duplicate: true,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    /// Records a photo stored as `file_name` in the data directory as one of the meter the
+    /// device is on. Returns the id of the upload and the meter.
+    fn insert_upload(
+        connection: &Connection,
+        device_id: &str,
+        file_name: &str,
+        details: &PhotoDetails,
+        sha256: &str,
+        size: usize,
+    ) -> Result<(i64, String), StorageError> {
+        Self::ensure_device(connection, device_id)?;
+        let meter_id = Self::device_meter_id(connection, device_id)?;
+        // A photo that was pruned and uploaded again takes the place of its earlier upload
+        let id = connection.query_row(
+            "INSERT INTO uploads
+                 (device_id, meter_id, file_name, captured_at, received_at, size, sha256,
+                  firmware_version, flash_brightness)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+             ON CONFLICT (file_name) DO UPDATE SET
+                 device_id = excluded.device_id, meter_id = excluded.meter_id,
+                 captured_at = excluded.captured_at, received_at = excluded.received_at,
+                 size = excluded.size, sha256 = excluded.sha256,
+                 firmware_version = excluded.firmware_version,
+                 flash_brightness = excluded.flash_brightness,
+                 pruned_at = NULL, recognition_error = NULL
+             WHERE uploads.pruned_at IS NOT NULL
+             RETURNING id",
+            params![
+                device_id,
+                meter_id,
+                file_name,
+                details.captured_at,
+                Local::now(),
+                size as i64,
+                sha256,
+                details.firmware_version,
+                details.flash_brightness,
+            ],
+            |row| row.get(0),
+        )?;
+        Ok((id, meter_id))
+    }
+
+    pub fn add_reading(&self, upload_id: i64, reading: &Reading) -> Result<(), StorageError> {
+        Self::insert_reading(&self.connection(), upload_id, reading)
+    }
+
+    fn insert_reading(
+        connection: &Connection,
+        upload_id: i64,
+        reading: &Reading,
+    ) -> Result<(), StorageError> {
+        connection.execute(
+            "INSERT OR REPLACE INTO readings (upload_id, value, confidence, digits, recognized_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                upload_id,
+                reading.value,
+                reading.confidence(),
+                serde_json::to_string(&reading.digits)?,
+                reading.recognized_at,
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Records why the photo `upload_id` could not be read.
+    pub fn add_recognition_error(&self, upload_id: i64, error: &str) -> Result<(), StorageError> {
+        self.connection().execute(
+            "UPDATE uploads SET recognition_error = ?1 WHERE id = ?2",
+            params![error, upload_id],
+        )?;
+        Ok(())
+    }
+
+    /// Outcomes of the recognition of the latest `limit` photos of `device_id`, newest first:
+    /// the confidence of the reading, `None` if it failed. Photos still being read are left out.
+    pub fn recognitions(
+        &self,
+        device_id: &str,
+        limit: u32,
+    ) -> Result<Vec<Option<f32>>, StorageError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT readings.confidence
+             FROM uploads LEFT JOIN readings ON readings.upload_id = uploads.id
+             WHERE uploads.device_id = ?1
+               AND (readings.id IS NOT NULL OR uploads.recognition_error IS NOT NULL)
+             ORDER BY uploads.received_at DESC, uploads.id DESC
+             LIMIT ?2",
+        )?;
+        let outcomes = statement
+            .query_map(params![device_id, limit], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(outcomes)
+    }
+
+    /// How well the photos of `device_id` were read at every flash brightness it used, from
+    /// dark to bright. Photos still being read or without a known brightness are left out.
+    pub fn recognition_by_flash(
+        &self,
+        device_id: &str,
+    ) -> Result<Vec<FlashRecognition>, StorageError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT uploads.flash_brightness, COUNT(*), COUNT(readings.id),
+                    AVG(readings.confidence)
+             FROM uploads LEFT JOIN readings ON readings.upload_id = uploads.id
+             WHERE uploads.device_id = ?1 AND uploads.flash_brightness IS NOT NULL
+               AND (readings.id IS NOT NULL OR uploads.recognition_error IS NOT NULL)
+             GROUP BY uploads.flash_brightness
+             ORDER BY uploads.flash_brightness",
+        )?;
+        let recognitions = statement
+            .query_map(params![device_id], |row| {
+                Ok(FlashRecognition {
+                    brightness: row.get(0)?,
+                    photos: row.get(1)?,
+                    read: row.get(2)?,
+                    mean_confidence: row.get(3)?,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(recognitions)
+    }
+
+    pub fn device_ids(&self) -> Result<Vec<String>, StorageError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT id FROM devices ORDER BY id")?;
+        let ids = statement
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(ids)
+    }
+
+    /// Battery voltages `device_id` reported since `since`, oldest first, with the time they
+    /// were received.
+    pub fn voltages(
+        &self,
+        device_id: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<(DateTime<Local>, f32)>, StorageError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT received_at, voltage FROM health_samples
+             WHERE device_id = ?1 AND received_at >= ?2 AND voltage IS NOT NULL
+             ORDER BY received_at",
+        )?;
+        let voltages = statement
+            .query_map(params![device_id, since.with_timezone(&Local)], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<Result<_, _>>()?;
+        Ok(voltages)
+    }
+
+    /// When `device_id` last sent a health sample or a photo.
+    pub fn last_seen(&self, device_id: &str) -> Result<Option<DateTime<Local>>, StorageError> {
+        Ok(self.connection().query_row(
+            "SELECT MAX(received_at) FROM (
+                 SELECT received_at FROM health_samples WHERE device_id = ?1
+                 UNION ALL
+                 SELECT received_at FROM uploads WHERE device_id = ?1
+             )",
+            params![device_id],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// The diagnostics of the newest health sample of `device_id`.
+    pub fn latest_diagnostics(
+        &self,
+        device_id: &str,
+    ) -> Result<Option<DeviceDiagnostics>, StorageError> {
+        Ok(self
+            .connection()
+            .query_row(
+                "SELECT received_at, firmware_version, wifi_rssi, wifi_channel, wifi_bssid,
+                        reset_reason, wake_reason, uptime_ms, associate_ms, dhcp_ms, sntp_ms,
+                        free_heap, free_psram, chip_temperature, build_hash,
+                        previous_capture_ms, previous_upload_ms
+                 FROM health_samples WHERE device_id = ?1
+                 ORDER BY received_at DESC LIMIT 1",
+                params![device_id],
+                |row| {
+                    Ok(DeviceDiagnostics {
+                        received_at: row.get(0)?,
+                        firmware_version: row.get(1)?,
+                        diagnostics: Diagnostics {
+                            wifi_rssi: row.get(2)?,
+                            wifi_channel: row.get(3)?,
+                            wifi_bssid: row.get(4)?,
+                            reset_reason: row.get(5)?,
+                            wake_reason: row.get(6)?,
+                            uptime_ms: row.get::<_, Option<i64>>(7)?.map(|ms| ms as u64),
+                            associate_ms: row.get(8)?,
+                            dhcp_ms: row.get(9)?,
+                            sntp_ms: row.get(10)?,
+                            free_heap: row.get(11)?,
+                            free_psram: row.get(12)?,
+                            chip_temperature: row.get(13)?,
+                            build_hash: row.get(14)?,
+                            previous_capture_ms: row.get(15)?,
+                            previous_upload_ms: row.get(16)?,
+                        },
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    /// The newest photo of the meter `meter_id` that has not been deleted yet.
+    pub fn latest_photo(&self, meter_id: &str) -> Result<Option<Photo>, StorageError> {
+        let connection = self.connection();
+        let row = connection
+            .query_row(
+                "SELECT uploads.file_name, uploads.device_id, uploads.captured_at,
+                        uploads.received_at, uploads.recognition_error,
+                        uploads.flash_brightness, readings.value, readings.digits,
+                        readings.recognized_at
+                 FROM uploads LEFT JOIN readings ON readings.upload_id = uploads.id
+                 WHERE uploads.meter_id = ?1 AND uploads.pruned_at IS NULL
+                 ORDER BY uploads.received_at DESC, uploads.id DESC
+                 LIMIT 1",
+                params![meter_id],
+                |row| {
+                    Ok((
+                        Photo {
+                            file_name: row.get(0)?,
+                            device_id: row.get(1)?,
+                            captured_at: row.get(2)?,
+                            received_at: row.get(3)?,
+                            reading: None,
+                            recognition_error: row.get(4)?,
+                            flash_brightness: row.get(5)?,
+                        },
+                        row.get::<_, Option<String>>(6)?,
+                        row.get::<_, Option<String>>(7)?,
+                        row.get::<_, Option<DateTime<Local>>>(8)?,
+                    ))
+                },
+            )
+            .optional()?;
+
+        let Some((mut photo, value, digits, recognized_at)) = row else {
+            return Ok(None);
+        };
+        if let (Some(value), Some(digits), Some(recognized_at)) = (value, digits, recognized_at) {
+            photo.reading = Some(Reading {
+                value,
+                digits: serde_json::from_str(&digits)?,
+                recognized_at,
+            });
+        }
+        Ok(Some(photo))
+    }
+
+    /// Whether `file_name` is an uploaded photo that has not been deleted yet.
+    pub fn has_photo(&self, file_name: &str) -> Result<bool, StorageError> {
+        Ok(self.connection().query_row(
+            "SELECT EXISTS (SELECT 1 FROM uploads WHERE file_name = ?1 AND pruned_at IS NULL)",
+            params![file_name],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Deletes the photos received before `before`. Their readings and upload records are kept
+    /// for the reports. Returns the number of deleted photos.
+    pub fn prune_uploads(&self, before: DateTime<Local>) -> Result<usize, StorageError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT id, file_name FROM uploads WHERE pruned_at IS NULL AND received_at < ?1",
+        )?;
+        let uploads = statement
+            .query_map(params![before], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for (id, file_name) in &uploads {
+            match std::fs::remove_file(self.data_directory.join(file_name)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            connection.execute(
+                "UPDATE uploads SET pruned_at = ?1 WHERE id = ?2",
+                params![Local::now(), id],
+            )?;
+        }
+        Ok(uploads.len())
+    }
+
+    /// Time the first reading of the meter `meter_id` was taken at.
+    pub fn first_reading_at(&self, meter_id: &str) -> Result<Option<NaiveDateTime>, StorageError> {
+        let connection = self.connection();
+        let row = connection
+            .query_row(
+                "SELECT uploads.captured_at, readings.recognized_at
+                 FROM readings JOIN uploads ON uploads.id = readings.upload_id
+                 WHERE uploads.meter_id = ?1
+                 ORDER BY COALESCE(uploads.captured_at, readings.recognized_at)
+                 LIMIT 1",
+                params![meter_id],
+                |row| {
+                    Ok((
+                        row.get::<_, Option<NaiveDateTime>>(0)?,
+                        row.get::<_, DateTime<Local>>(1)?,
+                    ))
+                },
+            )
+            .optional()?;
+        Ok(row
+            .map(|(captured_at, recognized_at)| captured_at.unwrap_or(recognized_at.naive_local())))
+    }
+
+    /// Readings of the meter `meter_id` from photos taken in `from..to`, oldest first.
+    /// Photos with an unknown capture time are placed at the time they were recognised.
+    pub fn readings(
+        &self,
+        meter_id: &str,
+        from: NaiveDateTime,
+        to: NaiveDateTime,
+    ) -> Result<Vec<StoredReading>, StorageError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT uploads.captured_at, readings.value, readings.digits, readings.recognized_at
+             FROM readings JOIN uploads ON uploads.id = readings.upload_id
+             WHERE uploads.meter_id = ?1
+               AND COALESCE(uploads.captured_at, readings.recognized_at) >= ?2
+               AND COALESCE(uploads.captured_at, readings.recognized_at) < ?3
+             ORDER BY COALESCE(uploads.captured_at, readings.recognized_at)",
+        )?;
+        let rows = statement.query_map(params![meter_id, from, to], |row| {
+            Ok((
+                row.get::<_, Option<NaiveDateTime>>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, DateTime<Local>>(3)?,
+            ))
+        })?;
+
+        let mut readings = Vec::new();
+        for row in rows {
+            let (captured_at, value, digits, recognized_at) = row?;
+            readings.push(StoredReading {
+                captured_at: captured_at.unwrap_or(recognized_at.naive_local()),
+                reading: Reading {
+                    value,
+                    digits: serde_json::from_str(&digits)?,
+                    recognized_at,
+                },
+            });
+        }
+        Ok(readings)
+    }
+}
+
+type MeterRow = (String, String, String, i64, i64);
+
+fn meter_row(row: &rusqlite::Row) -> rusqlite::Result<MeterRow> {
+    Ok((
+        row.get(0)?,
+        row.get(1)?,
+        row.get(2)?,
+        row.get(3)?,
+        row.get(4)?,
+    ))
+}
+
+fn meter_from_row((id, kind, unit, digits, decimals): MeterRow) -> Result<Meter, StorageError> {
+    Ok(Meter {
+        id,
+        kind: kind.parse()?,
+        unit,
+        digits: digits as usize,
+        decimals: decimals as usize,
+    })
+}
+
+/// Rejects names that could point outside the data directory or confuse the file system.
+fn validate_client_name(name: &str) -> Result<(), StorageError> {
+    let valid = !name.is_empty()
+        && name.len() <= 255
+        && name != "."
+        && !name.contains("..")
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':' | '+'));
+    if valid {
+        Ok(())
+    } else {
+        Err(StorageError::InvalidFileName(name.to_string()))
+    }
+}
+
+/// Replaces everything but ASCII letters, digits, `-` and `_` with `-`.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect()
+}
+
+/// Parses the capture time from a photo name in the device's format, e.g. `2025-07-01T22:00:00.jpg`.
+fn parse_capture_time(name: &str) -> Option<NaiveDateTime> {
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    NaiveDateTime::parse_from_str(stem, CAPTURE_TIME_FORMAT).ok()
+}
+
+fn sha256(data: &[u8]) -> String {
+    hex::encode(&Sha256::digest(data))
+}
+
+/// Deletes the photos older than `retention_days` once a day, forever.
+pub async fn enforce_retention(storage: Arc<Storage>, retention_days: u32) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(24 * 60 * 60));
+    loop {
+        interval.tick().await;
+        let before = Local::now() - chrono::Days::new(retention_days.into());
+        let storage = storage.clone();
+        match tokio::task::spawn_blocking(move || storage.prune_uploads(before)).await {
+            Ok(Ok(0)) => {}
+            Ok(Ok(count)) => log::info!("Deleted {count} photos older than {retention_days} days"),
+            Ok(Err(e)) => log::error!("Failed to delete old photos: {e}"),
+            Err(e) => log::error!("Retention task failed: {e}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use digit_protocol::PROTOCOL_VERSION;
+
+    /// A data directory of its own, deleted on drop.
+    struct TestDirectory(PathBuf);
+
+    impl TestDirectory {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("digit-storage-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn write(&self, name: &str, contents: &[u8]) {
+            std::fs::write(self.0.join(name), contents).unwrap();
+        }
+    }
+
+    impl Drop for TestDirectory {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn count(storage: &Storage, table: &str) -> i64 {
+        storage
+            .connection()
+            .query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| {
+                row.get(0)
+            })
+            .unwrap()
+    }
+
+    fn user_version(storage: &Storage) -> usize {
+        storage
+            .connection()
+            .pragma_query_value(None, "user_version", |row| row.get::<_, i64>(0))
+            .unwrap() as usize
+    }
+
+    #[test]
+    fn migrates_new_database_to_latest_version() {
+        let directory = TestDirectory::new("migrate");
+        let storage = Storage::open(&directory.0).unwrap();
+        assert_eq!(user_version(&storage), MIGRATIONS.len());
+        assert!(storage.meter(DEFAULT_METER).unwrap().is_some());
+        drop(storage);
+
+        // Opening it again has nothing left to do
+        let storage = Storage::open(&directory.0).unwrap();
+        assert_eq!(user_version(&storage), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn imports_legacy_files_once() {
+        let directory = TestDirectory::new("import");
+        directory.write(
+            LEGACY_HEALTH_LOG,
+            b"2025-07-01T22:00:00,3.91\nnot a sample\n2025-07-02T22:00:00,3.88\n",
+        );
+        directory.write("2025-07-01T22:00:00.jpg", b"first photo");
+        directory.write(
+            "2025-07-01T22:00:00.json",
+            br#"{"value":"00012345","digits":[],"recognized_at":"2025-07-01T22:00:05+00:00"}"#,
+        );
+        directory.write("2025-07-02T22:00:00.jpg", b"second photo");
+        directory.write("copy.jpg", b"second photo");
+
+        let storage = Storage::open(&directory.0).unwrap();
+        assert_eq!(count(&storage, "health_samples"), 2);
+        assert_eq!(count(&storage, "uploads"), 2);
+        assert_eq!(count(&storage, "readings"), 1);
+        let captured_at: NaiveDateTime = storage
+            .connection()
+            .query_row(
+                "SELECT captured_at FROM uploads WHERE file_name = '2025-07-01T22:00:00.jpg'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(captured_at, "2025-07-01T22:00:00".parse().unwrap());
+        drop(storage);
+
+        let storage = Storage::open(&directory.0).unwrap();
+        assert_eq!(count(&storage, "health_samples"), 2);
+        assert_eq!(count(&storage, "uploads"), 2);
+    }
+
+    #[test]
+    fn failed_import_is_tried_again() {
+        let directory = TestDirectory::new("failed-import");
+        directory.write(LEGACY_HEALTH_LOG, b"2025-07-01T22:00:00,3.91\n");
+        // A directory cannot be read like a photo
+        std::fs::create_dir(directory.0.join("broken.jpg")).unwrap();
+        assert!(Storage::open(&directory.0).is_err());
+
+        std::fs::remove_dir(directory.0.join("broken.jpg")).unwrap();
+        let storage = Storage::open(&directory.0).unwrap();
+        assert_eq!(user_version(&storage), MIGRATIONS.len());
+        assert_eq!(count(&storage, "health_samples"), 1);
+    }
+
+    fn files(directory: &TestDirectory) -> Vec<String> {
+        let mut files: Vec<String> = std::fs::read_dir(&directory.0)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| !name.starts_with(DATABASE_FILE))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn rejects_hostile_file_names() {
+        let directory = TestDirectory::new("hostile-names");
+        let storage = Storage::open(&directory.0).unwrap();
+        let long = format!("{}.jpg", "a".repeat(300));
+        let names = [
+            "../2025-07-01T22:00:00.jpg",
+            "..",
+            ".",
+            "photos/../../etc/passwd",
+            "/etc/passwd",
+            "/tmp/2025-07-01T22:00:00.jpg",
+            "C:\\photo.jpg",
+            "photo\0.jpg",
+            "photo.jpg\0.png",
+            "",
+            &long,
+        ];
+        for name in names {
+            assert!(
+                matches!(
+                    storage.store_upload(DEFAULT_DEVICE, Some(name), None, b"photo"),
+                    Err(StorageError::InvalidFileName(_))
+                ),
+                "{name:?}"
+            );
+        }
+        assert!(files(&directory).is_empty());
+        assert_eq!(count(&storage, "uploads"), 0);
+
+        // The name the device gives is only read for the capture time
+        let upload = storage
+            .store_upload(
+                DEFAULT_DEVICE,
+                Some("2025-07-01T22:00:00.jpg"),
+                None,
+                b"photo",
+            )
+            .unwrap();
+        assert!(upload.file_name.starts_with("espcam_20250701T220000_"));
+        assert_eq!(files(&directory), [upload.file_name]);
+    }
+
+    #[test]
+    fn stores_the_same_photo_once() {
+        let directory = TestDirectory::new("duplicate");
+        let storage = Storage::open(&directory.0).unwrap();
+        let first = storage
+            .store_upload(DEFAULT_DEVICE, None, None, b"photo")
+            .unwrap();
+        let second = storage
+            .store_upload(DEFAULT_DEVICE, None, None, b"photo")
+            .unwrap();
+        assert!(!first.duplicate);
+        assert!(second.duplicate);
+        assert_eq!((first.id, first.file_name), (second.id, second.file_name));
+        assert_eq!(count(&storage, "uploads"), 1);
+    }
+
+    #[test]
+    fn records_photo_left_behind_by_a_crash() {
+        let directory = TestDirectory::new("orphan");
+        let storage = Storage::open(&directory.0).unwrap();
+        let name = Some("2025-07-01T22:00:00.jpg");
+        let upload = storage
+            .store_upload(DEFAULT_DEVICE, name, None, b"photo")
+            .unwrap();
+        // As if the server stopped after writing the file
+        storage
+            .connection()
+            .execute("DELETE FROM uploads", [])
+            .unwrap();
+
+        let retry = storage
+            .store_upload(DEFAULT_DEVICE, name, None, b"photo")
+            .unwrap();
+        assert!(!retry.duplicate);
+        assert_eq!(retry.file_name, upload.file_name);
+        assert_eq!(count(&storage, "uploads"), 1);
+
+        // A different photo under that name is still not overwritten
+        storage
+            .connection()
+            .execute("DELETE FROM uploads", [])
+            .unwrap();
+        directory.write(&upload.file_name, b"other photo");
+        assert!(
+            storage
+                .store_upload(DEFAULT_DEVICE, name, None, b"photo")
+                .is_err()
+        );
+        assert_eq!(
+            std::fs::read(directory.0.join(&upload.file_name)).unwrap(),
+            b"other photo"
+        );
+    }
+
+    #[test]
+    fn stores_pruned_photo_again() {
+        let directory = TestDirectory::new("pruned");
+        let storage = Storage::open(&directory.0).unwrap();
+        let upload = storage
+            .store_upload(DEFAULT_DEVICE, None, None, b"photo")
+            .unwrap();
+        assert_eq!(
+            storage
+                .prune_uploads(Local::now() + chrono::Duration::seconds(1))
+                .unwrap(),
+            1
+        );
+        assert!(!storage.has_photo(&upload.file_name).unwrap());
+
+        let again = storage
+            .store_upload(DEFAULT_DEVICE, None, None, b"photo")
+            .unwrap();
+        assert!(!again.duplicate);
+        assert_eq!(again.id, upload.id);
+        assert!(storage.has_photo(&again.file_name).unwrap());
+        assert_eq!(count(&storage, "uploads"), 1);
+        assert_eq!(files(&directory), [again.file_name]);
+    }
+
+    fn checkin(timestamp: &str) -> CheckinRequest {
+        CheckinRequest {
+            version: PROTOCOL_VERSION,
+            device_id: "cam-1".into(),
+            firmware_version: "0.1.0".into(),
+            timestamp: timestamp.parse().unwrap(),
+            voltage: Some(3.9),
+            state_of_charge: None,
+            diagnostics: Diagnostics::default(),
+            captured_at: Some(timestamp.parse().unwrap()),
+            flash_brightness: Some(0),
+            config_revision: 0,
+        }
+    }
+
+    #[test]
+    fn records_retried_checkin_once() {
+        let directory = TestDirectory::new("retried-checkin");
+        let storage = Storage::open(&directory.0).unwrap();
+        let first = checkin("2025-07-01T22:00:00+02:00");
+        for _ in 0..2 {
+            storage
+                .store_checkin("cam-1", &first, None, Some(b"photo"))
+                .unwrap();
+        }
+        assert_eq!(count(&storage, "health_samples"), 1);
+        assert_eq!(count(&storage, "uploads"), 1);
+
+        let next = checkin("2025-07-02T22:00:00+02:00");
+        storage.store_checkin("cam-1", &next, None, None).unwrap();
+        storage
+            .store_checkin("cam-2", &first, None, Some(b"photo"))
+            .unwrap();
+        assert_eq!(count(&storage, "health_samples"), 3);
+    }
+
+    #[test]
+    fn leaves_out_unmeasured_voltages() {
+        let directory = TestDirectory::new("unmeasured-voltage");
+        let storage = Storage::open(&directory.0).unwrap();
+        let mut request = checkin("2025-07-01T22:00:00+02:00");
+        storage
+            .store_checkin("cam-1", &request, None, None)
+            .unwrap();
+        request.timestamp = "2025-07-02T22:00:00+02:00".parse().unwrap();
+        request.voltage = None;
+        storage
+            .store_checkin("cam-1", &request, None, None)
+            .unwrap();
+
+        assert_eq!(count(&storage, "health_samples"), 2);
+        let voltages = storage
+            .voltages("cam-1", Utc::now() - chrono::Days::new(1))
+            .unwrap();
+        assert_eq!(voltages.iter().map(|(_, v)| *v).collect::<Vec<_>>(), [3.9]);
+    }
+
+    #[test]
+    fn failed_photo_rolls_back_checkin() {
+        let directory = TestDirectory::new("failed-checkin");
+        let storage = Storage::open(&directory.0).unwrap();
+        let request = checkin("2025-07-01T22:00:00+02:00");
+        assert!(matches!(
+            storage.store_checkin("cam-1", &request, Some("../photo.jpg"), Some(b"photo")),
+            Err(StorageError::InvalidFileName(_))
+        ));
+        assert_eq!(count(&storage, "health_samples"), 0);
+        assert_eq!(count(&storage, "uploads"), 0);
+        assert!(files(&directory).is_empty());
+
+        // Retried with a valid name, the check-in is recorded as if the first never happened
+        storage
+            .store_checkin("cam-1", &request, Some("photo.jpg"), Some(b"photo"))
+            .unwrap();
+        assert_eq!(count(&storage, "health_samples"), 1);
+        assert_eq!(files(&directory).len(), 1);
+    }
+}
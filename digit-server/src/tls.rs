@@ -0,0 +1,51 @@
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::ServerConfig;
+use rustls::crypto::ring;
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Certificate and key the server terminates HTTPS with.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM file with the certificate, followed by any intermediate ones.
+    pub cert: PathBuf,
+    /// PEM file with the private key of the certificate.
+    pub key: PathBuf,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum TlsError {
+    #[error("could not read {0}: {1}")]
+    Read(PathBuf, pem::Error),
+    #[error("no certificate in {0}")]
+    NoCertificate(PathBuf),
+    #[error("invalid certificate or key: {0}")]
+    Rustls(#[from] rustls::Error),
+}
+
+/// Loads the certificate chain and key into a configuration for the listener. Only ring is
+/// built in, the provider is given explicitly rather than installed process wide.
+pub fn server_config(tls: &TlsConfig) -> Result<RustlsConfig, TlsError> {
+    let certs = read_certs(&tls.cert)?;
+    let key =
+        PrivateKeyDer::from_pem_file(&tls.key).map_err(|e| TlsError::Read(tls.key.clone(), e))?;
+
+    let mut config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| TlsError::Read(path.to_path_buf(), e))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_path_buf()));
+    }
+    Ok(certs)
+}
@@ -0,0 +1,121 @@
+//! Runs the server with a self-signed certificate and talks to it the way the camera does,
+//! trusting nothing but the pinned certificate.
+
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+use ureq::tls::{Certificate, RootCerts, TlsConfig};
+
+/// The server process, killed when the test ends however it ends.
+struct Server {
+    process: Child,
+    port: u16,
+    directory: PathBuf,
+}
+
+impl Server {
+    /// Starts the server on a free port with a new certificate for `localhost`, returning
+    /// it together with the certificate in PEM.
+    fn start(name: &str) -> (Server, String) {
+        let directory =
+            std::env::temp_dir().join(format!("digit-tls-{}-{name}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap();
+        let cert = certified.cert.pem();
+        std::fs::write(directory.join("cert.pem"), &cert).unwrap();
+        std::fs::write(
+            directory.join("key.pem"),
+            certified.signing_key.serialize_pem(),
+        )
+        .unwrap();
+
+        let port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let process = Command::new(env!("CARGO_BIN_EXE_digit-server"))
+            .current_dir(&directory)
+            .env("RUST_LOG", "warn")
+            .args(["--bind", &format!("127.0.0.1:{port}")])
+            .args(["--data-dir", "data"])
+            .args(["--tls-cert", "cert.pem", "--tls-key", "key.pem"])
+            .args(["--reports", "false", "--alerts", "false"])
+            .spawn()
+            .unwrap();
+        let server = Server {
+            process,
+            port,
+            directory,
+        };
+
+        let started = Instant::now();
+        while TcpStream::connect((Ipv4Addr::LOCALHOST, port)).is_err() {
+            assert!(
+                started.elapsed() < Duration::from_secs(10),
+                "server did not start"
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+        (server, cert)
+    }
+
+    fn url(&self) -> String {
+        format!("https://localhost:{}/", self.port)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        let _ = std::fs::remove_dir_all(&self.directory);
+    }
+}
+
+/// A client that trusts only `pinned`, like the camera with its pinned certificate.
+fn pinned_agent(pinned: &str) -> ureq::Agent {
+    let pinned = Certificate::from_pem(pinned.as_bytes()).unwrap();
+    ureq::Agent::config_builder()
+        .tls_config(
+            TlsConfig::builder()
+                .root_certs(RootCerts::new_with_certs(&[pinned]))
+                .build(),
+        )
+        .timeout_global(Some(Duration::from_secs(10)))
+        .build()
+        .new_agent()
+}
+
+#[test]
+fn serves_https_to_pinned_client() {
+    let (server, cert) = Server::start("pinned");
+    let body = pinned_agent(&cert)
+        .get(&server.url())
+        .call()
+        .unwrap()
+        .into_body()
+        .read_to_string()
+        .unwrap();
+    assert!(body.contains("<h1>Meters</h1>"), "{body}");
+}
+
+#[test]
+fn rejected_by_client_pinned_to_other_certificate() {
+    let (server, _) = Server::start("other");
+    let other = rcgen::generate_simple_self_signed(vec!["localhost".into()])
+        .unwrap()
+        .cert
+        .pem();
+    let error = pinned_agent(&other).get(&server.url()).call().unwrap_err();
+    assert!(
+        error.to_string().contains("invalid peer certificate"),
+        "{error}"
+    );
+
+    // Nor does the server answer plain HTTP on its port
+    let plain = format!("http://localhost:{}/", server.port);
+    assert!(pinned_agent(&other).get(&plain).call().is_err());
+}
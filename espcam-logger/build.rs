@@ -1,3 +1,35 @@
+use std::path::Path;
+
 fn main() {
     embuild::espidf::sysenv::output();
+
+    // Reported to the server, so that it knows exactly which firmware a device runs
+    if let Some(hash) = git(&["rev-parse", "--short", "HEAD"]) {
+        println!("cargo:rustc-env=DIGIT_BUILD_HASH={hash}");
+    }
+
+    // The hash changes with every commit, also with those that leave this package alone. Once
+    // anything is listed here cargo stops watching the package itself, so it is listed too.
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src");
+    let mut watched = vec!["HEAD".to_string(), "packed-refs".to_string()];
+    watched.extend(git(&["symbolic-ref", "-q", "HEAD"]));
+    for name in watched {
+        if let Some(path) = git(&["rev-parse", "--git-path", &name]) {
+            // A file that does not exist would rerun the script on every build
+            if Path::new(&path).exists() {
+                println!("cargo:rerun-if-changed={path}");
+            }
+        }
+    }
+}
+
+/// The output of git with `args`, `None` if it fails, e.g. outside a checkout.
+fn git(args: &[&str]) -> Option<String> {
+    std::process::Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
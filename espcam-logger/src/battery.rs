@@ -0,0 +1,84 @@
+use anyhow::{bail, Result};
+use digit_protocol::battery::{battery_voltage, state_of_charge, trimmed_mean, LI_ION_CURVE};
+use esp_idf_hal::adc::attenuation::DB_12;
+use esp_idf_hal::adc::oneshot::config::{AdcChannelConfig, Calibration};
+use esp_idf_hal::adc::oneshot::{AdcChannelDriver, AdcDriver};
+use esp_idf_hal::gpio::ADCPin;
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::peripherals::Peripherals;
+
+/// Number of samples averaged into one measurement.
+const SAMPLES: usize = 16;
+
+/// A battery measurement.
+#[derive(Debug, Clone, Copy)]
+pub struct Battery {
+    /// Battery voltage in volts.
+    pub voltage: f32,
+    /// Estimated state of charge in percent.
+    pub state_of_charge: f32,
+}
+
+/// Measures the battery voltage on GPIO `pin` behind a resistor divider with `divider_ratio`,
+/// `(R_top + R_bottom) / R_bottom`. Measure before Wi-Fi starts, the radio's current draw
+/// makes the voltage sag and it takes over the ADC2 pins.
+pub fn measure(peripherals: &mut Peripherals, pin: u8, divider_ratio: f32) -> Result<Battery> {
+    let (adc1, adc2, pins) = (
+        &mut peripherals.adc1,
+        &mut peripherals.adc2,
+        &mut peripherals.pins,
+    );
+    match pin {
+        32 => measure_on(adc1, &mut pins.gpio32, divider_ratio),
+        33 => measure_on(adc1, &mut pins.gpio33, divider_ratio),
+        34 => measure_on(adc1, &mut pins.gpio34, divider_ratio),
+        35 => measure_on(adc1, &mut pins.gpio35, divider_ratio),
+        36 => measure_on(adc1, &mut pins.gpio36, divider_ratio),
+        39 => measure_on(adc1, &mut pins.gpio39, divider_ratio),
+        0 => measure_on(adc2, &mut pins.gpio0, divider_ratio),
+        2 => measure_on(adc2, &mut pins.gpio2, divider_ratio),
+        4 => measure_on(adc2, &mut pins.gpio4, divider_ratio),
+        12 => measure_on(adc2, &mut pins.gpio12, divider_ratio),
+        13 => measure_on(adc2, &mut pins.gpio13, divider_ratio),
+        14 => measure_on(adc2, &mut pins.gpio14, divider_ratio),
+        15 => measure_on(adc2, &mut pins.gpio15, divider_ratio),
+        25 => measure_on(adc2, &mut pins.gpio25, divider_ratio),
+        26 => measure_on(adc2, &mut pins.gpio26, divider_ratio),
+        27 => measure_on(adc2, &mut pins.gpio27, divider_ratio),
+        pin => bail!("GPIO{pin} has no ADC channel"),
+    }
+}
+
+fn measure_on<P: ADCPin>(
+    adc: impl Peripheral<P = P::Adc>,
+    pin: impl Peripheral<P = P>,
+    divider_ratio: f32,
+) -> Result<Battery> {
+    let adc = AdcDriver::new(adc)?;
+    // With calibration enabled the readings are in millivolts
+    let config = AdcChannelConfig {
+        attenuation: DB_12,
+        calibration: Calibration::Line,
+        ..Default::default()
+    };
+    let mut channel = AdcChannelDriver::new(&adc, pin, &config)?;
+
+    let mut samples = [0u16; SAMPLES];
+    for sample in samples.iter_mut() {
+        *sample = adc.read(&mut channel)?;
+    }
+    let millivolts = trimmed_mean(&mut samples).unwrap_or(0);
+
+    let voltage = battery_voltage(u32::from(millivolts), divider_ratio);
+    let battery = Battery {
+        voltage,
+        state_of_charge: state_of_charge(voltage, LI_ION_CURVE),
+    };
+    log::info!(
+        "Battery at {:.2} V ({} mV at the pin), {:.0} %",
+        battery.voltage,
+        millivolts,
+        battery.state_of_charge
+    );
+    Ok(battery)
+}
@@ -0,0 +1,154 @@
+use crate::espcam::{self, Camera, CameraSensor, SensorError};
+use crate::flash::Flash;
+use anyhow::{anyhow, Result};
+use digit_protocol::exposure::{self, FrameScore};
+use digit_protocol::roi::{Roi, FRAME_HEIGHT, FRAME_WIDTH};
+
+/// JPEG quality of the uploaded photo from 1 to 100, high enough to keep the edges of the
+/// digits sharp.
+const JPEG_QUALITY: u8 = 90;
+/// Frames thrown away after changing the exposure, taken before the change or while the
+/// automatic exposure settles.
+const SETTLE_FRAMES: usize = 2;
+
+/// How the sensor exposes a frame.
+#[derive(Debug, Clone, Copy)]
+pub enum Exposure {
+    /// Automatic exposure and gain, shifted by an exposure level from -2 to 2.
+    Auto { ae_level: i8 },
+    /// Fixed exposure time from 0 to 1200 and gain from 0 to 30.
+    Manual { aec_value: u16, agc_gain: u8 },
+}
+
+/// Settings of one frame of the bracket.
+#[derive(Debug, Clone, Copy)]
+pub struct Bracket {
+    pub exposure: Exposure,
+    /// Brightness of the flash LED for the frame, in percent of the configured brightness.
+    pub flash: u8,
+}
+
+/// The frames taken on every wake. The first is what the camera always took, the darker one
+/// with the dimmed flash and the short one keep it from glaring off the meter glass and the
+/// last does without it for meters in daylight.
+const BRACKETS: &[Bracket] = &[
+    Bracket {
+        exposure: Exposure::Auto { ae_level: 0 },
+        flash: 100,
+    },
+    Bracket {
+        exposure: Exposure::Auto { ae_level: -2 },
+        flash: 50,
+    },
+    Bracket {
+        exposure: Exposure::Manual {
+            aec_value: 300,
+            agc_gain: 0,
+        },
+        flash: 100,
+    },
+    Bracket {
+        exposure: Exposure::Auto { ae_level: 2 },
+        flash: 0,
+    },
+];
+
+/// The photo to upload.
+pub struct Photo {
+    pub jpeg: Vec<u8>,
+    /// Brightness of the flash LED in percent while the frame was taken, 0 if it was off.
+    pub flash_brightness: u8,
+}
+
+/// Takes a frame for every bracket and returns the one best for reading the digits as JPEG,
+/// cut down to `roi` if there is one. The flash is lit at up to `brightness` percent, 0 takes
+/// every frame without it. The camera has to take grayscale frames, they are scored on their
+/// pixels and only the best is encoded.
+pub fn best_photo(
+    camera: &Camera,
+    flash: &mut Flash,
+    brightness: u8,
+    roi: Option<&Roi>,
+) -> Result<Photo> {
+    let sensor = espcam::sensor(camera)?;
+    let (width, height) = roi.map_or((FRAME_WIDTH, FRAME_HEIGHT), Roi::output_size);
+    // Only the JPEG of the best frame so far is kept, two whole frames would not fit in PSRAM
+    let mut best: Option<(FrameScore, Photo)> = None;
+
+    for (i, bracket) in BRACKETS.iter().enumerate() {
+        if let Err(e) = expose(&sensor, bracket.exposure) {
+            log::warn!("Skipping bracket {i}, failed to set the exposure: {e}");
+            continue;
+        }
+        let flash_brightness = (u16::from(brightness) * u16::from(bracket.flash) / 100) as u8;
+        flash.set(flash_brightness)?;
+        for _ in 0..SETTLE_FRAMES {
+            camera.frame();
+        }
+        let frame = camera.frame();
+        flash.off()?;
+        let Some(frame) = frame else {
+            log::warn!("No frame for bracket {i}");
+            continue;
+        };
+
+        let region = roi
+            .map(|roi| roi.crop(frame.data()))
+            .transpose()
+            .map_err(|e| anyhow!("Failed to crop the photo: {e}"))?;
+        let pixels = region.as_deref().unwrap_or(frame.data());
+        let Some(score) = exposure::score(pixels, width as usize, height as usize) else {
+            log::warn!("Frame of bracket {i} is not {width}x{height} pixels");
+            continue;
+        };
+        log::info!(
+            "Bracket {i} {bracket:?} at {flash_brightness}% flash scores {:.3}: sharpness {:.3}, \
+             contrast {:.2}, clipped {:.3}",
+            score.total(),
+            score.sharpness,
+            score.contrast,
+            score.clipped
+        );
+        if best
+            .as_ref()
+            .map_or(true, |(best, _)| score.total() > best.total())
+        {
+            let jpeg = espcam::encode_grayscale(pixels, width as u16, height as u16, JPEG_QUALITY)?;
+            best = Some((
+                score,
+                Photo {
+                    jpeg,
+                    flash_brightness,
+                },
+            ));
+        }
+    }
+
+    let (score, photo) = best.ok_or_else(|| anyhow!("No bracket gave a frame"))?;
+    log::info!(
+        "Uploading the frame scoring {:.3}, taken at {}% flash, {} bytes",
+        score.total(),
+        photo.flash_brightness,
+        photo.jpeg.len()
+    );
+    Ok(photo)
+}
+
+fn expose(sensor: &CameraSensor, exposure: Exposure) -> Result<(), SensorError> {
+    match exposure {
+        Exposure::Auto { ae_level } => {
+            sensor.set_exposure_ctrl(true)?;
+            sensor.set_gain_ctrl(true)?;
+            sensor.set_ae_level(ae_level)
+        }
+        Exposure::Manual {
+            aec_value,
+            agc_gain,
+        } => {
+            sensor.set_exposure_ctrl(false)?;
+            sensor.set_gain_ctrl(false)?;
+            sensor.set_aec_value(aec_value)?;
+            sensor.set_agc_gain(agc_gain)
+        }
+    }
+}
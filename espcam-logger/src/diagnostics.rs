@@ -0,0 +1,119 @@
+use digit_protocol::Diagnostics;
+use esp_idf_sys::*;
+use std::time::Duration;
+
+use crate::network::WifiTimings;
+
+/// Commit the firmware was built from, set by the build script.
+const BUILD_HASH: Option<&str> = option_env!("DIGIT_BUILD_HASH");
+
+// Durations of the previous wake, kept in RTC memory across deep sleep. 0 is unknown.
+#[link_section = ".rtc.data"]
+static mut PREVIOUS_CAPTURE_MS: u32 = 0;
+#[link_section = ".rtc.data"]
+static mut PREVIOUS_UPLOAD_MS: u32 = 0;
+
+extern "C" {
+    /// Undocumented and uncalibrated sensor of the original ESP32, in °F.
+    fn temprature_sens_read() -> u8;
+}
+
+/// Collects what the device knows about itself. The uptime is left for the caller to fill in
+/// right before sending.
+pub fn collect(wifi: Option<&WifiTimings>, sntp: Option<Duration>) -> Diagnostics {
+    let (wifi_rssi, wifi_channel, wifi_bssid) = match access_point() {
+        Some(ap) => (
+            Some(ap.rssi),
+            Some(ap.primary),
+            Some(
+                ap.bssid
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<Vec<_>>()
+                    .join(":"),
+            ),
+        ),
+        None => (None, None, None),
+    };
+    let (previous_capture_ms, previous_upload_ms) =
+        unsafe { (PREVIOUS_CAPTURE_MS, PREVIOUS_UPLOAD_MS) };
+
+    Diagnostics {
+        wifi_rssi,
+        wifi_channel,
+        wifi_bssid,
+        reset_reason: Some(reset_reason().to_string()),
+        wake_reason: Some(wake_reason().to_string()),
+        uptime_ms: None,
+        associate_ms: wifi.map(|t| millis(t.associate)),
+        dhcp_ms: wifi.map(|t| millis(t.dhcp)),
+        sntp_ms: sntp.map(millis),
+        free_heap: Some(unsafe { esp_get_free_heap_size() }),
+        free_psram: Some(unsafe { heap_caps_get_free_size(MALLOC_CAP_SPIRAM) } as u32),
+        chip_temperature: chip_temperature(),
+        build_hash: BUILD_HASH.map(str::to_string),
+        previous_capture_ms: Some(previous_capture_ms).filter(|&ms| ms > 0),
+        previous_upload_ms: Some(previous_upload_ms).filter(|&ms| ms > 0),
+    }
+}
+
+/// Milliseconds since the chip started.
+pub fn uptime_ms() -> u64 {
+    (unsafe { esp_timer_get_time() } / 1000) as u64
+}
+
+/// Remembers how long this wake took to capture and upload, for the next wake to report.
+pub fn remember_durations(capture: Option<Duration>, upload: Option<Duration>) {
+    unsafe {
+        PREVIOUS_CAPTURE_MS = capture.map_or(0, millis);
+        PREVIOUS_UPLOAD_MS = upload.map_or(0, millis);
+    }
+}
+
+fn millis(duration: Duration) -> u32 {
+    duration.as_millis().min(u32::MAX as u128) as u32
+}
+
+fn access_point() -> Option<wifi_ap_record_t> {
+    let mut record = wifi_ap_record_t::default();
+    esp!(unsafe { esp_wifi_sta_get_ap_info(&mut record) })
+        .ok()
+        .map(|_| record)
+}
+
+pub fn reset_reason() -> &'static str {
+    #[allow(non_upper_case_globals)]
+    match unsafe { esp_reset_reason() } {
+        esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        esp_reset_reason_t_ESP_RST_EXT => "external",
+        esp_reset_reason_t_ESP_RST_SW => "software",
+        esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt_watchdog",
+        esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
+
+fn wake_reason() -> &'static str {
+    #[allow(non_upper_case_globals)]
+    match unsafe { esp_sleep_get_wakeup_cause() } {
+        esp_sleep_source_t_ESP_SLEEP_WAKEUP_UNDEFINED => "none",
+        esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER => "timer",
+        esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT0 | esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT1 => {
+            "external"
+        }
+        esp_sleep_source_t_ESP_SLEEP_WAKEUP_TOUCHPAD => "touchpad",
+        esp_sleep_source_t_ESP_SLEEP_WAKEUP_ULP => "ulp",
+        _ => "other",
+    }
+}
+
+fn chip_temperature() -> Option<f32> {
+    let fahrenheit = unsafe { temprature_sens_read() };
+    // Reads 128 when the sensor is not powered
+    (fahrenheit != 128).then(|| (f32::from(fahrenheit) - 32.0) / 1.8)
+}
@@ -1,44 +1,59 @@
+use std::fmt;
 use std::marker::PhantomData;
+use std::sync::atomic::AtomicBool;
 
+use digit_protocol::sensor::{GainCeiling, Level, SensorProfile, WhiteBalance};
 use esp_idf_hal::gpio::*;
 use esp_idf_hal::peripheral::Peripheral;
 use esp_idf_sys::*;
 
-pub struct FrameBuffer<'a> {
-    fb: *mut camera::camera_fb_t,
-    _p: PhantomData<&'a camera::camera_fb_t>,
-}
-
-#[allow(dead_code)]
-impl<'a> FrameBuffer<'a> {
-    pub fn data(&self) -> &'a [u8] {
-        unsafe { std::slice::from_raw_parts((*self.fb).buf, (*self.fb).len) }
-    }
+/// Whether the driver is initialised, it has a single instance.
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
 
-    pub fn width(&self) -> usize {
-        unsafe { (*self.fb).width }
-    }
+/// The camera driver, of which there is at most one at a time. See
+/// [`digit_protocol::camera`] for who owns it and its frames.
+pub type Camera<'a> = digit_protocol::camera::Camera<Esp32Camera<'a>>;
 
-    pub fn height(&self) -> usize {
-        unsafe { (*self.fb).height }
-    }
+/// A sensor operation that did not go through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorError {
+    /// The driver found no sensor.
+    NotFound,
+    /// The driver of the sensor leaves out the operation.
+    Unsupported(&'static str),
+    /// The sensor answered the operation with an error code.
+    Failed(&'static str, i32),
+}
 
-    pub fn format(&self) -> camera::pixformat_t {
-        unsafe { (*self.fb).format }
+impl fmt::Display for SensorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SensorError::NotFound => write!(f, "no sensor found"),
+            SensorError::Unsupported(op) => write!(f, "sensor does not support {op}"),
+            SensorError::Failed(op, code) => write!(f, "sensor failed {op} with {code}"),
+        }
     }
+}
 
-    pub fn timestamp(&self) -> camera::timeval {
-        unsafe { (*self.fb).timestamp }
-    }
+impl std::error::Error for SensorError {}
 
-    pub fn fb_return(&self) {
-        unsafe { camera::esp_camera_fb_return(self.fb) }
-    }
+/// Calls the operation `$op` of the sensor driver with the arguments after the sensor, or
+/// returns [`SensorError::Unsupported`] if the driver has none.
+macro_rules! sensor_op {
+    ($self:ident, $op:ident $(, $arg:expr)*) => {{
+        let sensor = $self.sensor;
+        match unsafe { (*sensor).$op } {
+            Some(op) => (stringify!($op), unsafe { op(sensor $(, $arg)*) }),
+            None => return Err(SensorError::Unsupported(stringify!($op))),
+        }
+    }};
 }
 
-impl Drop for FrameBuffer<'_> {
-    fn drop(&mut self) {
-        self.fb_return();
+/// Turns the status an operation returned into a result, anything but 0 is a failure.
+fn status((op, status): (&'static str, i32)) -> Result<(), SensorError> {
+    match status {
+        0 => Ok(()),
+        code => Err(SensorError::Failed(op, code)),
     }
 }
 
@@ -50,112 +65,147 @@ pub struct CameraSensor<'a> {
 
 #[allow(dead_code)]
 impl<'a> CameraSensor<'a> {
-    pub fn init_status(&self) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).init_status.unwrap()(self.sensor) })
+    /// Applies every setting of `profile`, leaving the ones it does not give as they are.
+    /// Stops at the first setting the sensor does not take.
+    pub fn apply(&self, profile: &SensorProfile) -> Result<(), SensorError> {
+        if let Some(level) = profile.brightness {
+            self.set_brightness(level)?;
+        }
+        if let Some(level) = profile.contrast {
+            self.set_contrast(level)?;
+        }
+        if let Some(level) = profile.saturation {
+            self.set_saturation(level)?;
+        }
+        if let Some(ceiling) = profile.gain_ceiling {
+            self.set_gainceiling(ceiling)?;
+        }
+        if let Some(white_balance) = profile.white_balance {
+            self.set_white_balance(white_balance)?;
+        }
+        if let Some(enable) = profile.hmirror {
+            self.set_hmirror(enable)?;
+        }
+        if let Some(enable) = profile.vflip {
+            self.set_vflip(enable)?;
+        }
+        if let Some(enable) = profile.lens_correction {
+            self.set_lenc(enable)?;
+        }
+        Ok(())
     }
-    pub fn reset(&self) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).reset.unwrap()(self.sensor) })
+
+    pub fn init_status(&self) -> Result<(), SensorError> {
+        status(sensor_op!(self, init_status))
     }
-    pub fn set_pixformat(&self, format: camera::pixformat_t) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_pixformat.unwrap()(self.sensor, format) })
+    pub fn reset(&self) -> Result<(), SensorError> {
+        status(sensor_op!(self, reset))
     }
-    pub fn set_framesize(&self, framesize: camera::framesize_t) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_framesize.unwrap()(self.sensor, framesize) })
+    pub fn set_pixformat(&self, format: camera::pixformat_t) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_pixformat, format))
     }
-    pub fn set_contrast(&self, level: i32) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_contrast.unwrap()(self.sensor, level) })
+    pub fn set_framesize(&self, framesize: camera::framesize_t) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_framesize, framesize))
     }
-    pub fn set_brightness(&self, level: i32) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_brightness.unwrap()(self.sensor, level) })
+    pub fn set_contrast(&self, level: Level) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_contrast, level.get().into()))
     }
-    pub fn set_saturation(&self, level: i32) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_saturation.unwrap()(self.sensor, level) })
+    pub fn set_brightness(&self, level: Level) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_brightness, level.get().into()))
     }
-    pub fn set_sharpness(&self, level: i32) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_sharpness.unwrap()(self.sensor, level) })
+    pub fn set_saturation(&self, level: Level) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_saturation, level.get().into()))
     }
-    pub fn set_denoise(&self, level: i32) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_denoise.unwrap()(self.sensor, level) })
+    pub fn set_sharpness(&self, level: Level) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_sharpness, level.get().into()))
     }
-    pub fn set_gainceiling(&self, gainceiling: camera::gainceiling_t) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_gainceiling.unwrap()(self.sensor, gainceiling) })
+    pub fn set_denoise(&self, level: u8) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_denoise, level.into()))
     }
-    pub fn set_quality(&self, quality: i32) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_quality.unwrap()(self.sensor, quality) })
+    pub fn set_gainceiling(&self, ceiling: GainCeiling) -> Result<(), SensorError> {
+        status(sensor_op!(
+            self,
+            set_gainceiling,
+            camera::gainceiling_t::from(ceiling.index())
+        ))
     }
-    pub fn set_colorbar(&self, enable: bool) -> Result<(), EspError> {
-        esp!(unsafe {
-            (*self.sensor).set_colorbar.unwrap()(self.sensor, if enable { 1 } else { 0 })
-        })
+    /// JPEG quality from 0 for the best to 63 for the worst.
+    pub fn set_quality(&self, quality: u8) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_quality, quality.into()))
     }
-    pub fn set_whitebal(&self, enable: bool) -> Result<(), EspError> {
-        esp!(unsafe {
-            (*self.sensor).set_whitebal.unwrap()(self.sensor, if enable { 1 } else { 0 })
-        })
+    pub fn set_colorbar(&self, enable: bool) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_colorbar, enable.into()))
     }
-    pub fn set_gain_ctrl(&self, enable: bool) -> Result<(), EspError> {
-        esp!(unsafe {
-            (*self.sensor).set_gain_ctrl.unwrap()(self.sensor, if enable { 1 } else { 0 })
-        })
+    pub fn set_whitebal(&self, enable: bool) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_whitebal, enable.into()))
+    }
+    /// Turns the white balance off or on in the given mode.
+    pub fn set_white_balance(&self, white_balance: WhiteBalance) -> Result<(), SensorError> {
+        match white_balance.mode() {
+            Some(mode) => {
+                self.set_whitebal(true)?;
+                status(sensor_op!(self, set_wb_mode, mode.into()))
+            }
+            None => self.set_whitebal(false),
+        }
     }
-    pub fn set_exposure_ctrl(&self, enable: bool) -> Result<(), EspError> {
-        esp!(unsafe {
-            (*self.sensor).set_exposure_ctrl.unwrap()(self.sensor, if enable { 1 } else { 0 })
-        })
+    pub fn set_gain_ctrl(&self, enable: bool) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_gain_ctrl, enable.into()))
     }
-    pub fn set_hmirror(&self, enable: bool) -> Result<(), EspError> {
-        esp!(unsafe {
-            (*self.sensor).set_hmirror.unwrap()(self.sensor, if enable { 1 } else { 0 })
-        })
+    pub fn set_exposure_ctrl(&self, enable: bool) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_exposure_ctrl, enable.into()))
     }
-    pub fn set_vflip(&self, enable: bool) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_vflip.unwrap()(self.sensor, if enable { 1 } else { 0 }) })
+    pub fn set_hmirror(&self, enable: bool) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_hmirror, enable.into()))
     }
-    pub fn set_aec2(&self, enable: bool) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_aec2.unwrap()(self.sensor, if enable { 1 } else { 0 }) })
+    pub fn set_vflip(&self, enable: bool) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_vflip, enable.into()))
     }
-    pub fn set_awb_gain(&self, enable: bool) -> Result<(), EspError> {
-        esp!(unsafe {
-            (*self.sensor).set_awb_gain.unwrap()(self.sensor, if enable { 1 } else { 0 })
-        })
+    pub fn set_aec2(&self, enable: bool) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_aec2, enable.into()))
     }
-    pub fn set_agc_gain(&self, gain: i32) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_agc_gain.unwrap()(self.sensor, gain) })
+    pub fn set_awb_gain(&self, enable: bool) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_awb_gain, enable.into()))
     }
-    pub fn set_aec_value(&self, gain: i32) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_aec_value.unwrap()(self.sensor, gain) })
+    /// Fixed gain from 0 to 30, used while the automatic gain is off.
+    pub fn set_agc_gain(&self, gain: u8) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_agc_gain, gain.into()))
     }
-    pub fn set_special_effect(&self, effect: i32) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_special_effect.unwrap()(self.sensor, effect) })
+    /// Fixed exposure time from 0 to 1200, used while the automatic exposure is off.
+    pub fn set_aec_value(&self, value: u16) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_aec_value, value.into()))
     }
-    pub fn set_wb_mode(&self, mode: i32) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_wb_mode.unwrap()(self.sensor, mode) })
+    pub fn set_special_effect(&self, effect: u8) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_special_effect, effect.into()))
     }
-    pub fn set_ae_level(&self, level: i32) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_ae_level.unwrap()(self.sensor, level) })
+    /// Shifts the target of the automatic exposure, from -2 to 2.
+    pub fn set_ae_level(&self, level: i8) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_ae_level, level.into()))
     }
-    pub fn set_dcw(&self, enable: bool) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_dcw.unwrap()(self.sensor, if enable { 1 } else { 0 }) })
+    pub fn set_dcw(&self, enable: bool) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_dcw, enable.into()))
     }
-    pub fn set_bpc(&self, enable: bool) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_bpc.unwrap()(self.sensor, if enable { 1 } else { 0 }) })
+    pub fn set_bpc(&self, enable: bool) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_bpc, enable.into()))
     }
-    pub fn set_wpc(&self, enable: bool) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_wpc.unwrap()(self.sensor, if enable { 1 } else { 0 }) })
+    pub fn set_wpc(&self, enable: bool) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_wpc, enable.into()))
     }
-    pub fn set_raw_gma(&self, enable: bool) -> Result<(), EspError> {
-        esp!(unsafe {
-            (*self.sensor).set_raw_gma.unwrap()(self.sensor, if enable { 1 } else { 0 })
-        })
+    pub fn set_raw_gma(&self, enable: bool) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_raw_gma, enable.into()))
     }
-    pub fn set_lenc(&self, enable: bool) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_lenc.unwrap()(self.sensor, if enable { 1 } else { 0 }) })
+    pub fn set_lenc(&self, enable: bool) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_lenc, enable.into()))
     }
-    pub fn get_reg(&self, reg: i32, mask: i32) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).get_reg.unwrap()(self.sensor, reg, mask) })
+    /// The bits of `mask` of the register `reg`.
+    pub fn get_reg(&self, reg: i32, mask: i32) -> Result<i32, SensorError> {
+        match sensor_op!(self, get_reg, reg, mask) {
+            (op, value) if value < 0 => Err(SensorError::Failed(op, value)),
+            (_, value) => Ok(value),
+        }
     }
-    pub fn set_reg(&self, reg: i32, mask: i32, value: i32) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_reg.unwrap()(self.sensor, reg, mask, value) })
+    pub fn set_reg(&self, reg: i32, mask: i32, value: i32) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_reg, reg, mask, value))
     }
     pub fn set_res_raw(
         &self,
@@ -171,24 +221,23 @@ impl<'a> CameraSensor<'a> {
         output_y: i32,
         scale: bool,
         binning: bool,
-    ) -> Result<(), EspError> {
-        esp!(unsafe {
-            (*self.sensor).set_res_raw.unwrap()(
-                self.sensor,
-                start_x,
-                start_y,
-                end_x,
-                end_y,
-                offset_x,
-                offset_y,
-                total_x,
-                total_y,
-                output_x,
-                output_y,
-                scale,
-                binning,
-            )
-        })
+    ) -> Result<(), SensorError> {
+        status(sensor_op!(
+            self,
+            set_res_raw,
+            start_x,
+            start_y,
+            end_x,
+            end_y,
+            offset_x,
+            offset_y,
+            total_x,
+            total_y,
+            output_x,
+            output_y,
+            scale,
+            binning
+        ))
     }
     pub fn set_pll(
         &self,
@@ -200,32 +249,53 @@ impl<'a> CameraSensor<'a> {
         seld5: i32,
         pclken: i32,
         pclk: i32,
-    ) -> Result<(), EspError> {
-        esp!(unsafe {
-            (*self.sensor).set_pll.unwrap()(
-                self.sensor,
-                bypass,
-                mul,
-                sys,
-                root,
-                pre,
-                seld5,
-                pclken,
-                pclk,
-            )
-        })
-    }
-    pub fn set_xclk(&self, timer: i32, xclk: i32) -> Result<(), EspError> {
-        esp!(unsafe { (*self.sensor).set_xclk.unwrap()(self.sensor, timer, xclk) })
+    ) -> Result<(), SensorError> {
+        status(sensor_op!(
+            self, set_pll, bypass, mul, sys, root, pre, seld5, pclken, pclk
+        ))
+    }
+    pub fn set_xclk(&self, timer: i32, xclk: i32) -> Result<(), SensorError> {
+        status(sensor_op!(self, set_xclk, timer, xclk))
     }
 }
 
-pub struct Camera<'a> {
+/// Encodes a grayscale image with one byte per pixel as JPEG, `quality` going from 1 to 100.
+pub fn encode_grayscale(
+    pixels: &[u8],
+    width: u16,
+    height: u16,
+    quality: u8,
+) -> Result<Vec<u8>, EspError> {
+    let mut out: *mut u8 = std::ptr::null_mut();
+    let mut out_len = 0;
+    // The encoder only reads the pixels, despite taking them mutably
+    let encoded = unsafe {
+        camera::fmt2jpg(
+            pixels.as_ptr() as *mut u8,
+            pixels.len(),
+            width,
+            height,
+            camera::pixformat_t_PIXFORMAT_GRAYSCALE,
+            quality,
+            &mut out,
+            &mut out_len,
+        )
+    };
+    if !encoded || out.is_null() {
+        return Err(EspError::from_infallible::<ESP_ERR_NO_MEM>());
+    }
+    let jpeg = unsafe { std::slice::from_raw_parts(out, out_len) }.to_vec();
+    unsafe { free(out as *mut _) };
+    Ok(jpeg)
+}
+
+/// The esp32-camera driver for the pins it is given, initialised by [`Camera::new`].
+pub struct Esp32Camera<'a> {
+    config: camera::camera_config_t,
     _p: PhantomData<&'a ()>,
 }
 
-#[allow(dead_code)]
-impl<'a> Camera<'a> {
+impl<'a> Esp32Camera<'a> {
     pub fn new(
         pin_pwdn: impl Peripheral<P = impl InputPin + OutputPin> + 'a,
         pin_xclk: impl Peripheral<P = impl InputPin + OutputPin> + 'a,
@@ -244,7 +314,7 @@ impl<'a> Camera<'a> {
         pin_scl: impl Peripheral<P = impl InputPin + OutputPin> + 'a,
         pixel_format: camera::pixformat_t,
         frame_size: camera::framesize_t,
-    ) -> Result<Self, esp_idf_sys::EspError> {
+    ) -> Self {
         esp_idf_hal::into_ref!(
             pin_pwdn, pin_xclk, pin_d0, pin_d1, pin_d2, pin_d3, pin_d4, pin_d5, pin_d6, pin_d7,
             pin_vsync, pin_href, pin_pclk, pin_sda, pin_scl
@@ -289,33 +359,58 @@ impl<'a> Camera<'a> {
             ..Default::default()
         };
 
-        esp_idf_sys::esp!(unsafe { camera::esp_camera_init(&config) })?;
-        Ok(Self { _p: PhantomData })
+        Self {
+            config,
+            _p: PhantomData,
+        }
     }
+}
+
+/// The sensor of `camera`, valid for as long as the camera is. Fails if the driver did not find
+/// a sensor.
+pub fn sensor<'c>(_camera: &'c Camera<'_>) -> Result<CameraSensor<'c>, SensorError> {
+    let sensor = unsafe { camera::esp_camera_sensor_get() };
+    if sensor.is_null() {
+        return Err(SensorError::NotFound);
+    }
+    Ok(CameraSensor {
+        sensor,
+        _p: PhantomData,
+    })
+}
+
+// SAFETY: every instance shares `INITIALIZED`, and the driver keeps a frame buffer and its
+// pixels as they are until it is handed back or the driver is deinitialised
+unsafe impl digit_protocol::camera::Driver for Esp32Camera<'_> {
+    type Frame = *mut camera::camera_fb_t;
+    type Error = EspError;
 
-    pub fn get_framebuffer(&self) -> Option<FrameBuffer> {
+    fn instance() -> &'static AtomicBool {
+        &INITIALIZED
+    }
+
+    fn in_use() -> EspError {
+        EspError::from_infallible::<ESP_ERR_INVALID_STATE>()
+    }
+
+    fn init(&mut self) -> Result<(), EspError> {
+        esp!(unsafe { camera::esp_camera_init(&self.config) })
+    }
+
+    fn deinit(&mut self) {
+        esp!(unsafe { camera::esp_camera_deinit() }).expect("error during esp_camera_deinit");
+    }
+
+    fn frame_get(&self) -> Option<*mut camera::camera_fb_t> {
         let fb = unsafe { camera::esp_camera_fb_get() };
-        if fb.is_null() {
-            //unsafe { camera::esp_camera_fb_return(fb); }
-            None
-        } else {
-            Some(FrameBuffer {
-                fb,
-                _p: PhantomData,
-            })
-        }
+        (!fb.is_null()).then_some(fb)
     }
 
-    pub fn sensor(&self) -> CameraSensor<'a> {
-        CameraSensor {
-            sensor: unsafe { camera::esp_camera_sensor_get() },
-            _p: PhantomData,
-        }
+    unsafe fn frame_return(&self, fb: &*mut camera::camera_fb_t) {
+        camera::esp_camera_fb_return(*fb)
     }
-}
 
-impl<'a> Drop for Camera<'a> {
-    fn drop(&mut self) {
-        esp!(unsafe { camera::esp_camera_deinit() }).expect("error during esp_camera_deinit")
+    unsafe fn pixels(fb: &*mut camera::camera_fb_t) -> &[u8] {
+        std::slice::from_raw_parts((**fb).buf, (**fb).len)
     }
 }
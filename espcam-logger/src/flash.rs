@@ -0,0 +1,66 @@
+use esp_idf_hal::gpio::Gpio4;
+use esp_idf_hal::ledc::config::TimerConfig;
+use esp_idf_hal::ledc::{LedcDriver, LedcTimerDriver, Resolution, CHANNEL1, TIMER1};
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::prelude::*;
+use esp_idf_sys::EspError;
+use std::thread;
+use std::time::Duration;
+
+/// PWM frequency of the LED. The sensor reads the frame row by row, at a low frequency the
+/// rows lit and unlit would show as stripes.
+const FREQUENCY_KHZ: u32 = 50;
+
+/// The flash LED on GPIO4, dimmed by the LEDC PWM. The camera clock takes LEDC timer 0 and
+/// channel 0, the flash uses timer 1 and channel 1.
+pub struct Flash {
+    driver: LedcDriver<'static>,
+    /// How long the LED is lit before a frame is taken.
+    warm_up: Duration,
+    /// Brightness the LED is lit at in percent, 0 when it is off.
+    brightness: u8,
+}
+
+impl Flash {
+    pub fn new(
+        timer: impl Peripheral<P = TIMER1> + 'static,
+        channel: impl Peripheral<P = CHANNEL1> + 'static,
+        pin: impl Peripheral<P = Gpio4> + 'static,
+        warm_up: Duration,
+    ) -> Result<Self, EspError> {
+        let timer = LedcTimerDriver::new(
+            timer,
+            &TimerConfig::new()
+                .frequency(FREQUENCY_KHZ.kHz().into())
+                .resolution(Resolution::Bits10),
+        )?;
+        let mut driver = LedcDriver::new(channel, timer, pin)?;
+        driver.set_duty(0)?;
+        Ok(Self {
+            driver,
+            warm_up,
+            brightness: 0,
+        })
+    }
+
+    /// Lights the LED at `brightness` percent, 0 turns it off. Turning it on waits out the
+    /// warm-up, so that its light has settled when the next frame is taken.
+    pub fn set(&mut self, brightness: u8) -> Result<(), EspError> {
+        let brightness = brightness.min(100);
+        if brightness == self.brightness {
+            return Ok(());
+        }
+        let duty = self.driver.get_max_duty() * u32::from(brightness) / 100;
+        self.driver.set_duty(duty)?;
+        let warming_up = self.brightness == 0 && brightness > 0;
+        self.brightness = brightness;
+        if warming_up && !self.warm_up.is_zero() {
+            thread::sleep(self.warm_up);
+        }
+        Ok(())
+    }
+
+    pub fn off(&mut self) -> Result<(), EspError> {
+        self.set(0)
+    }
+}
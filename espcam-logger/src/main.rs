@@ -1,43 +1,188 @@
-use anyhow::Result;
-use embedded_svc::{http::client::Client, http::Method, io::Write};
+use anyhow::{anyhow, Result};
+use digit_protocol::hex;
+use digit_protocol::queue::OfflineQueue;
+use digit_protocol::sensor::SensorProfile;
+use digit_protocol::settings::Settings;
+use digit_protocol::supervisor::{
+    sleep_after_failures, Action, Outcome, RetryPolicy, Step, WakeCycle,
+};
+use digit_protocol::{
+    CheckinRequest, CheckinResponse, DeviceConfig, Diagnostics, ErrorBody, FirmwareUpdate,
+    Timestamp, PROTOCOL_VERSION,
+};
+use embedded_svc::{
+    http::client::Client,
+    http::Method,
+    io::{Read, Write},
+};
 use esp_idf_svc::{
-    eventloop::EspSystemEventLoop, hal::gpio::PinDriver, hal::prelude::*,
-    http::client::EspHttpConnection, nvs,
+    eventloop::EspSystemEventLoop,
+    hal::prelude::*,
+    nvs,
+    timer::{EspTaskTimerService, EspTimer},
 };
-use esp_idf_sys::{esp_deep_sleep_start, esp_sleep_enable_timer_wakeup};
-use serde::{Deserialize, Serialize};
-use std::time::Duration;
+use esp_idf_sys::{esp_deep_sleep_start, esp_restart, esp_sleep_enable_timer_wakeup};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::thread;
+use std::time::{Duration, Instant};
 
+mod battery;
+mod capture;
+mod diagnostics;
 mod espcam;
+mod flash;
 mod network;
+mod ota;
+mod provisioning;
+mod sdcard;
+mod tls;
+
+use battery::Battery;
+use capture::Photo;
+use espcam::{Camera, Esp32Camera};
+use flash::Flash;
+use sdcard::{sd_error, SdStorage};
 
-use espcam::Camera;
+/// Version of this firmware, reported to the server with every request.
+const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Shortest and longest sleep the server may ask for, anything else is taken for a mistake.
+const MIN_SLEEP: Duration = Duration::from_secs(60);
+const MAX_SLEEP: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// Most check-ins kept on the SD card while the server is unreachable, the oldest are dropped
+/// beyond that. At about 300 kB a photo this is a month of daily photos in 9 MB.
+const QUEUE_CAPACITY: usize = 30;
+/// Most queued check-ins sent on one wake, so that a long outage is caught up on over a few
+/// wakes instead of draining the battery in one.
+const REPLAYS_PER_WAKE: usize = 10;
+
+/// Every step of a wake is tried three times, waiting 2 s after the first failure and 4 s
+/// after the second.
+const RETRY_POLICY: RetryPolicy = RetryPolicy {
+    attempts: 3,
+    initial_delay: Duration::from_secs(2),
+    max_delay: Duration::from_secs(30),
+};
+/// No step is started or retried after this long awake.
+const WAKE_DEADLINE: Duration = Duration::from_secs(3 * 60);
+/// After this long awake the watchdog puts the camera to sleep, whatever it is doing.
+const WATCHDOG_DEADLINE: Duration = Duration::from_secs(5 * 60);
+/// Sleep after a wake that did not reach the server, doubled with every further one until it
+/// is as long as scheduled.
+const FAILED_WAKE_SLEEP: Duration = Duration::from_secs(15 * 60);
+const SNTP_TIMEOUT: Duration = Duration::from_secs(15);
+/// Sleep after the setup portal timed out before the camera was ever set up, to try again
+/// without draining the battery.
+const UNPROVISIONED_SLEEP: Duration = Duration::from_secs(60 * 60);
+/// Firmware updates are put off while the battery is below this state of charge in percent,
+/// running out in the middle of one would leave the camera without its previous firmware.
+const MIN_UPDATE_CHARGE: f32 = 30.0;
+/// Brightness of the flash LED in percent until the server sets one.
+const DEFAULT_FLASH_BRIGHTNESS: u8 = 100;
+/// Milliseconds the flash LED is lit before a frame is taken until the server sets them. The
+/// frames thrown away while the exposure settles give it time as well.
+const DEFAULT_FLASH_WARM_UP_MS: u16 = 50;
 
-#[derive(Serialize, Deserialize)]
-struct HealthRequest {
-    voltage: f32,
-    timestamp: String,
+// Settings received from the server. They live in RTC memory, which survives deep sleep, and
+// fall back to the built-in ones after a power loss, when the server sends them again.
+#[link_section = ".rtc.data"]
+static mut CONFIG_REVISION: u32 = 0;
+#[link_section = ".rtc.data"]
+static mut FLASH: bool = true;
+/// Brightness of the flash LED in percent.
+#[link_section = ".rtc.data"]
+static mut FLASH_BRIGHTNESS: u8 = DEFAULT_FLASH_BRIGHTNESS;
+/// Milliseconds the flash LED is lit before a frame is taken.
+#[link_section = ".rtc.data"]
+static mut FLASH_WARM_UP_MS: u16 = DEFAULT_FLASH_WARM_UP_MS;
+/// Image settings applied to the sensor before the photo is taken.
+#[link_section = ".rtc.data"]
+static mut SENSOR_PROFILE: Option<SensorProfile> = None;
+/// Wakes in a row that did not reach the server.
+#[link_section = ".rtc.data"]
+static mut FAILED_WAKES: u32 = 0;
+
+/// A request the server answered with an error status.
+#[derive(Debug)]
+struct ServerError {
+    status: u16,
+    code: String,
+    message: String,
+    /// Sending the same request again later may succeed.
+    retryable: bool,
+}
+
+impl std::fmt::Display for ServerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "server responded {} {}: {}",
+            self.status, self.code, self.message
+        )
+    }
 }
 
+impl std::error::Error for ServerError {}
+
+/// A request that did not reach the server, or whose response did not make it back, e.g.
+/// because the connection failed or timed out.
+#[derive(Debug)]
+struct TransportError(anyhow::Error);
+
+impl std::fmt::Display for TransportError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "could not reach the server: {}", self.0)
+    }
+}
+
+impl std::error::Error for TransportError {}
+
+fn transport(error: impl Into<anyhow::Error>) -> TransportError {
+    TransportError(error.into())
+}
+
+/// Whether a failed request is worth sending again: one that did not get through, or that
+/// the server says may succeed later. Anything else failed on the camera itself, e.g. for a
+/// missing device key, and fails the same way every time.
+fn is_retryable(error: &anyhow::Error) -> bool {
+    match error.downcast_ref::<ServerError>() {
+        Some(e) => e.retryable,
+        None => error.is::<TransportError>(),
+    }
+}
+
+/// Whether the server turned down the content of the request for good, e.g. as malformed or
+/// not a JPEG, so it is not worth keeping. A request turned away for its signature is kept,
+/// the device key or the clock may be fixed by a later wake.
+fn is_rejected(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<ServerError>()
+        .is_some_and(|e| !e.retryable && matches!(e.status, 400 | 413 | 415 | 422))
+}
+
+/// Settings of the hardware. Those of the network and the server are provisioned at runtime,
+/// see [`provisioning`].
 struct Config<'a> {
-    wifi_ssid: &'a str,
-    wifi_password: &'a str,
-    server_address: &'a str,
-    health_uri: &'a str,
-    upload_uri: &'a str,
+    /// GPIO the battery is connected to through a resistor divider, `None` without one. On the
+    /// AI-Thinker board GPIO33 also drives the red LED, which has to be removed.
+    battery_pin: Option<u8>,
+    /// Ratio of the resistor divider in front of the battery pin, `(R_top + R_bottom) / R_bottom`.
+    battery_divider_ratio: f32,
+    checkin_uri: &'a str,
+    /// Time of day to wake up at when the server does not say otherwise.
     wakeup_time: chrono::NaiveTime,
 }
 
 const CONFIG: Config = Config {
-    wifi_ssid: "Kaneelirull",
-    wifi_password: "palunW1f1t",
-    server_address: "http://synology:3000",
-    health_uri: "/health",
-    upload_uri: "/upload",
+    battery_pin: Some(33),
+    battery_divider_ratio: 2.0,
+    checkin_uri: "/v1/checkin",
     wakeup_time: chrono::NaiveTime::from_hms_opt(22, 0, 0).unwrap(),
 };
 
-fn main() -> Result<()> {
+fn main() {
     // It is necessary to call this function once. Otherwise some patches to the runtime
     // implemented by esp-idf-sys might not link properly. See https://github.com/esp-rs/esp-idf-template/issues/71
     esp_idf_svc::sys::link_patches();
@@ -45,151 +190,629 @@ fn main() -> Result<()> {
     // Bind the log crate to the ESP Logging facilities
     esp_idf_svc::log::EspLogger::initialize_default();
 
-    let peripherals = Peripherals::take()?;
+    let started = Instant::now();
+    let (checked_in, suggested_wake) = wake(started).unwrap_or_else(|e| {
+        log::error!("Wake failed: {e}");
+        (false, None)
+    });
+    sleep(checked_in, suggested_wake);
+}
+
+/// Puts the camera to sleep if the wake takes longer than [`WATCHDOG_DEADLINE`], whatever it
+/// is stuck on.
+fn watchdog() -> Result<EspTimer<'static>> {
+    let timer = EspTaskTimerService::new()?.timer(|| {
+        log::error!(
+            "Wake took longer than {} s, giving up",
+            WATCHDOG_DEADLINE.as_secs()
+        );
+        sleep(false, None);
+    })?;
+    timer.after(WATCHDOG_DEADLINE)?;
+    Ok(timer)
+}
+
+/// Runs the steps of a wake as the [`WakeCycle`] decides, after the setup portal if the
+/// camera has not been set up yet or the user asked for it. Returns whether the camera
+/// reached the server and the wake time the server suggested.
+fn wake(started: Instant) -> Result<(bool, Option<Timestamp>)> {
+    let mut peripherals = Peripherals::take()?;
 
-    nvs::EspNvsPartition::<nvs::NvsDefault>::take()?;
+    let partition = nvs::EspDefaultNvsPartition::take()?;
+    let mut nvs = nvs::EspNvs::new(partition, provisioning::NAMESPACE, true)?;
+
+    // Before Wi-Fi starts, its current draw would make the voltage sag
+    let battery = CONFIG.battery_pin.and_then(|pin| {
+        battery::measure(&mut peripherals, pin, CONFIG.battery_divider_ratio)
+            .map_err(|e| log::error!("Failed to measure the battery: {e}"))
+            .ok()
+    });
 
     let sysloop = EspSystemEventLoop::take()?;
-    // Connect to the Wi-Fi network
-    let _wifi = network::wifi(
-        CONFIG.wifi_ssid,
-        CONFIG.wifi_password,
-        peripherals.modem,
-        sysloop,
-    )
-    .expect("Could not connect to Wi-Fi network");
-    println!("Connected to Wi-Fi network!");
-
-    network::sntp("pool.ntp.org").expect("Could not set up SNTP");
-    let dt_now = chrono::Local::now();
-    log::info!("Current time {dt_now}");
-
-    send_health_data(dt_now)?;
-
-    let camera = Camera::new(
-        peripherals.pins.gpio32,
-        peripherals.pins.gpio0,
-        peripherals.pins.gpio5,
-        peripherals.pins.gpio18,
-        peripherals.pins.gpio19,
-        peripherals.pins.gpio21,
-        peripherals.pins.gpio36,
-        peripherals.pins.gpio39,
-        peripherals.pins.gpio34,
-        peripherals.pins.gpio35,
-        peripherals.pins.gpio25,
-        peripherals.pins.gpio23,
-        peripherals.pins.gpio22,
-        peripherals.pins.gpio26,
-        peripherals.pins.gpio27,
-        esp_idf_sys::camera::pixformat_t_PIXFORMAT_JPEG,
-        esp_idf_sys::camera::framesize_t_FRAMESIZE_UXGA,
+    let mut wifi = network::wifi(peripherals.modem, sysloop.clone())?;
+
+    let settings = provisioning::load(&nvs)?;
+    let gesture =
+        provisioning::reset_gesture(&nvs, diagnostics::reset_reason()).unwrap_or_else(|e| {
+            log::error!("Failed to count the resets: {e}");
+            false
+        });
+    let mut settings = match settings {
+        Some(settings) if !gesture => settings,
+        current => {
+            let provisioned = current.is_some();
+            match provisioning::portal(&mut wifi, sysloop.clone(), &mut nvs, current)? {
+                // Start over with the new settings
+                Some(_) => unsafe { esp_restart() },
+                None if provisioned => unsafe { esp_restart() },
+                None => {
+                    deep_sleep_until(chrono::Local::now() + UNPROVISIONED_SLEEP);
+                    unreachable!("Deep sleep does not return")
+                }
+            }
+        }
+    };
+
+    if settings.device_id.is_empty() {
+        settings.device_id = network::device_id(&wifi)?;
+    }
+    log::info!("Device id {}", settings.device_id);
+
+    let roi = provisioning::load_roi(&nvs).unwrap_or_else(|e| {
+        log::error!("Failed to load the region of interest: {e}");
+        None
+    });
+
+    if let Err(e) = tls::pin(&settings) {
+        // Every request fails the handshake now, which the supervisor reports like any
+        // other unreachable server
+        log::error!("Failed to pin the server certificate: {e}");
+    }
+
+    // Armed only after the setup portal, which may take longer. The camera goes to sleep
+    // right after the wake returns
+    let _watchdog = watchdog()
+        .map_err(|e| log::error!("Failed to start the watchdog: {e}"))
+        .ok();
+
+    // A freshly updated image has to check in before it is kept
+    let mut unverified = ota::running_unverified().unwrap_or_else(|e| {
+        log::error!("Failed to read the state of the firmware: {e}");
+        false
+    });
+    if unverified {
+        log::info!("Running firmware {FIRMWARE_VERSION} for the first time");
+    }
+
+    let mut flash = Flash::new(
+        peripherals.ledc.timer1,
+        peripherals.ledc.channel1,
+        peripherals.pins.gpio4,
+        Duration::from_millis(unsafe { FLASH_WARM_UP_MS }.into()),
     )?;
 
-    let mut led = PinDriver::output(peripherals.pins.gpio4)?;
+    // The SD card slot of the ESP32-CAM, used in SPI mode
+    let mut queue = SdStorage::mount(
+        peripherals.spi2,
+        peripherals.pins.gpio14,
+        peripherals.pins.gpio15,
+        peripherals.pins.gpio2,
+        peripherals.pins.gpio13,
+    )
+    .and_then(|storage| OfflineQueue::open(storage, QUEUE_CAPACITY).map_err(sd_error))
+    .map_err(|e| log::warn!("No offline queue, check-ins that fail are lost: {e}"))
+    .ok();
 
-    led.set_high()?;
-    camera.get_framebuffer();
-    // take two frames to get a fresh one
-    let framebuffer = camera.get_framebuffer();
-    led.set_low()?;
+    let mut wifi_timings = None;
+    // Keeps the clock in sync for as long as the camera is awake
+    let mut _sntp = None;
+    let mut sntp_time = None;
+    let mut captured_at = chrono::Local::now();
+    let mut image = None;
+    let mut capture_time = None;
+    let mut request = None;
+    let mut upload_time = None;
+    let mut suggested_wake = None;
+    let mut firmware_update = None;
 
-    if let Some(framebuffer) = framebuffer {
-        let image = framebuffer.data();
-        let filename = dt_now.format("%Y-%m-%dT%H:%M:%S.jpg").to_string();
-        send_image(&filename, image)?;
-    } else {
-        log::info!("No framebuffer available");
+    let mut cycle = WakeCycle::new(RETRY_POLICY, WAKE_DEADLINE);
+    let mut action = cycle.start();
+    while let Action::Run { step, after } = action {
+        if !after.is_zero() {
+            log::info!("Retrying {step:?} in {} s", after.as_secs());
+            thread::sleep(after);
+        }
+        let outcome = match step {
+            Step::Connect => {
+                match network::connect(
+                    &mut wifi,
+                    sysloop.clone(),
+                    &settings.wifi_ssid,
+                    &settings.wifi_password,
+                ) {
+                    Ok(timings) => {
+                        log::info!("Connected to Wi-Fi network");
+                        wifi_timings = Some(timings);
+                        Outcome::Succeeded
+                    }
+                    Err(e) => {
+                        log::error!("Failed to connect to Wi-Fi network: {e}");
+                        Outcome::Failed
+                    }
+                }
+            }
+            Step::SyncTime => {
+                let sntp_started = Instant::now();
+                match network::sntp("pool.ntp.org", SNTP_TIMEOUT) {
+                    Ok(sntp) => {
+                        _sntp = Some(sntp);
+                        sntp_time = Some(sntp_started.elapsed());
+                        log::info!("Current time {}", chrono::Local::now());
+                        Outcome::Succeeded
+                    }
+                    Err(e) => {
+                        // The clock keeps running through deep sleep, so it is only far off
+                        // after a power loss
+                        log::error!("Failed to set the clock: {e}");
+                        Outcome::Failed
+                    }
+                }
+            }
+            Step::Capture => {
+                let capture_started = Instant::now();
+                captured_at = chrono::Local::now();
+                let photo = Camera::new(Esp32Camera::new(
+                    &mut peripherals.pins.gpio32,
+                    &mut peripherals.pins.gpio0,
+                    &mut peripherals.pins.gpio5,
+                    &mut peripherals.pins.gpio18,
+                    &mut peripherals.pins.gpio19,
+                    &mut peripherals.pins.gpio21,
+                    &mut peripherals.pins.gpio36,
+                    &mut peripherals.pins.gpio39,
+                    &mut peripherals.pins.gpio34,
+                    &mut peripherals.pins.gpio35,
+                    &mut peripherals.pins.gpio25,
+                    &mut peripherals.pins.gpio23,
+                    &mut peripherals.pins.gpio22,
+                    &mut peripherals.pins.gpio26,
+                    &mut peripherals.pins.gpio27,
+                    // The frames are scored on their pixels and only the best is encoded
+                    esp_idf_sys::camera::pixformat_t_PIXFORMAT_GRAYSCALE,
+                    esp_idf_sys::camera::framesize_t_FRAMESIZE_UXGA,
+                ))
+                .map_err(anyhow::Error::from)
+                .and_then(|camera| {
+                    if let Some(profile) = unsafe { SENSOR_PROFILE } {
+                        // The photo may still be readable with the sensor's defaults
+                        if let Err(e) =
+                            espcam::sensor(&camera).and_then(|sensor| sensor.apply(&profile))
+                        {
+                            log::error!("Failed to apply the sensor profile: {e}");
+                        }
+                    }
+                    let brightness = if unsafe { FLASH } {
+                        unsafe { FLASH_BRIGHTNESS }
+                    } else {
+                        0
+                    };
+                    capture::best_photo(&camera, &mut flash, brightness, roi.as_ref())
+                });
+                match photo {
+                    Ok(photo) => {
+                        image = Some(photo);
+                        capture_time = Some(capture_started.elapsed());
+                        Outcome::Succeeded
+                    }
+                    Err(e) => {
+                        log::error!("Failed to take a photo: {e}");
+                        Outcome::Failed
+                    }
+                }
+            }
+            Step::CheckIn => {
+                let request = request.get_or_insert_with(|| {
+                    let diagnostics = diagnostics::collect(wifi_timings.as_ref(), sntp_time);
+                    checkin_request(&settings, captured_at, battery, diagnostics, image.as_ref())
+                });
+                let upload_started = Instant::now();
+                let response = check_in(&settings, request, jpeg(&image));
+                upload_time = Some(upload_started.elapsed());
+                match response {
+                    Ok(response) => {
+                        if let Some(config) = response.config {
+                            apply_config(&config, &mut nvs);
+                        }
+                        if unverified {
+                            match ota::mark_valid() {
+                                Ok(()) => unverified = false,
+                                Err(e) => log::error!("Failed to keep the firmware: {e}"),
+                            }
+                        }
+                        firmware_update = response.firmware;
+                        if let Some(queue) = &mut queue {
+                            if let Err(e) = replay(&settings, queue, started) {
+                                log::error!("Failed to replay queued check-ins: {e}");
+                            }
+                        }
+                        suggested_wake = Some(response.next_wake);
+                        Outcome::Succeeded
+                    }
+                    // The error has been logged already
+                    Err(e) if is_retryable(&e) => Outcome::Failed,
+                    Err(e) if is_rejected(&e) => Outcome::Rejected,
+                    // Kept for a later wake, e.g. once the device key has been fixed
+                    Err(_) => Outcome::Deferred,
+                }
+            }
+            Step::Queue => {
+                let request = request.get_or_insert_with(|| {
+                    let diagnostics = diagnostics::collect(wifi_timings.as_ref(), sntp_time);
+                    checkin_request(&settings, captured_at, battery, diagnostics, image.as_ref())
+                });
+                match &mut queue {
+                    Some(queue) => match enqueue(queue, request, jpeg(&image)) {
+                        Ok(()) => Outcome::Succeeded,
+                        Err(e) => {
+                            log::error!("Failed to queue the check-in: {e}");
+                            Outcome::Failed
+                        }
+                    },
+                    None => Outcome::Rejected,
+                }
+            }
+        };
+        action = cycle.record(outcome, started.elapsed());
+    }
+    diagnostics::remember_durations(capture_time, upload_time);
+    // The wake was not cut short by a reset
+    if let Err(e) = provisioning::clear_resets(&nvs) {
+        log::error!("Failed to clear the reset count: {e}");
     }
 
-    deep_sleep_until(
-        (dt_now + Duration::from_secs(86400))
-            .with_time(CONFIG.wakeup_time)
-            .unwrap(),
-    );
+    if unverified {
+        // The bootloader would roll back on the next start anyway, this way it happens now
+        return Err(ota::roll_back());
+    }
+    if let Some(update) = firmware_update {
+        install_update(&settings, &update, battery, started);
+    }
 
-    Ok(())
+    Ok((cycle.checked_in(), suggested_wake))
 }
 
-fn send_post_request(uri: &str, headers: &[(&str, &str)], data: &[u8]) -> Result<()> {
-    let http_conn = EspHttpConnection::new(&esp_idf_svc::http::client::Configuration {
-        timeout: Some(Duration::from_secs(60)),
-        buffer_size: Some(4096),
-        buffer_size_tx: Some(4096),
-        ..Default::default()
-    })?;
+/// Installs the firmware update the server offered and restarts into it, unless the battery
+/// is too low or the wake has run out of time. A failed update is offered again on the next
+/// check-in.
+fn install_update(
+    settings: &Settings,
+    update: &FirmwareUpdate,
+    battery: Option<Battery>,
+    started: Instant,
+) {
+    if started.elapsed() >= WAKE_DEADLINE {
+        log::info!("No time left for firmware {}", update.version);
+        return;
+    }
+    if let Some(charge) = battery
+        .map(|b| b.state_of_charge)
+        .filter(|&charge| charge < MIN_UPDATE_CHARGE)
+    {
+        log::warn!(
+            "Putting off firmware {} with the battery at {charge:.0} %",
+            update.version
+        );
+        return;
+    }
+    match ota::install(settings, update) {
+        Ok(()) => unsafe { esp_restart() },
+        Err(e) => log::error!("Failed to update the firmware: {e}"),
+    }
+}
+
+/// Goes to deep sleep until the next wake, or sooner when this wake did not reach the server.
+fn sleep(checked_in: bool, suggested_wake: Option<Timestamp>) {
+    let now = chrono::Local::now();
+    let mut target = next_wake(now, suggested_wake);
+    let failed_wakes = unsafe {
+        FAILED_WAKES = if checked_in {
+            0
+        } else {
+            FAILED_WAKES.saturating_add(1)
+        };
+        FAILED_WAKES
+    };
+    if failed_wakes > 0 {
+        let scheduled = (target - now).to_std().unwrap_or(MIN_SLEEP);
+        let sleep = sleep_after_failures(scheduled, failed_wakes, FAILED_WAKE_SLEEP);
+        log::warn!(
+            "{failed_wakes} wakes in a row did not reach the server, trying again in {} s",
+            sleep.as_secs()
+        );
+        target = now + sleep;
+    }
+    deep_sleep_until(target);
+}
+
+/// Signs the request body the way the server expects, returning the timestamp and the
+/// hex encoded HMAC-SHA256 of the timestamp and the body.
+fn sign_request(settings: &Settings, data: &[u8]) -> Result<(String, String)> {
+    let key = settings.device_key().map_err(|e| anyhow!("{e}"))?;
+    let timestamp = chrono::Utc::now().timestamp().to_string();
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(&key).expect("HMAC can take key of any size");
+    mac.update(timestamp.as_bytes());
+    mac.update(b"\n");
+    mac.update(data);
+    Ok((timestamp, hex::encode(&mac.finalize().into_bytes())))
+}
+
+/// Sends a signed POST request and returns the body of a successful response.
+fn send_post_request(
+    settings: &Settings,
+    uri: &str,
+    headers: &[(&str, &str)],
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    let (timestamp, signature) = sign_request(settings, data)?;
+    let mut headers = headers.to_vec();
+    headers.extend([
+        ("X-Device-Id", settings.device_id.as_str()),
+        ("X-Timestamp", &timestamp),
+        ("X-Signature", &signature),
+    ]);
+
+    let http_conn = tls::connect(
+        settings,
+        esp_idf_svc::http::client::Configuration {
+            timeout: Some(Duration::from_secs(60)),
+            buffer_size: Some(4096),
+            buffer_size_tx: Some(4096),
+            ..Default::default()
+        },
+    )
+    .map_err(transport)?;
     let mut client = Client::wrap(http_conn);
-    let mut request = client.request(Method::Post, uri, headers)?;
-    request.write_all(data)?;
-    let response = request.submit()?;
+    let mut request = client
+        .request(Method::Post, uri, &headers)
+        .map_err(transport)?;
+    request.write_all(data).map_err(transport)?;
+    let mut response = request.submit().map_err(transport)?;
+    let status = response.status();
     log::info!(
-        "Response status: {}",
+        "Response status: {} {}",
+        status,
         response.status_message().unwrap_or("None")
     );
-    Ok(())
+    let mut body = [0u8; 2048];
+    let mut len = 0;
+    while len < body.len() {
+        match response.read(&mut body[len..]).map_err(transport)? {
+            0 => break,
+            n => len += n,
+        }
+    }
+    if (200..300).contains(&status) {
+        return Ok(body[..len].to_vec());
+    }
+
+    let error = match serde_json::from_slice::<ErrorBody>(&body[..len]) {
+        Ok(body) => {
+            // The clock is off, so the request has to be signed again with the server's time
+            if let Some(server_time) = body.server_time {
+                set_clock(server_time);
+            }
+            ServerError {
+                status,
+                code: body.error,
+                message: body.message,
+                retryable: body.retryable,
+            }
+        }
+        // Not one of our server's errors, e.g. a proxy in between
+        Err(_) => ServerError {
+            status,
+            code: "unknown".to_string(),
+            message: String::from_utf8_lossy(&body[..len]).into_owned(),
+            retryable: status >= 500 || status == 408 || status == 429,
+        },
+    };
+    Err(error.into())
+}
+
+/// Sets the clock to `time`, for when SNTP could not and the server found it off.
+fn set_clock(time: Timestamp) {
+    let now = esp_idf_sys::timeval {
+        tv_sec: time.timestamp() as _,
+        tv_usec: time.timestamp_subsec_micros() as _,
+    };
+    if unsafe { esp_idf_sys::settimeofday(&now, std::ptr::null()) } == 0 {
+        log::warn!("Clock was off, set it to the server's time {time}");
+    } else {
+        log::error!("Failed to set the clock to the server's time {time}");
+    }
+}
+
+/// The wake time the server suggested if it is plausible, otherwise the next built-in one.
+fn next_wake(
+    now: chrono::DateTime<chrono::Local>,
+    suggested: Option<Timestamp>,
+) -> chrono::DateTime<chrono::Local> {
+    let builtin = (now + Duration::from_secs(86400))
+        .with_time(CONFIG.wakeup_time)
+        .unwrap();
+    match suggested {
+        Some(wake) if wake > now + MIN_SLEEP && wake < now + MAX_SLEEP => {
+            log::info!("Server asks to wake up at {wake}");
+            wake.with_timezone(&chrono::Local)
+        }
+        Some(wake) => {
+            log::warn!("Ignoring implausible wake time {wake} from the server");
+            builtin
+        }
+        None => builtin,
+    }
 }
 
+/// Deep sleeps until `target_time`, or for [`MIN_SLEEP`] if it has passed already. Does not
+/// return, the camera starts over when it wakes up.
 fn deep_sleep_until(target_time: chrono::DateTime<chrono::Local>) {
     let now = chrono::Local::now();
-    if target_time > now {
-        let sleep_duration = target_time - now; // chrono::Duration
-        let sleep_us = sleep_duration.num_microseconds().unwrap_or(0) as u64;
-
-        log::info!(
-            "Deep sleeping until {}, {} s",
-            target_time,
-            sleep_duration.num_seconds()
-        );
-        unsafe {
-            esp_sleep_enable_timer_wakeup(sleep_us);
-            esp_deep_sleep_start();
-        }
-    } else {
-        // Target time is in the past, handle accordingly
-        // For example, sleep a minimal time or skip sleeping
-        log::warn!("Target time is in the past, no deep sleep triggered.");
+    let sleep_duration = (target_time - now).to_std().unwrap_or_else(|_| {
+        log::warn!("Target time {target_time} is in the past");
+        MIN_SLEEP
+    });
+
+    log::info!(
+        "Deep sleeping until {}, {} s",
+        target_time,
+        sleep_duration.as_secs()
+    );
+    unsafe {
+        esp_sleep_enable_timer_wakeup(sleep_duration.as_micros() as u64);
+        esp_deep_sleep_start();
     }
 }
 
-fn send_health_data(dt: chrono::DateTime<chrono::Local>) -> Result<()> {
-    let health_body = serde_json::to_string(&HealthRequest {
-        voltage: 0.0,
-        timestamp: dt.format("%Y-%m-%dT%H:%M:%S").to_string(),
-    })?;
-    log::info!("Health request body {}", health_body);
-    let headers = [
-        ("Content-Type", "application/json"),
-        ("Content-Length", &health_body.len().to_string()),
-    ];
+/// Uses the settings the server sent from the next wake on. The region of interest is kept
+/// in NVS, so that a power loss does not bring back whole photos until the next check-in.
+fn apply_config(config: &DeviceConfig, nvs: &mut nvs::EspNvs<nvs::NvsDefault>) {
+    log::info!("Applying configuration revision {}", config.revision);
+    unsafe {
+        CONFIG_REVISION = config.revision;
+        FLASH = config.flash.unwrap_or(true);
+        FLASH_BRIGHTNESS = config
+            .flash_brightness
+            .unwrap_or(DEFAULT_FLASH_BRIGHTNESS)
+            .clamp(1, 100);
+        FLASH_WARM_UP_MS = config.flash_warm_up_ms.unwrap_or(DEFAULT_FLASH_WARM_UP_MS);
+        SENSOR_PROFILE = config.sensor;
+    }
+    if let Err(e) = provisioning::save_roi(nvs, config.roi.as_ref()) {
+        log::error!("Failed to save the region of interest: {e}");
+    }
+}
 
-    match send_post_request(
-        &format!("{}{}", CONFIG.server_address, CONFIG.health_uri),
-        &headers,
-        health_body.as_bytes(),
-    ) {
-        Ok(_) => log::info!("Health request successful"),
-        Err(e) => {
-            log::error!("Failed to initiate health request: {}", e);
-            return Err(e);
-        }
+/// The telemetry of this wake, `dt` being the time the photo was taken at if there is one.
+fn checkin_request(
+    settings: &Settings,
+    dt: chrono::DateTime<chrono::Local>,
+    battery: Option<Battery>,
+    diagnostics: Diagnostics,
+    photo: Option<&Photo>,
+) -> CheckinRequest {
+    CheckinRequest {
+        version: PROTOCOL_VERSION,
+        device_id: settings.device_id.clone(),
+        firmware_version: FIRMWARE_VERSION.to_string(),
+        timestamp: chrono::Local::now().fixed_offset(),
+        voltage: battery.map(|b| b.voltage),
+        state_of_charge: battery.map(|b| b.state_of_charge),
+        diagnostics: Diagnostics {
+            uptime_ms: Some(diagnostics::uptime_ms()),
+            ..diagnostics
+        },
+        captured_at: photo.map(|_| dt.fixed_offset()),
+        flash_brightness: photo.map(|photo| photo.flash_brightness),
+        config_revision: unsafe { CONFIG_REVISION },
     }
+}
 
+/// The JPEG of the photo, if the wake took one.
+fn jpeg(photo: &Option<Photo>) -> Option<&[u8]> {
+    photo.as_ref().map(|photo| photo.jpeg.as_slice())
+}
+
+/// Keeps a check-in the server did not get for a later wake.
+fn enqueue(
+    queue: &mut OfflineQueue<SdStorage>,
+    request: &CheckinRequest,
+    image: Option<&[u8]>,
+) -> Result<()> {
+    let dropped = queue
+        .push(&serde_json::to_vec(request)?, image)
+        .map_err(sd_error)?;
+    if dropped > 0 {
+        log::warn!("Offline queue is full, dropped the {dropped} oldest check-ins");
+    }
+    log::info!("Queued the check-in for a later wake");
+    Ok(())
+}
+
+/// Sends the check-ins earlier wakes could not deliver, oldest first, until one fails or the
+/// wake runs out of time. They are removed from the queue once the server has them, or has
+/// rejected their content for good. Any other failure, e.g. of the signature, stops the
+/// replay and keeps them all.
+fn replay(
+    settings: &Settings,
+    queue: &mut OfflineQueue<SdStorage>,
+    started: Instant,
+) -> Result<()> {
+    for _ in 0..REPLAYS_PER_WAKE {
+        if started.elapsed() >= WAKE_DEADLINE {
+            break;
+        }
+        let Some(queued) = queue.oldest().map_err(sd_error)? else {
+            return Ok(());
+        };
+        log::info!("Replaying queued check-in {}", queued.sequence);
+        match serde_json::from_slice::<CheckinRequest>(&queued.telemetry) {
+            Ok(request) => match check_in(settings, &request, queued.image.as_deref()) {
+                Ok(_) => {}
+                Err(e) if is_rejected(&e) => {
+                    log::warn!("Dropping queued check-in {}", queued.sequence)
+                }
+                // Kept for a later wake, whatever kept it from the server may be fixed by then
+                Err(_) => return Ok(()),
+            },
+            Err(e) => log::error!(
+                "Dropping unreadable queued check-in {}: {e}",
+                queued.sequence
+            ),
+        }
+        queue.acknowledge(queued.sequence).map_err(sd_error)?;
+    }
+    let left = queue.len().map_err(sd_error)?;
+    if left > 0 {
+        log::info!("{left} queued check-ins left for the next wake");
+    }
     Ok(())
 }
 
-fn send_image(filename: &str, image: &[u8]) -> Result<()> {
+/// Sends the telemetry and the photo, if there is one, to the server in a single request.
+fn check_in(
+    settings: &Settings,
+    request: &CheckinRequest,
+    image: Option<&[u8]>,
+) -> Result<CheckinResponse> {
+    let telemetry = serde_json::to_string(request)?;
+    log::info!("Check-in telemetry {}", telemetry);
+
     let boundary = "----WebKitFormBoundary7MA4YWxkTrZu0gW";
     let mut body = Vec::new();
-    // Build multipart body
+    // Build multipart body, the telemetry has to come before the photo
     write!(body, "--{}\r\n", boundary)?;
     write!(
         body,
-        "Content-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n",
-        filename
+        "Content-Disposition: form-data; name=\"telemetry\"\r\n"
     )?;
-    write!(body, "Content-Type: image/jpeg\r\n\r\n")?;
-    body.extend_from_slice(image);
-    write!(body, "\r\n--{}--\r\n", boundary)?;
+    write!(body, "Content-Type: application/json\r\n\r\n")?;
+    write!(body, "{}\r\n", telemetry)?;
+    if let Some(image) = image {
+        write!(body, "--{}\r\n", boundary)?;
+        write!(
+            body,
+            "Content-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\n",
+            request
+                .captured_at
+                .unwrap_or(request.timestamp)
+                .format("%Y-%m-%dT%H:%M:%S.jpg")
+        )?;
+        write!(body, "Content-Type: image/jpeg\r\n\r\n")?;
+        body.extend_from_slice(image);
+        write!(body, "\r\n")?;
+    }
+    write!(body, "--{}--\r\n", boundary)?;
 
     let headers: [(&str, &str); 2] = [
         (
@@ -200,16 +823,27 @@ fn send_image(filename: &str, image: &[u8]) -> Result<()> {
     ];
 
     match send_post_request(
-        &format!("{}{}", CONFIG.server_address, CONFIG.upload_uri),
+        settings,
+        &format!("{}{}", settings.server_address, CONFIG.checkin_uri),
         &headers,
         &body,
     ) {
-        Ok(_) => log::info!("Uploaded file {filename}"),
+        Ok(response) => {
+            let response: CheckinResponse = serde_json::from_slice(&response)?;
+            match &response.file {
+                Some(file) => log::info!("Checked in, photo stored as {}", file.file_name),
+                None => log::info!("Checked in without a photo"),
+            }
+            Ok(response)
+        }
         Err(e) => {
-            log::error!("Failed to upload file {filename}: {}", e);
-            return Err(e);
+            let kind = if is_retryable(&e) {
+                "retryable"
+            } else {
+                "permanent"
+            };
+            log::error!("Failed to check in ({kind}): {}", e);
+            Err(e)
         }
     }
-
-    Ok(())
 }
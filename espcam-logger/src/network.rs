@@ -1,20 +1,41 @@
 use anyhow::{bail, Result};
+use digit_protocol::settings::device_id_from_mac;
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::peripheral,
     sntp::{EspSntp, OperatingMode, SntpConf, SyncMode, SyncStatus},
-    wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi},
+    wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi, WifiDeviceId},
 };
 use log::info;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// How long connecting to the Wi-Fi network took.
+pub struct WifiTimings {
+    pub associate: Duration,
+    pub dhcp: Duration,
+}
 
 pub fn wifi(
-    ssid: &str,
-    pass: &str,
     modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
     sysloop: EspSystemEventLoop,
 ) -> Result<Box<EspWifi<'static>>> {
+    Ok(Box::new(EspWifi::new(modem, sysloop, None)?))
+}
+
+/// The id this camera goes by unless it was given another, derived from its station MAC
+/// address.
+pub fn device_id(esp_wifi: &EspWifi<'static>) -> Result<String> {
+    Ok(device_id_from_mac(esp_wifi.get_mac(WifiDeviceId::Sta)?))
+}
+
+/// Connects to the access point `ssid`. Can be called again after it failed.
+pub fn connect(
+    esp_wifi: &mut EspWifi<'static>,
+    sysloop: EspSystemEventLoop,
+    ssid: &str,
+    pass: &str,
+) -> Result<WifiTimings> {
     let mut auth_method = AuthMethod::WPA2Personal;
     if ssid.is_empty() {
         bail!("Missing WiFi name")
@@ -23,15 +44,19 @@ pub fn wifi(
         auth_method = AuthMethod::None;
         info!("Wifi password is empty");
     }
-    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), None)?;
 
-    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
+    let mut wifi = BlockingWifi::wrap(esp_wifi, sysloop)?;
 
-    wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+    if wifi.is_started()? {
+        // Left over from a failed attempt
+        let _ = wifi.disconnect();
+    } else {
+        wifi.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
 
-    info!("Starting wifi...");
+        info!("Starting wifi...");
 
-    wifi.start()?;
+        wifi.start()?;
+    }
 
     info!("Scanning...");
 
@@ -67,20 +92,25 @@ pub fn wifi(
 
     info!("Connecting wifi...");
 
+    let started = Instant::now();
     wifi.connect()?;
+    let associate = started.elapsed();
 
     info!("Waiting for DHCP lease...");
 
+    let started = Instant::now();
     wifi.wait_netif_up()?;
+    let dhcp = started.elapsed();
 
     let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
 
     info!("Wifi DHCP info: {:?}", ip_info);
 
-    Ok(Box::new(esp_wifi))
+    Ok(WifiTimings { associate, dhcp })
 }
 
-pub fn sntp(server: &str) -> Result<EspSntp> {
+/// Sets the clock from `server`, giving up after `timeout`.
+pub fn sntp(server: &str, timeout: Duration) -> Result<EspSntp<'static>> {
     let conf = SntpConf {
         servers: [server],
         operating_mode: OperatingMode::Poll,
@@ -91,7 +121,11 @@ pub fn sntp(server: &str) -> Result<EspSntp> {
     info!("SNTP set up!");
 
     log::info!("Synchronising NTP...");
+    let started = Instant::now();
     while sntp.get_sync_status() != SyncStatus::Completed {
+        if started.elapsed() > timeout {
+            bail!("No answer from {server} in {} s", timeout.as_secs());
+        }
         thread::sleep(Duration::from_millis(100));
     }
 
@@ -0,0 +1,147 @@
+use anyhow::{anyhow, bail, Result};
+use digit_protocol::hex;
+use digit_protocol::ota::{is_newer, signed_message, FirmwareVersion};
+use digit_protocol::settings::Settings;
+use digit_protocol::FirmwareUpdate;
+use ed25519_dalek::{Signature, VerifyingKey};
+use embedded_svc::{
+    http::client::{Client, Response},
+    http::Method,
+    io::Read,
+};
+use esp_idf_svc::http::client::EspHttpConnection;
+use esp_idf_svc::ota::{EspOta, EspOtaUpdate, SlotState};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+/// Hex encoded public key the releases are signed with, see `digit-server firmware keygen`.
+/// Firmware built without it does not update itself.
+const PUBLIC_KEY: Option<&str> = option_env!("DIGIT_FIRMWARE_PUBLIC_KEY");
+
+/// Whether this is the first boot of a newly installed image, which is rolled back unless
+/// [`mark_valid`] is called before the camera resets or sleeps.
+pub fn running_unverified() -> Result<bool> {
+    let slot = EspOta::new()?.get_running_slot()?;
+    Ok(matches!(slot.state, SlotState::Unverified))
+}
+
+/// Keeps the running image for good.
+pub fn mark_valid() -> Result<()> {
+    EspOta::new()?.mark_running_slot_valid()?;
+    log::info!("Firmware {} is valid", crate::FIRMWARE_VERSION);
+    Ok(())
+}
+
+/// Goes back to the previous image and restarts. Only returns if that is not possible.
+pub fn roll_back() -> anyhow::Error {
+    log::error!(
+        "Firmware {} did not check in, rolling back",
+        crate::FIRMWARE_VERSION
+    );
+    match EspOta::new() {
+        Ok(mut ota) => ota.mark_running_slot_invalid_and_reboot().into(),
+        Err(e) => e.into(),
+    }
+}
+
+/// Downloads `update` into the other app partition and boots from it on the next start.
+/// The signature is checked before the download and the image against the signed hash while
+/// it is written, so that nothing but a release signed with [`PUBLIC_KEY`] is ever booted.
+/// Only a release newer than the running firmware is taken, the signature covers the
+/// version, so that nobody can roll the camera back to an older release with known flaws.
+pub fn install(settings: &Settings, update: &FirmwareUpdate) -> Result<()> {
+    let Some(public_key) = PUBLIC_KEY else {
+        bail!(
+            "Built without DIGIT_FIRMWARE_PUBLIC_KEY, ignoring firmware {}",
+            update.version
+        );
+    };
+    let version: FirmwareVersion = update.version.parse().map_err(|e| anyhow!("{e}"))?;
+    if !is_newer(crate::FIRMWARE_VERSION, version) {
+        bail!(
+            "Firmware {version} is not newer than the running {}, ignoring it",
+            crate::FIRMWARE_VERSION
+        );
+    }
+    let public_key = VerifyingKey::from_bytes(&decode_hex(public_key)?)
+        .map_err(|_| anyhow!("Built-in firmware key is not an Ed25519 key"))?;
+    let sha256: [u8; 32] = decode_hex(&update.sha256)?;
+    let signature = Signature::from_bytes(&decode_hex(&update.signature)?);
+    public_key
+        .verify_strict(&signed_message(version, &sha256), &signature)
+        .map_err(|_| anyhow!("Firmware {version} is not signed with the built-in key"))?;
+
+    log::info!("Downloading firmware {version}, {} bytes", update.size);
+    let (timestamp, signature) = crate::sign_request(settings, b"")?;
+    let headers = [
+        ("X-Device-Id", settings.device_id.as_str()),
+        ("X-Timestamp", &timestamp),
+        ("X-Signature", &signature),
+    ];
+    let http_conn = crate::tls::connect(
+        settings,
+        esp_idf_svc::http::client::Configuration {
+            timeout: Some(Duration::from_secs(60)),
+            buffer_size: Some(4096),
+            ..Default::default()
+        },
+    )?;
+    let mut client = Client::wrap(http_conn);
+    let uri = format!("{}{}", settings.server_address, update.path);
+    let mut response = client.request(Method::Get, &uri, &headers)?.submit()?;
+    if response.status() != 200 {
+        bail!(
+            "Server responded {} to the firmware download",
+            response.status()
+        );
+    }
+
+    let mut ota = EspOta::new()?;
+    let mut partition = ota.initiate_update()?;
+    let written = write_image(&mut response, &mut partition, update.size as usize);
+    match written {
+        Ok((size, digest)) if size == update.size as usize && digest == sha256 => {
+            // Checks the image header too and boots the new image from the next start on
+            partition.complete()?;
+            log::info!("Installed firmware {version}");
+            Ok(())
+        }
+        Ok((size, _)) => {
+            partition.abort()?;
+            bail!("Downloaded firmware {version} ({size} bytes) does not match its signed hash")
+        }
+        Err(e) => {
+            partition.abort()?;
+            Err(e)
+        }
+    }
+}
+
+/// Copies the response into the partition, returning its size and SHA-256. Stops at
+/// `max_size`, the release is not larger than that.
+fn write_image(
+    response: &mut Response<&mut EspHttpConnection>,
+    partition: &mut EspOtaUpdate,
+    max_size: usize,
+) -> Result<(usize, [u8; 32])> {
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 4096];
+    let mut size = 0;
+    loop {
+        let n = response.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        size += n;
+        if size > max_size {
+            bail!("Firmware download is larger than the {max_size} bytes offered");
+        }
+        hasher.update(&buf[..n]);
+        partition.write(&buf[..n])?;
+    }
+    Ok((size, hasher.finalize().into()))
+}
+
+fn decode_hex<const N: usize>(hex: &str) -> Result<[u8; N]> {
+    hex::decode_array(hex).ok_or_else(|| anyhow!("Expected {N} hex encoded bytes, got {hex:?}"))
+}
@@ -0,0 +1,336 @@
+use anyhow::Result;
+use digit_protocol::roi::Roi;
+use digit_protocol::settings::Settings;
+
+use crate::network;
+use embedded_svc::{
+    http::Method,
+    io::{Read, Write},
+};
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    http::server::{Configuration as HttpConfiguration, EspHttpServer},
+    nvs::{EspNvs, NvsDefault},
+    wifi::{AccessPointConfiguration, AuthMethod, BlockingWifi, Configuration, EspWifi},
+};
+use std::fmt::Write as _;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// NVS namespace everything of this firmware is kept in.
+pub const NAMESPACE: &str = "digit";
+/// Settings as JSON.
+const SETTINGS_KEY: &str = "settings";
+/// Boots in a row by the reset button or by plugging the camera in.
+const RESETS_KEY: &str = "resets";
+/// Region of the photo to upload as JSON, set by the server.
+const ROI_KEY: &str = "roi";
+
+/// Resetting the camera this many times in a row, each time before it has finished its wake,
+/// starts the setup portal again. The ESP32-CAM has no button to spare for it, the one on
+/// GPIO0 is the camera's clock.
+const RESETS_TO_PROVISION: u8 = 3;
+/// Name of the open access point the setup portal is reached on.
+const AP_SSID: &str = "espcam-setup";
+/// How long the portal waits for the settings before giving up.
+const PORTAL_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+/// Largest form the portal accepts, room for a URL encoded certificate.
+const MAX_FORM_LEN: usize = 8192;
+/// Largest settings blob, the certificate takes up most of it.
+const MAX_SETTINGS_LEN: usize = 4096;
+
+/// The settings kept in NVS, or `None` before the camera has been set up. Settings that do
+/// not validate are treated as missing, so that they are entered again.
+pub fn load(nvs: &EspNvs<NvsDefault>) -> Result<Option<Settings>> {
+    let mut buf = vec![0u8; MAX_SETTINGS_LEN];
+    let Some(json) = nvs.get_blob(SETTINGS_KEY, &mut buf)? else {
+        return Ok(None);
+    };
+    let settings = match serde_json::from_slice::<Settings>(json) {
+        Ok(settings) => settings,
+        Err(e) => {
+            log::warn!("Ignoring unreadable settings: {e}");
+            return Ok(None);
+        }
+    };
+    match settings.validate() {
+        Ok(()) => Ok(Some(settings)),
+        Err(e) => {
+            log::warn!("Ignoring invalid settings: {e}");
+            Ok(None)
+        }
+    }
+}
+
+pub fn save(nvs: &mut EspNvs<NvsDefault>, settings: &Settings) -> Result<()> {
+    nvs.set_blob(SETTINGS_KEY, &serde_json::to_vec(settings)?)?;
+    log::info!("Saved the settings");
+    Ok(())
+}
+
+/// Counts this boot if the user reset the camera, returning whether they did it often
+/// enough in a row to ask for the setup portal. The count is cleared by
+/// [`clear_resets`] at the end of every wake that was not interrupted.
+pub fn reset_gesture(nvs: &EspNvs<NvsDefault>, reset_reason: &str) -> Result<bool> {
+    // The reset button pulls EN low, which the chip reports as a power-on
+    if !matches!(reset_reason, "power_on" | "external") {
+        return Ok(false);
+    }
+    let resets = nvs.get_u8(RESETS_KEY)?.unwrap_or(0).saturating_add(1);
+    if resets >= RESETS_TO_PROVISION {
+        log::info!("Reset {resets} times in a row, starting the setup portal");
+        nvs.set_u8(RESETS_KEY, 0)?;
+        return Ok(true);
+    }
+    nvs.set_u8(RESETS_KEY, resets)?;
+    Ok(false)
+}
+
+pub fn clear_resets(nvs: &EspNvs<NvsDefault>) -> Result<()> {
+    nvs.set_u8(RESETS_KEY, 0)?;
+    Ok(())
+}
+
+/// The region of the photo to upload, `None` for the whole photo. A region that does not
+/// validate is ignored, a whole photo is more use than none.
+pub fn load_roi(nvs: &EspNvs<NvsDefault>) -> Result<Option<Roi>> {
+    let mut buf = [0u8; 128];
+    let Some(json) = nvs.get_blob(ROI_KEY, &mut buf)? else {
+        return Ok(None);
+    };
+    match serde_json::from_slice::<Roi>(json).map(|roi| (roi.validate(), roi)) {
+        Ok((Ok(()), roi)) => Ok(Some(roi)),
+        Ok((Err(e), _)) => {
+            log::warn!("Ignoring invalid region of interest: {e}");
+            Ok(None)
+        }
+        Err(e) => {
+            log::warn!("Ignoring unreadable region of interest: {e}");
+            Ok(None)
+        }
+    }
+}
+
+/// Keeps the region of the photo to upload across power losses, `None` removes it.
+pub fn save_roi(nvs: &mut EspNvs<NvsDefault>, roi: Option<&Roi>) -> Result<()> {
+    match roi {
+        Some(roi) => nvs.set_blob(ROI_KEY, &serde_json::to_vec(roi)?)?,
+        None => {
+            nvs.remove(ROI_KEY)?;
+        }
+    }
+    Ok(())
+}
+
+/// Opens the access point [`AP_SSID`] with a captive portal whose form sets the settings,
+/// prefilled with `current`. Returns the settings once they were submitted and saved, or
+/// `None` when nobody submitted them in [`PORTAL_TIMEOUT`].
+pub fn portal(
+    esp_wifi: &mut EspWifi<'static>,
+    sysloop: EspSystemEventLoop,
+    nvs: &mut EspNvs<NvsDefault>,
+    current: Option<Settings>,
+) -> Result<Option<Settings>> {
+    let mut wifi = BlockingWifi::wrap(esp_wifi, sysloop)?;
+    wifi.set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+        ssid: AP_SSID.try_into().expect("SSID fits"),
+        auth_method: AuthMethod::None,
+        max_connections: 2,
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+    wifi.wait_netif_up()?;
+    let ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    log::info!("Setup portal on access point {AP_SSID}, http://{ip}/");
+    let device_id = Arc::new(network::device_id(wifi.wifi())?);
+
+    thread::Builder::new().stack_size(4096).spawn(move || {
+        if let Err(e) = answer_dns(ip) {
+            log::error!("DNS server failed: {e}");
+        }
+    })?;
+
+    let submitted = Arc::new(Mutex::new(None::<Settings>));
+    let current = Arc::new(current);
+    let mut server = EspHttpServer::new(&HttpConfiguration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    let form_settings = current.clone();
+    let form_device_id = device_id.clone();
+    server.fn_handler("/", Method::Get, move |request| {
+        let page = form((*form_settings).as_ref(), &form_device_id, None);
+        request.into_ok_response()?.write_all(page.as_bytes())
+    })?;
+
+    let form_settings = current.clone();
+    let form_device_id = device_id.clone();
+    let form_submitted = submitted.clone();
+    server.fn_handler("/", Method::Post, move |mut request| {
+        let mut body = vec![0; MAX_FORM_LEN];
+        let mut len = 0;
+        while len < body.len() {
+            match request.read(&mut body[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        match Settings::from_form(&body[..len], (*form_settings).as_ref()) {
+            Ok(settings) => {
+                *form_submitted.lock().unwrap() = Some(settings);
+                request.into_ok_response()?.write_all(SAVED_PAGE.as_bytes())
+            }
+            Err(e) => {
+                let page = form(
+                    (*form_settings).as_ref(),
+                    &form_device_id,
+                    Some(&e.to_string()),
+                );
+                request
+                    .into_status_response(400)?
+                    .write_all(page.as_bytes())
+            }
+        }
+    })?;
+
+    // Phones probe a known address to detect captive portals, sending them to the form
+    // makes the phone open it
+    let location = format!("http://{ip}/");
+    server.fn_handler("/*", Method::Get, move |request| {
+        request
+            .into_response(302, Some("Found"), &[("Location", &location)])
+            .map(|_| ())
+    })?;
+
+    let started = Instant::now();
+    while started.elapsed() < PORTAL_TIMEOUT {
+        if let Some(settings) = submitted.lock().unwrap().take() {
+            save(nvs, &settings)?;
+            // Let the response reach the browser before the caller restarts
+            thread::sleep(Duration::from_secs(1));
+            return Ok(Some(settings));
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+    log::warn!("No settings submitted in {} s", PORTAL_TIMEOUT.as_secs());
+    Ok(None)
+}
+
+/// Answers every DNS query with `ip`, so that any address the client looks up leads to the
+/// portal.
+fn answer_dns(ip: Ipv4Addr) -> Result<()> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 53))?;
+    let mut buf = [0u8; 512];
+    loop {
+        let (len, client) = socket.recv_from(&mut buf)?;
+        let Some(response) = dns_response(&buf[..len], ip) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&response, client) {
+            log::warn!("Failed to answer DNS query: {e}");
+        }
+    }
+}
+
+/// An answer to the first question of `query` pointing at `ip`, or `None` if `query` is not
+/// a query with a question.
+fn dns_response(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    const HEADER_LEN: usize = 12;
+    let is_query = query.len() > HEADER_LEN && query[2] & 0x80 == 0;
+    let questions = u16::from_be_bytes([*query.get(4)?, *query.get(5)?]);
+    if !is_query || questions == 0 {
+        return None;
+    }
+    // The name is a sequence of length prefixed labels ending with a zero length, followed
+    // by the type and class
+    let mut end = HEADER_LEN;
+    while *query.get(end)? != 0 {
+        end += 1 + *query.get(end)? as usize;
+    }
+    let question = query.get(HEADER_LEN..end + 5)?;
+
+    // Only IPv4 addresses are answered, other types get an empty answer
+    let is_a = question[question.len() - 4..question.len() - 2] == [0, 1];
+
+    let mut response = Vec::with_capacity(question.len() + 32);
+    response.extend_from_slice(&query[..2]);
+    // A recursion-desired response without errors, with one question and maybe one answer
+    response.extend_from_slice(&[0x81, 0x80, 0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+    response.extend_from_slice(question);
+    if is_a {
+        // Pointer to the name in the question, type A, class IN, TTL 60 s, 4 bytes of address
+        response.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        response.extend_from_slice(&ip.octets());
+    }
+    Some(response)
+}
+
+const SAVED_PAGE: &str = "<!DOCTYPE html><html><head><meta name=\"viewport\" \
+    content=\"width=device-width\"><title>Digit setup</title></head><body>\
+    <p>Saved. The camera restarts and connects to the network.</p></body></html>";
+
+/// The settings form, prefilled with `current` except for the secrets, with `error` above it.
+/// `device_id` is the one derived from the MAC, used when the field is left empty.
+fn form(current: Option<&Settings>, device_id: &str, error: Option<&str>) -> String {
+    let value = |field: fn(&Settings) -> &str| current.map_or("", field);
+    let mut page = String::from(
+        "<!DOCTYPE html><html><head><meta name=\"viewport\" content=\"width=device-width\">\
+        <title>Digit setup</title></head><body><h1>Digit setup</h1>",
+    );
+    if let Some(error) = error {
+        let _ = write!(page, "<p style=\"color:red\">{}</p>", escape(error));
+    }
+    let secret_hint = if current.is_some() {
+        " placeholder=\"unchanged\""
+    } else {
+        ""
+    };
+    // Without settings to keep the key of, one has to be entered
+    let key_hint = if current.is_some() {
+        secret_hint
+    } else {
+        " required"
+    };
+    let certificate_hint = match current {
+        Some(current) if !current.server_certificate.is_empty() => " placeholder=\"unchanged\"",
+        _ => " placeholder=\"-----BEGIN CERTIFICATE-----\"",
+    };
+    let _ = write!(
+        page,
+        "<form method=\"post\" action=\"/\">\
+        <p><label>Wi-Fi name<br><input name=\"wifi_ssid\" value=\"{}\" maxlength=\"32\" required></label></p>\
+        <p><label>Wi-Fi password<br><input name=\"wifi_password\" type=\"password\"{secret_hint}></label>\
+        <label><input name=\"clear_password\" type=\"checkbox\"> open network</label></p>\
+        <p><label>Server address<br><input name=\"server_address\" value=\"{}\" \
+        placeholder=\"http://synology:3000\" required></label></p>\
+        <p><label>Server certificate, for https:// only<br><textarea name=\"server_certificate\" \
+        rows=\"6\" cols=\"40\"{certificate_hint}></textarea></label></p>\
+        <p><label>Device id, leave empty for {device_id}<br><input name=\"device_id\" value=\"{}\" \
+        placeholder=\"{device_id}\"></label></p>\
+        <p><label>Device key, from digit-server keys add<br>\
+        <input name=\"device_key\"{key_hint}></label></p>\
+        <p><button>Save</button></p></form></body></html>",
+        escape(value(|s| s.wifi_ssid.as_str())),
+        escape(value(|s| s.server_address.as_str())),
+        escape(value(|s| s.device_id.as_str())),
+    );
+    page
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,215 @@
+use anyhow::{anyhow, Result};
+use chrono::{Datelike, Timelike};
+use digit_protocol::queue::QueueStorage;
+use embedded_hal::spi::{ErrorType, SpiBus, SpiDevice};
+use embedded_hal_bus::spi::ExclusiveDevice;
+use embedded_sdmmc::{
+    Mode, RawDirectory, RawFile, SdCard, SdCardError, TimeSource, Timestamp, VolumeIdx,
+    VolumeManager,
+};
+use esp_idf_hal::delay::Ets;
+use esp_idf_hal::gpio::{AnyOutputPin, Gpio13, Gpio14, Gpio15, Gpio2, Output, PinDriver};
+use esp_idf_hal::peripheral::Peripheral;
+use esp_idf_hal::spi::{config, SpiDeviceDriver, SpiDriver, SpiError, SPI2};
+use esp_idf_hal::units::{FromValueType, Hertz};
+use std::rc::Rc;
+
+/// Directory in the root of the card the queue is kept in.
+const QUEUE_DIRECTORY: &str = "QUEUE";
+
+/// Cards have to be initialised at 400 kHz at most.
+const INIT_BAUDRATE_KHZ: u32 = 400;
+/// Clock of the bus once the card is initialised. Every card manages 25 MHz in SPI mode, the
+/// wiring of the ESP32-CAM slot is not made for more.
+const BAUDRATE_MHZ: u32 = 20;
+
+type Spi = ExclusiveDevice<Bus, PinDriver<'static, Gpio13, Output>, Ets>;
+
+pub type Error = embedded_sdmmc::Error<SdCardError>;
+
+/// Dates the files with the system clock, which SNTP has set by the time anything is written.
+pub struct Clock;
+
+impl TimeSource for Clock {
+    fn get_timestamp(&self) -> Timestamp {
+        let now = chrono::Local::now();
+        Timestamp::from_calendar(
+            now.year().clamp(1970, 2225) as u16,
+            now.month() as u8,
+            now.day() as u8,
+            now.hour() as u8,
+            now.minute() as u8,
+            now.second() as u8,
+        )
+        .expect("Clamped date is valid")
+    }
+}
+
+/// The queue directory on the SD card in the slot of the ESP32-CAM.
+pub struct SdStorage {
+    volume_manager: VolumeManager<SdCard<Spi, Ets>, Clock>,
+    directory: RawDirectory,
+}
+
+impl SdStorage {
+    /// Mounts the first partition of the card in SPI mode and opens the queue directory,
+    /// creating it on first use. The card has to be FAT formatted.
+    pub fn mount(
+        spi: impl Peripheral<P = SPI2> + 'static,
+        sclk: impl Peripheral<P = Gpio14> + 'static,
+        mosi: impl Peripheral<P = Gpio15> + 'static,
+        miso: impl Peripheral<P = Gpio2> + 'static,
+        cs: impl Peripheral<P = Gpio13> + 'static,
+    ) -> Result<Self> {
+        let driver = SpiDriver::new(spi, sclk, mosi, Some(miso), &config::DriverConfig::new())?;
+        let bus = Bus::new(driver, INIT_BAUDRATE_KHZ.kHz().into())?;
+        let device = ExclusiveDevice::new(bus, PinDriver::output(cs)?, Ets)?;
+        let card = SdCard::new(device, Ets);
+        // Asking for the size initialises the card, after that it can be read at full speed
+        let size = card
+            .num_bytes()
+            .map_err(|e| anyhow!("SD card error: {e:?}"))?;
+        card.spi(|device| device.bus_mut().set_baudrate(BAUDRATE_MHZ.MHz().into()))?;
+        let volume_manager = VolumeManager::new(card, Clock);
+
+        let directory = open_queue_directory(&volume_manager).map_err(sd_error)?;
+        log::info!("Mounted SD card of {} MB", size / 1_000_000);
+        Ok(Self {
+            volume_manager,
+            directory,
+        })
+    }
+
+    fn read_file(&self, file: RawFile) -> Result<Vec<u8>, Error> {
+        let mut data = vec![0; self.volume_manager.file_length(file)? as usize];
+        let mut len = 0;
+        while len < data.len() {
+            match self.volume_manager.read(file, &mut data[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        data.truncate(len);
+        Ok(data)
+    }
+}
+
+/// The SPI bus of the card. The clock of an SPI device is fixed when it is added to the bus,
+/// so the device is added again to change it. The chip select is left to [`ExclusiveDevice`].
+struct Bus {
+    driver: Rc<SpiDriver<'static>>,
+    device: SpiDeviceDriver<'static, Rc<SpiDriver<'static>>>,
+}
+
+impl Bus {
+    fn new(driver: SpiDriver<'static>, baudrate: Hertz) -> Result<Self> {
+        let driver = Rc::new(driver);
+        let device = Self::add_device(&driver, baudrate)?;
+        Ok(Self { driver, device })
+    }
+
+    fn add_device(
+        driver: &Rc<SpiDriver<'static>>,
+        baudrate: Hertz,
+    ) -> Result<SpiDeviceDriver<'static, Rc<SpiDriver<'static>>>> {
+        Ok(SpiDeviceDriver::new(
+            driver.clone(),
+            Option::<AnyOutputPin>::None,
+            &config::Config::new().baudrate(baudrate),
+        )?)
+    }
+
+    /// Replacing the device removes the previous one from the bus.
+    fn set_baudrate(&mut self, baudrate: Hertz) -> Result<()> {
+        self.device = Self::add_device(&self.driver, baudrate)?;
+        Ok(())
+    }
+}
+
+impl ErrorType for Bus {
+    type Error = SpiError;
+}
+
+impl SpiBus for Bus {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), SpiError> {
+        SpiDevice::read(&mut self.device, words)
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), SpiError> {
+        SpiDevice::write(&mut self.device, words)
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), SpiError> {
+        SpiDevice::transfer(&mut self.device, read, write)
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), SpiError> {
+        SpiDevice::transfer_in_place(&mut self.device, words)
+    }
+
+    /// Every transfer has finished by the time it returns.
+    fn flush(&mut self) -> Result<(), SpiError> {
+        Ok(())
+    }
+}
+
+fn open_queue_directory(
+    volume_manager: &VolumeManager<SdCard<Spi, Ets>, Clock>,
+) -> Result<RawDirectory, Error> {
+    let volume = volume_manager.open_raw_volume(VolumeIdx(0))?;
+    let root = volume_manager.open_root_dir(volume)?;
+    match volume_manager.make_dir_in_dir(root, QUEUE_DIRECTORY) {
+        Ok(()) | Err(embedded_sdmmc::Error::DirAlreadyExists) => {}
+        Err(e) => return Err(e),
+    }
+    let directory = volume_manager.open_dir(root, QUEUE_DIRECTORY)?;
+    volume_manager.close_dir(root)?;
+    Ok(directory)
+}
+
+/// The errors of the card only implement `Debug`.
+pub fn sd_error(error: Error) -> anyhow::Error {
+    anyhow!("SD card error: {error:?}")
+}
+
+impl QueueStorage for SdStorage {
+    type Error = Error;
+
+    fn files(&mut self) -> Result<Vec<String>, Error> {
+        let mut files = Vec::new();
+        self.volume_manager.iterate_dir(self.directory, |entry| {
+            if !entry.attributes.is_directory() && !entry.attributes.is_volume() {
+                files.push(entry.name.to_string());
+            }
+        })?;
+        Ok(files)
+    }
+
+    fn read(&mut self, name: &str) -> Result<Vec<u8>, Error> {
+        let file = self
+            .volume_manager
+            .open_file_in_dir(self.directory, name, Mode::ReadOnly)?;
+        let data = self.read_file(file);
+        // Close the file even after a failed read, an open file cannot be opened again
+        let closed = self.volume_manager.close_file(file);
+        let data = data?;
+        closed?;
+        Ok(data)
+    }
+
+    fn write(&mut self, name: &str, data: &[u8]) -> Result<(), Error> {
+        let file = self.volume_manager.open_file_in_dir(
+            self.directory,
+            name,
+            Mode::ReadWriteCreateOrTruncate,
+        )?;
+        let written = self.volume_manager.write(file, data);
+        // Closing flushes the file to the card
+        let closed = self.volume_manager.close_file(file);
+        written.and(closed)
+    }
+
+    fn remove(&mut self, name: &str) -> Result<(), Error> {
+        self.volume_manager.delete_file_in_dir(self.directory, name)
+    }
+}
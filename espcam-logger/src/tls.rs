@@ -0,0 +1,34 @@
+use anyhow::{anyhow, Result};
+use digit_protocol::settings::Settings;
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_idf_sys::{esp, esp_tls_free_global_ca_store, esp_tls_set_global_ca_store};
+use std::ffi::CString;
+
+/// Makes the server certificate of `settings` the only one HTTPS connections trust. The
+/// certificate bundle of ESP-IDF is never attached, so a server is only accepted if its
+/// certificate is the pinned one or was issued by it, and its name matches the address.
+pub fn pin(settings: &Settings) -> Result<()> {
+    if !settings.uses_tls() {
+        return Ok(());
+    }
+    // mbedtls only recognizes PEM with its terminating nul counted in
+    let pem = CString::new(settings.server_certificate.as_str())
+        .map_err(|_| anyhow!("Server certificate contains a nul byte"))?;
+    let pem = pem.as_bytes_with_nul();
+    unsafe {
+        esp_tls_free_global_ca_store();
+        esp!(esp_tls_set_global_ca_store(pem.as_ptr(), pem.len() as _))?;
+    }
+    log::info!("Pinned the server certificate");
+    Ok(())
+}
+
+/// A connection to the server, verified against the certificate from [`pin`] when it is
+/// reached over HTTPS.
+pub fn connect(settings: &Settings, configuration: Configuration) -> Result<EspHttpConnection> {
+    Ok(EspHttpConnection::new(&Configuration {
+        use_global_ca_store: settings.uses_tls(),
+        crt_bundle_attach: None,
+        ..configuration
+    })?)
+}